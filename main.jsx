@@ -4,6 +4,11 @@ import ReactDOM from "react-dom";
 import Immutable from "immutable";
 import _ from "underscore";
 
+function csrfToken(): string {
+  const meta = document.querySelector("meta[name=csrf-token]");
+  return meta ? meta.getAttribute("content") : "";
+}
+
 function http(url: string, method, data): Promise<string> {
   return new Promise((resolve, reject) => {
     const xhr = new XMLHttpRequest();
@@ -21,6 +26,9 @@ function http(url: string, method, data): Promise<string> {
     };
     xhr.onerror = (e: Error) => { reject(e); };
     xhr.open(method, url);
+    if (method !== "get") {
+      xhr.setRequestHeader("X-Sandstorm-App-CSRF-Token", csrfToken());
+    }
     xhr.send(data);
   });
 }
@@ -75,10 +83,21 @@ const interfaces = {
   // }))
 };
 
+// Outside of Sandstorm, there is no parent frame with a powerbox to ask. The dev server mints
+// a request token for a stand-in grain instead.
+function devPowerboxRequest() {
+  const title = window.prompt("Title of the stand-in grain:", "Dev grain");
+  if (!title) {
+    return Promise.resolve({ canceled: true });
+  }
+  return http("/api/dev/powerbox", "post", title).then((text) => JSON.parse(text));
+}
+
 function doRequest(serializedPowerboxDescriptor) {
-  sendRpc("powerboxRequest", {
-    query: [serializedPowerboxDescriptor]
-  }).then((response) => {
+  const request = window.parent === window ?
+        devPowerboxRequest() :
+        sendRpc("powerboxRequest", { query: [serializedPowerboxDescriptor] });
+  return request.then((response) => {
     if (response.canceled) {
       console.log("powerbox request was canceled");
     } else {
@@ -86,7 +105,15 @@ function doRequest(serializedPowerboxDescriptor) {
         throw new Error("Parent frame returned malformed token: " + response.token);
       }
 
-      return http("/token/" + response.token, "post", response.descriptor);
+      return http("/token/" + response.token, "post", response.descriptor).then((text) => {
+        // With merge-duplicates on, the server says when the grain looks like one that was
+        // already there. It only has the app and title to go on, so we ask before merging.
+        const added = text ? JSON.parse(text) : {};
+        if (added.duplicateOf && window.confirm(
+            "This grain looks like one that is already in the collection. Keep just that one?")) {
+          return http("/api/merge/" + added.token, "post", added.duplicateOf);
+        }
+      });
     }
   });
 }
@@ -114,6 +141,17 @@ const REFRESH_ICON = <svg version="1.1" viewBox="0 0 25 25">
   </g>
   </svg>;
 
+// Opens the powerbox to pick a grain to add. Resolves once the grain has been added or the
+// user has canceled.
+function requestGrain() {
+  // Managers can narrow what we ask for, so the server builds the descriptor.
+  return http("/api/powerbox-query", "get").then((descriptor) => {
+    return doRequest(descriptor.trim());
+  }, () => {
+    return doRequest(interfaces.uiView);
+  });
+}
+
 class AddGrain extends React.Component {
   props: {};
   state: {};
@@ -124,7 +162,7 @@ class AddGrain extends React.Component {
 
   handleClick(event) {
     event.preventDefault();
-    doRequest(interfaces.uiView);
+    requestGrain();
   }
 
   render() {
@@ -138,6 +176,155 @@ class AddGrain extends React.Component {
   }
 }
 
+// Returns a form of `text` for matching against other text: composed (NFC) so that "e" plus a
+// combining accent matches the precomposed letter, then case folded. Upper- then lowercasing
+// folds a few characters that lowercasing alone leaves alone, such as "ß" and "SS".
+function foldForMatching(text: string): string {
+  const composed = text.normalize ? text.normalize("NFC") : text;
+  return composed.toUpperCase().toLowerCase();
+}
+
+// Reads a grain list exported from Sandstorm. We accept either a bare array or an object with a
+// `grains` array, and the field names used by both the grain list and the database dump.
+function parseGrainListExport(text) {
+  const parsed = JSON.parse(text);
+  const grains = Array.isArray(parsed) ? parsed : parsed && parsed.grains;
+  if (!Array.isArray(grains)) {
+    throw new Error("This file doesn't look like a grain list.");
+  }
+
+  return grains.filter((g) => g && typeof g.title === "string").map((g, index) => ({
+    id: g._id || g.grainId || g.id || ("row" + index),
+    title: g.title,
+    appTitle: g.appTitle || g.appName || g.appId || "",
+  }));
+}
+
+// Walks the user through adding the grains from a Sandstorm grain-list export or a collection
+// bundle from `GET /api/bundle`. The powerbox has no way to pick a grain for the user, so we
+// open it once per selected row and say which grain to choose.
+class ImportGrains extends React.Component {
+  props: { grains: Immutable.Map };
+  state: { rows: Array,
+           selected: Immutable.Set,
+           current: Object,
+           error: String,
+         };
+
+  constructor(props) {
+    super(props);
+    this.state = { rows: null, selected: Immutable.Set() };
+  }
+
+  handleFile(e) {
+    const file = e.target.files[0];
+    if (!file) {
+      return;
+    }
+
+    const reader = new FileReader();
+    reader.onload = () => {
+      let rows;
+      try {
+        rows = parseGrainListExport(reader.result);
+      } catch (err) {
+        this.setState({ rows: null, error: err.message });
+        return;
+      }
+
+      if (JSON.parse(reader.result).format === "collections-bundle") {
+        // A copy of another collection. The server recreates what it can and hands back the
+        // grains, which have to be picked again.
+        http("/api/bundle", "post", reader.result).then((response) => {
+          this.showRows(parseGrainListExport(response));
+        }, (err) => {
+          this.setState({ rows: null, error: err.message });
+        });
+      } else {
+        this.showRows(rows);
+      }
+    };
+    reader.readAsText(file);
+  }
+
+  showRows(rows) {
+    // Start with everything selected except grains that look like they're already here.
+    const present = Immutable.Set(
+      this.props.grains.valueSeq().map((g) => foldForMatching(g.title || "")));
+    const selected = Immutable.Set(
+      rows.filter((r) => !present.has(foldForMatching(r.title || ""))).map((r) => r.id));
+    this.setState({ rows, selected, error: null });
+  }
+
+  toggleRow(id) {
+    const selected = this.state.selected;
+    this.setState({ selected: selected.has(id) ? selected.remove(id) : selected.add(id) });
+  }
+
+  importSelected() {
+    const queue = this.state.rows.filter((r) => this.state.selected.has(r.id));
+    const next = (index) => {
+      if (index >= queue.length || this.stopped) {
+        this.setState({ rows: null, current: null });
+        return;
+      }
+
+      this.setState({ current: { row: queue[index], index, total: queue.length } });
+      requestGrain().then(() => next(index + 1), (err) => {
+        console.log("failed to add imported grain:", err);
+        next(index + 1);
+      });
+    };
+
+    this.stopped = false;
+    next(0);
+  }
+
+  render() {
+    if (this.state.current) {
+      const current = this.state.current;
+      return <div className="import-grains">
+        <p>Choose <b>{current.row.title}</b>
+          {current.row.appTitle ? " (" + current.row.appTitle + ")" : ""} in the powerbox.
+          Grain {current.index + 1} of {current.total}.</p>
+        <button className="secondary-button" onClick={() => { this.stopped = true; }}>
+          stop after this one
+        </button>
+      </div>;
+    }
+
+    if (this.state.rows) {
+      return <div className="import-grains">
+        <table>
+          <tbody>
+            {this.state.rows.map((row) =>
+              <tr key={row.id}>
+                <td><input type="checkbox" checked={this.state.selected.has(row.id)}
+                           onChange={this.toggleRow.bind(this, row.id)}/></td>
+                <td>{row.title}</td>
+                <td>{row.appTitle}</td>
+              </tr>)}
+          </tbody>
+        </table>
+        <button className="primary-button" disabled={this.state.selected.size === 0}
+                onClick={this.importSelected.bind(this)}>
+          Add {this.state.selected.size} grains
+        </button>
+        <button className="secondary-button" onClick={() => this.setState({ rows: null })}>
+          cancel
+        </button>
+      </div>;
+    }
+
+    return <div className="import-grains">
+      <label>Import from a Sandstorm grain list or a collection bundle:{" "}
+        <input type="file" accept="application/json,.json" onChange={this.handleFile.bind(this)}/>
+      </label>
+      {this.state.error ? <p className="error">{this.state.error}</p> : null}
+    </div>;
+  }
+}
+
 const MONTHS = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
 function makeDateString(date) {
   if (!date) {
@@ -162,10 +349,27 @@ function makeDateString(date) {
   return result;
 };
 
+// Returns an underscore chain of rows, ordered according to the collection's sort order.
+const sortGrainRows = function (rows, sortOrder) {
+  const newestFirst = _.chain(rows).sortBy((r) => parseInt(r.grain.dateAdded)).reverse();
+  if (sortOrder === "manual") {
+    return newestFirst.reverse();
+  } else if (sortOrder === "title") {
+    return newestFirst.sortBy((r) => r.grain.title.toLowerCase());
+  } else if (sortOrder === "app") {
+    // Entries whose app we don't know go last.
+    return newestFirst.sortBy((r) => r.grain.title.toLowerCase())
+      .sortBy((r) => r.info.ok ? "0" + r.info.ok.appTitle.toLowerCase() : "1");
+  } else {
+    return newestFirst;
+  }
+};
+
 class GrainList extends React.Component {
   props: { grains: Immutable.Map,
            viewInfos: Immutable.Map,
            users: Immutable.Map,
+           sortOrder: String,
            canWrite: bool,
            userId: String,
          };
@@ -195,6 +399,17 @@ class GrainList extends React.Component {
     this.setState({ selectedGrains: newSelected });
   }
 
+  uploadThumbnail(e) {
+    const file = e.target.files[0];
+    e.target.value = "";
+    const token = this.state.selectedGrains.find((t) => t in this._currentlyRendered);
+    if (file && token) {
+      http("/sturdyref/" + token + "/thumbnail", "put", file).catch((err) => {
+        window.alert(err.message);
+      });
+    }
+  }
+
   selectGrain(token, e) {
     if (this.state.selectedGrains.get(token)) {
       this.setState({ selectedGrains: this.state.selectedGrains.remove(token) });
@@ -236,7 +451,13 @@ class GrainList extends React.Component {
   }
 
   offerUiView(token) {
-    http("/offer/" + token, "post");
+    const grain = this.props.grains.get(token);
+    if (grain && grain.bookmark) {
+      window.open(grain.bookmark.url, "_blank", "noopener,noreferrer");
+    } else {
+      http("/offer/" + token, "post");
+    }
+    http("/api/opened/" + token, "post");
   }
 
   searchStringChange(e) {
@@ -245,8 +466,8 @@ class GrainList extends React.Component {
 
   matchesAppOrGrainTitle = function (needle, grain, info) {
     if (!info || info.err) return false;
-    if (grain && grain.title && grain.title.toLowerCase().indexOf(needle) !== -1) return true;
-    if (info.ok && info.ok.appTitle && info.ok.appTitle.toLowerCase().indexOf(needle) !== -1) {
+    if (grain && grain.title && foldForMatching(grain.title).indexOf(needle) !== -1) return true;
+    if (info.ok && info.ok.appTitle && foldForMatching(info.ok.appTitle).indexOf(needle) !== -1) {
       return true;
     }
     return false;
@@ -261,7 +482,7 @@ class GrainList extends React.Component {
   }
 
   render() {
-    const searchKeys = this.state.searchString.toLowerCase()
+    const searchKeys = foldForMatching(this.state.searchString)
           .split(" ")
           .filter((k) => k !== "");
 
@@ -281,7 +502,10 @@ class GrainList extends React.Component {
     const grains = [];
     for (let e of this.props.grains.entries()) {
       const grain = e[1];
-      const info = this.props.viewInfos.get(e[0]) || {};
+      // Bookmarks have no view info, so we present their URL in place of the app.
+      const info = grain.bookmark ?
+            { ok: { appTitle: grain.bookmark.url, grainIconUrl: grain.bookmark.faviconUrl } } :
+            this.props.viewInfos.get(e[0]) || {};
       if ((info.ok || this.props.canWrite) && matchFilter(grain, info)) {
         if (this.state.selectedGrains.get(e[0])) {
           numShownAndSelected += 1;
@@ -291,15 +515,19 @@ class GrainList extends React.Component {
         grains.push({token: e[0], grain, info });
       }
     }
-    const grainRows = _.chain(grains).sortBy((r) => r.grain.dateAdded).reverse().map((r) => {
+    const grainRows = sortGrainRows(grains, this.props.sortOrder).map((r) => {
       const checkbox = this.props.canWrite ?
            <td onClick={this.clickCheckboxContainer.bind(this)}>
             <input type="checkbox" checked={!!this.state.selectedGrains.get(r.token)}
                     onChange={this.selectGrain.bind(this, r.token)}/></td>
         : [];
+      // An uploaded thumbnail takes the place of the app icon.
+      const iconUrl = r.grain.thumbnailUpdated ?
+            "/thumbnails/" + r.token + "?v=" + r.grain.thumbnailUpdated :
+            r.info.ok && r.info.ok.grainIconUrl;
       const appIcon = r.info.ok ?
             <td className="td-app-icon click-to-go" onClick={this.offerUiView.bind(this, r.token)}>
-             <img title={r.info.ok.appTitle} src={r.info.ok.grainIconUrl} className="grain-icon">
+             <img title={r.info.ok.appTitle} src={iconUrl} className="grain-icon">
              </img>
             </td> :
         <td className="td-app-icon">
@@ -310,6 +538,10 @@ class GrainList extends React.Component {
       const grainTitle = r.info.ok ?
             <td className="click-to-go grain-title" onClick={this.offerUiView.bind(this, r.token)}>
             <button onClick={(e) => {e.preventDefault();} }>{r.grain.title}</button>
+            {r.grain.expires ?
+             <span className="expires">
+              expires {makeDateString(new Date(parseInt(r.grain.expires)))}
+             </span> : null}
             </td> :
             <td><span className="broken-link" title={"broken: " + r.info.err}>
              {r.grain.title}</span>
@@ -328,13 +560,16 @@ class GrainList extends React.Component {
 
       const addedBy = r.info.ok?
             <td className="click-to-go added-by" onClick={this.offerUiView.bind(this, r.token)}>
-            <span><img title={addedByUser.displayName} src={addedByUser.pictureUrl}
+            <span><img title={addedByUser.displayName}
+                 src={r.grain.addedBy ? "/avatar/" + r.grain.addedBy : addedByUser.pictureUrl}
                  className="user-profile-pic">
             </img></span>
             </td> :
             <td className="added-by"><span></span></td>;
 
-      return <tr className={r.info.ok ? "grain" : "broken-grain"} key={r.token}>
+      const rowClass = (r.info.ok ? "grain" : "broken-grain") + (r.grain.isNew ? " new-grain" : "") +
+            (r.grain.isCollection ? " nested-collection" : "");
+      return <tr className={rowClass} key={r.token}>
           {checkbox}{appIcon}{grainTitle}{addedBy}{dateAdded}
         </tr>;
     }).value();
@@ -347,6 +582,17 @@ class GrainList extends React.Component {
                  title={numShownAndSelected==0 ?
                         "select grains to unlink them" : "unlink selected grains"}
                   onClick={this.clickRemoveGrain.bind(this)}>Unlink from collection</button>);
+      bulkActionButtons.push(
+          <button key="thumbnail"
+                  disabled={numShownAndSelected != 1}
+                  title={numShownAndSelected != 1 ?
+                         "select one grain to set its thumbnail" : "upload a thumbnail image"}
+                  onClick={() => this._thumbnailInput.click()}>Set thumbnail...</button>);
+      bulkActionButtons.push(
+          <input key="thumbnail-file" type="file" style={{ display: "none" }}
+                 accept="image/png,image/jpeg,image/gif,image/webp"
+                 ref={(input) => { this._thumbnailInput = input; }}
+                 onChange={this.uploadThumbnail.bind(this)}/>);
     }
 
     return <div className="grain-list">
@@ -449,6 +695,7 @@ class Description extends React.Component {
 class Main extends React.Component {
   props: {};
   state: { canWrite: bool,
+           locked: bool,
            userId: String,
            description: String,
            grains: Immutable.Map,
@@ -468,6 +715,13 @@ class Main extends React.Component {
 
   componentDidMount() {
     this.openWebSocket(1000);
+
+    // Shortcuts opened from the WebDAV view link here with the entry's token. They go through
+    // the session's own host, so they only work while the session that served them lasts.
+    const match = /^#open=(.+)$/.exec(window.location.hash);
+    if (match) {
+      http("/offer/" + match[1], "post");
+    }
   }
 
   openWebSocket(delayOnFailure) {
@@ -478,7 +732,9 @@ class Main extends React.Component {
     this.setState({socketReadyState: { connecting: true } });
 
     let wsProtocol = window.location.protocol == "http:" ? "ws" : "wss";
-    let ws = new WebSocket(wsProtocol + "://" + window.location.host);
+    // If we've been connected before, ask only for what we missed.
+    const since = this.revision === undefined ? "" : "/?since=" + this.revision;
+    let ws = new WebSocket(wsProtocol + "://" + window.location.host + since);
 
     ws.onopen = (e) => {
       this.setState({ socketReadyState: { open: true } });
@@ -514,43 +770,115 @@ class Main extends React.Component {
 
     ws.onmessage = (m) => {
       const action = JSON.parse(m.data);
-      if (action.canWrite) {
-        this.setState({canWrite: action.canWrite});
-      } else if (action.userId) {
-        this.setState({userId: action.userId});
-      } else if (action.description) {
-        this.setState({ description: action.description });
-      } else if (action.insert) {
-        const newGrains = this.state.grains.set(action.insert.token,
-                                                action.insert.data);
-        this.setState({grains: newGrains});
-
-        if (!this.state.viewInfos.get(action.insert.token)) {
-          // HACK: We are likely in an intermediate state between receiving the info
-          // about the grian and receiving its view info. If we don't add an "ok" viewinfo here,
-          // then the UI will briefly display the grain as broken.
-          // Maybe we should combine the `insert` and `viewInfo` messages?
-          const newViewInfos = this.state.viewInfos.set(action.insert.token, { ok: {} });
-          this.setState({ viewInfos: newViewInfos });
+      if ("revision" in action) {
+        // A message that is just `{revision}` ends the initial sync. Every broadcast after that
+        // carries the next revision; if we skipped one, reconnect and catch up.
+        const isMarker = Object.keys(action).length === 1;
+        if (!isMarker && this.revision !== undefined && action.revision !== this.revision + 1) {
+          ws.close();
+          return;
         }
-      } else if (action.remove) {
-        const newGrains = this.state.grains.delete(action.remove.token);
-        this.setState({ grains: newGrains });
-      } else if (action.viewInfo) {
-        const data = action.viewInfo.data ?
-              { ok: action.viewInfo.data } :
-              { err: action.viewInfo.failed.split("\n")[0] }; // HACK to drop the stack trace.
-
-        const newViewInfos = this.state.viewInfos.set(action.viewInfo.token, data);
-        this.setState({ viewInfos: newViewInfos });
-      } else if (action.user) {
-        const newUsers = this.state.users.set(action.user.id, action.user.data);
-        this.setState({ users: newUsers });
+        this.revision = action.revision;
       }
+
+      this.applyAction(action);
     };
 
   }
 
+  applyAction(action) {
+    if (action.batch) {
+      // Several changes made together, e.g. by a bulk edit.
+      for (const a of action.batch) {
+        this.applyAction(a);
+      }
+    } else if (action.summary) {
+      this.setState({ summary: action.summary });
+    } else if (action.canWrite) {
+      this.setState({canWrite: action.canWrite});
+    } else if (action.userId) {
+      this.setState({userId: action.userId});
+    } else if (action.description) {
+      this.setState({ description: action.description });
+    } else if (action.insert) {
+      const data = Object.assign({}, action.insert.data, { isNew: action.insert.isNew });
+      const newGrains = this.state.grains.set(action.insert.token, data);
+      this.setState({grains: newGrains});
+
+      if (!this.state.viewInfos.get(action.insert.token)) {
+        // HACK: We are likely in an intermediate state between receiving the info
+        // about the grian and receiving its view info. If we don't add an "ok" viewinfo here,
+        // then the UI will briefly display the grain as broken.
+        // Maybe we should combine the `insert` and `viewInfo` messages?
+        const newViewInfos = this.state.viewInfos.set(action.insert.token, { ok: {} });
+        this.setState({ viewInfos: newViewInfos });
+      }
+    } else if (action.remove) {
+      const newGrains = this.state.grains.delete(action.remove.token);
+      this.setState({ grains: newGrains });
+    } else if (action.viewInfo) {
+      const data = action.viewInfo.data ?
+            { ok: action.viewInfo.data } :
+            { err: action.viewInfo.failed.split("\n")[0] }; // HACK to drop the stack trace.
+
+      const newViewInfos = this.state.viewInfos.set(action.viewInfo.token, data);
+      this.setState({ viewInfos: newViewInfos });
+    } else if (action.update) {
+      // Either an entry's metadata changed, which doesn't make it any less new to us, or its
+      // grain can no longer be restored and it is shown as broken. When the grain comes back,
+      // a `viewInfo` follows with the details.
+      if (action.update.data) {
+        const old = this.state.grains.get(action.update.token);
+        if (old) {
+          const data = Object.assign({}, action.update.data, { isNew: old.isNew });
+          this.setState({ grains: this.state.grains.set(action.update.token, data) });
+        }
+      } else if (action.update.unavailable) {
+        const newViewInfos = this.state.viewInfos.set(action.update.token,
+                                                      { err: action.update.unavailable });
+        this.setState({ viewInfos: newViewInfos });
+      }
+    } else if (action.folders) {
+      this.setState({ folders: action.folders });
+    } else if (action.move) {
+      let newGrains = this.state.grains;
+      action.move.tokens.forEach((token, idx) => {
+        const grain = newGrains.get(token);
+        if (grain) {
+          const changes = { folder: action.move.folder };
+          if (action.move.versions) {
+            changes.version = action.move.versions[idx];
+          }
+          newGrains = newGrains.set(token, Object.assign({}, grain, changes));
+        }
+      });
+      this.setState({ grains: newGrains });
+    } else if (action.sortOrder) {
+      this.setState({ sortOrder: action.sortOrder });
+    } else if ("locked" in action) {
+      this.setState({ locked: action.locked });
+    } else if (action.reload) {
+      // The dev server saw the frontend change.
+      window.location.reload();
+    } else if (action.commandFailed) {
+      console.log("command failed: " + action.commandFailed.reason);
+    } else if (action.cleared) {
+      this.setState({ grains: Immutable.Map(), viewInfos: Immutable.Map() });
+    } else if (action.comment) {
+      const grain = this.state.grains.get(action.comment.token);
+      if (grain) {
+        const comments = (grain.comments || []).concat([action.comment.data]);
+        const version = action.comment.version;
+        const newGrains = this.state.grains.set(action.comment.token,
+                                                Object.assign({}, grain, { comments, version }));
+        this.setState({ grains: newGrains });
+      }
+    } else if (action.user) {
+      const newUsers = this.state.users.set(action.user.id, action.user.data);
+      this.setState({ users: newUsers });
+    }
+  }
+
   retryConnect() {
     if (this.state.socketReadyState.tryingAgainLater) {
       window.clearTimeout(this.state.socketReadyState.tryingAgainLater.timeout);
@@ -571,13 +899,32 @@ class Main extends React.Component {
         </p>;
     }
 
+    let maybeLocked = null;
+    if (this.state.locked) {
+      maybeLocked = <p className="locked-notice">
+        This collection is locked. It cannot be changed until a manager unlocks it.
+        </p>;
+    }
+    // Nobody can change a locked collection, so we hide the editing controls.
+    const canWrite = this.state.canWrite && !this.state.locked;
+
+    let maybeLoading = null;
+    const summary = this.state.summary;
+    if (summary && this.state.grains.size < summary.count) {
+      maybeLoading = <p>Loading {summary.count} grains...</p>;
+    }
+
     return <div>
       {maybeSocketWarning}
-      <Description canWrite={this.state.canWrite} description={this.state.description}/>
+      {maybeLocked}
+      <Description canWrite={canWrite}
+                   description={this.state.description || (summary && summary.title)}/>
       <hr/>
+      {maybeLoading}
       <GrainList grains={this.state.grains} viewInfos={this.state.viewInfos}
-                 users={this.state.users}
-                 canWrite={this.state.canWrite} userId={this.state.userId} />
+                 users={this.state.users} sortOrder={this.state.sortOrder}
+                 canWrite={canWrite} userId={this.state.userId} />
+      {canWrite ? <ImportGrains grains={this.state.grains}/> : null}
       </div>;
   }
 }
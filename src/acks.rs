@@ -0,0 +1,170 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! The last broadcast revision that each acked-mode websocket client has confirmed, so that a
+//! client that reconnects is resent everything after it. Stored as one
+//! "<owner>/<client ID> <revision> <time>" line per client, where the owner is the identity ID
+//! of the user whose client it is, or "anonymous".
+
+use capnp::Error;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+/// Client IDs longer than this are rejected.
+const MAX_CLIENT_ID_LENGTH: usize = 64;
+
+/// When there are more clients than this, the one that acked least recently is forgotten. If it
+/// comes back, it gets a full snapshot, which is slower but still misses nothing.
+const MAX_CLIENTS: usize = 256;
+
+/// How many clients one owner may have. Past this, the owner's own stalest client is forgotten,
+/// so that one user can't push everyone else's clients out.
+const MAX_CLIENTS_PER_OWNER: usize = 16;
+
+/// Who a client belongs to: the identity ID of its user, if it has one.
+pub fn owner(identity_id: &Option<String>) -> &str {
+    identity_id.as_ref().map_or("anonymous", |id| &id[..])
+}
+
+fn key(owner: &str, client_id: &str) -> String {
+    format!("{}/{}", owner, client_id)
+}
+
+/// Client IDs are chosen by the client, and must be short strings of letters, digits, '-' and
+/// '_'.
+pub fn validate_client_id(id: &str) -> Result<(), Error> {
+    if id.is_empty() || id.len() > MAX_CLIENT_ID_LENGTH ||
+        !id.chars().all(|c| c.is_digit(36) || c == '-' || c == '_')
+    {
+        Err(Error::failed(format!("invalid client ID: {:?}", id)))
+    } else {
+        Ok(())
+    }
+}
+
+pub struct Acks {
+    path: ::std::path::PathBuf,
+
+    /// (revision, time of the ack in milliseconds since the unix epoch) by "<owner>/<client ID>".
+    clients: BTreeMap<String, (u64, u64)>,
+
+    /// Whether `clients` has changed since it was last saved.
+    dirty: bool,
+}
+
+impl Acks {
+    pub fn open<P>(path: P) -> Result<Acks, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let mut clients = BTreeMap::new();
+        match ::std::fs::File::open(&path) {
+            Ok(f) => {
+                for line in ::std::io::BufReader::new(f).lines() {
+                    let line = try!(line);
+                    let parts: Vec<&str> = line.split(' ').collect();
+                    if parts.len() != 3 {
+                        println!("skipping malformed ack line: {}", line);
+                        continue
+                    }
+                    match (parts[1].parse::<u64>(), parts[2].parse::<u64>()) {
+                        (Ok(revision), Ok(time)) => {
+                            clients.insert(parts[0].to_string(), (revision, time));
+                        }
+                        _ => println!("skipping malformed ack line: {}", line),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Acks {
+            path: path.as_ref().to_path_buf(),
+            clients: clients,
+            dirty: false,
+        })
+    }
+
+    /// The last revision that `owner`'s client `client_id` acknowledged, if we know of it.
+    pub fn get(&self, owner: &str, client_id: &str) -> Option<u64> {
+        self.clients.get(&key(owner, client_id)).map(|&(revision, _)| revision)
+    }
+
+    /// Records that `owner`'s client `client_id` has received every broadcast up to and including
+    /// `revision`. Acks never go backwards, so a late duplicate is harmless. Nothing is written
+    /// until `flush()`; losing an ack only means that its client is sent some broadcasts again.
+    pub fn ack(&mut self,
+               owner: &str,
+               client_id: &str,
+               revision: u64,
+               now_millis: u64) -> Result<(), Error> {
+        try!(validate_client_id(client_id));
+        if self.get(owner, client_id).map_or(false, |r| r >= revision) {
+            return Ok(())
+        }
+        self.clients.insert(key(owner, client_id), (revision, now_millis));
+        self.dirty = true;
+
+        let prefix = key(owner, "");
+        while self.clients.keys().filter(|k| k.starts_with(&prefix)).count() >
+            MAX_CLIENTS_PER_OWNER
+        {
+            self.forget_stalest(|k| k.starts_with(&prefix));
+        }
+        while self.clients.len() > MAX_CLIENTS {
+            self.forget_stalest(|_| true);
+        }
+        Ok(())
+    }
+
+    /// Forgets the client that acked least recently among those whose keys match `filter`.
+    fn forget_stalest<F>(&mut self, filter: F) where F: Fn(&str) -> bool {
+        let stalest = self.clients.iter()
+            .filter(|&(k, _)| filter(k))
+            .min_by_key(|&(_, &(_, time))| time)
+            .map(|(k, _)| k.clone());
+        if let Some(k) = stalest {
+            self.clients.remove(&k);
+        }
+    }
+
+    /// Writes out the acks if they have changed since the last time.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            try!(self.save());
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let temp_path = self.path.with_extension("uploading");
+        {
+            let mut file = try!(::std::fs::File::create(&temp_path));
+            for (id, &(revision, time)) in &self.clients {
+                try!(file.write_all(format!("{} {} {}\n", id, revision, time).as_bytes()));
+            }
+            try!(file.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, &self.path));
+        Ok(())
+    }
+}
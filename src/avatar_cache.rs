@@ -0,0 +1,98 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! On-disk cache of collaborators' profile pictures, so that the grain can serve them from its
+//! own origin. Each picture is stored as a CachedAvatar message named by the hex identity ID.
+
+use capnp::Error;
+use collections_capnp::cached_avatar;
+
+#[derive(Clone, Debug)]
+pub struct CachedAvatar {
+    pub mime_type: String,
+    pub content: Vec<u8>,
+
+    /// When we fetched the picture, in milliseconds since the unix epoch.
+    pub fetched: u64,
+}
+
+pub struct AvatarCache {
+    directory: ::std::path::PathBuf,
+}
+
+fn check_identity_id(identity_id: &str) -> Result<(), Error> {
+    if !identity_id.is_empty() && identity_id.chars().all(|c| c.is_digit(16)) {
+        Ok(())
+    } else {
+        Err(Error::failed(format!("invalid identity ID {}", identity_id)))
+    }
+}
+
+impl AvatarCache {
+    pub fn new<P>(directory: P) -> Result<AvatarCache, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        try!(::std::fs::create_dir_all(&directory));
+        Ok(AvatarCache { directory: directory.as_ref().to_path_buf() })
+    }
+
+    pub fn get(&self, identity_id: &str) -> Result<Option<CachedAvatar>, Error> {
+        try!(check_identity_id(identity_id));
+        let mut path = self.directory.clone();
+        path.push(identity_id);
+        let mut f = match ::std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let message = try!(::capnp::serialize::read_message(&mut f, Default::default()));
+        let avatar: cached_avatar::Reader = try!(message.get_root());
+        Ok(Some(CachedAvatar {
+            mime_type: try!(avatar.get_mime_type()).into(),
+            content: try!(avatar.get_content()).to_vec(),
+            fetched: avatar.get_fetched(),
+        }))
+    }
+
+    pub fn put(&self, identity_id: &str, avatar: &CachedAvatar) -> Result<(), Error> {
+        try!(check_identity_id(identity_id));
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let mut root = message.init_root::<cached_avatar::Builder>();
+            root.set_mime_type(&avatar.mime_type);
+            root.set_content(&avatar.content);
+            root.set_fetched(avatar.fetched);
+        }
+
+        let mut path = self.directory.clone();
+        path.push(identity_id);
+        let temp_path = path.with_extension("uploading");
+        {
+            let mut writer = try!(::std::fs::File::create(&temp_path));
+            try!(::capnp::serialize::write_message(&mut writer, &message));
+            try!(writer.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, path));
+        Ok(())
+    }
+}
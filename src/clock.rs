@@ -0,0 +1,111 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Time sources. Everything that needs the current time or needs to wait goes through these
+//! traits, so that tests can substitute a fake clock and fire timers by hand.
+
+use capnp::capability::Promise;
+use capnp::Error;
+use futures::Future;
+use futures::future::{Loop, loop_fn};
+use std::rc::Rc;
+
+pub trait Clock {
+    /// Milliseconds since the unix epoch.
+    fn now_millis(&self) -> u64;
+}
+
+pub trait Timer {
+    /// Returns a promise that resolves once `duration` has elapsed.
+    fn after(&self, duration: ::std::time::Duration) -> Promise<(), Error>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        match ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH) {
+            Ok(dur) => dur.as_secs() * 1000 + (dur.subsec_nanos() / 1000000) as u64,
+            Err(_) => 0, // clock is set to before 1970
+        }
+    }
+}
+
+pub struct ReactorTimer {
+    handle: ::tokio_core::reactor::Handle,
+}
+
+impl ReactorTimer {
+    pub fn new(handle: &::tokio_core::reactor::Handle) -> ReactorTimer {
+        ReactorTimer { handle: handle.clone() }
+    }
+}
+
+impl Timer for ReactorTimer {
+    fn after(&self, duration: ::std::time::Duration) -> Promise<(), Error> {
+        let timeout = pry!(::tokio_core::reactor::Timeout::new(duration, &self.handle));
+        Promise::from_future(timeout.map_err(Into::into))
+    }
+}
+
+/// Fails with an `Overloaded` error if `promise` hasn't resolved within `duration`, so that a
+/// peer that never answers can't leave us waiting forever. `what` names the call in the error.
+pub fn with_timeout<T>(timer: &Timer,
+                       duration: ::std::time::Duration,
+                       what: &str,
+                       promise: Promise<T, Error>) -> Promise<T, Error>
+    where T: 'static
+{
+    let description = format!("timed out after {} seconds waiting for {}",
+                              duration.as_secs(), what);
+    let expired = timer.after(duration).and_then(move |()| {
+        Err(Error { kind: ::capnp::ErrorKind::Overloaded, description: description })
+    });
+    Promise::from_future(promise.select(expired).map(|(v, _)| v).map_err(|(e, _)| e))
+}
+
+/// Calls `attempt` until it succeeds, at most `max_attempts` times. The first retry waits
+/// `initial_backoff`, and each one after that waits twice as long as the last. Errors for which
+/// `is_transient` returns false are passed straight through.
+pub fn retry<T, F, P>(timer: Rc<Timer>,
+                      max_attempts: u32,
+                      initial_backoff: ::std::time::Duration,
+                      is_transient: P,
+                      mut attempt: F) -> Promise<T, Error>
+    where T: 'static,
+          F: FnMut() -> Promise<T, Error> + 'static,
+          P: Fn(&Error) -> bool + 'static
+{
+    let is_transient = Rc::new(is_transient);
+    Promise::from_future(loop_fn(0, move |tries| {
+        let timer = timer.clone();
+        let is_transient = is_transient.clone();
+        attempt().then(move |result| match result {
+            Ok(value) => Promise::ok(Loop::Break(value)),
+            Err(e) => if tries + 1 >= max_attempts || !is_transient(&e) {
+                Promise::err(e)
+            } else {
+                let delay = initial_backoff * (1 << tries);
+                Promise::from_future(timer.after(delay).map(move |()| Loop::Continue(tries + 1)))
+            },
+        })
+    }))
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Sort keys for titles that order them the way readers of a language expect, rather than by
+//! code point: accented Latin letters sort with their base letters ("Élan" among the E's, not
+//! after "Zebra"), except where a language treats them as letters of their own, like the
+//! Swedish "ö", which comes after "z". Other scripts keep their code point order, which is
+//! alphabetical for most of them.
+
+/// A language's rules for ordering letters. Languages without rules of their own use `Root`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Collation {
+    Root,
+
+    /// Swedish and Finnish: "å", "ä" and "ö" follow "z", in that order.
+    Swedish,
+
+    /// Danish and Norwegian: "æ", "ø" and "å" follow "z", in that order.
+    Danish,
+
+    /// Spanish: "ñ" follows "n".
+    Spanish,
+}
+
+const TAILORED: &'static [(&'static str, Collation)] = &[
+    ("sv", Collation::Swedish),
+    ("fi", Collation::Swedish),
+    ("da", Collation::Danish),
+    ("nb", Collation::Danish),
+    ("nn", Collation::Danish),
+    ("no", Collation::Danish),
+    ("es", Collation::Spanish),
+];
+
+/// Room between the weights of adjacent code points, for letters that a language slots in.
+const SPACING: u32 = 8;
+
+impl Collation {
+    /// The rules for the user's preferred language, the first entry of `acceptable`.
+    pub fn negotiate<'a, I>(acceptable: I) -> Collation
+        where I: IntoIterator<Item=&'a str>
+    {
+        let first = match acceptable.into_iter().next() {
+            Some(tag) => tag,
+            None => return Collation::Root,
+        };
+        let primary = first.split(|c| c == '-' || c == '_' || c == ';').next().unwrap_or("");
+        let primary = primary.trim().to_lowercase();
+        for &(code, collation) in TAILORED {
+            if primary == code {
+                return collation
+            }
+        }
+        Collation::Root
+    }
+
+    /// Where a letter that this language places after `base` goes; `rank` orders several such
+    /// letters among themselves.
+    fn after(base: char, rank: u32) -> u32 {
+        base as u32 * SPACING + rank
+    }
+
+    /// The weight of `c` if this language gives it a place of its own.
+    fn tailored(self, c: char) -> Option<u32> {
+        match (self, c) {
+            (Collation::Swedish, 'å') => Some(Collation::after('z', 1)),
+            (Collation::Swedish, 'ä') | (Collation::Swedish, 'æ') => {
+                Some(Collation::after('z', 2))
+            }
+            (Collation::Swedish, 'ö') | (Collation::Swedish, 'ø') => {
+                Some(Collation::after('z', 3))
+            }
+            (Collation::Danish, 'æ') | (Collation::Danish, 'ä') => Some(Collation::after('z', 1)),
+            (Collation::Danish, 'ø') | (Collation::Danish, 'ö') => Some(Collation::after('z', 2)),
+            (Collation::Danish, 'å') => Some(Collation::after('z', 3)),
+            (Collation::Spanish, 'ñ') => Some(Collation::after('n', 1)),
+            _ => None,
+        }
+    }
+
+    /// The sort key of `title`. Keys compare first by letters with accents and case set aside,
+    /// and then, to break ties deterministically, by the lowercased title itself.
+    pub fn key(self, title: &str) -> (Vec<u32>, String) {
+        let lower = title.to_lowercase();
+        let mut weights = Vec::with_capacity(lower.len());
+        for c in lower.chars() {
+            if let Some(weight) = self.tailored(c) {
+                weights.push(weight);
+                continue
+            }
+            for base in fold(c).chars() {
+                weights.push(base as u32 * SPACING);
+            }
+        }
+        (weights, lower)
+    }
+}
+
+/// The unaccented letters that a lowercase Latin letter sorts as, or the letter itself if it has
+/// no accent to drop.
+fn fold(c: char) -> String {
+    let folded = match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ð' | 'ď' | 'đ' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return c.to_string(),
+    };
+    folded.to_string()
+}
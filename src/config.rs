@@ -0,0 +1,127 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! How this server process was started: as a grain under Sandstorm, or as a development server
+//! on the developer's own machine. Everything that differs between the two lives here.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Where we keep persistent state. In a grain, this is /var.
+    pub var_dir: PathBuf,
+
+    /// A directory holding uncompressed builds of script.js and style.css, or None to serve the
+    /// gzipped copies at the root of the package.
+    pub asset_dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// The configuration of a grain running under Sandstorm.
+    pub fn grain() -> Config {
+        Config {
+            var_dir: PathBuf::from("/var"),
+            asset_dir: None,
+        }
+    }
+
+    /// A development server that keeps its state in `var_dir` and serves the frontend from
+    /// `asset_dir`, where `npm run-script bundle` and `npm run-script sass` put it.
+    pub fn dev<P1, P2>(var_dir: P1, asset_dir: P2) -> Config
+        where P1: AsRef<Path>, P2: AsRef<Path>
+    {
+        Config {
+            var_dir: var_dir.as_ref().to_path_buf(),
+            asset_dir: Some(asset_dir.as_ref().to_path_buf()),
+        }
+    }
+
+    /// The path of `name` within the state directory.
+    pub fn var_path(&self, name: &str) -> PathBuf {
+        self.var_dir.join(name)
+    }
+}
+
+/// Subsystems that managers can turn on or off for their collection. Experimental ones start
+/// out off, so that they can ship before they're ready for everyone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    Comments,
+    WebDav,
+    Webhooks,
+    MergeDuplicates,
+    WebPublishing,
+    Ratings,
+    Upvotes,
+}
+
+/// Every feature, with its name in settings and whether it is on unless a manager says otherwise.
+const FEATURES: &'static [(Feature, &'static str, bool)] = &[
+    (Feature::Comments, "comments", true),
+    (Feature::WebDav, "webdav", true),
+    (Feature::Webhooks, "webhooks", true),
+    (Feature::MergeDuplicates, "merge-duplicates", false),
+    (Feature::WebPublishing, "web-publishing", false),
+    (Feature::Ratings, "ratings", false),
+    (Feature::Upvotes, "upvotes", false),
+];
+
+impl Feature {
+    pub fn all() -> Vec<Feature> {
+        FEATURES.iter().map(|&(feature, _, _)| feature).collect()
+    }
+
+    pub fn name(self) -> &'static str {
+        FEATURES.iter().find(|&&(f, _, _)| f == self).map(|&(_, name, _)| name).unwrap_or("")
+    }
+
+    pub fn from_name(name: &str) -> Option<Feature> {
+        FEATURES.iter().find(|&&(_, n, _)| n == name).map(|&(feature, _, _)| feature)
+    }
+
+    fn enabled_by_default(self) -> bool {
+        FEATURES.iter().find(|&&(f, _, _)| f == self).map_or(false, |&(_, _, on)| on)
+    }
+}
+
+/// A collection's choices about which features are on. Only the features that a manager has
+/// switched are stored, by name, so that flags written by a newer version of the app survive a
+/// downgrade.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFlags {
+    pub overrides: BTreeMap<String, bool>,
+}
+
+impl FeatureFlags {
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.overrides.get(feature.name()).cloned().unwrap_or(feature.enabled_by_default())
+    }
+
+    pub fn set(&mut self, feature: Feature, enabled: bool) {
+        if enabled == feature.enabled_by_default() {
+            self.overrides.remove(feature.name());
+        } else {
+            self.overrides.insert(feature.name().to_string(), enabled);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Just enough CSV (RFC 4180) to produce spreadsheet-friendly downloads.
+
+fn write_field(out: &mut String, field: &str) {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        out.push('"');
+        out.push_str(&field.replace("\"", "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+/// Formats a time as "YYYY-MM-DD hh:mm:ss" in UTC, which spreadsheets recognize as a date.
+pub fn datetime(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (year, month, day) = ::webdav::civil_from_days((seconds / 86400) as i64);
+    format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day,
+            (seconds % 86400) / 3600, (seconds % 3600) / 60, seconds % 60)
+}
+
+/// Appends one CSV record, terminated by CRLF, to `out`.
+pub fn write_record(out: &mut String, fields: &[&str]) {
+    for (idx, field) in fields.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        write_field(out, field);
+    }
+    out.push_str("\r\n");
+}
@@ -0,0 +1,759 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! A development server, which serves a collection over plain HTTP on localhost with stand-ins
+//! for Sandstorm, so that the frontend and the JSON and websocket protocols can be worked on
+//! without packaging and launching a grain. Start it with
+//!
+//! ```text
+//! collections-server --dev [--port PORT] [--var DIR] [--assets DIR]
+//! ```
+//!
+//! State is kept in the var directory (default `dev-var`), and the frontend is served from the
+//! uncompressed build in the asset directory (default `tmp`, where `npm run-script bundle` and
+//! `npm run-script sass` put it). Every request comes from the same user, who holds every
+//! permission.
+//!
+//! There is no powerbox either, so the frontend asks `api/dev/powerbox` for a request token and
+//! descriptor instead. Claiming that token yields a stand-in grain with canned view info, and
+//! saving and restoring stand-in grains work as they would in Sandstorm, so the whole path for
+//! adding a grain can be tried out. Other things that need Sandstorm's help, like fetching
+//! avatars, fail.
+//!
+//! The frontend files are read afresh for every request, and responses are marked as not to be
+//! cached. When the script or stylesheet changes on disk, every connected page is told to reload.
+
+use capnp::capability::Promise;
+use capnp::Error;
+use futures::{Future, Stream};
+use futures::future::{Loop, loop_fn};
+use rustc_serialize::base64::{self, ToBase64};
+use sandstorm::grain_capnp::{sandstorm_api, session_context, ui_view};
+use sandstorm::identity_capnp::user_info;
+use sandstorm::powerbox_capnp::powerbox_descriptor;
+use sandstorm::util_capnp::static_asset;
+use sandstorm::web_session_capnp::web_session;
+use sandstorm::web_session_capnp::web_session::web_socket_stream;
+use std::rc::Rc;
+use tokio_core::io::Io;
+use tokio_core::net::{TcpListener, TcpStream};
+
+use clock::{ReactorTimer, SystemClock, Timer};
+use config::Config;
+use identity_map::IdentityMap;
+use request_log::status_of;
+use server::{SavedUiViewSet, WebSession};
+
+const DEFAULT_PORT: u16 = 8000;
+
+/// How often we look for changes to the frontend files.
+const ASSET_POLL_INTERVAL_MILLIS: u64 = 500;
+
+/// The frontend files whose changes make pages reload.
+const WATCHED_ASSETS: &'static [&'static str] = &["script.js", "style.css"];
+
+/// Requests whose headers are longer than this are rejected.
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// Requests whose bodies are longer than this are rejected.
+const MAX_BODY_BYTES: usize = 32 * 1024 * 1024;
+
+/// Appended to the client's key to compute the `Sec-WebSocket-Accept` header, per RFC 6455.
+const WEBSOCKET_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The identity ID of the dev server's one user.
+const DEV_IDENTITY_ID: [u8; 32] = [0xde; 32];
+
+/// Request tokens and sturdyref tokens that the dev server mints start with these, so that we
+/// can tell them from anything else.
+const REQUEST_TOKEN_PREFIX: &'static str = "dev-request-";
+const STURDYREF_PREFIX: &'static str = "dev-grain-";
+
+/// Served as the icon of every stand-in grain.
+const GRAIN_ICON_SVG: &'static str =
+    "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 24 24\">\
+     <rect x=\"2\" y=\"2\" width=\"20\" height=\"20\" rx=\"4\" fill=\"#762f87\"/></svg>";
+
+fn random_hex_string(num_bytes: usize) -> ::std::io::Result<String> {
+    use std::io::Read;
+    let mut bytes = vec![0u8; num_bytes];
+    try!(try!(::std::fs::File::open("/dev/urandom")).read_exact(&mut bytes));
+    Ok(::rustc_serialize::hex::ToHex::to_hex(&bytes[..]))
+}
+
+/// Answers `api/dev/powerbox` with what Sandstorm's powerbox would post back to the frontend
+/// after the user picked a grain titled `title`.
+fn mint_powerbox_response(title: &str) -> Result<String, Error> {
+    use capnp::traits::HasTypeId;
+    let mut message = ::capnp::message::Builder::new_default();
+    {
+        let descriptor = message.init_root::<powerbox_descriptor::Builder>();
+        let mut tag = descriptor.init_tags(1).get(0);
+        tag.set_id(ui_view::Client::type_id());
+        tag.init_value().init_as::<ui_view::powerbox_tag::Builder>().set_title(title);
+    }
+    let mut bytes = Vec::new();
+    try!(::capnp::serialize_packed::write_message(&mut bytes, &message));
+
+    let token = format!("{}{}", REQUEST_TOKEN_PREFIX, try!(random_hex_string(8)));
+    Ok(format!("{{\"token\":\"{}\",\"descriptor\":\"{}\"}}",
+               token, bytes.to_base64(base64::URL_SAFE)))
+}
+
+/// The icon of stand-in grains, which the dev server serves itself.
+struct DevIcon {
+    host_path: String,
+}
+
+impl static_asset::Server for DevIcon {
+    fn get_url(&mut self,
+               _params: static_asset::GetUrlParams,
+               mut results: static_asset::GetUrlResults)
+               -> Promise<(), Error>
+    {
+        results.get().set_protocol(static_asset::Protocol::Http);
+        results.get().set_host_path(&self.host_path);
+        Promise::ok(())
+    }
+}
+
+/// A stand-in for a grain that was picked in the powerbox.
+struct DevGrain {
+    icon_host_path: String,
+}
+
+impl ui_view::Server for DevGrain {
+    fn get_view_info(&mut self,
+                     _params: ui_view::GetViewInfoParams,
+                     mut results: ui_view::GetViewInfoResults)
+                     -> Promise<(), Error>
+    {
+        let mut view_info = results.get();
+        view_info.borrow().init_app_title().set_default_text("Dev App");
+        view_info.set_grain_icon(
+            static_asset::ToClient::new(DevIcon { host_path: self.icon_host_path.clone() })
+                .from_server::<::capnp_rpc::Server>());
+        Promise::ok(())
+    }
+}
+
+fn dev_grain(icon_host_path: &str) -> ui_view::Client {
+    ui_view::ToClient::new(DevGrain { icon_host_path: icon_host_path.to_string() })
+        .from_server::<::capnp_rpc::Server>()
+}
+
+/// Stands in for the Sandstorm API. It can save and restore stand-in grains; everything else
+/// fails as unimplemented.
+struct DevSandstormApi {
+    icon_host_path: String,
+}
+
+impl sandstorm_api::Server<::capnp::any_pointer::Owned> for DevSandstormApi {
+    fn save(&mut self,
+            _params: sandstorm_api::SaveParams<::capnp::any_pointer::Owned>,
+            mut results: sandstorm_api::SaveResults<::capnp::any_pointer::Owned>)
+            -> Promise<(), Error>
+    {
+        // Every stand-in grain is the same, so there is nothing to remember about it.
+        let token = format!("{}{}", STURDYREF_PREFIX, pry!(random_hex_string(8)));
+        results.get().set_token(token.as_bytes());
+        Promise::ok(())
+    }
+
+    fn restore(&mut self,
+               params: sandstorm_api::RestoreParams<::capnp::any_pointer::Owned>,
+               mut results: sandstorm_api::RestoreResults<::capnp::any_pointer::Owned>)
+               -> Promise<(), Error>
+    {
+        let token = pry!(pry!(params.get()).get_token());
+        if !token.starts_with(STURDYREF_PREFIX.as_bytes()) {
+            return Promise::err(Error::failed("the dev server can only restore its own grains".into()))
+        }
+        results.get().get_cap().set_as_capability(dev_grain(&self.icon_host_path).client.hook);
+        Promise::ok(())
+    }
+
+    fn drop(&mut self,
+            _params: sandstorm_api::DropParams<::capnp::any_pointer::Owned>,
+            _results: sandstorm_api::DropResults<::capnp::any_pointer::Owned>)
+            -> Promise<(), Error>
+    {
+        Promise::ok(())
+    }
+}
+
+/// Stands in for the session context. It accepts the request tokens from `api/dev/powerbox`
+/// and ignores activity events; everything else fails as unimplemented.
+struct DevSessionContext {
+    icon_host_path: String,
+}
+
+impl session_context::Server for DevSessionContext {
+    fn claim_request(&mut self,
+                     params: session_context::ClaimRequestParams,
+                     mut results: session_context::ClaimRequestResults)
+                     -> Promise<(), Error>
+    {
+        let token = pry!(pry!(params.get()).get_request_token());
+        if !token.starts_with(REQUEST_TOKEN_PREFIX) {
+            return Promise::err(Error::failed(format!("unknown request token {}", token)))
+        }
+        results.get().get_cap().set_as_capability(dev_grain(&self.icon_host_path).client.hook);
+        Promise::ok(())
+    }
+
+    fn activity(&mut self,
+                _params: session_context::ActivityParams,
+                _results: session_context::ActivityResults)
+                -> Promise<(), Error>
+    {
+        Promise::ok(())
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+
+    /// With lowercase names.
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref value)| &value[..])
+    }
+}
+
+fn bad_request(message: &str) -> ::std::io::Error {
+    ::std::io::Error::new(::std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// The length of the request line and headers, including the blank line that ends them, if
+/// `buffer` holds all of them yet.
+fn head_length(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|w| w == b"\r\n\r\n").map(|idx| idx + 4)
+}
+
+fn parse_head(head: &[u8]) -> Result<Request, ::std::io::Error> {
+    let head = try!(::std::str::from_utf8(head).map_err(|_| bad_request("non-UTF-8 headers")));
+    let mut lines = head.split("\r\n");
+    let request_line: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+    if request_line.len() != 3 || !request_line[1].starts_with('/') {
+        return Err(bad_request("malformed request line"))
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        match line.find(':') {
+            Some(idx) => headers.push((line[..idx].trim().to_lowercase(),
+                                       line[idx + 1..].trim().to_string())),
+            None => return Err(bad_request("malformed header")),
+        }
+    }
+
+    Ok(Request {
+        method: request_line[0].to_string(),
+        // Sandstorm hands us paths without the leading slash.
+        path: request_line[1][1..].to_string(),
+        headers: headers,
+        body: Vec::new(),
+    })
+}
+
+/// Reads one request from `socket`.
+fn read_request(socket: TcpStream)
+                -> Box<Future<Item=(TcpStream, Request), Error=::std::io::Error>>
+{
+    let head = loop_fn((socket, Vec::new()), |(socket, mut buffer)| {
+        ::tokio_core::io::read(socket, vec![0; 4096]).and_then(move |(socket, chunk, n)| {
+            if n == 0 {
+                return Err(bad_request("connection closed before the end of the headers"))
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+            if head_length(&buffer).is_some() {
+                Ok(Loop::Break((socket, buffer)))
+            } else if buffer.len() > MAX_HEAD_BYTES {
+                Err(bad_request("headers too long"))
+            } else {
+                Ok(Loop::Continue((socket, buffer)))
+            }
+        })
+    });
+
+    Box::new(head.and_then(|(socket, buffer)| {
+        let length = head_length(&buffer).unwrap_or(buffer.len());
+        let mut request = try!(parse_head(&buffer[..length]));
+        let content_length = match request.header("content-length") {
+            Some(l) => try!(l.parse::<usize>().map_err(|_| bad_request("bad content-length"))),
+            None => 0,
+        };
+        if content_length > MAX_BODY_BYTES {
+            return Err(bad_request("body too long"))
+        }
+        request.body = buffer[length..].to_vec();
+        request.body.truncate(content_length);
+        let missing = content_length - request.body.len();
+        Ok((socket, request, missing))
+    }).and_then(|(socket, mut request, missing)| {
+        ::tokio_core::io::read_exact(socket, vec![0; missing]).map(move |(socket, rest)| {
+            request.body.extend_from_slice(&rest);
+            (socket, request)
+        })
+    }))
+}
+
+/// Passes on the headers that Sandstorm would: the ones with the `x-sandstorm-app-` prefix.
+fn fill_in_context(context: web_session::context::Builder, request: &Request) {
+    let headers: Vec<&(String, String)> = request.headers.iter()
+        .filter(|&&(ref name, _)| name.starts_with("x-sandstorm-app-"))
+        .collect();
+    let mut list = context.init_additional_headers(headers.len() as u32);
+    for (idx, &&(ref name, ref value)) in headers.iter().enumerate() {
+        let mut header = list.borrow().get(idx as u32);
+        header.set_name(name);
+        header.set_value(value);
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        303 => "See Other",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        _ => "",
+    }
+}
+
+fn serialize_response(status: u16, headers: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    // Nothing is cached, so that edits to the frontend show up on the next load.
+    let mut head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n\
+                            Cache-Control: no-store\r\n",
+                           status, reason_phrase(status), body.len());
+    for &(ref name, ref value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// Turns the session's answer into the HTTP response that Sandstorm would send.
+fn http_response(response: web_session::response::Reader) -> Result<Vec<u8>, Error> {
+    use sandstorm::web_session_capnp::web_session::response::{self, Which};
+    let status = status_of(response).unwrap_or(500);
+    let mut headers = Vec::new();
+    for header in try!(response.get_additional_headers()).iter() {
+        headers.push((try!(header.get_name()).to_string(), try!(header.get_value()).to_string()));
+    }
+
+    let body = match try!(response.which()) {
+        Which::Content(content) => {
+            headers.push(("Content-Type".into(), try!(content.get_mime_type()).to_string()));
+            if content.has_encoding() {
+                headers.push(("Content-Encoding".into(), try!(content.get_encoding()).to_string()));
+            }
+            match try!(content.get_body().which()) {
+                response::content::body::Which::Bytes(bytes) => try!(bytes).to_vec(),
+                _ => return Err(Error::unimplemented(
+                    "the dev server does not support streamed responses".into())),
+            }
+        }
+        Which::Redirect(redirect) => {
+            headers.push(("Location".into(), try!(redirect.get_location()).to_string()));
+            Vec::new()
+        }
+        Which::ClientError(error) => {
+            headers.push(("Content-Type".into(), "text/html; charset=UTF-8".into()));
+            try!(error.get_description_html()).as_bytes().to_vec()
+        }
+        Which::ServerError(error) => {
+            headers.push(("Content-Type".into(), "text/html; charset=UTF-8".into()));
+            try!(error.get_description_html()).as_bytes().to_vec()
+        }
+        _ => Vec::new(),
+    };
+    Ok(serialize_response(status, &headers, &body))
+}
+
+/// Sends `request` to the session in the way that Sandstorm would, and answers with the HTTP
+/// response.
+fn dispatch(session: &web_session::Client, request: &Request) -> Promise<Vec<u8>, Error> {
+    macro_rules! send {
+        ($req:expr) => {
+            Promise::from_future($req.send().promise.and_then(|response| {
+                http_response(try!(response.get()))
+            }))
+        }
+    }
+
+    let mime_type = request.header("content-type").unwrap_or("application/octet-stream");
+    match &request.method[..] {
+        "GET" | "HEAD" => {
+            let mut req = session.get_request();
+            req.get().set_path(&request.path);
+            req.get().set_ignore_body(request.method == "HEAD");
+            fill_in_context(req.get().init_context(), request);
+            send!(req)
+        }
+        "POST" => {
+            let mut req = session.post_request();
+            req.get().set_path(&request.path);
+            {
+                let mut content = req.get().init_content();
+                content.set_mime_type(mime_type);
+                content.set_content(&request.body);
+            }
+            fill_in_context(req.get().init_context(), request);
+            send!(req)
+        }
+        "PUT" => {
+            let mut req = session.put_request();
+            req.get().set_path(&request.path);
+            {
+                let mut content = req.get().init_content();
+                content.set_mime_type(mime_type);
+                content.set_content(&request.body);
+            }
+            fill_in_context(req.get().init_context(), request);
+            send!(req)
+        }
+        "DELETE" => {
+            let mut req = session.delete_request();
+            req.get().set_path(&request.path);
+            fill_in_context(req.get().init_context(), request);
+            send!(req)
+        }
+        _ => {
+            let headers = vec![("Allow".to_string(), "GET, HEAD, POST, PUT, DELETE".to_string())];
+            Promise::ok(serialize_response(405, &headers, b""))
+        }
+    }
+}
+
+/// SHA-1, which the websocket handshake needs and nothing else does.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bit_length = (data.len() as u64) * 8;
+    for idx in 0..8 {
+        message.push((bit_length >> (56 - 8 * idx)) as u8);
+    }
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for idx in 0..16 {
+            w[idx] = (block[4 * idx] as u32) << 24 | (block[4 * idx + 1] as u32) << 16 |
+                (block[4 * idx + 2] as u32) << 8 | block[4 * idx + 3] as u32;
+        }
+        for idx in 16..80 {
+            w[idx] = (w[idx - 3] ^ w[idx - 8] ^ w[idx - 14] ^ w[idx - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for idx in 0..80 {
+            let (f, k) = if idx < 20 {
+                ((b & c) | (!b & d), 0x5a827999)
+            } else if idx < 40 {
+                (b ^ c ^ d, 0x6ed9eba1)
+            } else if idx < 60 {
+                ((b & c) | (b & d) | (c & d), 0x8f1bbcdc)
+            } else {
+                (b ^ c ^ d, 0xca62c1d6)
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e)
+                .wrapping_add(k).wrapping_add(w[idx]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for idx in 0..20 {
+        digest[idx] = (h[idx / 4] >> (24 - 8 * (idx % 4))) as u8;
+    }
+    digest
+}
+
+/// Writes what the session sends on a websocket to the browser's connection. Sandstorm's
+/// websocket streams carry raw frames, so there is nothing to translate.
+struct SocketWriter {
+    sender: ::futures::sync::mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl web_socket_stream::Server for SocketWriter {
+    fn send_bytes(&mut self,
+                  params: web_socket_stream::SendBytesParams,
+                  _results: web_socket_stream::SendBytesResults)
+                  -> Promise<(), Error>
+    {
+        let message = pry!(pry!(params.get()).get_message()).to_vec();
+        match self.sender.send(message) {
+            Ok(()) => Promise::ok(()),
+            Err(_) => Promise::err(Error::disconnected("websocket closed".into())),
+        }
+    }
+}
+
+/// Completes the websocket handshake and then copies frames in both directions until the
+/// browser goes away.
+fn open_web_socket(handle: &::tokio_core::reactor::Handle,
+                   session: &web_session::Client,
+                   socket: TcpStream,
+                   request: Request)
+                   -> Promise<(), Error>
+{
+    let key = match request.header("sec-websocket-key") {
+        Some(key) => key.to_string(),
+        None => {
+            let response = serialize_response(400, &[], b"missing Sec-WebSocket-Key");
+            return Promise::from_future(::tokio_core::io::write_all(socket, response)
+                                        .map(|_| ()).map_err(|e| e.into()))
+        }
+    };
+    let accept = sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()).to_base64(base64::STANDARD);
+
+    let (reader, writer) = socket.split();
+    let (sender, receiver) = ::futures::sync::mpsc::unbounded();
+    let handshake = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept);
+    let writes = ::tokio_core::io::write_all(writer, handshake.into_bytes()).map_err(|_| ())
+        .and_then(|(writer, _)| receiver.fold(writer, |writer, frame| {
+            ::tokio_core::io::write_all(writer, frame).map(|(writer, _)| writer).map_err(|_| ())
+        }))
+        .map(|_| ());
+    handle.spawn(writes);
+
+    let mut req = session.open_web_socket_request();
+    req.get().set_path(&request.path);
+    fill_in_context(req.get().init_context(), &request);
+    req.get().set_client_stream(
+        web_socket_stream::ToClient::new(SocketWriter { sender: sender })
+            .from_server::<::capnp_rpc::Server>());
+    Promise::from_future(req.send().promise.and_then(move |response| {
+        let server_stream = try!(try!(response.get()).get_server_stream());
+        Ok(server_stream)
+    }).and_then(move |server_stream| {
+        loop_fn(reader, move |reader| {
+            let server_stream = server_stream.clone();
+            ::tokio_core::io::read(reader, vec![0; 4096]).map_err(Error::from)
+                .and_then(move |(reader, chunk, n)| {
+                    if n == 0 {
+                        return Promise::ok(Loop::Break(()))
+                    }
+                    let mut req = server_stream.send_bytes_request();
+                    req.get().set_message(&chunk[..n]);
+                    Promise::from_future(req.send().promise.map(move |_| Loop::Continue(reader)))
+                })
+        })
+    }))
+}
+
+fn serve_connection(handle: ::tokio_core::reactor::Handle,
+                    session: web_session::Client,
+                    socket: TcpStream)
+                    -> Promise<(), Error>
+{
+    Promise::from_future(read_request(socket).map_err(Error::from).and_then(move |(socket, request)| {
+        let upgrade = request.header("upgrade").map_or(false, |u| u.to_lowercase() == "websocket");
+        if upgrade {
+            return open_web_socket(&handle, &session, socket, request)
+        }
+
+        let method = request.method.clone();
+        let path = request.path.clone();
+        let answer = if method == "POST" && path == "api/dev/powerbox" {
+            let title = String::from_utf8_lossy(&request.body).into_owned();
+            let text = pry!(mint_powerbox_response(&title));
+            let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+            Promise::ok(serialize_response(200, &headers, text.as_bytes()))
+        } else if method == "GET" && path == "dev/icon.svg" {
+            let headers = vec![("Content-Type".to_string(), "image/svg+xml".to_string())];
+            Promise::ok(serialize_response(200, &headers, GRAIN_ICON_SVG.as_bytes()))
+        } else {
+            dispatch(&session, &request)
+        };
+        Promise::from_future(answer.then(move |result| {
+            let response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    println!("{} /{} failed: {}", method, path, e);
+                    serialize_response(500, &[], format!("{}", e).as_bytes())
+                }
+            };
+            ::tokio_core::io::write_all(socket, response).map(|_| ()).map_err(Error::from)
+        }))
+    }))
+}
+
+/// The modification times of the watched frontend files, with None for files that are missing.
+fn asset_mtimes(asset_dir: &::std::path::Path) -> Vec<Option<::std::time::SystemTime>> {
+    WATCHED_ASSETS.iter().map(|name| {
+        ::std::fs::metadata(asset_dir.join(name)).and_then(|m| m.modified()).ok()
+    }).collect()
+}
+
+/// Polls the frontend files, and reloads every connected page when one of them changes.
+fn watch_assets(timer: Rc<ReactorTimer>,
+                asset_dir: ::std::path::PathBuf,
+                saved_ui_views: SavedUiViewSet)
+                -> Promise<(), Error>
+{
+    let initial = asset_mtimes(&asset_dir);
+    Promise::from_future(loop_fn(initial, move |previous| {
+        let asset_dir = asset_dir.clone();
+        let mut saved_ui_views = saved_ui_views.clone();
+        timer.after(::std::time::Duration::from_millis(ASSET_POLL_INTERVAL_MILLIS)).map(move |()| {
+            let current = asset_mtimes(&asset_dir);
+            if current != previous {
+                println!("frontend changed; reloading pages");
+                saved_ui_views.reload_clients();
+            }
+            Loop::Continue(current)
+        })
+    }))
+}
+
+fn icon_host_path(port: u16) -> String {
+    format!("localhost:{}/dev/icon.svg", port)
+}
+
+/// Builds the user info and session params that Sandstorm would pass to `newSession()`, and
+/// opens the session that every request goes to.
+fn new_session(saved_ui_views: SavedUiViewSet,
+               sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+               port: u16)
+               -> ::capnp::Result<web_session::Client>
+{
+    let mut user_info_message = ::capnp::message::Builder::new_default();
+    {
+        let mut user_info = user_info_message.init_root::<user_info::Builder>();
+        user_info.borrow().init_display_name().set_default_text("Dev User");
+        user_info.borrow().set_preferred_handle("dev");
+        user_info.set_identity_id(&DEV_IDENTITY_ID);
+        let mut permissions = user_info.init_permissions(3);
+        permissions.set(0, true);
+        permissions.set(1, true);
+        permissions.set(2, true);
+    }
+
+    let mut params_message = ::capnp::message::Builder::new_default();
+    {
+        let mut params = params_message.init_root::<web_session::params::Builder>();
+        params.set_base_path(&format!("http://localhost:{}", port));
+        params.set_user_agent("collections dev server");
+        params.init_acceptable_languages(1).set(0, "en");
+    }
+
+    let context: session_context::Client =
+        session_context::ToClient::new(DevSessionContext { icon_host_path: icon_host_path(port) })
+            .from_server::<::capnp_rpc::Server>();
+    let session = try!(WebSession::new(
+        try!(user_info_message.get_root_as_reader()),
+        context,
+        try!(params_message.get_root_as_reader()),
+        sandstorm_api,
+        saved_ui_views));
+    Ok(web_session::ToClient::new(session).from_server::<::capnp_rpc::Server>())
+}
+
+pub fn main(args: &[String]) -> Result<(), Box<::std::error::Error>> {
+    let mut port = DEFAULT_PORT;
+    let mut var_dir = "dev-var".to_string();
+    let mut asset_dir = "tmp".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(format!("missing value for {}", arg).into()),
+        };
+        match &arg[..] {
+            "--port" => port = try!(value.parse()),
+            "--var" => var_dir = value.clone(),
+            "--assets" => asset_dir = value.clone(),
+            _ => return Err(format!("unknown option {}", arg).into()),
+        }
+    }
+
+    try!(::std::fs::create_dir_all(&var_dir));
+    let config = Rc::new(Config::dev(&var_dir, &asset_dir));
+    let _lock = try!(::instance_lock::InstanceLock::acquire(config.var_path("server.lock")));
+
+    let mut core = try!(::tokio_core::reactor::Core::new());
+    let handle = core.handle();
+
+    let sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+        sandstorm_api::ToClient::new(DevSandstormApi { icon_host_path: icon_host_path(port) })
+            .from_server::<::capnp_rpc::Server>();
+    let identity_map = try!(IdentityMap::new(
+        config.var_path("identities"),
+        config.var_path("trash"),
+        &sandstorm_api,
+        &handle));
+    let timer = Rc::new(ReactorTimer::new(&handle));
+    let saved_ui_views = try!(SavedUiViewSet::new(
+        config.clone(),
+        &sandstorm_api,
+        identity_map,
+        Rc::new(SystemClock),
+        timer.clone(),
+        &handle));
+    handle.spawn(watch_assets(timer, asset_dir.into(), saved_ui_views.clone())
+                 .map_err(|e| println!("stopped watching the frontend: {}", e)));
+    let session = try!(new_session(saved_ui_views, sandstorm_api, port));
+
+    let address = ::std::net::SocketAddr::new(
+        ::std::net::IpAddr::V4(::std::net::Ipv4Addr::new(127, 0, 0, 1)), port);
+    let listener = try!(TcpListener::bind(&address, &handle));
+    println!("dev server listening on http://localhost:{}/", port);
+
+    let handle1 = handle.clone();
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let connection = serve_connection(handle1.clone(), session.clone(), socket);
+        handle1.spawn(connection.map_err(|e| println!("dev server connection failed: {}", e)));
+        Ok(())
+    });
+    try!(core.run(server));
+    Ok(())
+}
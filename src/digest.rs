@@ -0,0 +1,191 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Opt-in email digests of collection activity. Each subscriber gets a daily or weekly summary
+//! of the entries that were added and removed since their previous digest.
+
+use capnp::Error;
+use std::collections::btree_map::BTreeMap;
+
+use collections_capnp::{digest_subscription, digest_subscriptions};
+use journal::{JournalEntry, ACTIVITY_EVENT_TYPES};
+
+pub use collections_capnp::digest_subscription::Frequency;
+
+pub fn frequency_name(frequency: Frequency) -> &'static str {
+    match frequency {
+        Frequency::Daily => "daily",
+        Frequency::Weekly => "weekly",
+    }
+}
+
+pub fn parse_frequency(name: &str) -> Result<Option<Frequency>, Error> {
+    match name.trim() {
+        "daily" => Ok(Some(Frequency::Daily)),
+        "weekly" => Ok(Some(Frequency::Weekly)),
+        "off" => Ok(None),
+        other => Err(Error::failed(format!("unknown digest frequency: {:?}", other))),
+    }
+}
+
+fn period_millis(frequency: Frequency) -> u64 {
+    match frequency {
+        Frequency::Daily => 24 * 60 * 60 * 1000,
+        Frequency::Weekly => 7 * 24 * 60 * 60 * 1000,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    /// Where to send the digest.
+    pub address: String,
+    pub frequency: Frequency,
+
+    /// When the subscriber's previous digest covered activity up to, in milliseconds since the
+    /// unix epoch.
+    pub last_sent: u64,
+}
+
+pub struct Digests {
+    path: ::std::path::PathBuf,
+
+    /// Keyed by identity ID, encoded in hexadecimal format.
+    subscriptions: BTreeMap<String, Subscription>,
+}
+
+impl Digests {
+    pub fn open<P>(path: P) -> Result<Digests, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let mut subscriptions = BTreeMap::new();
+        match ::std::fs::File::open(&path) {
+            Ok(mut f) => {
+                let message = try!(::capnp::serialize::read_message(&mut f, Default::default()));
+                let root: digest_subscriptions::Reader = try!(message.get_root());
+                for subscription in try!(root.get_subscriptions()).iter() {
+                    subscriptions.insert(try!(subscription.get_identity_id()).into(), Subscription {
+                        address: try!(subscription.get_address()).into(),
+                        frequency: try!(subscription.get_frequency()),
+                        last_sent: subscription.get_last_sent(),
+                    });
+                }
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Digests {
+            path: path.as_ref().to_path_buf(),
+            subscriptions: subscriptions,
+        })
+    }
+
+    pub fn get(&self, identity_id: &str) -> Option<&Subscription> {
+        self.subscriptions.get(identity_id)
+    }
+
+    /// Subscribes `identity_id` at `address`, or changes the frequency of an existing
+    /// subscription. The first digest covers activity from `now` on.
+    pub fn subscribe(&mut self,
+                     identity_id: &str,
+                     address: String,
+                     frequency: Frequency,
+                     now: u64) -> Result<(), Error> {
+        let last_sent = self.subscriptions.get(identity_id).map(|s| s.last_sent).unwrap_or(now);
+        self.subscriptions.insert(identity_id.into(), Subscription {
+            address: address,
+            frequency: frequency,
+            last_sent: last_sent,
+        });
+        self.save()
+    }
+
+    pub fn unsubscribe(&mut self, identity_id: &str) -> Result<(), Error> {
+        if self.subscriptions.remove(identity_id).is_some() {
+            try!(self.save());
+        }
+        Ok(())
+    }
+
+    /// Identity IDs and subscriptions whose next digest is due at `now`.
+    pub fn due(&self, now: u64) -> Vec<(String, Subscription)> {
+        self.subscriptions.iter().filter(|&(_, s)| {
+            s.last_sent + period_millis(s.frequency) <= now
+        }).map(|(id, s)| (id.clone(), s.clone())).collect()
+    }
+
+    pub fn mark_sent(&mut self, identity_id: &str, time: u64) -> Result<(), Error> {
+        match self.subscriptions.get_mut(identity_id) {
+            Some(s) => s.last_sent = time,
+            None => return Ok(()),
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let root = message.init_root::<digest_subscriptions::Builder>();
+            let mut list = root.init_subscriptions(self.subscriptions.len() as u32);
+            for (idx, (identity_id, s)) in self.subscriptions.iter().enumerate() {
+                let mut subscription: digest_subscription::Builder = list.borrow().get(idx as u32);
+                subscription.set_identity_id(identity_id);
+                subscription.set_address(&s.address);
+                subscription.set_frequency(s.frequency);
+                subscription.set_last_sent(s.last_sent);
+            }
+        }
+
+        let mut temp_path = self.path.clone();
+        temp_path.set_extension("uploading");
+        {
+            let mut writer = try!(::std::fs::File::create(&temp_path));
+            try!(::capnp::serialize::write_message(&mut writer, &message));
+            try!(writer.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, &self.path));
+        Ok(())
+    }
+}
+
+/// Renders the plain-text body of a digest covering the entries after `since`, or None if
+/// nothing worth reporting happened. Each line uses the same verb phrase as the corresponding
+/// event in Sandstorm's activity log.
+pub fn render(collection_title: &str, entries: &[JournalEntry], since: u64) -> Option<String> {
+    let mut lines = Vec::new();
+    for entry in entries.iter().filter(|e| e.time > since) {
+        let verb_phrase = match ACTIVITY_EVENT_TYPES.iter().find(|&&(op, _)| op == entry.op) {
+            Some(&(_, verb_phrase)) => verb_phrase,
+            None => continue,
+        };
+        match entry.title {
+            Some(ref title) => lines.push(format!("- {}: {}", verb_phrase, title)),
+            None => lines.push(format!("- {}", verb_phrase)),
+        }
+    }
+
+    if lines.is_empty() {
+        None
+    } else {
+        Some(format!("Recent activity in {}:\n\n{}\n", collection_title, lines.join("\n")))
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! The languages that the server's own messages are available in. Most of the app's text lives
+//! in the frontend; this covers the pages and errors that the server renders itself.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    English,
+    German,
+}
+
+const SUPPORTED: &'static [(&'static str, Language)] = &[
+    ("en", Language::English),
+    ("de", Language::German),
+];
+
+impl Language {
+    /// Picks the first entry of `acceptable`, which is in order of the user's preference, that we
+    /// support. Only the primary subtag is compared, so "de-CH" selects German. Falls back to
+    /// English.
+    pub fn negotiate<'a, I>(acceptable: I) -> Language
+        where I: IntoIterator<Item=&'a str>
+    {
+        for tag in acceptable {
+            let primary = tag.split(|c| c == '-' || c == '_' || c == ';').next().unwrap_or("");
+            let primary = primary.trim().to_lowercase();
+            for &(code, language) in SUPPORTED {
+                if primary == code {
+                    return language
+                }
+            }
+        }
+        Language::English
+    }
+
+    /// Every language we support, English first.
+    pub fn all() -> Vec<Language> {
+        SUPPORTED.iter().map(|&(_, language)| language).collect()
+    }
+
+    /// The BCP 47 tag, suitable for an HTML `lang` attribute.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::German => "de",
+        }
+    }
+}
+
+pub enum Message<'a> {
+    /// The user lacks the named permission.
+    PermissionRequired(&'a str),
+
+    /// The user has hit the rate limit and should retry after this many seconds.
+    TooManyChanges(u64),
+
+    /// Anonymous users have no email address to send digests to.
+    AnonymousDigest,
+
+    /// Ratings and upvotes are kept per identity, which anonymous users lack.
+    AnonymousRating,
+
+    /// A manager has locked the collection against changes.
+    Locked,
+
+    /// Storage maintenance is under way; the user should retry after this many seconds.
+    Maintenance(u64),
+
+    /// A manager has turned off the named feature in this collection.
+    FeatureDisabled(&'a str),
+
+    /// Labels the sturdyref that a collection saves for a grain, for the user's list of what
+    /// they have shared. The collection's title is left out if it has none.
+    SaveLabel { collection: Option<&'a str>, grain: &'a str },
+}
+
+impl<'a> Message<'a> {
+    pub fn localize(&self, language: Language) -> String {
+        match (self, language) {
+            (&Message::PermissionRequired(name), Language::English) => {
+                format!("this requires the \"{}\" permission", name)
+            }
+            (&Message::PermissionRequired(name), Language::German) => {
+                format!("dafür ist die Berechtigung \"{}\" erforderlich", name)
+            }
+            (&Message::TooManyChanges(seconds), Language::English) => {
+                format!("too many changes; please wait {} seconds and try again", seconds)
+            }
+            (&Message::TooManyChanges(seconds), Language::German) => {
+                format!("zu viele Änderungen; bitte warten Sie {} Sekunden und versuchen Sie es \
+                         erneut", seconds)
+            }
+            (&Message::AnonymousDigest, Language::English) => {
+                "anonymous users cannot subscribe to digests".into()
+            }
+            (&Message::AnonymousDigest, Language::German) => {
+                "anonyme Benutzer können keine Zusammenfassungen abonnieren".into()
+            }
+            (&Message::AnonymousRating, Language::English) => {
+                "anonymous users cannot rate or upvote entries".into()
+            }
+            (&Message::AnonymousRating, Language::German) => {
+                "anonyme Benutzer können Einträge weder bewerten noch empfehlen".into()
+            }
+            (&Message::Locked, Language::English) => {
+                "this collection is locked and cannot be changed".into()
+            }
+            (&Message::Locked, Language::German) => {
+                "diese Sammlung ist gesperrt und kann nicht geändert werden".into()
+            }
+            (&Message::Maintenance(seconds), Language::English) => {
+                format!("this collection is undergoing maintenance; please wait {} seconds and \
+                         try again", seconds)
+            }
+            (&Message::Maintenance(seconds), Language::German) => {
+                format!("diese Sammlung wird gerade gewartet; bitte warten Sie {} Sekunden und \
+                         versuchen Sie es erneut", seconds)
+            }
+            (&Message::FeatureDisabled(name), Language::English) => {
+                format!("the \"{}\" feature is turned off in this collection", name)
+            }
+            (&Message::FeatureDisabled(name), Language::German) => {
+                format!("die Funktion \"{}\" ist in dieser Sammlung ausgeschaltet", name)
+            }
+            (&Message::SaveLabel { collection: Some(collection), grain }, Language::English) => {
+                format!("Saved in collection \"{}\": {}", collection, grain)
+            }
+            (&Message::SaveLabel { collection: None, grain }, Language::English) => {
+                format!("Saved in a collection: {}", grain)
+            }
+            (&Message::SaveLabel { collection: Some(collection), grain }, Language::German) => {
+                format!("Gespeichert in der Sammlung \"{}\": {}", collection, grain)
+            }
+            (&Message::SaveLabel { collection: None, grain }, Language::German) => {
+                format!("In einer Sammlung gespeichert: {}", grain)
+            }
+        }
+    }
+}
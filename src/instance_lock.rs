@@ -0,0 +1,70 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Makes sure that only one server process at a time works on the grain's storage.
+//!
+//! We hold an exclusive `flock()` on a lock file for as long as we run. The kernel releases it
+//! when our process exits, however that happens, so there is no stale lock to detect, and two
+//! starting processes can't both take it. The file holds the ID of the process that has it, for
+//! the error message of whoever finds it taken.
+
+use capnp::Error;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+
+pub struct InstanceLock {
+    /// The open lock file. Closing it releases the lock. We leave the file itself in place: a
+    /// process that already opened it would otherwise lock a file that nobody else can see.
+    _file: ::std::fs::File,
+}
+
+/// The ID of this process, as the kernel's procfs sees it.
+fn own_pid() -> Result<String, Error> {
+    let link = try!(::std::fs::read_link("/proc/self"));
+    Ok(link.to_string_lossy().into_owned())
+}
+
+impl InstanceLock {
+    /// Locks the file at `path`, creating it if needed, and fails if another process holds it.
+    pub fn acquire<P>(path: P) -> Result<InstanceLock, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let path = path.as_ref();
+        let mut file = try!(::std::fs::OpenOptions::new()
+                            .read(true).write(true).create(true).open(path));
+        if unsafe { ::libc::flock(file.as_raw_fd(), ::libc::LOCK_EX | ::libc::LOCK_NB) } != 0 {
+            let e = ::std::io::Error::last_os_error();
+            if e.kind() != ::std::io::ErrorKind::WouldBlock {
+                return Err(e.into())
+            }
+            let mut holder = String::new();
+            try!(file.read_to_string(&mut holder));
+            return Err(Error::failed(format!(
+                "another server process ({}) is already using {}", holder.trim(), path.display())))
+        }
+
+        try!(file.set_len(0));
+        try!(file.write_all(try!(own_pid()).as_bytes()));
+        try!(file.sync_all());
+        Ok(InstanceLock { _file: file })
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Append-only record of changes made to the collection, stored as one JSON object per line.
+
+use capnp::Error;
+use rustc_serialize::json;
+use std::io::{BufRead, Write};
+
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    /// Milliseconds since the unix epoch.
+    pub time: u64,
+
+    /// Identity ID of whoever made the change, encoded in hexadecimal format.
+    pub actor: Option<String>,
+
+    /// The actor's display name at the time, where we know it. Only removals record it, since
+    /// the entry (and so whatever it says about who added it) is gone afterwards.
+    pub actor_name: Option<String>,
+
+    /// What happened, e.g. "add", "remove", or "description".
+    pub op: String,
+
+    pub token: Option<String>,
+    pub title: Option<String>,
+}
+
+/// The ops that we also report to Sandstorm's activity log, each with the verb phrase that
+/// describes it there. The position of each op is its activity event type index.
+pub const ACTIVITY_EVENT_TYPES: &'static [(&'static str, &'static str)] = &[
+    ("add", "added grain"),
+    ("remove", "removed grain"),
+    ("description", "edited description"),
+];
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// How many entries were added and removed on one UTC day.
+pub struct DailyCount {
+    /// The start of the day, in milliseconds since the unix epoch.
+    pub day: u64,
+    pub added: u64,
+    pub removed: u64,
+}
+
+/// Counts the "add" and "remove" entries on each of the `days` UTC days up to and including the
+/// one containing `now`. Days without any changes are included, so the result has no gaps.
+pub fn daily_counts(entries: &[&JournalEntry], now: u64, days: u64) -> Vec<DailyCount> {
+    let last_day = now / MILLIS_PER_DAY;
+    let first_day = (last_day + 1).saturating_sub(days);
+    let mut counts: Vec<DailyCount> = (first_day..last_day + 1).map(|day| DailyCount {
+        day: day * MILLIS_PER_DAY,
+        added: 0,
+        removed: 0,
+    }).collect();
+
+    for entry in entries {
+        let day = entry.time / MILLIS_PER_DAY;
+        if day < first_day || day > last_day { continue }
+        let count = &mut counts[(day - first_day) as usize];
+        match &entry.op[..] {
+            "add" => count.added += 1,
+            "remove" => count.removed += 1,
+            _ => (),
+        }
+    }
+    counts
+}
+
+fn optional_to_json(value: &Option<String>) -> String {
+    match value {
+        &None => "null".into(),
+        &Some(ref s) => format!("{}", json::ToJson::to_json(s)),
+    }
+}
+
+fn optional_from_json(object: &json::Object, key: &str) -> Option<String> {
+    object.get(key).and_then(|v| v.as_string()).map(|s| s.to_string())
+}
+
+/// The count in a `{"purged": n}` line, if `line` is one.
+fn parse_purged(line: &str) -> Option<usize> {
+    json::Json::from_str(line).ok()
+        .and_then(|value| value.find("purged").and_then(|n| n.as_u64()))
+        .map(|n| n as usize)
+}
+
+impl JournalEntry {
+    pub fn to_json(&self) -> String {
+        format!("{{\"time\":{},\"actor\":{},\"actorName\":{},\"op\":{},\"token\":{},\
+                   \"title\":{}}}",
+                self.time,
+                optional_to_json(&self.actor),
+                optional_to_json(&self.actor_name),
+                json::ToJson::to_json(&self.op),
+                optional_to_json(&self.token),
+                optional_to_json(&self.title))
+    }
+
+    /// JSON for webhooks, which go to services outside Sandstorm. It leaves out the token, which
+    /// is a sturdyref, and the actor's identity ID; `actor_name` stands in for the actor.
+    pub fn to_webhook_json(&self, actor_name: &Option<String>) -> String {
+        format!("{{\"time\":{},\"actorName\":{},\"op\":{},\"title\":{}}}",
+                self.time,
+                optional_to_json(actor_name),
+                json::ToJson::to_json(&self.op),
+                optional_to_json(&self.title))
+    }
+
+    fn from_json(line: &str) -> Result<JournalEntry, Error> {
+        let value = try!(json::Json::from_str(line).map_err(|e| Error::failed(format!("{}", e))));
+        let object = match value.as_object() {
+            Some(o) => o,
+            None => return Err(Error::failed(format!("journal entry is not an object: {}", line))),
+        };
+
+        let time = match object.get("time").and_then(|v| v.as_u64()) {
+            Some(t) => t,
+            None => return Err(Error::failed(format!("journal entry has no time: {}", line))),
+        };
+        let op = match optional_from_json(object, "op") {
+            Some(op) => op,
+            None => return Err(Error::failed(format!("journal entry has no op: {}", line))),
+        };
+
+        Ok(JournalEntry {
+            time: time,
+            actor: optional_from_json(object, "actor"),
+            actor_name: optional_from_json(object, "actorName"),
+            op: op,
+            token: optional_from_json(object, "token"),
+            title: optional_from_json(object, "title"),
+        })
+    }
+}
+
+pub struct Journal {
+    path: ::std::path::PathBuf,
+    entries: Vec<JournalEntry>,
+
+    /// How many of the oldest entries `truncate()` has dropped. An entry's revision is its
+    /// position in the journal counting from 1, so the kept entries start at `purged + 1`.
+    /// Stored as a `{"purged": n}` line at the top of the file.
+    purged: usize,
+
+    /// How many lines of the file failed to parse.
+    skipped: usize,
+}
+
+impl Journal {
+    /// Reads the journal at `path`, creating it if it does not exist yet. Lines that fail to
+    /// parse (for example, a line that was only half written when the grain shut down) are
+    /// skipped.
+    pub fn open<P>(path: P) -> Result<Journal, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let mut entries = Vec::new();
+        let mut skipped = 0;
+        let mut purged = 0;
+        match ::std::fs::File::open(&path) {
+            Ok(f) => {
+                for line in ::std::io::BufReader::new(f).lines() {
+                    let line = try!(line);
+                    if line.is_empty() { continue }
+                    if entries.is_empty() {
+                        if let Some(n) = parse_purged(&line) {
+                            purged = n;
+                            continue
+                        }
+                    }
+                    match JournalEntry::from_json(&line) {
+                        Ok(entry) => entries.push(entry),
+                        Err(e) => {
+                            println!("skipping malformed journal entry: {}", e);
+                            skipped += 1;
+                        }
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Journal {
+            path: path.as_ref().to_path_buf(),
+            entries: entries,
+            purged: purged,
+            skipped: skipped,
+        })
+    }
+
+    pub fn append(&mut self, entry: JournalEntry) -> Result<(), Error> {
+        let mut file = try!(::std::fs::OpenOptions::new().create(true).append(true).open(&self.path));
+        try!(file.write_all(format!("{}\n", entry.to_json()).as_bytes()));
+        try!(file.sync_all());
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries[..]
+    }
+
+    pub fn purged(&self) -> usize {
+        self.purged
+    }
+
+    /// Drops all but the newest `keep` entries, returning how many were dropped.
+    pub fn truncate(&mut self, keep: usize) -> Result<usize, Error> {
+        if self.entries.len() <= keep {
+            return Ok(0)
+        }
+        let dropped = self.entries.len() - keep;
+        self.entries.drain(..dropped);
+        self.purged += dropped;
+        try!(self.rewrite());
+        Ok(dropped)
+    }
+
+    pub fn skipped_lines(&self) -> usize {
+        self.skipped
+    }
+
+    /// Rewrites the file with just the entries that parsed, dropping any malformed lines.
+    pub fn rewrite(&mut self) -> Result<(), Error> {
+        let temp_path = self.path.with_extension("uploading");
+        {
+            let mut file = try!(::std::fs::File::create(&temp_path));
+            if self.purged > 0 {
+                try!(file.write_all(format!("{{\"purged\":{}}}\n", self.purged).as_bytes()));
+            }
+            for entry in &self.entries {
+                try!(file.write_all(format!("{}\n", entry.to_json()).as_bytes()));
+            }
+            try!(file.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, &self.path));
+        self.skipped = 0;
+        Ok(())
+    }
+}
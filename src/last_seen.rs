@@ -0,0 +1,82 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Remembers when each identity last opened the collection. Each identity gets a small file
+//! under the given directory, named by its hex identity ID and holding a decimal timestamp.
+
+use capnp::Error;
+use std::collections::hash_map::HashMap;
+
+pub struct LastSeen {
+    directory: ::std::path::PathBuf,
+    times: HashMap<String, u64>,
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_digit(16))
+}
+
+impl LastSeen {
+    pub fn new<P>(directory: P) -> Result<LastSeen, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        use std::io::Read;
+        try!(::std::fs::create_dir_all(&directory));
+
+        let mut times = HashMap::new();
+        for file in try!(::std::fs::read_dir(&directory)) {
+            let dir_entry = try!(file);
+            let identity_id = match dir_entry.file_name().to_str() {
+                Some(s) if is_hex(s) => s.to_string(),
+                _ => continue,
+            };
+            let mut text = String::new();
+            try!(try!(::std::fs::File::open(dir_entry.path())).read_to_string(&mut text));
+            if let Ok(time) = text.trim().parse::<u64>() {
+                times.insert(identity_id, time);
+            }
+        }
+
+        Ok(LastSeen {
+            directory: directory.as_ref().to_path_buf(),
+            times: times,
+        })
+    }
+
+    /// Records a visit by `identity_id` at `now_millis` and returns the time of the previous
+    /// visit, if there was one.
+    pub fn visit(&mut self, identity_id: &str, now_millis: u64) -> Result<Option<u64>, Error> {
+        use std::io::Write;
+        if !is_hex(identity_id) {
+            return Err(Error::failed(format!("invalid identity ID {}", identity_id)));
+        }
+
+        let mut path = self.directory.clone();
+        path.push(identity_id);
+        let mut temp_path = self.directory.clone();
+        temp_path.push(format!("{}.uploading", identity_id));
+        try!(try!(::std::fs::File::create(&temp_path)).write_all(now_millis.to_string().as_bytes()));
+        try!(::std::fs::rename(temp_path, path));
+
+        Ok(self.times.insert(identity_id.to_string(), now_millis))
+    }
+}
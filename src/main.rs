@@ -21,23 +21,70 @@
 
 #[macro_use] extern crate futures;
 extern crate tokio_core;
+extern crate libc;
 extern crate mio_uds;
 extern crate capnp;
 #[macro_use] extern crate capnp_rpc;
 extern crate rustc_serialize;
 extern crate sandstorm;
 extern crate url;
+extern crate unicode_normalization;
 extern crate multipoll;
 
 pub mod collections_capnp {
   include!(concat!(env!("OUT_DIR"), "/collections_capnp.rs"));
 }
 
+pub mod acks;
+pub mod avatar_cache;
+pub mod clock;
+pub mod collation;
+pub mod config;
+pub mod csv;
+pub mod dev_server;
+pub mod digest;
+pub mod i18n;
 pub mod identity_map;
+pub mod instance_lock;
+pub mod journal;
+pub mod last_seen;
+pub mod offline;
+pub mod rate_limit;
+pub mod replay;
+pub mod request_log;
+pub mod router;
+pub mod search_index;
+pub mod settings;
+pub mod shards;
+pub mod thumbnails;
 pub mod web_socket;
+pub mod webdav;
+pub mod webhooks;
 pub mod server;
 
 fn main() {
+    let args: Vec<String> = ::std::env::args().skip(1).collect();
+    if args.get(0).map(|a| &a[..]) == Some("--dev") {
+        dev_server::main(&args[1..]).expect("dev server error");
+        return
+    }
+
+    // With other arguments, we work on the storage offline instead of serving.
+    if !args.is_empty() {
+        match offline::run(&args) {
+            Ok(true) => return,
+            Ok(false) => ::std::process::exit(1),
+            Err(e) => {
+                println!("{}", e);
+                ::std::process::exit(2)
+            }
+        }
+    }
+
+    // If the supervisor ever starts a second process before the first has exited, the two would
+    // overwrite each other's files.
+    let _lock = instance_lock::InstanceLock::acquire("/var/server.lock")
+        .expect("could not lock /var");
     server::main().expect("top level error");
 }
 
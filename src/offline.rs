@@ -0,0 +1,382 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Inspects and repairs a grain's storage without connecting to Sandstorm, for example in an
+//! unpacked backup. Run the server binary as
+//!
+//! ```text
+//! collections-server dump|check|repair [VAR_DIR]
+//! collections-server --check [--repair] [VAR_DIR]
+//! ```
+//!
+//! where VAR_DIR defaults to /var. `dump` prints the entries, `check` reports problems, and
+//! `repair` reports problems and fixes those that it can. `--check` is for scripts, such as
+//! backup tooling that vets a grain before restoring it: it does the same as `check` (or, with
+//! `--repair`, `repair`), but ends its output with a one-line JSON report, and exits with 1
+//! only if problems remain that repair cannot fix (2 if the check itself failed).
+
+use capnp::Error;
+use collections_capnp::ui_view_metadata;
+use rustc_serialize::json;
+use journal::Journal;
+use server::{SCHEMA_VERSION, SCHEMA_VERSION_FILE};
+use settings::Settings;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::path::Path;
+
+pub const USAGE: &'static str =
+    "usage: collections-server dump|check|repair [VAR_DIR]\n       \
+     collections-server --check [--repair] [VAR_DIR]";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Dump,
+    Check,
+    Repair,
+}
+
+/// The parts of an entry's metadata that we print or cross-check.
+struct Entry {
+    token: String,
+    title: String,
+    date_added: u64,
+    added_by: Option<String>,
+    folder: Option<String>,
+
+    /// The bookmark's URL, or None for a grain.
+    url: Option<String>,
+    has_thumbnail: bool,
+}
+
+fn read_entry(path: &Path, token: String) -> Result<Entry, Error> {
+    let mut reader = try!(::std::fs::File::open(path));
+    let message = try!(::capnp::serialize::read_message(&mut reader, Default::default()));
+    let metadata: ui_view_metadata::Reader = try!(message.get_root());
+    Ok(Entry {
+        token: token,
+        title: try!(metadata.get_title()).into(),
+        date_added: metadata.get_date_added(),
+        added_by: if metadata.has_added_by() {
+            Some(try!(metadata.get_added_by()).into())
+        } else {
+            None
+        },
+        folder: if metadata.has_folder() { Some(try!(metadata.get_folder()).into()) } else { None },
+        url: match try!(metadata.which()) {
+            ui_view_metadata::Which::UiView(()) => None,
+            ui_view_metadata::Which::Bookmark(bookmark) => Some(try!(bookmark.get_url()).into()),
+        },
+        has_thumbnail: metadata.has_thumbnail_mime_type(),
+    })
+}
+
+/// The names of the files in `dir`, or nothing if it does not exist.
+fn file_names(dir: &Path) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+    let entries = match ::std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(e.into()),
+    };
+    for dir_entry in entries {
+        let dir_entry = try!(dir_entry);
+        match dir_entry.file_name().into_string() {
+            Ok(name) => names.push(name),
+            Err(name) => println!("skipping file with non-UTF-8 name {:?}", name),
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// One problem found by `check` or `repair`, for the `--check` report.
+struct Finding {
+    description: String,
+    fixable: bool,
+    fixed: bool,
+}
+
+impl Finding {
+    fn to_json(&self) -> String {
+        format!("{{\"problem\":{},\"fixable\":{},\"fixed\":{}}}",
+                json::ToJson::to_json(&self.description), self.fixable, self.fixed)
+    }
+}
+
+/// Counts the problems that `check` and `repair` find, and fixes them in `repair` mode.
+/// Problems that are not fixed, whether because repair can't fix them or because fixing them
+/// failed, count as unfixed; in `check` mode, those that repair could fix don't.
+struct Checker {
+    mode: Mode,
+    problems: usize,
+    unfixed: usize,
+    findings: Vec<Finding>,
+}
+
+impl Checker {
+    /// Reports a problem that we don't know how to fix.
+    fn problem(&mut self, description: String) {
+        println!("problem: {}", description);
+        self.problems += 1;
+        self.unfixed += 1;
+        self.findings.push(Finding { description: description, fixable: false, fixed: false });
+    }
+
+    /// Reports a problem. If we're repairing, runs `fix` and reports how that went.
+    fn fixable<F>(&mut self, description: String, fix: F)
+        where F: FnOnce() -> Result<(), Error>
+    {
+        self.problems += 1;
+        if self.mode != Mode::Repair {
+            println!("problem: {} (repair can fix this)", description);
+            self.findings.push(Finding { description: description, fixable: true, fixed: false });
+            return
+        }
+        let fixed = match fix() {
+            Ok(()) => {
+                println!("fixed: {}", description);
+                true
+            }
+            Err(e) => {
+                println!("could not fix: {}: {}", description, e);
+                self.unfixed += 1;
+                false
+            }
+        };
+        self.findings.push(Finding { description: description, fixable: true, fixed: fixed });
+    }
+}
+
+fn move_file(from: &Path, to_dir: &Path) -> Result<(), Error> {
+    try!(::std::fs::create_dir_all(to_dir));
+    let name = match from.file_name() {
+        Some(name) => name,
+        None => return Err(Error::failed(format!("no file name in {}", from.display()))),
+    };
+    try!(::std::fs::rename(from, to_dir.join(name)));
+    Ok(())
+}
+
+fn read_folders(path: &Path) -> Result<BTreeSet<String>, Error> {
+    let mut text = String::new();
+    match ::std::fs::File::open(path) {
+        Ok(mut f) => { try!(f.read_to_string(&mut text)); }
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(text.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect())
+}
+
+fn write_folders(path: &Path, folders: &BTreeSet<String>) -> Result<(), Error> {
+    let text: Vec<&str> = folders.iter().map(|f| &f[..]).collect();
+    let temp_path = path.with_extension("uploading");
+    try!(try!(::std::fs::File::create(&temp_path)).write_all(text.join("\n").as_bytes()));
+    try!(::std::fs::rename(temp_path, path));
+    Ok(())
+}
+
+/// The schema version recorded under `var`. Grains that predate the record are at version 1.
+fn read_schema_version(var: &Path) -> Result<u32, Error> {
+    let mut text = String::new();
+    match ::std::fs::File::open(var.join(SCHEMA_VERSION_FILE)) {
+        Ok(mut f) => { try!(f.read_to_string(&mut text)); }
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(1),
+        Err(e) => return Err(e.into()),
+    }
+    text.trim().parse().map_err(|e| Error::failed(format!("bad {}: {}", SCHEMA_VERSION_FILE, e)))
+}
+
+/// Reads every entry, checking (and in repair mode fixing) the storage along the way. Returns
+/// the entries that could be read.
+fn scan(var: &Path, checker: &mut Checker) -> Result<Vec<Entry>, Error> {
+    let schema = try!(read_schema_version(var));
+    if schema > SCHEMA_VERSION {
+        return Err(Error::failed(format!(
+            "the data is at schema version {}, but this build only understands up to {}",
+            schema, SCHEMA_VERSION)))
+    }
+
+    let sturdyref_dir = var.join("sturdyrefs");
+    let trash_dir = var.join("sturdyref-trash");
+
+    let mut entries = Vec::new();
+    let mut files = match ::shards::list(&sturdyref_dir) {
+        Ok(files) => files,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    files.sort();
+    for (name, path) in files {
+        if name.ends_with(".uploading") {
+            checker.fixable(format!("leftover temporary file {}", path.display()),
+                            || ::std::fs::remove_file(&path).map_err(|e| e.into()));
+            continue
+        }
+        match read_entry(&path, name.clone()) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                checker.fixable(format!("unreadable entry {} ({}); repair moves it to the trash",
+                                        path.display(), e),
+                                || move_file(&path, &trash_dir));
+            }
+        }
+    }
+
+    let thumbnail_dir = var.join("thumbnails");
+    {
+        let tokens: BTreeSet<&str> = entries.iter().map(|e| &e.token[..]).collect();
+        for name in try!(file_names(&thumbnail_dir)) {
+            if !tokens.contains(&name[..]) {
+                let path = thumbnail_dir.join(&name);
+                checker.fixable(format!("thumbnail {} belongs to no entry", path.display()),
+                                || ::std::fs::remove_file(&path).map_err(|e| e.into()));
+            }
+        }
+    }
+    for entry in &entries {
+        if entry.has_thumbnail && !thumbnail_dir.join(&entry.token).exists() {
+            checker.problem(format!("thumbnail of entry {} is missing", entry.token));
+        }
+    }
+
+    let folders_path = var.join("folders");
+    let mut folders = try!(read_folders(&folders_path));
+    let missing: BTreeSet<String> = entries.iter()
+        .filter_map(|e| e.folder.clone())
+        .filter(|f| !folders.contains(f))
+        .collect();
+    if !missing.is_empty() {
+        let description = format!("entries are in folders that {} does not list: {:?}",
+                                  folders_path.display(), missing);
+        checker.fixable(description, || {
+            folders.extend(missing.iter().cloned());
+            write_folders(&folders_path, &folders)
+        });
+    }
+
+    if let Err(e) = Settings::load(var.join("settings")) {
+        checker.problem(format!("unreadable settings: {}", e));
+    }
+
+    let journal_path = var.join("journal");
+    let mut journal = try!(Journal::open(&journal_path));
+    if journal.skipped_lines() > 0 {
+        let description = format!("{} malformed lines in {}",
+                                  journal.skipped_lines(), journal_path.display());
+        checker.fixable(description, || journal.rewrite());
+    }
+
+    entries.sort_by(|a, b| (a.date_added, &a.token).cmp(&(b.date_added, &b.token)));
+    Ok(entries)
+}
+
+fn dump(var: &Path, entries: &[Entry]) -> Result<(), Error> {
+    let mut description = String::new();
+    match ::std::fs::File::open(var.join("description")) {
+        Ok(mut f) => { try!(f.read_to_string(&mut description)); }
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    println!("description: {:?}", description);
+    println!("{} entries:", entries.len());
+    for entry in entries {
+        println!("{}\t{}\t{:?}\t{}\t{}\t{}",
+                 entry.token,
+                 entry.date_added,
+                 entry.title,
+                 entry.url.as_ref().map(|u| &u[..]).unwrap_or("(grain)"),
+                 entry.folder.as_ref().map(|f| &f[..]).unwrap_or("-"),
+                 entry.added_by.as_ref().map(|a| &a[..]).unwrap_or("-"));
+    }
+    Ok(())
+}
+
+/// Runs the command named by `args`. Returns false if the storage has problems that remain.
+pub fn run(args: &[String]) -> Result<bool, Error> {
+    if args.get(0).map(|a| &a[..]) == Some("--check") {
+        return run_check(&args[1..])
+    }
+    let mode = match args.get(0).map(|a| &a[..]) {
+        Some("dump") => Mode::Dump,
+        Some("check") => Mode::Check,
+        Some("repair") => Mode::Repair,
+        _ => return Err(Error::failed(USAGE.into())),
+    };
+    if args.len() > 2 {
+        return Err(Error::failed(USAGE.into()))
+    }
+    let var = Path::new(args.get(1).map(|a| &a[..]).unwrap_or("/var"));
+
+    // Repairing a grain that is running would race with the server's own writes.
+    let _lock = if mode == Mode::Repair {
+        Some(try!(::instance_lock::InstanceLock::acquire(var.join("server.lock"))))
+    } else {
+        None
+    };
+
+    let mut checker = Checker { mode: mode, problems: 0, unfixed: 0, findings: Vec::new() };
+    let entries = try!(scan(var, &mut checker));
+    if mode == Mode::Dump {
+        try!(dump(var, &entries));
+    } else if mode == Mode::Check {
+        println!("{} entries, {} problems", entries.len(), checker.problems);
+    } else {
+        println!("{} entries, {} problems, {} not fixed",
+                 entries.len(), checker.problems, checker.unfixed);
+    }
+    Ok(match mode {
+        Mode::Dump => true,
+        Mode::Check => checker.problems == 0,
+        Mode::Repair => checker.unfixed == 0,
+    })
+}
+
+/// `--check [--repair] [VAR_DIR]`: like `check` or `repair`, followed by a JSON report such as
+/// `{"entries":12,"schema":2,"problems":[...],"unfixed":0}` on the last line. Only unfixed
+/// problems count against the result, so a check that finds only what repair could fix still
+/// succeeds.
+fn run_check(args: &[String]) -> Result<bool, Error> {
+    let (mode, rest) = match args.get(0).map(|a| &a[..]) {
+        Some("--repair") => (Mode::Repair, &args[1..]),
+        _ => (Mode::Check, args),
+    };
+    if rest.len() > 1 {
+        return Err(Error::failed(USAGE.into()))
+    }
+    let var = Path::new(rest.get(0).map(|a| &a[..]).unwrap_or("/var"));
+
+    let _lock = if mode == Mode::Repair {
+        Some(try!(::instance_lock::InstanceLock::acquire(var.join("server.lock"))))
+    } else {
+        None
+    };
+
+    let mut checker = Checker { mode: mode, problems: 0, unfixed: 0, findings: Vec::new() };
+    let entries = try!(scan(var, &mut checker));
+    let schema = try!(read_schema_version(var));
+    let findings: Vec<String> = checker.findings.iter().map(|f| f.to_json()).collect();
+    println!("{{\"entries\":{},\"schema\":{},\"problems\":[{}],\"unfixed\":{}}}",
+             entries.len(), schema, findings.join(","), checker.unfixed);
+    try!(::std::io::stdout().flush());
+    Ok(checker.unfixed == 0)
+}
@@ -0,0 +1,81 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Token-bucket rate limiting, keyed by identity ID.
+
+use std::collections::hash_map::HashMap;
+
+struct Bucket {
+    tokens: f64,
+    last_refill_millis: u64,
+}
+
+pub struct RateLimiter {
+    capacity: f64,
+    tokens_per_second: f64,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    /// Allows bursts of up to `capacity` requests, refilling at `tokens_per_second`.
+    pub fn new(capacity: u32, tokens_per_second: f64) -> RateLimiter {
+        RateLimiter {
+            capacity: capacity as f64,
+            tokens_per_second: tokens_per_second,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket. Returns the number of milliseconds to wait
+    /// before retrying if the bucket is empty.
+    pub fn try_acquire(&mut self, key: &str, now_millis: u64) -> Result<(), u64> {
+        let capacity = self.capacity;
+        let tokens_per_second = self.tokens_per_second;
+        let bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            last_refill_millis: now_millis,
+        });
+
+        if now_millis > bucket.last_refill_millis {
+            let elapsed = (now_millis - bucket.last_refill_millis) as f64 / 1000.0;
+            bucket.tokens = (bucket.tokens + elapsed * tokens_per_second).min(capacity);
+            bucket.last_refill_millis = now_millis;
+        }
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / tokens_per_second * 1000.0).ceil() as u64)
+        }
+    }
+
+    /// Forgets buckets that have refilled completely, so that the map doesn't grow without bound.
+    pub fn prune(&mut self, now_millis: u64) {
+        let capacity = self.capacity;
+        let tokens_per_second = self.tokens_per_second;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now_millis.saturating_sub(bucket.last_refill_millis) as f64 / 1000.0;
+            bucket.tokens + elapsed * tokens_per_second < capacity
+        });
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! The most recent websocket broadcasts, numbered and persisted, so that a client that was
+//! disconnected (even across a grain restart) can catch up on what it missed instead of
+//! fetching a whole new snapshot.
+
+use capnp::Error;
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+
+pub struct ReplayBuffer {
+    path: ::std::path::PathBuf,
+
+    /// How many broadcasts to keep.
+    capacity: usize,
+
+    /// (revision, JSON text) of the kept broadcasts, oldest first.
+    entries: VecDeque<(u64, String)>,
+
+    /// The revision of the latest broadcast, or 0 if there has been none.
+    revision: u64,
+
+    /// Number of lines in the file, which grows to twice `capacity` before we compact it.
+    lines_on_disk: usize,
+}
+
+impl ReplayBuffer {
+    /// Reads the buffer at `path`, which holds one "<revision> <json>" line per broadcast.
+    /// Malformed lines are skipped.
+    pub fn open<P>(path: P, capacity: usize) -> Result<ReplayBuffer, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let mut entries = VecDeque::new();
+        let mut lines_on_disk = 0;
+        match ::std::fs::File::open(&path) {
+            Ok(f) => {
+                for line in ::std::io::BufReader::new(f).lines() {
+                    let line = try!(line);
+                    lines_on_disk += 1;
+                    let mut parts = line.splitn(2, ' ');
+                    match (parts.next().and_then(|r| r.parse::<u64>().ok()), parts.next()) {
+                        (Some(revision), Some(text)) => entries.push_back((revision, text.to_string())),
+                        _ => println!("skipping malformed replay buffer line: {}", line),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        while entries.len() > capacity {
+            entries.pop_front();
+        }
+
+        Ok(ReplayBuffer {
+            path: path.as_ref().to_path_buf(),
+            capacity: capacity,
+            revision: entries.back().map(|&(r, _)| r).unwrap_or(0),
+            entries: entries,
+            lines_on_disk: lines_on_disk,
+        })
+    }
+
+    /// Changes how many broadcasts to keep, forgetting the oldest ones if there are now too
+    /// many. The file catches up the next time it is compacted.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Records a broadcast under the next revision and returns its text with a "revision" field
+    /// added, e.g. `{"revision":7,"remove":...}`. `text` must be a JSON object. Failing to write
+    /// the file only costs clients their chance to catch up after a restart, so we log it and
+    /// carry on.
+    pub fn push(&mut self, text: &str) -> String {
+        let revision = self.revision + 1;
+        let stamped = format!("{{\"revision\":{},{}", revision, &text[1..]);
+        if let Err(e) = self.write(revision, &stamped) {
+            println!("failed to record broadcast for replay: {}", e);
+        }
+
+        self.entries.push_back((revision, stamped.clone()));
+        if self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+        self.revision = revision;
+        stamped
+    }
+
+    fn write(&mut self, revision: u64, text: &str) -> Result<(), Error> {
+        if self.lines_on_disk >= 2 * self.capacity {
+            try!(self.compact());
+        }
+        let mut file = try!(::std::fs::OpenOptions::new().create(true).append(true).open(&self.path));
+        try!(file.write_all(format!("{} {}\n", revision, text).as_bytes()));
+        self.lines_on_disk += 1;
+        Ok(())
+    }
+
+    /// The broadcasts after `revision`, oldest first, or None if some of them have already
+    /// been forgotten (or `revision` is from the future, e.g. because the buffer was lost).
+    pub fn since(&self, revision: u64) -> Option<Vec<String>> {
+        if revision > self.revision {
+            return None
+        }
+        let oldest = self.entries.front().map(|&(r, _)| r).unwrap_or(self.revision + 1);
+        if revision + 1 < oldest {
+            return None
+        }
+        Some(self.entries.iter()
+             .filter(|&&(r, _)| r > revision)
+             .map(|&(_, ref text)| text.clone())
+             .collect())
+    }
+
+    /// Rewrites the file so that it only holds the entries that we still keep.
+    fn compact(&mut self) -> Result<(), Error> {
+        let temp_path = self.path.with_extension("uploading");
+        {
+            let mut writer = try!(::std::fs::File::create(&temp_path));
+            for &(revision, ref text) in &self.entries {
+                try!(writer.write_all(format!("{} {}\n", revision, text).as_bytes()));
+            }
+            try!(writer.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, &self.path));
+        self.lines_on_disk = self.entries.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayBuffer;
+
+    #[test]
+    fn push_stamps_revisions() {
+        let path = ::std::env::temp_dir().join("collections-replay-test");
+        let _ = ::std::fs::remove_file(&path);
+        {
+            let mut replay = ReplayBuffer::open(&path, 10).unwrap();
+            assert_eq!(replay.revision(), 0);
+            assert_eq!(replay.push("{\"cleared\":true}"), "{\"revision\":1,\"cleared\":true}");
+            assert_eq!(replay.push("{\"locked\":false}"), "{\"revision\":2,\"locked\":false}");
+            assert_eq!(replay.since(1),
+                       Some(vec!["{\"revision\":2,\"locked\":false}".to_string()]));
+        }
+
+        // The revisions survive a restart.
+        let replay = ReplayBuffer::open(&path, 10).unwrap();
+        assert_eq!(replay.revision(), 2);
+        assert_eq!(replay.since(0),
+                   Some(vec!["{\"revision\":1,\"cleared\":true}".to_string(),
+                             "{\"revision\":2,\"locked\":false}".to_string()]));
+        assert_eq!(replay.since(3), None);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
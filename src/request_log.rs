@@ -0,0 +1,358 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Per-request logging. `LoggedSession` wraps a `WebSession` and, for every request, prints one
+//! JSON object per line with the method, path, status, identity and elapsed time, and counts the
+//! outcome in the shared `RequestStats`. Paths are logged without their query strings or the
+//! tokens in them.
+
+use capnp::capability::Promise;
+use capnp::Error;
+use futures::Future;
+use rustc_serialize::json;
+use sandstorm::web_session_capnp::web_session;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Counts of the requests served since the grain started, reported by `healthz`.
+#[derive(Default)]
+pub struct RequestStats {
+    pub total: Cell<u64>,
+    pub client_errors: Cell<u64>,
+    pub server_errors: Cell<u64>,
+}
+
+impl RequestStats {
+    pub fn to_json(&self) -> String {
+        format!("{{\"total\":{},\"clientErrors\":{},\"serverErrors\":{}}}",
+                self.total.get(), self.client_errors.get(), self.server_errors.get())
+    }
+
+    fn record(&self, status: Option<u16>) {
+        self.total.set(self.total.get() + 1);
+        match status {
+            Some(s) if s >= 500 => self.server_errors.set(self.server_errors.get() + 1),
+            Some(s) if s >= 400 => self.client_errors.set(self.client_errors.get() + 1),
+            _ => (),
+        }
+    }
+}
+
+/// The HTTP status that Sandstorm will send for `response`.
+pub fn status_of(response: web_session::response::Reader) -> Option<u16> {
+    use sandstorm::web_session_capnp::web_session::response::{ClientErrorCode, SuccessCode, Which};
+    match response.which() {
+        Ok(Which::Content(content)) => Some(match content.get_status_code() {
+            Ok(SuccessCode::Ok) => 200,
+            Ok(SuccessCode::Created) => 201,
+            Ok(SuccessCode::Accepted) => 202,
+            Ok(SuccessCode::MultiStatus) => 207,
+            _ => 200,
+        }),
+        Ok(Which::NoContent(_)) => Some(204),
+        Ok(Which::Redirect(redirect)) => Some(if redirect.get_is_permanent() { 301 } else { 303 }),
+        Ok(Which::PreconditionFailed(_)) => Some(412),
+        Ok(Which::ClientError(error)) => Some(match error.get_status_code() {
+            Ok(ClientErrorCode::BadRequest) => 400,
+            Ok(ClientErrorCode::Forbidden) => 403,
+            Ok(ClientErrorCode::NotFound) => 404,
+            Ok(ClientErrorCode::MethodNotAllowed) => 405,
+            Ok(ClientErrorCode::Conflict) => 409,
+            Ok(ClientErrorCode::PreconditionFailed) => 412,
+            Ok(ClientErrorCode::UnsupportedMediaType) => 415,
+            Ok(ClientErrorCode::UnprocessableEntity) => 422,
+            _ => 400,
+        }),
+        Ok(Which::ServerError(_)) => Some(500),
+        _ => None,
+    }
+}
+
+/// Paths under these prefixes name an entry or a powerbox request by its token, which is as good
+/// as a capability to whoever reads the log.
+const TOKEN_PREFIXES: &'static [&'static str] = &[
+    "sturdyref/", "offer/", "token/", "refresh/", "api/opened/", "api/views/", "api/webhook/",
+    "api/merge/", "thumbnails/",
+];
+
+/// `path` as it goes in the log: without the query string, which holds whatever the user searched
+/// for, and with only the first few characters of a token.
+fn loggable_path(path: &str) -> String {
+    let path = path.split('?').next().unwrap_or("");
+    for prefix in TOKEN_PREFIXES {
+        if path.starts_with(prefix) && path.len() > prefix.len() {
+            let rest = &path[prefix.len()..];
+            let end = rest.find('/').unwrap_or(rest.len());
+            let shown: String = rest[..end].chars().take(4).collect();
+            return format!("{}{}...{}", prefix, shown, &rest[end..])
+        }
+    }
+    path.to_string()
+}
+
+/// One request in flight.
+struct Pending {
+    stats: Rc<RequestStats>,
+    method: &'static str,
+    path: String,
+    identity_id: Option<String>,
+    start: ::std::time::Instant,
+}
+
+impl Pending {
+    /// Logs the request. `status` is None for requests that answer with a capability rather
+    /// than an HTTP response, and for requests that failed outright.
+    fn finish(self, status: Option<u16>, error: Option<&Error>) {
+        let status = match (status, error) {
+            (None, Some(_)) => Some(500),
+            (status, _) => status,
+        };
+        self.stats.record(status);
+
+        let elapsed = self.start.elapsed();
+        let elapsed_millis = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64;
+        let status = match status {
+            Some(s) => s.to_string(),
+            None => "null".into(),
+        };
+        let identity = match self.identity_id {
+            Some(ref id) => format!("{}", json::ToJson::to_json(id)),
+            None => "null".into(),
+        };
+        let error = match error {
+            Some(e) => format!(",\"error\":{}", json::ToJson::to_json(&format!("{}", e))),
+            None => "".into(),
+        };
+        println!("{{\"method\":\"{}\",\"path\":{},\"status\":{},\"identity\":{},\
+                  \"elapsedMillis\":{}{}}}",
+                 self.method, json::ToJson::to_json(&self.path), status, identity,
+                 elapsed_millis, error);
+    }
+}
+
+pub struct LoggedSession {
+    session: web_session::Client,
+    identity_id: Option<String>,
+    stats: Rc<RequestStats>,
+}
+
+impl LoggedSession {
+    pub fn new(session: web_session::Client,
+               identity_id: Option<String>,
+               stats: Rc<RequestStats>)
+               -> LoggedSession
+    {
+        LoggedSession {
+            session: session,
+            identity_id: identity_id,
+            stats: stats,
+        }
+    }
+
+    fn start(&self, method: &'static str, path: &str) -> Pending {
+        Pending {
+            stats: self.stats.clone(),
+            method: method,
+            path: loggable_path(path),
+            identity_id: self.identity_id.clone(),
+            start: ::std::time::Instant::now(),
+        }
+    }
+}
+
+/// Sends `$request`, whose params have already been copied in, and copies the answer into
+/// `$results`. `$status` maps the answer to an HTTP status for the log.
+macro_rules! forward {
+    ($pending:expr, $request:expr, $results:expr, $status:expr) => {{
+        let pending = $pending;
+        let mut results = $results;
+        Promise::from_future($request.send().promise.then(move |result| {
+            let response = match result {
+                Ok(r) => r,
+                Err(e) => {
+                    pending.finish(None, Some(&e));
+                    return Err(e)
+                }
+            };
+            let answer = match response.get() {
+                Ok(a) => a,
+                Err(e) => {
+                    pending.finish(None, Some(&e));
+                    return Err(e)
+                }
+            };
+            pending.finish($status(answer), None);
+            results.set(answer)
+        }))
+    }}
+}
+
+/// Copies the params of a call we received into the request we forward.
+macro_rules! copy_params {
+    ($params:expr, $request:expr, $builder:ty) => {{
+        let params = pry!($params.get());
+        let path = pry!(params.get_path()).to_string();
+        pry!($request.hook.get().set_as::<$builder, _>(params));
+        path
+    }}
+}
+
+fn no_status<T>(_: T) -> Option<u16> {
+    None
+}
+
+impl web_session::Server for LoggedSession {
+    fn get(&mut self,
+           params: web_session::GetParams,
+           results: web_session::GetResults)
+           -> Promise<(), Error>
+    {
+        let mut req = self.session.get_request();
+        let path = copy_params!(params, req, web_session::get_params::Builder);
+        forward!(self.start("GET", &path), req, results, status_of)
+    }
+
+    fn post(&mut self,
+            params: web_session::PostParams,
+            results: web_session::PostResults)
+            -> Promise<(), Error>
+    {
+        let mut req = self.session.post_request();
+        let path = copy_params!(params, req, web_session::post_params::Builder);
+        forward!(self.start("POST", &path), req, results, status_of)
+    }
+
+    fn put(&mut self,
+           params: web_session::PutParams,
+           results: web_session::PutResults)
+           -> Promise<(), Error>
+    {
+        let mut req = self.session.put_request();
+        let path = copy_params!(params, req, web_session::put_params::Builder);
+        forward!(self.start("PUT", &path), req, results, status_of)
+    }
+
+    fn delete(&mut self,
+              params: web_session::DeleteParams,
+              results: web_session::DeleteResults)
+              -> Promise<(), Error>
+    {
+        let mut req = self.session.delete_request();
+        let path = copy_params!(params, req, web_session::delete_params::Builder);
+        forward!(self.start("DELETE", &path), req, results, status_of)
+    }
+
+    fn open_web_socket(&mut self,
+                       params: web_session::OpenWebSocketParams,
+                       results: web_session::OpenWebSocketResults)
+                       -> Promise<(), Error>
+    {
+        let mut req = self.session.open_web_socket_request();
+        let path = copy_params!(params, req, web_session::open_web_socket_params::Builder);
+        forward!(self.start("WEBSOCKET", &path), req, results, no_status)
+    }
+
+    fn propfind(&mut self,
+                params: web_session::PropfindParams,
+                results: web_session::PropfindResults)
+                -> Promise<(), Error>
+    {
+        let mut req = self.session.propfind_request();
+        let path = copy_params!(params, req, web_session::propfind_params::Builder);
+        forward!(self.start("PROPFIND", &path), req, results, status_of)
+    }
+
+    fn mkcol(&mut self,
+             params: web_session::MkcolParams,
+             results: web_session::MkcolResults)
+             -> Promise<(), Error>
+    {
+        let mut req = self.session.mkcol_request();
+        let path = copy_params!(params, req, web_session::mkcol_params::Builder);
+        forward!(self.start("MKCOL", &path), req, results, status_of)
+    }
+
+    fn copy(&mut self,
+            params: web_session::CopyParams,
+            results: web_session::CopyResults)
+            -> Promise<(), Error>
+    {
+        let mut req = self.session.copy_request();
+        let path = copy_params!(params, req, web_session::copy_params::Builder);
+        forward!(self.start("COPY", &path), req, results, status_of)
+    }
+
+    fn move_(&mut self,
+             params: web_session::MoveParams,
+             results: web_session::MoveResults)
+             -> Promise<(), Error>
+    {
+        let mut req = self.session.move_request();
+        let path = copy_params!(params, req, web_session::move_params::Builder);
+        forward!(self.start("MOVE", &path), req, results, status_of)
+    }
+
+    fn post_streaming(&mut self,
+                      params: web_session::PostStreamingParams,
+                      results: web_session::PostStreamingResults)
+                      -> Promise<(), Error>
+    {
+        // The HTTP status only comes out of the stream's getResponse(), after we've let go.
+        let mut req = self.session.post_streaming_request();
+        let path = copy_params!(params, req, web_session::post_streaming_params::Builder);
+        forward!(self.start("POST", &path), req, results, no_status)
+    }
+
+    fn put_streaming(&mut self,
+                     params: web_session::PutStreamingParams,
+                     results: web_session::PutStreamingResults)
+                     -> Promise<(), Error>
+    {
+        let mut req = self.session.put_streaming_request();
+        let path = copy_params!(params, req, web_session::put_streaming_params::Builder);
+        forward!(self.start("PUT", &path), req, results, no_status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::loggable_path;
+
+    #[test]
+    fn loggable_paths() {
+        let cases = vec![
+            ("", ""),
+            ("api/search?q=secret", "api/search"),
+            ("sturdyref/abcdefgh", "sturdyref/abcd..."),
+            ("sturdyref/abcdefgh/rating", "sturdyref/abcd.../rating"),
+            ("token/abcdefgh?publishAt=5", "token/abcd..."),
+            ("api/views/abcdefgh/comments", "api/views/abcd.../comments"),
+            ("api/webhook/abcdefgh", "api/webhook/abcd..."),
+            ("api/webhook/abcdefgh?url=https://example.com/", "api/webhook/abcd..."),
+            ("api/merge/abcdefgh", "api/merge/abcd..."),
+            ("api/webhook", "api/webhook"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(loggable_path(path), expected, "{:?}", path);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Splitting request paths into the route and its query parameters. Sandstorm hands us the path
+//! and query as one string, so every handler that takes parameters goes through `split()`.
+
+use capnp::Error;
+use std::collections::HashMap;
+use std::str::FromStr;
+use url::form_urlencoded;
+
+/// The decoded query parameters of a request. When a parameter is given more than once, the
+/// last value wins.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    params: HashMap<String, String>,
+}
+
+/// Splits a request path into the part before the '?' and the decoded query parameters.
+pub fn split(path: &str) -> (&str, Query) {
+    match path.find('?') {
+        None => (path, Query::default()),
+        Some(idx) => {
+            let params = form_urlencoded::parse(path[idx + 1..].as_bytes())
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect();
+            (&path[..idx], Query { params: params })
+        }
+    }
+}
+
+/// Whether a request for `path` (without its query) is handled by `route`. A route ending in
+/// '/' handles everything beneath it.
+pub fn matches(route: &str, path: &str) -> bool {
+    path == route || (route.ends_with('/') && path.starts_with(route))
+}
+
+/// The part of `path` between `prefix` and `suffix`, such as the token in
+/// `sturdyref/<token>/rating`, or None if `path` is not of that form or the part is empty.
+pub fn between<'a>(path: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    if path.len() > prefix.len() + suffix.len() && path.starts_with(prefix) &&
+        path.ends_with(suffix)
+    {
+        Some(&path[prefix.len()..path.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+impl Query {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|v| &v[..])
+    }
+
+    pub fn required(&self, name: &str) -> Result<&str, Error> {
+        self.get(name).ok_or(Error::failed(format!("missing {} parameter", name)))
+    }
+
+    /// Parses parameter `name`, if it was given. A value that does not parse is an error rather
+    /// than being ignored, so that a typo doesn't silently return the wrong results.
+    pub fn parse<T>(&self, name: &str) -> Result<Option<T>, Error>
+        where T: FromStr
+    {
+        match self.get(name) {
+            None => Ok(None),
+            Some(value) => match value.parse::<T>() {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(Error::failed(format!("invalid {} parameter: {:?}", name, value))),
+            },
+        }
+    }
+
+    /// Parses count parameter `name`, falling back to `default` and capping it at `max`.
+    pub fn count<T>(&self, name: &str, default: T, max: T) -> Result<T, Error>
+        where T: FromStr + Ord
+    {
+        Ok(try!(self.parse(name)).unwrap_or(default).min(max))
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Inverted index from words to the entries that contain them, so that searches don't have to
+//! scan every entry. The index is persisted as a log with one JSON object per line, each giving
+//! the full set of terms for one token (or null, once the token is gone). The log is compacted
+//! when it grows much longer than the index itself.
+
+use capnp::Error;
+use rustc_serialize::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+use unicode_normalization::UnicodeNormalization;
+
+/// Splits `text` into case-folded words, for indexing and for queries alike. The text is composed
+/// to NFC first, so that "Café" typed with a combining accent is one word, and matches the
+/// precomposed form. Like `foldForMatching()` in main.jsx, we fold case by going through upper
+/// case, which also matches "ß" with "ss".
+fn words(text: &str) -> Vec<String> {
+    let composed: String = text.nfc().collect();
+    composed.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_uppercase().to_lowercase())
+        .collect()
+}
+
+pub struct SearchIndex {
+    path: ::std::path::PathBuf,
+    terms_by_token: BTreeMap<String, BTreeSet<String>>,
+    tokens_by_term: BTreeMap<String, BTreeSet<String>>,
+
+    /// How many lines the log file has, so that we know when to compact it.
+    log_lines: usize,
+}
+
+fn line_json(token: &str, terms: Option<&BTreeSet<String>>) -> String {
+    let terms = match terms {
+        None => "null".to_string(),
+        Some(terms) => {
+            let terms: Vec<String> =
+                terms.iter().map(|t| format!("{}", json::ToJson::to_json(t))).collect();
+            format!("[{}]", terms.join(","))
+        }
+    };
+    format!("{{\"token\":{},\"terms\":{}}}\n", json::ToJson::to_json(token), terms)
+}
+
+fn parse_line(line: &str) -> Option<(String, Option<BTreeSet<String>>)> {
+    let value = match json::Json::from_str(line) {
+        Ok(v) => v,
+        Err(_) => return None,
+    };
+    let token = match value.find("token").and_then(|t| t.as_string()) {
+        Some(t) => t.to_string(),
+        None => return None,
+    };
+    match value.find("terms") {
+        Some(&json::Json::Null) => Some((token, None)),
+        Some(&json::Json::Array(ref terms)) => {
+            Some((token, Some(terms.iter().filter_map(|t| t.as_string())
+                                 .map(|t| t.to_string()).collect())))
+        }
+        _ => None,
+    }
+}
+
+impl SearchIndex {
+    /// Reads the index at `path`. A missing file gives an empty index, and malformed lines are
+    /// skipped; either way, the caller is expected to `update()` every entry afterwards, which
+    /// repairs whatever the file was missing.
+    pub fn open<P>(path: P) -> Result<SearchIndex, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let mut index = SearchIndex {
+            path: path.as_ref().to_path_buf(),
+            terms_by_token: BTreeMap::new(),
+            tokens_by_term: BTreeMap::new(),
+            log_lines: 0,
+        };
+        match ::std::fs::File::open(&path) {
+            Ok(f) => {
+                for line in ::std::io::BufReader::new(f).lines() {
+                    let line = try!(line);
+                    index.log_lines += 1;
+                    match parse_line(&line) {
+                        Some((token, Some(terms))) => index.set_terms(&token, terms),
+                        Some((token, None)) => index.unset_terms(&token),
+                        None => println!("skipping malformed search index line: {}", line),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(index)
+    }
+
+    /// Tokens of every indexed entry.
+    pub fn tokens(&self) -> Vec<String> {
+        self.terms_by_token.keys().cloned().collect()
+    }
+
+    /// Indexes `text` as the searchable content of `token`, replacing whatever was indexed for
+    /// it before. Does not touch the disk if nothing changed.
+    pub fn update(&mut self, token: &str, text: &str) -> Result<(), Error> {
+        let terms: BTreeSet<String> = words(text).into_iter().collect();
+        if self.terms_by_token.get(token) == Some(&terms) {
+            return Ok(())
+        }
+        try!(self.append(&line_json(token, Some(&terms))));
+        self.set_terms(token, terms);
+        self.compact_if_needed()
+    }
+
+    pub fn remove(&mut self, token: &str) -> Result<(), Error> {
+        if !self.terms_by_token.contains_key(token) {
+            return Ok(())
+        }
+        try!(self.append(&line_json(token, None)));
+        self.unset_terms(token);
+        self.compact_if_needed()
+    }
+
+    /// Tokens of the entries that contain every word of `query`. The last word also matches as
+    /// a prefix, so that results can be shown while the user is still typing.
+    pub fn search(&self, query: &str) -> BTreeSet<String> {
+        let words = words(query);
+        let mut result: Option<BTreeSet<String>> = None;
+        for (idx, word) in words.iter().enumerate() {
+            let mut matches = BTreeSet::new();
+            if idx + 1 == words.len() {
+                for (term, tokens) in &self.tokens_by_term {
+                    if term.starts_with(&word[..]) {
+                        matches.extend(tokens.iter().cloned());
+                    }
+                }
+            } else if let Some(tokens) = self.tokens_by_term.get(word) {
+                matches = tokens.clone();
+            }
+            result = Some(match result {
+                None => matches,
+                Some(r) => r.intersection(&matches).cloned().collect(),
+            });
+        }
+        result.unwrap_or(BTreeSet::new())
+    }
+
+    fn set_terms(&mut self, token: &str, terms: BTreeSet<String>) {
+        self.unset_terms(token);
+        for term in &terms {
+            self.tokens_by_term.entry(term.clone()).or_insert(BTreeSet::new())
+                .insert(token.to_string());
+        }
+        self.terms_by_token.insert(token.to_string(), terms);
+    }
+
+    fn unset_terms(&mut self, token: &str) {
+        let old_terms = match self.terms_by_token.remove(token) {
+            Some(terms) => terms,
+            None => return,
+        };
+        for term in &old_terms {
+            let now_empty = match self.tokens_by_term.get_mut(term) {
+                Some(tokens) => {
+                    tokens.remove(token);
+                    tokens.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                self.tokens_by_term.remove(term);
+            }
+        }
+    }
+
+    fn append(&mut self, line: &str) -> Result<(), Error> {
+        let mut file = try!(::std::fs::OpenOptions::new().create(true).append(true)
+                            .open(&self.path));
+        try!(file.write_all(line.as_bytes()));
+        self.log_lines += 1;
+        Ok(())
+    }
+
+    /// Rewrites the log with one line per indexed token, once superseded lines outnumber live
+    /// ones.
+    fn compact_if_needed(&mut self) -> Result<(), Error> {
+        if self.log_lines < 2 * self.terms_by_token.len() + 100 {
+            return Ok(())
+        }
+        let temp_path = self.path.with_extension("uploading");
+        {
+            let mut file = try!(::std::fs::File::create(&temp_path));
+            for (token, terms) in &self.terms_by_token {
+                try!(file.write_all(line_json(token, Some(terms)).as_bytes()));
+            }
+            try!(file.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, &self.path));
+        self.log_lines = self.terms_by_token.len();
+        Ok(())
+    }
+}
@@ -24,44 +24,243 @@ use capnp::Error;
 use capnp::capability::Promise;
 use capnp_rpc::{RpcSystem, twoparty, rpc_twoparty_capnp};
 use rustc_serialize::{base64, hex, json};
+use url::percent_encoding;
 
+use std::collections::btree_map::BTreeMap;
+use std::collections::btree_set::BTreeSet;
 use std::collections::hash_map::HashMap;
 use std::collections::hash_set::HashSet;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
 use futures::Future;
-use collections_capnp::ui_view_metadata;
+use collections_capnp::{object_id, ui_view_metadata};
 use web_socket;
+use acks::Acks;
+use avatar_cache::{AvatarCache, CachedAvatar};
+use thumbnails::Thumbnails;
 use identity_map::IdentityMap;
-
+use config::{Config, Feature};
+use collation::Collation;
+use clock::{Clock, Timer, SystemClock, ReactorTimer, retry, with_timeout};
+use rate_limit::RateLimiter;
+use replay::ReplayBuffer;
+use request_log::{LoggedSession, RequestStats};
+use router;
+use search_index::SearchIndex;
+use journal::{Journal, JournalEntry, ACTIVITY_EVENT_TYPES};
+use digest::Digests;
+use i18n::{Language, Message};
+use last_seen::LastSeen;
+use settings::{Retention, Role, Settings, SortOrder};
+use webhooks::Webhooks;
+use webdav;
+
+use sandstorm::api_session_capnp::api_session;
 use sandstorm::powerbox_capnp::powerbox_descriptor;
 use sandstorm::identity_capnp::{user_info};
-use sandstorm::grain_capnp::{session_context, ui_view, ui_session, sandstorm_api};
-use sandstorm::util_capnp::{static_asset};
+use sandstorm::grain_capnp::{main_view, session_context, ui_view, ui_session, sandstorm_api};
+use sandstorm::hack_session_capnp::hack_session_context;
+use sandstorm::util_capnp::{byte_stream, localized_text, static_asset};
 use sandstorm::web_session_capnp::{web_session};
 use sandstorm::web_session_capnp::web_session::web_socket_stream;
 
 pub struct WebSocketStream {
     id: u64,
     saved_ui_views: SavedUiViewSet,
+    permissions: Permissions,
+    identity_id: Option<String>,
+    display_name: Option<String>,
+
+    /// Who the user is, for refusing commands on entries that they can't see.
+    audience: Audience,
+
+    /// Set if the client connected in acked mode, under this client ID.
+    client_id: Option<String>,
+    channel: Channel,
+
+    /// The "id" of the command being handled, if the client gave one, to echo back in the
+    /// messages about it.
+    command_id: Option<json::Json>,
+
+    /// Why the command being handled was refused, as told to the client in `commandFailed`.
+    failure_reason: Option<&'static str>,
 }
 
 impl Drop for WebSocketStream {
     fn drop(&mut self) {
-        self.saved_ui_views.inner.borrow_mut().subscribers.remove(&self.id);
+        self.saved_ui_views.unsubscribe(self.id);
     }
 }
 
 impl WebSocketStream {
     fn new(id: u64,
-           saved_ui_views: SavedUiViewSet)
+           saved_ui_views: SavedUiViewSet,
+           permissions: Permissions,
+           viewer: Viewer,
+           client_id: Option<String>,
+           channel: Channel)
            -> WebSocketStream
     {
         WebSocketStream {
             id: id,
             saved_ui_views: saved_ui_views,
+            permissions: permissions,
+            audience: viewer.audience(),
+            identity_id: viewer.identity_id,
+            display_name: viewer.display_name,
+            client_id: client_id,
+            channel: channel,
+            command_id: None,
+            failure_reason: None,
+        }
+    }
+
+    /// Executes a command sent by the client. Commands are JSON objects with a single key
+    /// naming the command, like `{"move": {"tokens": [...], "folder": "..."}}`. A `batch`
+    /// command holds a list of `move` and `remove` commands, which are checked together and
+    /// then applied together. `comment` needs only the comment permission. In acked mode, the
+    /// client periodically sends `{"ack": <revision>}` for the latest revision that it has
+    /// processed. A command may also carry an "id" of the client's choosing, which is echoed in
+    /// the `commandFailed` message if the command is refused, and in a `commandResult` message
+    /// once it has been handled either way. Returns the tokens of the entries that the command
+    /// touched.
+    fn handle_command(&mut self, text: &str) -> Result<Vec<String>, Error> {
+        if self.channel != Channel::Views {
+            return Err(Error::failed("the activity channel takes no commands".into()))
+        }
+        let command = try!(json::Json::from_str(text).map_err(|e| Error::failed(format!("{}", e))));
+        self.command_id = command.find("id").cloned();
+        if let Some(revision) = command.find("ack") {
+            let client_id = match self.client_id {
+                Some(ref id) => id.clone(),
+                None => return Err(Error::failed("ack: not connected in acked mode".into())),
+            };
+            match revision.as_u64() {
+                Some(revision) => {
+                    try!(self.saved_ui_views.ack(::acks::owner(&self.identity_id), &client_id,
+                                                 revision));
+                    Ok(Vec::new())
+                }
+                None => Err(Error::failed("ack: expected a revision".into())),
+            }
+        } else if let Some(commands) = command.find("batch") {
+            let commands = match commands.as_array() {
+                Some(commands) => commands,
+                None => return Err(Error::failed("batch: expected a list of commands".into())),
+            };
+            try!(self.require_permission(Permission::Write));
+            try!(self.require_unlocked());
+            try!(self.saved_ui_views.check_write_rate(&self.identity_id, self.permissions.language));
+            let mut ops = Vec::new();
+            for command in commands {
+                ops.push(try!(parse_batch_op(command)));
+            }
+            let mut tokens = Vec::new();
+            for op in &ops {
+                match op {
+                    &BatchOp::Move(ref args) => {
+                        try!(self.require_visible(&args.tokens));
+                        try!(self.require_versions(&args.versions));
+                        tokens.extend(args.tokens.iter().cloned());
+                    }
+                    &BatchOp::Remove { tokens: ref removed } => {
+                        try!(self.require_visible(removed));
+                        tokens.extend(removed.iter().cloned())
+                    }
+                }
+            }
+            try!(self.saved_ui_views.apply_batch(ops, self.identity_id.clone(),
+                                                 self.display_name.clone()));
+            Ok(tokens)
+        } else if let Some(args) = command.find("move") {
+            try!(self.require_permission(Permission::Write));
+            try!(self.require_unlocked());
+            try!(self.saved_ui_views.check_write_rate(&self.identity_id, self.permissions.language));
+            let args = try!(parse_move_args(args));
+            try!(self.require_visible(&args.tokens));
+            try!(self.require_versions(&args.versions));
+            try!(self.saved_ui_views.move_entries(&args.tokens, args.folder,
+                                                  self.identity_id.clone()));
+            Ok(args.tokens)
+        } else if let Some(args) = command.find("comment") {
+            try!(self.require_permission(Permission::Comment));
+            try!(self.saved_ui_views.require_feature(Feature::Comments, self.permissions.language));
+            try!(self.require_unlocked());
+            try!(self.saved_ui_views.check_write_rate(&self.identity_id, self.permissions.language));
+            let (token, text, version) = try!(parse_comment_args(args));
+            try!(self.require_visible(&[token.clone()]));
+            let mut versions = HashMap::new();
+            if let Some(version) = version {
+                versions.insert(token.clone(), version);
+            }
+            try!(self.require_versions(&versions));
+            try!(self.saved_ui_views.add_comment(&token, text.as_bytes(),
+                                                 self.identity_id.clone()));
+            Ok(vec![token])
+        } else {
+            Err(Error::failed(format!("unrecognized command: {}", text)))
+        }
+    }
+
+    /// Tells the client that the command it is sending did nothing, and why.
+    fn command_failed(&mut self, reason: &'static str) {
+        self.failure_reason = Some(reason);
+        let id = self.id;
+        let command_id = self.command_id.clone();
+        self.saved_ui_views.send_action_to_subscriber(
+            id, Action::CommandFailed { id: command_id, reason: reason.into() });
+    }
+
+    /// Fails if the user lacks `permission`, telling the client that its command is forbidden.
+    fn require_permission(&mut self, permission: Permission) -> Result<(), Error> {
+        let result = self.permissions.require(permission);
+        if result.is_err() {
+            self.command_failed("forbidden");
+        }
+        result
+    }
+
+    /// Fails if the collection is locked, telling the client why its command did nothing.
+    fn require_unlocked(&mut self) -> Result<(), Error> {
+        let result = self.saved_ui_views.require_unlocked(self.permissions.language);
+        if result.is_err() {
+            let reason = if self.saved_ui_views.in_maintenance() {
+                "maintenance"
+            } else {
+                "locked"
+            };
+            self.command_failed(reason);
         }
+        result
+    }
+
+    /// Fails if one of `tokens` names an entry that the user can't see, because it doesn't exist
+    /// or is hidden from them, telling the client that the entry is missing.
+    fn require_visible(&mut self, tokens: &[String]) -> Result<(), Error> {
+        let missing = {
+            let inner = self.saved_ui_views.inner.borrow();
+            tokens.iter().find(|token| {
+                !inner.views.contains_key(*token) || !inner.is_visible(token, &self.audience)
+            }).cloned()
+        };
+        match missing {
+            None => Ok(()),
+            Some(token) => {
+                self.command_failed("missing");
+                Err(Error::failed(format!("no such entry: {}", token)))
+            }
+        }
+    }
+
+    /// Fails if someone else has edited one of the entries since the client saw it, telling the
+    /// client that its command was based on stale data.
+    fn require_versions(&mut self, versions: &HashMap<String, u64>) -> Result<(), Error> {
+        let result = self.saved_ui_views.require_versions(versions);
+        if result.is_err() {
+            self.command_failed("stale");
+        }
+        result
     }
 }
 
@@ -69,7 +268,32 @@ impl web_socket::MessageHandler for WebSocketStream {
     fn handle_message(&mut self, message: web_socket::Message) -> Promise<(), Error> {
         // TODO: move PUTs and POSTs into websocket requests?
         match message {
-            web_socket::Message::Text(_t) => {
+            web_socket::Message::Text(t) => {
+                self.command_id = None;
+                self.failure_reason = None;
+                let result = self.handle_command(&t);
+                if let Err(ref e) = result {
+                    println!("websocket command failed: {}", e);
+                }
+                if let Some(command_id) = self.command_id.take() {
+                    let outcome = match result {
+                        Ok(tokens) => {
+                            let inner = self.saved_ui_views.inner.borrow();
+                            Ok(tokens.into_iter().map(|token| {
+                                let data = if inner.is_visible(&token, &self.audience) {
+                                    inner.views.get(&token).cloned()
+                                } else {
+                                    None
+                                };
+                                (token, data)
+                            }).collect())
+                        }
+                        Err(e) => Err((self.failure_reason, e.description)),
+                    };
+                    let id = self.id;
+                    self.saved_ui_views.send_action_to_subscriber(
+                        id, Action::CommandResult { id: command_id, outcome: outcome });
+                }
             }
             web_socket::Message::Data(_d) => {
             }
@@ -78,11 +302,347 @@ impl web_socket::MessageHandler for WebSocketStream {
     }
 }
 
+/// What a websocket client subscribes to. The views channel carries the whole collection and
+/// every change to it. The activity channel carries only the summary, a notice for each entry
+/// added or removed, comment or description change, and how many clients are connected, which
+/// is all that an embedded widget needs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Channel {
+    Views,
+    Activity,
+}
+
+/// Websocket subprotocols naming each channel, for clients that negotiate the channel instead
+/// of choosing it by path. A requested subprotocol takes precedence over the path.
+const CHANNEL_PROTOCOLS: &'static [(&'static str, Channel)] = &[
+    ("collections.views", Channel::Views),
+    ("collections.activity", Channel::Activity),
+];
+
+/// The channel served at websocket path `path` (without its query).
+fn channel_for_path(path: &str) -> Result<Channel, Error> {
+    match path {
+        "" | "views" => Ok(Channel::Views),
+        "activity" => Ok(Channel::Activity),
+        _ => Err(Error::failed(format!("no websocket channel at {:?}", path))),
+    }
+}
+
+/// Journal operations that are also delivered to the activity channel.
+const ACTIVITY_CHANNEL_OPS: &'static [&'static str] =
+    &["add", "publish", "remove", "description", "comment"];
+
+/// Parses the "tokens" list out of a command's arguments.
+fn parse_tokens(args: &json::Json, command: &str) -> Result<Vec<String>, Error> {
+    let tokens = match args.find("tokens").and_then(|t| t.as_array()) {
+        Some(tokens) => tokens,
+        None => return Err(Error::failed(format!("{}: expected a list of tokens", command))),
+    };
+    let mut result = Vec::new();
+    for token in tokens {
+        match token.as_string() {
+            Some(t) => result.push(t.to_string()),
+            None => return Err(Error::failed(format!("{}: tokens must be strings", command))),
+        }
+    }
+    Ok(result)
+}
+
+/// The arguments of a `move` command.
+struct MoveArgs {
+    tokens: Vec<String>,
+    folder: Option<String>,
+
+    /// The version of each entry that the client last saw, for the entries where it cares.
+    versions: HashMap<String, u64>,
+}
+
+/// Parses `{"tokens": [...], "folder": "...", "versions": {...}}`, where a missing or null
+/// folder means the top level of the collection, and the optional versions map tokens to the
+/// versions that the move expects them to have.
+fn parse_move_args(args: &json::Json) -> Result<MoveArgs, Error> {
+    let tokens = try!(parse_tokens(args, "move"));
+    let folder = match args.find("folder") {
+        None | Some(&json::Json::Null) => None,
+        Some(&json::Json::String(ref f)) => Some(f.clone()),
+        Some(_) => return Err(Error::failed("move: folder must be a string or null".into())),
+    };
+    let mut versions = HashMap::new();
+    match args.find("versions") {
+        None | Some(&json::Json::Null) => (),
+        Some(&json::Json::Object(ref object)) => {
+            for (token, version) in object {
+                match version.as_u64() {
+                    Some(v) => { versions.insert(token.clone(), v); }
+                    None => return Err(Error::failed("move: versions must be numbers".into())),
+                }
+            }
+        }
+        Some(_) => return Err(Error::failed("move: versions must be an object".into())),
+    }
+    Ok(MoveArgs { tokens: tokens, folder: folder, versions: versions })
+}
+
+/// Parses `{"token": "...", "text": "...", "version": ...}`, where the version the comment
+/// expects the entry to have is optional.
+fn parse_comment_args(args: &json::Json) -> Result<(String, String, Option<u64>), Error> {
+    let token = match args.find("token").and_then(|t| t.as_string()) {
+        Some(t) => t.to_string(),
+        None => return Err(Error::failed("comment: expected a token".into())),
+    };
+    let text = match args.find("text").and_then(|t| t.as_string()) {
+        Some(t) => t.to_string(),
+        None => return Err(Error::failed("comment: expected a text".into())),
+    };
+    let version = match args.find("version") {
+        None | Some(&json::Json::Null) => None,
+        Some(v) => match v.as_u64() {
+            Some(v) => Some(v),
+            None => return Err(Error::failed("comment: version must be a number".into())),
+        },
+    };
+    Ok((token, text, version))
+}
+
+/// One change within a websocket `batch` command.
+enum BatchOp {
+    Move(MoveArgs),
+    Remove { tokens: Vec<String> },
+}
+
+/// Parses one element of a `batch` command: `{"move": {...}}` or `{"remove": {"tokens": [...]}}`.
+fn parse_batch_op(command: &json::Json) -> Result<BatchOp, Error> {
+    if let Some(args) = command.find("move") {
+        Ok(BatchOp::Move(try!(parse_move_args(args))))
+    } else if let Some(args) = command.find("remove") {
+        Ok(BatchOp::Remove { tokens: try!(parse_tokens(args, "remove")) })
+    } else {
+        Err(Error::failed(format!("batch: unrecognized command: {}", command)))
+    }
+}
+
+/// Parses the body of `POST api/bookmarks`: `{"url": ..., "title": ..., "faviconUrl": ...}`,
+/// where only the URL is required. Only http and https links are accepted, since anything else
+/// would be opened with the collection's privileges or not at all.
+fn parse_bookmark(text: &str) -> Result<(String, BookmarkData), Error> {
+    fn web_url(url: &str) -> Result<String, Error> {
+        match ::url::Url::parse(url) {
+            Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {
+                Ok(parsed.as_str().to_string())
+            }
+            Ok(_) => Err(Error::failed(format!("not an http or https URL: {}", url))),
+            Err(e) => Err(Error::failed(format!("invalid URL {}: {}", url, e))),
+        }
+    }
+
+    let args = try!(json::Json::from_str(text).map_err(|e| Error::failed(format!("{}", e))));
+    let url = match args.find("url").and_then(|u| u.as_string()) {
+        Some(url) => try!(web_url(url)),
+        None => return Err(Error::failed("bookmark: expected a url".into())),
+    };
+    let favicon_url = match args.find("faviconUrl").and_then(|u| u.as_string()) {
+        Some(favicon_url) => Some(try!(web_url(favicon_url))),
+        None => None,
+    };
+    let title = match args.find("title").and_then(|t| t.as_string()) {
+        Some(title) if !title.trim().is_empty() => title.to_string(),
+        _ => url.clone(),
+    };
+    Ok((title, BookmarkData { url: url, favicon_url: favicon_url }))
+}
+
+/// The powerbox query, as URL-safe base64 of a packed PowerboxDescriptor, that asks the user for
+/// an ApiSession to `url`.
+fn webhook_powerbox_descriptor(url: &str) -> Result<String, Error> {
+    use capnp::traits::HasTypeId;
+    match ::url::Url::parse(url) {
+        Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => (),
+        _ => return Err(Error::failed(format!("not an http or https URL: {}", url))),
+    }
+
+    let mut message = ::capnp::message::Builder::new_default();
+    {
+        let descriptor = message.init_root::<powerbox_descriptor::Builder>();
+        let mut tag = descriptor.init_tags(1).get(0);
+        tag.set_id(api_session::Client::type_id());
+        tag.get_value().init_as::<api_session::powerbox_tag::Builder>().set_canonical_url(url);
+    }
+    let mut bytes = Vec::new();
+    try!(::capnp::serialize_packed::write_message(&mut bytes, &message));
+    Ok(base64::ToBase64::to_base64(&bytes[..], base64::URL_SAFE))
+}
+
+/// Builds the base64-encoded descriptor that the "add grain" button passes to the powerbox: a
+/// UiView, further narrowed by any tag IDs that the managers require.
+fn ui_view_powerbox_descriptor(required_tag_ids: &[u64]) -> Result<String, Error> {
+    use capnp::traits::HasTypeId;
+    let mut message = ::capnp::message::Builder::new_default();
+    {
+        let descriptor = message.init_root::<powerbox_descriptor::Builder>();
+        let mut tags = descriptor.init_tags(1 + required_tag_ids.len() as u32);
+        tags.borrow().get(0).set_id(ui_view::Client::type_id());
+        for (idx, &tag_id) in required_tag_ids.iter().enumerate() {
+            tags.borrow().get(1 + idx as u32).set_id(tag_id);
+        }
+    }
+    let mut bytes = Vec::new();
+    try!(::capnp::serialize_packed::write_message(&mut bytes, &message));
+    Ok(base64::ToBase64::to_base64(&bytes[..], base64::URL_SAFE))
+}
+
+/// Parses the body of `PUT api/settings/powerbox`, e.g.
+/// `{"appIds":["s3u2..."],"tagIds":["d5d1b2ff6e0a3f1c"]}`. Tag IDs are hex strings because
+/// JSON numbers can't hold every 64-bit ID.
+fn parse_powerbox_filter(args: &json::Json) -> Result<(Vec<String>, Vec<u64>), Error> {
+    let strings = |key: &str| -> Result<Vec<String>, Error> {
+        match args.find(key) {
+            None => Ok(Vec::new()),
+            Some(&json::Json::Array(ref items)) => items.iter().map(|item| match item {
+                &json::Json::String(ref s) => Ok(s.clone()),
+                _ => Err(Error::failed(format!("{} must be a list of strings", key))),
+            }).collect(),
+            Some(_) => Err(Error::failed(format!("{} must be a list of strings", key))),
+        }
+    };
+    let app_ids = try!(strings("appIds"));
+    let mut tag_ids = Vec::new();
+    for text in try!(strings("tagIds")) {
+        match u64::from_str_radix(&text, 16) {
+            Ok(id) => tag_ids.push(id),
+            Err(_) => return Err(Error::failed(format!("invalid tag ID: {:?}", text))),
+        }
+    }
+    Ok((app_ids, tag_ids))
+}
+
+/// Folder names longer than this many characters are rejected.
+const MAX_FOLDER_NAME_LENGTH: usize = 128;
+
+fn validate_folder_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() {
+        Err(Error::failed("folder name is empty".into()))
+    } else if name.chars().count() > MAX_FOLDER_NAME_LENGTH {
+        Err(Error::failed(format!("folder name is longer than {} characters",
+                                  MAX_FOLDER_NAME_LENGTH)))
+    } else if name.trim() != name || name.contains('/') || name.chars().any(|c| c.is_control()) {
+        Err(Error::failed(format!("invalid folder name: {:?}", name)))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct CommentData {
+    author: Option<String>,
+    date: u64,
+    text: String,
+}
+
+impl CommentData {
+    fn to_json(&self) -> String {
+        format!("{{\"author\":{},\"date\":\"{}\",\"text\":{}}}",
+                optional_string_to_json(&self.author),
+                self.date,
+                json::ToJson::to_json(&self.text))
+    }
+}
+
+/// Comments longer than this many bytes are rejected.
+const MAX_COMMENT_LENGTH: usize = 4096;
+
 #[derive(Clone)]
 struct SavedUiViewData {
     title: String,
     date_added: u64,
     added_by: Option<String>,
+    open_count: u64,
+    comments: Vec<CommentData>,
+    folder: Option<String>,
+    is_collection: bool,
+
+    /// Set if this entry is a plain link rather than a saved UiView.
+    bookmark: Option<BookmarkData>,
+
+    /// Set if an editor uploaded a thumbnail to show instead of the app icon.
+    thumbnail: Option<ThumbnailData>,
+
+    /// When the entry should be moved to the trash, in milliseconds since the unix epoch.
+    expires: Option<u64>,
+
+    /// When the entry was or will be published, in milliseconds since the unix epoch. Until
+    /// then, only editors can see it.
+    publish_at: Option<u64>,
+
+    /// Incremented by every edit. Clients that pass the version they last saw along with an
+    /// edit get a conflict instead of overwriting someone else's change.
+    version: u64,
+
+    /// See `grain_fingerprint()`. Unset for bookmarks, and for grains whose view info we have
+    /// not yet retrieved.
+    fingerprint: Option<String>,
+
+    /// Stars, from 1 to 5, by the identity ID of whoever gave them. Rating doesn't change the
+    /// version, since it can't conflict with an edit.
+    ratings: BTreeMap<String, u8>,
+
+    /// Identity IDs of everyone who upvoted the entry. Like ratings, upvotes leave the version
+    /// alone.
+    upvoters: BTreeSet<String>,
+
+    /// Set if only the user who added the entry can see it. Entries without an `added_by` are
+    /// never private.
+    private: bool,
+}
+
+/// The collection as of one journal revision. Exports serialize this rather than the live
+/// state, which other sessions may change while an export waits for display names.
+struct ExportSnapshot {
+    /// How many changes the journal had recorded when the snapshot was taken.
+    revision: usize,
+    description: String,
+
+    /// (token, entry, app title) of every entry, in sort order. The app title is set for grains
+    /// whose view info we have retrieved.
+    entries: Vec<(String, SavedUiViewData, Option<String>)>,
+}
+
+/// What one identity's entries and comments take up, for `GET api/usage`.
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    entries: u64,
+    metadata_bytes: u64,
+    thumbnail_bytes: u64,
+    comments: u64,
+    comment_bytes: u64,
+}
+
+impl Usage {
+    fn total_bytes(&self) -> u64 {
+        self.metadata_bytes + self.thumbnail_bytes + self.comment_bytes
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ThumbnailData {
+    mime_type: String,
+
+    /// When the thumbnail was uploaded, in milliseconds since the unix epoch. Clients add this to
+    /// the thumbnail URL so that a new upload isn't hidden by the browser's cache.
+    updated: u64,
+}
+
+#[derive(Clone, Debug)]
+struct BookmarkData {
+    url: String,
+    favicon_url: Option<String>,
+}
+
+impl BookmarkData {
+    fn to_json(&self) -> String {
+        format!("{{\"url\":{},\"faviconUrl\":{}}}",
+                json::ToJson::to_json(&self.url),
+                optional_string_to_json(&self.favicon_url))
+    }
 }
 
 fn optional_string_to_json(optional_string: &Option<String>) -> String {
@@ -92,19 +652,315 @@ fn optional_string_to_json(optional_string: &Option<String>) -> String {
     }
 }
 
+fn entry_json(token: &str, data: &SavedUiViewData, is_new: bool) -> String {
+    format!("{{\"token\":\"{}\",\"data\":{},\"isNew\":{}}}", token, data.to_json(), is_new)
+}
+
+/// The user on whose behalf we are rendering entries.
+#[derive(Clone)]
+struct Viewer {
+    identity_id: Option<String>,
+
+    /// The user's name as Sandstorm gave it for this session, for telling others who did what.
+    display_name: Option<String>,
+
+    /// When this user last opened the collection before the current session.
+    previous_visit: Option<u64>,
+
+    /// How titles are ordered for this user, by the language of their browser.
+    collation: Collation,
+
+    /// Whether the user may edit the collection.
+    can_write: bool,
+}
+
+/// Who is looking at the collection, for deciding which hidden entries they may see.
+#[derive(Clone, Default)]
+struct Audience {
+    identity_id: Option<String>,
+
+    /// Editors also see entries that are scheduled for later publication.
+    editor: bool,
+}
+
+impl Audience {
+    /// Whoever is not signed in, such as the readers of the published page.
+    fn public() -> Audience {
+        Audience::default()
+    }
+
+    fn can_see(&self, visibility: Visibility) -> bool {
+        match visibility {
+            Visibility::Everyone => true,
+            Visibility::Editors => self.editor,
+            Visibility::Owner(owner) => self.identity_id.as_ref().map_or(false, |id| id == owner),
+        }
+    }
+}
+
+/// Who may see an entry.
+#[derive(Clone, Copy, PartialEq)]
+enum Visibility<'a> {
+    Everyone,
+
+    /// The entry is scheduled for later publication.
+    Editors,
+
+    /// The entry is private to the user who added it.
+    Owner(&'a str),
+}
+
+impl Viewer {
+    fn audience(&self) -> Audience {
+        Audience { identity_id: self.identity_id.clone(), editor: self.can_write }
+    }
+
+    /// Whether `data` was added by someone else since the viewer's previous visit.
+    fn is_new(&self, data: &SavedUiViewData) -> bool {
+        match self.previous_visit {
+            Some(t) => data.date_added > t && data.added_by != self.identity_id,
+            None => false,
+        }
+    }
+}
+
 impl SavedUiViewData {
+    /// The text that searches look through: the title, comments, and bookmark URL.
+    fn search_text(&self) -> String {
+        let mut text = self.title.clone();
+        for comment in &self.comments {
+            text.push('\n');
+            text.push_str(&comment.text);
+        }
+        if let Some(ref bookmark) = self.bookmark {
+            text.push('\n');
+            text.push_str(&bookmark.url);
+        }
+        text
+    }
+
+    fn from_metadata(metadata: ui_view_metadata::Reader) -> ::capnp::Result<SavedUiViewData> {
+        let added_by = if metadata.has_added_by() {
+            Some(try!(metadata.get_added_by()).into())
+        } else {
+            None
+        };
+
+        let mut comments = Vec::new();
+        for comment in try!(metadata.get_comments()).iter() {
+            let author = if comment.has_author() {
+                Some(try!(comment.get_author()).into())
+            } else {
+                None
+            };
+            comments.push(CommentData {
+                author: author,
+                date: comment.get_date(),
+                text: try!(comment.get_text()).into(),
+            });
+        }
+
+        let mut ratings = BTreeMap::new();
+        for rating in try!(metadata.get_ratings()).iter() {
+            ratings.insert(try!(rating.get_rater()).to_string(), rating.get_stars());
+        }
+        let mut upvoters = BTreeSet::new();
+        for upvoter in try!(metadata.get_upvoters()).iter() {
+            upvoters.insert(try!(upvoter).to_string());
+        }
+
+        Ok(SavedUiViewData {
+            title: try!(metadata.get_title()).into(),
+            date_added: metadata.get_date_added(),
+            added_by: added_by,
+            open_count: metadata.get_open_count(),
+            comments: comments,
+            folder: if metadata.has_folder() { Some(try!(metadata.get_folder()).into()) } else { None },
+            is_collection: metadata.get_is_collection(),
+            thumbnail: if metadata.has_thumbnail_mime_type() {
+                Some(ThumbnailData {
+                    mime_type: try!(metadata.get_thumbnail_mime_type()).into(),
+                    updated: metadata.get_thumbnail_updated(),
+                })
+            } else {
+                None
+            },
+            expires: match metadata.get_expires() {
+                0 => None,
+                time => Some(time),
+            },
+            publish_at: match metadata.get_publish_at() {
+                0 => None,
+                time => Some(time),
+            },
+            version: metadata.get_version(),
+            fingerprint: if metadata.has_fingerprint() {
+                Some(try!(metadata.get_fingerprint()).into())
+            } else {
+                None
+            },
+            ratings: ratings,
+            upvoters: upvoters,
+            private: metadata.get_private(),
+            bookmark: match try!(metadata.which()) {
+                ui_view_metadata::Which::UiView(()) => None,
+                ui_view_metadata::Which::Bookmark(bookmark) => Some(BookmarkData {
+                    url: try!(bookmark.get_url()).into(),
+                    favicon_url: if bookmark.has_favicon_url() {
+                        Some(try!(bookmark.get_favicon_url()).into())
+                    } else {
+                        None
+                    },
+                }),
+            },
+        })
+    }
+
+    fn to_metadata(&self, mut metadata: ui_view_metadata::Builder) {
+        metadata.set_title(&self.title);
+        metadata.set_date_added(self.date_added);
+        match self.added_by {
+            Some(ref s) => metadata.set_added_by(s),
+            None => (),
+        }
+        metadata.set_open_count(self.open_count);
+        metadata.set_is_collection(self.is_collection);
+        if let Some(ref thumbnail) = self.thumbnail {
+            metadata.set_thumbnail_mime_type(&thumbnail.mime_type);
+            metadata.set_thumbnail_updated(thumbnail.updated);
+        }
+        metadata.set_expires(self.expires.unwrap_or(0));
+        metadata.set_publish_at(self.publish_at.unwrap_or(0));
+        metadata.set_version(self.version);
+        metadata.set_private(self.private);
+        if let Some(ref fingerprint) = self.fingerprint {
+            metadata.set_fingerprint(fingerprint);
+        }
+        match self.bookmark {
+            None => metadata.set_ui_view(()),
+            Some(ref bookmark) => {
+                let mut group = metadata.borrow().init_bookmark();
+                group.set_url(&bookmark.url);
+                if let Some(ref favicon_url) = bookmark.favicon_url {
+                    group.set_favicon_url(favicon_url);
+                }
+            }
+        }
+        match self.folder {
+            Some(ref s) => metadata.set_folder(s),
+            None => (),
+        }
+
+        let mut comments = metadata.borrow().init_comments(self.comments.len() as u32);
+        for (idx, comment) in self.comments.iter().enumerate() {
+            let mut builder = comments.borrow().get(idx as u32);
+            match comment.author {
+                Some(ref s) => builder.set_author(s),
+                None => (),
+            }
+            builder.set_date(comment.date);
+            builder.set_text(&comment.text);
+        }
+
+        let mut ratings = metadata.borrow().init_ratings(self.ratings.len() as u32);
+        for (idx, (rater, &stars)) in self.ratings.iter().enumerate() {
+            let mut builder = ratings.borrow().get(idx as u32);
+            builder.set_rater(rater);
+            builder.set_stars(stars);
+        }
+
+        let mut upvoters = metadata.borrow().init_upvoters(self.upvoters.len() as u32);
+        for (idx, upvoter) in self.upvoters.iter().enumerate() {
+            upvoters.set(idx as u32, upvoter);
+        }
+    }
+
+    /// The number of ratings and their average, if there are any.
+    fn rating_summary(&self) -> Option<(usize, f64)> {
+        if self.ratings.is_empty() {
+            return None
+        }
+        let total: u64 = self.ratings.values().map(|&stars| stars as u64).sum();
+        Some((self.ratings.len(), total as f64 / self.ratings.len() as f64))
+    }
+
     fn to_json(&self) -> String {
-        format!("{{\"title\":{},\"dateAdded\": \"{}\",\"addedBy\":{}}}",
+        let comments: Vec<String> = self.comments.iter().map(|c| c.to_json()).collect();
+        format!("{{\"title\":{},\"dateAdded\": \"{}\",\"addedBy\":{},\"openCount\":{},\
+                 \"comments\":[{}],\"folder\":{},\"isCollection\":{},\"bookmark\":{},\
+                 \"thumbnailUpdated\":{},\"expires\":{},\"version\":{},\"ratings\":{},\
+                 \"rating\":{},\"upvoters\":{},\"upvotes\":{},\"private\":{},\"publishAt\":{}}}",
                 json::ToJson::to_json(&self.title),
                 self.date_added,
-                optional_string_to_json(&self.added_by))
+                optional_string_to_json(&self.added_by),
+                self.open_count,
+                comments.join(","),
+                optional_string_to_json(&self.folder),
+                self.is_collection,
+                self.bookmark.as_ref().map(|b| b.to_json()).unwrap_or("null".into()),
+                self.thumbnail.as_ref().map(|t| t.updated.to_string()).unwrap_or("null".into()),
+                self.expires.map(|t| t.to_string()).unwrap_or("null".into()),
+                self.version,
+                json::ToJson::to_json(&self.ratings),
+                rating_summary_json(self.rating_summary()),
+                json::ToJson::to_json(&self.upvoters.iter().cloned().collect::<Vec<String>>()),
+                self.upvoters.len(),
+                self.private,
+                self.publish_at.map(|t| t.to_string()).unwrap_or("null".into()))
+    }
+}
+
+/// `{"count": ..., "average": ...}` for `SavedUiViewData::rating_summary()`, or null if the entry
+/// has no ratings.
+fn rating_summary_json(summary: Option<(usize, f64)>) -> String {
+    match summary {
+        Some((count, average)) => format!("{{\"count\":{},\"average\":{:.2}}}", count, average),
+        None => "null".into(),
+    }
+}
+
+/// Identifies the grain behind an entry, so that the same grain shared with us twice can be
+/// recognized even though each share gives us a different sturdyref. Sandstorm doesn't tell apps
+/// grain IDs, so this is the app ID plus the grain's (sanitized) title; two grains of the same
+/// app with the same title look alike, which is why merging duplicates is opt-in.
+fn grain_fingerprint(app_id: &str, title: &str) -> String {
+    format!("{}:{}", app_id, title)
+}
+
+/// Titles longer than this many characters get truncated.
+const MAX_TITLE_LENGTH: usize = 256;
+
+/// Cleans up a title supplied by the grain being added. The title comes from a powerbox tag, so
+/// it is fully under the control of whoever offered the grain.
+fn sanitize_title(title: &str) -> Result<String, Error> {
+    let mut cleaned = String::new();
+    for c in title.trim().chars() {
+        if c.is_control() {
+            if c.is_whitespace() {
+                cleaned.push(' ');
+            }
+        } else {
+            cleaned.push(c);
+        }
+    }
+
+    let cleaned: String = cleaned.trim().chars().take(MAX_TITLE_LENGTH).collect();
+    if cleaned.is_empty() {
+        Err(Error::failed("grain title is empty".into()))
+    } else {
+        Ok(cleaned)
     }
 }
 
 #[derive(Clone, Debug)]
 struct ViewInfoData {
+    app_id: String,
     app_title: String,
     grain_icon_url: String,
+
+    /// Whether the grain is an instance of this app. Reported to clients as part of the entry's
+    /// data rather than here, so that it is available even while the grain is unreachable.
+    is_collection: bool,
 }
 
 impl ViewInfoData {
@@ -115,7 +971,7 @@ impl ViewInfoData {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 struct ProfileData {
     display_name: String,
     picture_url: String,
@@ -132,24 +988,72 @@ impl ProfileData {
 
 #[derive(Clone)]
 enum Action {
-    Insert { token: String, data: SavedUiViewData },
-    Remove { token: String },
+    Insert { token: String, data: SavedUiViewData, is_new: bool },
+    /// `actor` and `actor_name` are whoever removed the entry, if anyone did, and `time` is
+    /// when.
+    Remove { token: String, actor: Option<String>, actor_name: Option<String>, time: u64 },
     ViewInfo { token: String, data: Result<ViewInfoData, Error> },
+
+    /// The entry's grain could not be restored, for the given reason, or (if None) it can be
+    /// again. If `data` is set, the entry's metadata changed instead and `unavailable` is unused.
+    Update { token: String, unavailable: Option<String>, data: Option<SavedUiViewData> },
     CanWrite(bool),
+    CanComment(bool),
     UserId(Option<String>),
     Description(String),
     User { id: String, data: ProfileData },
+    Comment { token: String, data: CommentData, version: u64 },
+
+    /// `rater` gave the entry `stars`, or withdrew their rating if None. `summary` is what
+    /// `SavedUiViewData::rating_summary()` now gives.
+    Rating { token: String, rater: String, stars: Option<u8>, summary: Option<(usize, f64)> },
+
+    /// `voter` upvoted the entry, or took their upvote back if `up` is false, which leaves it
+    /// with `upvotes` in all.
+    Upvote { token: String, voter: String, up: bool, upvotes: usize },
+    Folders(Vec<String>),
+    /// `versions` are the new versions of the entries, in the same order as `tokens`.
+    Move { tokens: Vec<String>, folder: Option<String>, versions: Vec<u64> },
+    Cleared,
+    SortOrder(SortOrder),
+    Locked(bool),
+
+    /// Storage maintenance has started (true) or finished (false). Writes fail while it runs.
+    Maintenance(bool),
+    /// `id` is the failed command's "id", if it had one.
+    CommandFailed { id: Option<json::Json>, reason: String },
+
+    /// How a command with the given "id" turned out: the entries that it touched as they are now
+    /// (None if gone), or why it failed, as in `CommandFailed` if we know, and the error.
+    CommandResult {
+        id: json::Json,
+        outcome: Result<Vec<(String, Option<SavedUiViewData>)>, (Option<&'static str>, String)>,
+    },
+    Reload,
+    Revision(u64),
+    Batch(Vec<Action>),
+    Summary { count: usize, revision: u64, title: String },
+
+    /// A journal entry, already in JSON. Only sent on the activity channel.
+    Activity(String),
+
+    /// How many websocket clients are connected, on either channel. Only sent on the activity
+    /// channel.
+    Presence(usize),
 }
 
 impl Action {
     fn to_json(&self) -> String {
         match self {
-            &Action::Insert { ref token, ref data } => {
-                format!("{{\"insert\":{{\"token\":\"{}\",\"data\":{} }} }}",
-                        token, data.to_json())
+            &Action::Insert { ref token, ref data, is_new } => {
+                format!("{{\"insert\":{{\"token\":\"{}\",\"data\":{},\"isNew\":{} }} }}",
+                        token, data.to_json(), is_new)
             }
-            &Action::Remove { ref token } => {
-                format!("{{\"remove\":{{\"token\":\"{}\"}}}}", token)
+            &Action::Remove { ref token, ref actor, ref actor_name, time } => {
+                format!("{{\"remove\":{{\"token\":\"{}\",\"actor\":{},\"actorName\":{},\
+                         \"time\":{}}}}}",
+                        token, optional_string_to_json(actor), optional_string_to_json(actor_name),
+                        time)
             }
             &Action::ViewInfo { ref token, data: Ok(ref data) } => {
                 format!("{{\"viewInfo\":{{\"token\":\"{}\",\"data\":{} }} }}",
@@ -160,10 +1064,20 @@ impl Action {
                         token,
                         json::ToJson::to_json(&format!("{}", e)))
             }
-
+            &Action::Update { ref token, data: Some(ref data), .. } => {
+                format!("{{\"update\":{{\"token\":{},\"data\":{}}}}}",
+                        json::ToJson::to_json(token), data.to_json())
+            }
+            &Action::Update { ref token, ref unavailable, data: None } => {
+                format!("{{\"update\":{{\"token\":{},\"unavailable\":{}}}}}",
+                        json::ToJson::to_json(token), optional_string_to_json(unavailable))
+            }
             &Action::CanWrite(b) => {
                 format!("{{\"canWrite\":{}}}", b)
             }
+            &Action::CanComment(b) => {
+                format!("{{\"canComment\":{}}}", b)
+            }
             &Action::UserId(ref s) => {
                 format!("{{\"userId\":{}}}", optional_string_to_json(s))
             }
@@ -175,7 +1089,105 @@ impl Action {
                     "{{\"user\":{{\"id\":{}, \"data\":{} }}}}",
                     json::ToJson::to_json(id), data.to_json())
             }
+            &Action::Comment { ref token, ref data, version } => {
+                format!("{{\"comment\":{{\"token\":\"{}\",\"data\":{},\"version\":{} }} }}",
+                        token, data.to_json(), version)
+            }
+            &Action::Rating { ref token, ref rater, stars, summary } => {
+                format!("{{\"rating\":{{\"token\":{},\"rater\":{},\"stars\":{},\"summary\":{}}}}}",
+                        json::ToJson::to_json(token), json::ToJson::to_json(rater),
+                        stars.map(|s| s.to_string()).unwrap_or("null".into()),
+                        rating_summary_json(summary))
+            }
+            &Action::Upvote { ref token, ref voter, up, upvotes } => {
+                format!("{{\"upvote\":{{\"token\":{},\"voter\":{},\"up\":{},\"upvotes\":{}}}}}",
+                        json::ToJson::to_json(token), json::ToJson::to_json(voter), up, upvotes)
+            }
+            &Action::Folders(ref names) => {
+                format!("{{\"folders\":{}}}", json::ToJson::to_json(names))
+            }
+            &Action::Move { ref tokens, ref folder, ref versions } => {
+                format!("{{\"move\":{{\"tokens\":{},\"folder\":{},\"versions\":{}}}}}",
+                        json::ToJson::to_json(tokens), optional_string_to_json(folder),
+                        json::ToJson::to_json(versions))
+            }
+            &Action::Cleared => {
+                format!("{{\"cleared\":true}}")
+            }
+            &Action::SortOrder(order) => {
+                format!("{{\"sortOrder\":\"{}\"}}", sort_order_name(order))
+            }
+            &Action::Locked(locked) => {
+                format!("{{\"locked\":{}}}", locked)
+            }
+            &Action::Maintenance(active) => {
+                format!("{{\"maintenance\":{}}}", active)
+            }
+            &Action::Reload => {
+                format!("{{\"reload\":true}}")
+            }
+            &Action::CommandResult { ref id, outcome: Ok(ref entries) } => {
+                let entries: Vec<String> = entries.iter().map(|&(ref token, ref data)| {
+                    format!("{{\"token\":{},\"data\":{}}}", json::ToJson::to_json(token),
+                            data.as_ref().map(|d| d.to_json()).unwrap_or("null".into()))
+                }).collect();
+                format!("{{\"commandResult\":{{\"id\":{},\"ok\":true,\"entries\":[{}]}}}}",
+                        id, entries.join(","))
+            }
+            &Action::CommandResult { ref id, outcome: Err((reason, ref error)) } => {
+                let reason = reason.map(|r| r.to_string());
+                format!("{{\"commandResult\":{{\"id\":{},\"ok\":false,\"reason\":{},\
+                         \"error\":{}}}}}",
+                        id, optional_string_to_json(&reason), json::ToJson::to_json(error))
+            }
+            &Action::CommandFailed { ref id, ref reason } => {
+                let id = id.as_ref().map(|id| format!("{}", id)).unwrap_or("null".into());
+                format!("{{\"commandFailed\":{{\"id\":{},\"reason\":{}}}}}",
+                        id, json::ToJson::to_json(reason))
+            }
+            &Action::Revision(revision) => {
+                format!("{{\"revision\":{}}}", revision)
+            }
+            &Action::Summary { count, revision, ref title } => {
+                format!("{{\"summary\":{{\"count\":{},\"revision\":{},\"title\":{}}}}}",
+                        count, revision, json::ToJson::to_json(title))
+            }
+            &Action::Batch(ref actions) => {
+                let actions: Vec<String> = actions.iter().map(|a| a.to_json()).collect();
+                format!("{{\"batch\":[{}]}}", actions.join(","))
+            }
+            &Action::Activity(ref entry) => {
+                format!("{{\"activity\":{}}}", entry)
+            }
+            &Action::Presence(connected) => {
+                format!("{{\"presence\":{{\"connected\":{}}}}}", connected)
+            }
+        }
+    }
+}
+
+/// Stops work that a web session started once the session is gone.
+fn session_closed_error() -> Error {
+    Error::disconnected("the session was closed".into())
+}
+
+/// Whether an RPC that failed with `error` might succeed if we try again.
+fn is_transient_rpc_error(error: &Error) -> bool {
+    match error.kind {
+        ::capnp::ErrorKind::Overloaded | ::capnp::ErrorKind::Disconnected => true,
+        _ => false,
+    }
+}
+
+/// Converts `error` as `From` would, except that the kinds of io error that can clear up by
+/// themselves come out as Overloaded, so that `is_transient_rpc_error()` retries them too.
+fn storage_error(error: ::std::io::Error) -> Error {
+    use std::io::ErrorKind;
+    match error.kind() {
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            Error::overloaded(format!("{}", error))
         }
+        _ => error.into(),
     }
 }
 
@@ -191,16 +1203,83 @@ fn url_of_static_asset(asset: static_asset::Client) -> Promise<String, Error> {
     }))
 }
 
-struct Reaper;
+/// The session context we pass when opening a session on a nested collection. The session is
+/// only used to read the listing, so it never needs to share, request or offer anything.
+struct NullSessionContext;
 
-impl Finisher<(), Error> for Reaper {
-    fn done_err(&mut self, error: Error) {
-        // TODO better message.
-        println!("task failed: {}", error);
-    }
-}
+impl session_context::Server for NullSessionContext {}
 
-struct SavedUiViewSetInner {
+/// The app ID in sandstorm-pkgdef.capnp, used to recognize grains that are themselves
+/// collections.
+const COLLECTIONS_APP_ID: &'static str = "s3u2xgmqwznz2n3apf30sm3gw1d85y029enw5pymx734cnk5n78h";
+
+/// After this many storage failures in a row we stop treating them as flukes: `healthz` reports
+/// the grain as unhealthy and the log says so loudly.
+const STORAGE_FAILURE_ESCALATION: u64 = 3;
+
+/// Counts of things that went wrong in the background, shared between the `Reaper` and the
+/// code that reports on them in `debug/state` and `healthz`.
+#[derive(Default)]
+struct Failures {
+    tasks: Cell<u64>,
+
+    /// Subscribers that we dropped because a send to them failed.
+    subscribers: Cell<u64>,
+
+    /// Storage writes that have failed since the last one that succeeded.
+    storage_streak: Cell<u64>,
+}
+
+impl Failures {
+    /// Notes the outcome of a storage write that we can't report to anyone, logging it if it
+    /// failed. `what` says what we were trying to do, e.g. "record visit".
+    fn storage<T>(&self, what: &str, result: Result<T, Error>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.storage_streak.set(0);
+                Some(value)
+            }
+            Err(e) => {
+                println!("failed to {}: {}", what, e);
+                let streak = self.storage_streak.get() + 1;
+                self.storage_streak.set(streak);
+                if streak == STORAGE_FAILURE_ESCALATION {
+                    println!("ERROR: {} storage writes in a row have failed; the grain's disk \
+                              may be full", streak);
+                }
+                None
+            }
+        }
+    }
+
+    fn storage_healthy(&self) -> bool {
+        self.storage_streak.get() < STORAGE_FAILURE_ESCALATION
+    }
+}
+
+/// Prefixes the error of `task` with `label`, so that the reaper can say which task failed.
+fn labeled<F>(label: String, task: F) -> Promise<(), Error>
+    where F: Future<Item=(), Error=Error> + 'static
+{
+    Promise::from_future(task.map_err(move |e| Error {
+        kind: e.kind,
+        description: format!("{}: {}", label, e.description),
+    }))
+}
+
+struct Reaper {
+    failures: Rc<Failures>,
+}
+
+impl Finisher<(), Error> for Reaper {
+    fn done_err(&mut self, error: Error) {
+        println!("background task failed: {}", error);
+        self.failures.tasks.set(self.failures.tasks.get() + 1);
+    }
+}
+
+struct SavedUiViewSetInner {
+    config: Rc<Config>,
     tmp_dir: ::std::path::PathBuf,
     sturdyref_dir: ::std::path::PathBuf,
 
@@ -208,39 +1287,541 @@ struct SavedUiViewSetInner {
     /// out Action::Insert messages to each subscriber.
     views: HashMap<String, SavedUiViewData>,
 
+    /// (date_added, token) for every entry in `views`, so that we can list the newest entries
+    /// without sorting the whole collection.
+    by_date: BTreeSet<(u64, String)>,
+
     view_infos: HashMap<String, Result<ViewInfoData, Error>>,
+
+    /// Why restoring each of these entries' grains failed, e.g. because the grain was deleted or
+    /// the user's access was revoked. Transient failures don't count.
+    unavailable: HashMap<String, String>,
+
+    /// Tokens whose view info we're fetching right now. Everyone hears about the result through
+    /// the `viewInfo` broadcast, so a second request for the same token can just wait for that.
+    view_infos_in_flight: HashSet<String>,
+
+    /// The adder of every entry that is private, or was when it was removed, by token.
+    /// Broadcasts are filtered by this rather than by `views`, because some are sent after the
+    /// entry is gone.
+    private_owners: HashMap<String, String>,
+
+    /// Publication time of every entry that isn't published yet, by token.
+    scheduled: HashMap<String, u64>,
+
     next_id: u64,
     subscribers: HashMap<u64, web_socket_stream::Client>,
+
+    /// Who each subscriber on the views channel is, for deciding which hidden entries it may
+    /// hear about.
+    subscriber_audiences: HashMap<u64, Audience>,
+
+    activity_subscribers: HashMap<u64, web_socket_stream::Client>,
     tasks: PollerHandle<(), Error>,
+    failures: Rc<Failures>,
+
+    /// When a broadcast last reached a subscriber, for `healthz`.
+    last_broadcast: Rc<Cell<Option<u64>>>,
+    request_stats: Rc<RequestStats>,
+
+    /// When `healthz` last tried writing to storage, and how that went.
+    storage_probe: RefCell<Option<(u64, Result<(), String>)>>,
+
     description: String,
     sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
     identity_map: ::identity_map::IdentityMap,
+    clock: Rc<Clock>,
+    timer: Rc<Timer>,
+
+    /// Limits how fast each identity can make changes to the collection.
+    write_limiter: RateLimiter,
+
+    journal: Journal,
+    last_seen: LastSeen,
+    webhooks: Webhooks,
+    digests: Digests,
+    avatars: AvatarCache,
+    thumbnails: Thumbnails,
+
+    /// The context of the most recently opened session, through which we send digest emails.
+    /// None until someone opens the grain.
+    mail_context: Option<hack_session_context::Client>,
+
+    /// The profiles that the last refresh found, by identity ID, so that we only tell clients
+    /// about changes.
+    profiles: HashMap<String, ProfileData>,
+
+    /// Names of all folders, including empty ones. Persisted in /var/folders, one per line.
+    folders: BTreeSet<String>,
+
+    /// Manager-controlled preferences. Persisted in /var/settings.
+    settings: Settings,
+
+    /// Recent broadcasts, so that reconnecting clients can catch up. Persisted in /var/replay.
+    replay: ReplayBuffer,
+
+    /// How far each acked-mode client has confirmed the broadcasts. Persisted in /var/acks.
+    acks: Acks,
+
+    /// Words of every entry's searchable text. Persisted in /var/search-index, and brought up to
+    /// date with `views` on startup.
+    search_index: SearchIndex,
 
+    /// Tokens whose entry in `views` has changed in ways that we write out lazily (see
+    /// `write_metadata_later()`), and whether a flush of them is already scheduled.
+    dirty_metadata: BTreeSet<String>,
+    metadata_flush_scheduled: bool,
+
+    /// Whether `publish()` is already scheduled to run.
+    publish_scheduled: bool,
+
+    /// Set while `run_maintenance()` has work pending or under way.
+    maintenance: bool,
+
+    /// While a batch is being applied, the actions that it will broadcast all at once.
+    batch: Option<Vec<Action>>,
 }
 
 impl SavedUiViewSetInner {
+    /// The first line of the description, which serves as the collection's title, shortened to
+    /// fit in labels and subject lines.
+    fn collection_title(&self) -> Option<String> {
+        match self.description.lines().next() {
+            Some(line) if !line.trim().is_empty() => {
+                let line = line.trim();
+                if line.chars().count() > MAX_COLLECTION_TITLE_CHARS {
+                    let short: String = line.chars().take(MAX_COLLECTION_TITLE_CHARS).collect();
+                    Some(format!("{}…", short))
+                } else {
+                    Some(line.to_string())
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The page published at the grain's public address: the description and the title of
+    /// every entry, by folder, with links only for bookmarks. Grains can't be opened by the
+    /// public, and names and comments stay private, so nothing else is shown.
+    fn public_html(&self) -> String {
+        let mut groups: BTreeMap<Option<String>, Vec<String>> = BTreeMap::new();
+        for token in self.sorted_tokens(Collation::Root, &Audience::public()) {
+            if let Some(data) = self.views.get(&token) {
+                groups.entry(data.folder.clone()).or_insert(Vec::new()).push(token);
+            }
+        }
+
+        let mut body = String::new();
+        if !self.description.is_empty() {
+            body.push_str(&format!("<p class=\"description\">{}</p>\n",
+                                   escape_html(&self.description)));
+        }
+        for (folder, tokens) in &groups {
+            if let &Some(ref folder) = folder {
+                body.push_str(&format!("<h2>{}</h2>\n", escape_html(folder)));
+            }
+            body.push_str("<ul>\n");
+            for token in tokens {
+                let data = &self.views[token];
+                match (&data.bookmark, self.view_infos.get(token)) {
+                    (&Some(ref bookmark), _) => {
+                        body.push_str(&format!("<li><a href=\"{}\" rel=\"nofollow\">{}</a></li>\n",
+                                               escape_html(&bookmark.url),
+                                               escape_html(&data.title)));
+                    }
+                    (&None, Some(&Ok(ref info))) => {
+                        body.push_str(&format!("<li>{} ({})</li>\n", escape_html(&data.title),
+                                               escape_html(&info.app_title)));
+                    }
+                    (&None, _) => {
+                        body.push_str(&format!("<li>{}</li>\n", escape_html(&data.title)));
+                    }
+                }
+            }
+            body.push_str("</ul>\n");
+        }
+
+        let title = self.collection_title().unwrap_or("Collection".to_string());
+        format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                 <title>{}</title>\n\
+                 <style>body {{ font-family: sans-serif; }} \
+                 .description {{ font-weight: bold; }}</style>\n\
+                 </head>\n<body>\n{}</body>\n</html>\n", escape_html(&title), body)
+    }
+
     fn get_saved_data<'a>(&'a self, token: &'a String) -> Option<&'a SavedUiViewData> {
         self.views.get(token)
     }
+
+    fn visibility(&self, token: &str) -> Visibility {
+        if let Some(owner) = self.private_owners.get(token) {
+            Visibility::Owner(owner)
+        } else if self.scheduled.contains_key(token) {
+            Visibility::Editors
+        } else {
+            Visibility::Everyone
+        }
+    }
+
+    fn is_visible(&self, token: &str, audience: &Audience) -> bool {
+        audience.can_see(self.visibility(token))
+    }
+
+    /// How many entries `audience` may see.
+    fn visible_count(&self, audience: &Audience) -> usize {
+        self.views.keys().filter(|token| self.is_visible(token, audience)).count()
+    }
+
+    /// The journal entries that `audience` may see: those about entries that it can see, and
+    /// those about no entry at all.
+    fn visible_journal(&self, audience: &Audience) -> Vec<&JournalEntry> {
+        self.journal.entries().iter()
+            .filter(|e| e.token.as_ref().map_or(true, |t| self.is_visible(t, audience)))
+            .collect()
+    }
+
+    /// Whether `audience` can see any entries that some others can't. Broadcasts about those
+    /// aren't replayed.
+    fn sees_hidden(&self, audience: &Audience) -> bool {
+        (audience.editor && !self.scheduled.is_empty()) ||
+            self.private_owners.values().any(|owner| audience.identity_id.as_ref() == Some(owner))
+    }
+
+    /// Tokens of the entries that `audience` may see, ordered according to the collection's
+    /// sort order, with titles compared by `collation`.
+    fn sorted_tokens(&self, collation: Collation, audience: &Audience) -> Vec<String> {
+        self.sorted_tokens_by(self.settings.sort_order, collation).into_iter()
+            .filter(|token| self.is_visible(token, audience))
+            .collect()
+    }
+
+    /// The part of `action` about entries whose visibility `keep` accepts, or None if nothing is
+    /// left. Actions that aren't about entries count as visible to everyone.
+    fn restrict<F>(&self, action: &Action, keep: &F) -> Option<Action>
+        where F: Fn(Visibility) -> bool
+    {
+        let visibility = |token: &String| self.visibility(token);
+        match action {
+            &Action::Insert { ref token, .. } | &Action::Remove { ref token, .. } |
+            &Action::ViewInfo { ref token, .. } | &Action::Update { ref token, .. } |
+            &Action::Comment { ref token, .. } | &Action::Rating { ref token, .. } |
+            &Action::Upvote { ref token, .. } => {
+                if keep(visibility(token)) { Some(action.clone()) } else { None }
+            }
+            &Action::Move { ref tokens, ref folder, ref versions } => {
+                let (tokens, versions): (Vec<String>, Vec<u64>) = tokens.iter().zip(versions)
+                    .filter(|&(token, _)| keep(visibility(token)))
+                    .map(|(token, &version)| (token.clone(), version))
+                    .unzip();
+                if tokens.is_empty() {
+                    None
+                } else {
+                    Some(Action::Move {
+                        tokens: tokens, folder: folder.clone(), versions: versions,
+                    })
+                }
+            }
+            &Action::Batch(ref actions) => {
+                let actions: Vec<Action> =
+                    actions.iter().filter_map(|action| self.restrict(action, keep)).collect();
+                if actions.is_empty() { None } else { Some(Action::Batch(actions)) }
+            }
+            _ => if keep(Visibility::Everyone) { Some(action.clone()) } else { None },
+        }
+    }
+
+    fn sorted_tokens_by(&self, sort_order: SortOrder, collation: Collation) -> Vec<String> {
+        let mut tokens: Vec<String> = match sort_order {
+            SortOrder::Manual => self.by_date.iter().map(|&(_, ref t)| t.clone()).collect(),
+            _ => self.by_date.iter().rev().map(|&(_, ref t)| t.clone()).collect(),
+        };
+
+        // Sorting is stable, so ties stay newest first.
+        let title_key = |token: &String| {
+            self.views.get(token).map(|data| collation.key(&data.title))
+        };
+        let upvotes = |token: &String| self.views.get(token).map_or(0, |data| data.upvoters.len());
+        match sort_order {
+            SortOrder::DateAdded | SortOrder::Manual => (),
+            SortOrder::Title => tokens.sort_by_key(|t| title_key(t)),
+            SortOrder::Votes => tokens.sort_by(|a, b| upvotes(b).cmp(&upvotes(a))),
+            SortOrder::App => tokens.sort_by_key(|t| {
+                // Entries without view info sort last.
+                let app_title = match self.view_infos.get(t) {
+                    Some(&Ok(ref info)) => Some(collation.key(&info.app_title)),
+                    _ => None,
+                };
+                (app_title.is_none(), app_title, title_key(t))
+            }),
+        }
+        tokens
+    }
+
+    /// The page of entries selected by `options` among those for which `keep` returns true.
+    fn list_tokens<F>(&self, options: &ListOptions, viewer: &Viewer, keep: F) -> Vec<String>
+        where F: Fn(&String) -> bool
+    {
+        let sort_order = options.sort_order.unwrap_or(self.settings.sort_order);
+        let audience = viewer.audience();
+        self.sorted_tokens_by(sort_order, viewer.collation).into_iter()
+            .filter(|token| self.is_visible(token, &audience))
+            .filter(|token| match (&options.folder, self.views.get(token)) {
+                (&None, _) => true,
+                (&Some(ref folder), Some(data)) => &data.folder == folder,
+                (&Some(_), None) => false,
+            })
+            .filter(|token| keep(token))
+            .skip(options.offset)
+            .take(options.limit.unwrap_or(usize::max_value()))
+            .collect()
+    }
+
+    /// What `audience` may see of the collection right now, for exports.
+    fn export_snapshot(&self, collation: Collation, audience: &Audience) -> ExportSnapshot {
+        let entries = self.sorted_tokens(collation, audience).into_iter().filter_map(|token| {
+            let app_title = match self.view_infos.get(&token) {
+                Some(&Ok(ref info)) => Some(info.app_title.clone()),
+                _ => None,
+            };
+            self.views.get(&token).cloned().map(|data| (token, data, app_title))
+        }).collect();
+        ExportSnapshot {
+            revision: self.journal.purged() + self.journal.entries().len(),
+            description: self.description.clone(),
+            entries: entries,
+        }
+    }
+}
+
+/// Filter, sort and pagination parameters of the endpoints that list entries: `folder` (empty
+/// for entries outside of any folder), `sort` (named as in the settings), `offset` and `limit`.
+struct ListOptions {
+    folder: Option<Option<String>>,
+    sort_order: Option<SortOrder>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl ListOptions {
+    fn parse(query: &router::Query) -> Result<ListOptions, Error> {
+        let sort_order = match query.get("sort") {
+            None => None,
+            Some(name) => Some(try!(parse_sort_order(name))),
+        };
+        Ok(ListOptions {
+            folder: query.get("folder")
+                .map(|f| if f.is_empty() { None } else { Some(f.to_string()) }),
+            sort_order: sort_order,
+            offset: try!(query.parse("offset")).unwrap_or(0),
+            limit: try!(query.parse("limit")),
+        })
+    }
+}
+
+/// Writes the set of folder names to `path`, one per line.
+fn write_folders(path: &::std::path::Path, folders: &BTreeSet<String>) -> ::capnp::Result<()> {
+    use std::io::Write;
+    let text: Vec<&str> = folders.iter().map(|f| &f[..]).collect();
+    let temp_path = path.with_extension("uploading");
+    try!(try!(::std::fs::File::create(&temp_path)).write_all(text.join("\n").as_bytes()));
+    try!(::std::fs::rename(temp_path, path));
+    Ok(())
+}
+
+fn sort_order_name(order: SortOrder) -> &'static str {
+    match order {
+        SortOrder::DateAdded => "date",
+        SortOrder::Manual => "manual",
+        SortOrder::Title => "title",
+        SortOrder::App => "app",
+        SortOrder::Votes => "votes",
+    }
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Viewer => "viewer",
+        Role::Editor => "editor",
+        Role::Manager => "manager",
+        Role::Commenter => "commenter",
+    }
+}
+
+fn parse_role(name: &str) -> Result<Role, Error> {
+    match name.trim() {
+        "viewer" => Ok(Role::Viewer),
+        "editor" => Ok(Role::Editor),
+        "manager" => Ok(Role::Manager),
+        "commenter" => Ok(Role::Commenter),
+        other => Err(Error::failed(format!("unknown role: {:?}", other))),
+    }
 }
 
+fn parse_lock(text: &str) -> Result<bool, Error> {
+    match text.trim() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        other => Err(Error::failed(format!("expected \"true\" or \"false\", got {:?}", other))),
+    }
+}
+
+/// Parses a JSON object mapping feature names to booleans, e.g. `{"comments":false}`.
+fn parse_feature_changes(text: &str) -> Result<Vec<(Feature, bool)>, Error> {
+    let value = try!(json::Json::from_str(text).map_err(|e| Error::failed(format!("{}", e))));
+    let object = match value.as_object() {
+        Some(o) => o,
+        None => return Err(Error::failed("expected a JSON object".into())),
+    };
+    let mut changes = Vec::new();
+    for (name, enabled) in object {
+        let feature = match Feature::from_name(name) {
+            Some(f) => f,
+            None => return Err(Error::failed(format!("unknown feature: {:?}", name))),
+        };
+        match enabled.as_boolean() {
+            Some(b) => changes.push((feature, b)),
+            None => return Err(Error::failed(format!("expected a boolean for {:?}", name))),
+        }
+    }
+    Ok(changes)
+}
+
+/// Parses the body of `PUT api/settings/retention`, e.g.
+/// `{"trashDays": 30, "journalEntries": null, "replayBroadcasts": 500}`, where null or a
+/// missing field means no limit (or, for the replay buffer, the default).
+fn parse_retention(text: &str) -> Result<Retention, Error> {
+    let value = try!(json::Json::from_str(text).map_err(|e| Error::failed(format!("{}", e))));
+    let object = match value.as_object() {
+        Some(o) => o,
+        None => return Err(Error::failed("expected a JSON object".into())),
+    };
+    let mut retention = Retention::default();
+    for (name, limit) in object {
+        let limit = match *limit {
+            json::Json::Null => None,
+            _ => match limit.as_u64() {
+                Some(n) if n > 0 && n <= ::std::u32::MAX as u64 => Some(n as u32),
+                _ => return Err(Error::failed(
+                    format!("expected a positive number or null for {:?}", name))),
+            },
+        };
+        match &name[..] {
+            "trashDays" => retention.trash_days = limit,
+            "journalEntries" => retention.journal_entries = limit,
+            "replayBroadcasts" => retention.replay_broadcasts = limit,
+            _ => return Err(Error::failed(format!("unknown retention setting: {:?}", name))),
+        }
+    }
+    if retention.replay_broadcasts.map_or(false, |n| n > MAX_REPLAY_CAPACITY) {
+        return Err(Error::failed(
+            format!("replayBroadcasts can be at most {}", MAX_REPLAY_CAPACITY)))
+    }
+    Ok(retention)
+}
+
+fn retention_json(retention: &Retention) -> String {
+    fn limit_json(limit: Option<u32>) -> String {
+        limit.map_or("null".to_string(), |n| n.to_string())
+    }
+    format!("{{\"trashDays\":{},\"journalEntries\":{},\"replayBroadcasts\":{}}}",
+            limit_json(retention.trash_days), limit_json(retention.journal_entries),
+            limit_json(retention.replay_broadcasts))
+}
+
+fn features_json(features: &::config::FeatureFlags) -> String {
+    let fields: Vec<String> = Feature::all().into_iter()
+        .map(|f| format!("\"{}\":{}", f.name(), features.is_enabled(f)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn parse_sort_order(name: &str) -> Result<SortOrder, Error> {
+    match name.trim() {
+        "date" => Ok(SortOrder::DateAdded),
+        "manual" => Ok(SortOrder::Manual),
+        "title" => Ok(SortOrder::Title),
+        "app" => Ok(SortOrder::App),
+        "votes" => Ok(SortOrder::Votes),
+        other => Err(Error::failed(format!("unknown sort order: {:?}", other))),
+    }
+}
+
+/// The description of a new collection, and what `DELETE description` restores. Empty, so that
+/// the page offers to add one.
+const DEFAULT_DESCRIPTION: &'static str = "";
+
+/// Journal operations that are also delivered to the webhook.
+const WEBHOOK_OPS: &'static [&'static str] = &["add", "publish", "remove", "description"];
+
+/// How long a cached profile picture is served before we fetch it again.
+const AVATAR_MAX_AGE_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+/// How often we re-fetch the profiles of the people who appear in the collection.
+const PROFILE_REFRESH_INTERVAL_MILLIS: u64 = 6 * 60 * 60 * 1000;
+
+/// How often we look for entries that have expired.
+const EXPIRY_CHECK_INTERVAL_MILLIS: u64 = 60 * 1000;
+
+/// How often we look for scheduled entries that are due to be published.
+const PUBLICATION_CHECK_INTERVAL_MILLIS: u64 = 60 * 1000;
+
+/// How often we enforce the retention settings.
+const PURGE_INTERVAL_MILLIS: u64 = 60 * 60 * 1000;
+
+/// How long writes rejected during maintenance are told to wait before retrying.
+const MAINTENANCE_RETRY_SECS: u64 = 5;
+
+/// How often we check whether any digests are due.
+const DIGEST_CHECK_INTERVAL_MILLIS: u64 = 60 * 60 * 1000;
+
+/// Longest that a lazily written metadata change stays only in memory.
+const METADATA_FLUSH_DELAY_MILLIS: u64 = 2000;
+
+/// Sends a plain-text email from the grain's own address.
+fn send_email(context: &hack_session_context::Client,
+              to: String,
+              subject: String,
+              body: String) -> Promise<(), Error> {
+    let context = context.clone();
+    Promise::from_future(context.get_public_id_request().send().promise.and_then(move |response| {
+        let response = pry!(response.get());
+        let from = format!("{}@{}", pry!(response.get_public_id()), pry!(response.get_hostname()));
+        let mut req = context.send_request();
+        {
+            let mut email = req.get().init_email();
+            email.borrow().init_from().set_address(&from);
+            email.borrow().init_to(1).get(0).set_address(&to);
+            email.borrow().set_subject(&subject);
+            email.set_text(&body);
+        }
+        Promise::from_future(req.send().promise.map(|_| ()))
+    }))
+}
+
+/// How many writes an identity can make in quick succession.
+const WRITE_BURST: u32 = 30;
+
+/// How quickly an identity's write allowance refills.
+const WRITE_REFILL_PER_SECOND: f64 = 1.0;
+
 #[derive(Clone)]
 pub struct SavedUiViewSet {
     inner: Rc<RefCell<SavedUiViewSetInner>>,
 }
 
 impl SavedUiViewSet {
-    pub fn new<P1, P2>(tmp_dir: P1,
-                       sturdyref_dir: P2,
-                       sandstorm_api: &sandstorm_api::Client<::capnp::any_pointer::Owned>,
+    pub fn new(config: Rc<Config>,
+               sandstorm_api: &sandstorm_api::Client<::capnp::any_pointer::Owned>,
                        identity_map: ::identity_map::IdentityMap,
+                       clock: Rc<Clock>,
+                       timer: Rc<Timer>,
                        handle: &::tokio_core::reactor::Handle,
     )
                   -> ::capnp::Result<SavedUiViewSet>
-        where P1: AsRef<::std::path::Path>,
-              P2: AsRef<::std::path::Path>
     {
-        let description = match ::std::fs::File::open("/var/description") {
+        let tmp_dir = config.var_path("tmp");
+        let sturdyref_dir = config.var_path("sturdyrefs");
+        let description = match ::std::fs::File::open(config.var_path("description")) {
             Ok(mut f) => {
                 use std::io::Read;
                 let mut result = String::new();
@@ -249,8 +1830,8 @@ impl SavedUiViewSet {
             }
             Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
                 use std::io::Write;
-                let mut f = try!(::std::fs::File::create("/var/description"));
-                let result = "";
+                let mut f = try!(::std::fs::File::create(config.var_path("description")));
+                let result = DEFAULT_DESCRIPTION;
                 try!(f.write_all(result.as_bytes()));
                 result.into()
             }
@@ -259,26 +1840,100 @@ impl SavedUiViewSet {
             }
         };
 
-        let (tx, poller) = Poller::new(Reaper);
+        let journal = try!(Journal::open(config.var_path("journal")));
+        let last_seen = try!(LastSeen::new(config.var_path("last-seen")));
+        let webhooks = try!(Webhooks::new(config.var_path("webhook"), sandstorm_api, timer.clone()));
+        let digests = try!(Digests::open(config.var_path("digests")));
+        let avatars = try!(AvatarCache::new(config.var_path("avatars")));
+        let thumbnails = try!(Thumbnails::new(config.var_path("thumbnails")));
+
+        // Streaming uploads that were cut short by a restart will never finish.
+        let _ = ::std::fs::remove_dir_all(config.var_path("uploads"));
+        try!(::std::fs::create_dir_all(config.var_path("uploads")));
+
+        let settings = try!(Settings::load(config.var_path("settings")));
+        let replay = try!(ReplayBuffer::open(config.var_path("replay"),
+                                             replay_capacity(&settings.retention)));
+        let acks = try!(Acks::open(config.var_path("acks")));
+        let search_index = try!(SearchIndex::open(config.var_path("search-index")));
+
+        let folders = match ::std::fs::File::open(config.var_path("folders")) {
+            Ok(mut f) => {
+                use std::io::Read;
+                let mut text = String::new();
+                try!(f.read_to_string(&mut text));
+                text.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect()
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let failures = Rc::new(Failures::default());
+        let (tx, poller) = Poller::new(Reaper { failures: failures.clone() });
         handle.spawn(poller.map_err(|_|()));
 
         let result = SavedUiViewSet {
             inner: Rc::new(RefCell::new(SavedUiViewSetInner {
-                tmp_dir: tmp_dir.as_ref().to_path_buf(),
-                sturdyref_dir: sturdyref_dir.as_ref().to_path_buf(),
+                config: config.clone(),
+                tmp_dir: tmp_dir.clone(),
+                sturdyref_dir: sturdyref_dir.clone(),
                 views: HashMap::new(),
+                by_date: BTreeSet::new(),
                 view_infos: HashMap::new(),
+                view_infos_in_flight: HashSet::new(),
+                unavailable: HashMap::new(),
                 next_id: 0,
+                private_owners: HashMap::new(),
+                scheduled: HashMap::new(),
                 subscribers: HashMap::new(),
+                subscriber_audiences: HashMap::new(),
+                activity_subscribers: HashMap::new(),
                 tasks: tx,
+                failures: failures,
+                last_broadcast: Rc::new(Cell::new(None)),
+                request_stats: Rc::new(RequestStats::default()),
+                storage_probe: RefCell::new(None),
                 description: description,
                 sandstorm_api: sandstorm_api.clone(),
                 identity_map: identity_map,
+                clock: clock,
+                timer: timer,
+                write_limiter: RateLimiter::new(WRITE_BURST, WRITE_REFILL_PER_SECOND),
+                journal: journal,
+                last_seen: last_seen,
+                webhooks: webhooks,
+                digests: digests,
+                avatars: avatars,
+                thumbnails: thumbnails,
+                mail_context: None,
+                profiles: HashMap::new(),
+                folders: folders,
+                settings: settings,
+                replay: replay,
+                acks: acks,
+                search_index: search_index,
+                dirty_metadata: BTreeSet::new(),
+                metadata_flush_scheduled: false,
+                publish_scheduled: false,
+                maintenance: false,
+                batch: None,
             })),
         };
 
         // create sturdyref directory if it does not yet exist
         try!(::std::fs::create_dir_all(&sturdyref_dir));
+        let migrated = try!(::shards::migrate(&sturdyref_dir));
+        if migrated > 0 {
+            println!("moved {} entries into sharded subdirectories", migrated);
+        }
+        {
+            use std::io::Write;
+            let path = config.var_path(SCHEMA_VERSION_FILE);
+            let temp_path = path.with_extension("uploading");
+            try!(try!(::std::fs::File::create(&temp_path))
+                 .write_all(format!("{}\n", SCHEMA_VERSION).as_bytes()));
+            try!(::std::fs::rename(temp_path, path));
+        }
 
         // clear and create tmp directory
         match ::std::fs::remove_dir_all(&tmp_dir) {
@@ -288,43 +1943,67 @@ impl SavedUiViewSet {
         }
         try!(::std::fs::create_dir_all(&tmp_dir));
 
-        for token_file in try!(::std::fs::read_dir(&sturdyref_dir)) {
-            let dir_entry = try!(token_file);
-            let token: String = match dir_entry.file_name().to_str() {
-                None => {
-                    println!("malformed token: {:?}", dir_entry.file_name());
-                    continue
-                }
-                Some(s) => s.into(),
-            };
-
+        let mut written_at = HashMap::new();
+        for (token, path) in try!(::shards::list(&sturdyref_dir)) {
             if token.ends_with(".uploading") {
                 // At one point, these temporary files got uploading directly into this directory.
-                try!(::std::fs::remove_file(dir_entry.path()));
+                try!(::std::fs::remove_file(path));
             } else {
-                let mut reader = try!(::std::fs::File::open(dir_entry.path()));
+                let modified = try!(::std::fs::metadata(&path)).modified().ok()
+                    .and_then(|time| time.duration_since(::std::time::UNIX_EPOCH).ok());
+                if let Some(d) = modified {
+                    written_at.insert(token.clone(),
+                                      d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64);
+                }
+                let mut reader = try!(::std::fs::File::open(path));
                 let message = try!(::capnp::serialize::read_message(&mut reader,
                                                                     Default::default()));
-                let metadata: ui_view_metadata::Reader = try!(message.get_root());
-
-                let added_by = if metadata.has_added_by() {
-                    Some(try!(metadata.get_added_by()).into())
-                } else {
-                    None
-                };
-
-                let entry = SavedUiViewData {
-                    title: try!(metadata.get_title()).into(),
-                    date_added: metadata.get_date_added(),
-                    added_by: added_by,
-                };
+                let entry = try!(SavedUiViewData::from_metadata(try!(message.get_root())));
 
+                if let (true, &Some(ref owner)) = (entry.private, &entry.added_by) {
+                    result.inner.borrow_mut().private_owners.insert(token.clone(), owner.clone());
+                }
+                if let Some(time) = entry.publish_at {
+                    if time > result.inner.borrow().clock.now_millis() {
+                        result.inner.borrow_mut().scheduled.insert(token.clone(), time);
+                    }
+                }
+                result.inner.borrow_mut().by_date.insert((entry.date_added, token.clone()));
                 result.inner.borrow_mut().views.insert(token.clone(), entry);
 
                 try!(result.retrieve_view_info(token));
             }
         }
 
+        let recovered = result.recover_moves(&written_at);
+        if recovered > 0 {
+            println!("recovered {} moves from the journal", recovered);
+            result.flush_metadata();
+        }
+
+        {
+            // The index may have missed changes, e.g. if the grain shut down mid-write.
+            let inner = &mut *result.inner.borrow_mut();
+            for token in inner.search_index.tokens() {
+                if !inner.views.contains_key(&token) {
+                    let remove = inner.search_index.remove(&token);
+                    inner.failures.storage("update search index", remove);
+                }
+            }
+            for (token, data) in &inner.views {
+                let update = inner.search_index.update(token, &data.search_text());
+                inner.failures.storage("update search index", update);
+            }
+        }
+
+        result.schedule_digests();
+        result.schedule_expirations();
+        result.schedule_publications();
+        result.schedule_purges();
+        if result.is_enabled(Feature::WebPublishing) {
+            result.publish_later();
+        }
+        result.schedule_profile_refresh();
         Ok(result)
     }
 
@@ -333,28 +2012,68 @@ impl SavedUiViewSet {
         // SandstormApi.restore, then call getViewInfo,
         // then call get_url() on the grain static asset.
 
+        if self.inner.borrow().views.get(&token).map_or(false, |data| data.bookmark.is_some()) {
+            // Bookmarks are not grains, so there is no view info to retrieve.
+            return Ok(())
+        }
+
         let mut self1 = self.clone();
         let binary_token = match base64::FromBase64::from_base64(&token[..]) {
             Ok(b) => b,
             Err(e) => return Err(Error::failed(format!("{}", e))),
         };
 
+        if !self.inner.borrow_mut().view_infos_in_flight.insert(token.clone()) {
+            return Ok(())
+        }
+
         let mut req = self.inner.borrow().sandstorm_api.restore_request();
         req.get().set_token(&binary_token);
-        let task = req.send().promise.and_then(move |response| {
+        let timer = self.inner.borrow().timer.clone();
+        let timeout = ::std::time::Duration::from_secs(GRAIN_RPC_TIMEOUT_SECONDS);
+        let mut self2 = self.clone();
+        let token2 = token.clone();
+        let task = req.send().promise.then(move |restored| {
+            match restored {
+                Ok(_) => self2.mark_available(&token2),
+                Err(ref e) => self2.mark_unavailable(&token2, e),
+            }
+            restored
+        }).and_then(move |response| {
             let view: ui_view::Client =
                 pry!(pry!(response.get()).get_cap().get_as_capability());
-            Promise::from_future(view.get_view_info_request().send().promise.and_then(move |response| {
+            let view_info = Promise::from_future(view.get_view_info_request().send().promise);
+            let view_info = with_timeout(&*timer, timeout, "the grain's view info", view_info);
+            Promise::from_future(view_info.and_then(move |response| {
                 let view_info = pry!(response.get());
                 let app_title = pry!(pry!(view_info.get_app_title()).get_default_text()).to_string();
-                Promise::from_future(url_of_static_asset(pry!(view_info.get_grain_icon())).map(move |url| {
+                let app_id = if view_info.has_app_id() {
+                    pry!(view_info.get_app_id()).to_string()
+                } else {
+                    String::new()
+                };
+                let is_collection = app_id == COLLECTIONS_APP_ID;
+                let url = with_timeout(&*timer, timeout, "the grain icon's URL",
+                                       url_of_static_asset(pry!(view_info.get_grain_icon())));
+                Promise::from_future(url.map(move |url| {
                     ViewInfoData {
+                        app_id: app_id,
                         app_title: app_title,
                         grain_icon_url: url,
+                        is_collection: is_collection,
                     }
                 }))
             }))
         }).then(move |result| {
+            self1.inner.borrow_mut().view_infos_in_flight.remove(&token);
+            if let Ok(ref info) = result {
+                let written = self1.set_is_collection(&token, info.is_collection);
+                let failures = self1.inner.borrow().failures.clone();
+                failures.storage(&format!("update nested-collection flag of {}", token), written);
+                if !info.app_id.is_empty() {
+                    self1.note_app_id(&token, &info.app_id);
+                }
+            }
             self1.inner.borrow_mut().view_infos.insert(token.clone(), result.clone());
             self1.send_action_to_subscribers(Action::ViewInfo {
                 token: token,
@@ -368,6 +2087,28 @@ impl SavedUiViewSet {
         Ok(())
     }
 
+    /// Tells everyone that the grain of entry `token` can't be restored, unless `error` is
+    /// likely to go away by itself.
+    fn mark_unavailable(&mut self, token: &str, error: &Error) {
+        if is_transient_rpc_error(error) || !self.inner.borrow().views.contains_key(token) {
+            return
+        }
+        let reason = format!("{}", error);
+        let previous = self.inner.borrow_mut().unavailable.insert(token.into(), reason.clone());
+        if previous.as_ref() != Some(&reason) {
+            self.send_action_to_subscribers(
+                Action::Update { token: token.into(), unavailable: Some(reason), data: None });
+        }
+    }
+
+    /// Undoes `mark_unavailable()` once the grain of entry `token` has been restored.
+    fn mark_available(&mut self, token: &str) {
+        if self.inner.borrow_mut().unavailable.remove(token).is_some() {
+            self.send_action_to_subscribers(
+                Action::Update { token: token.into(), unavailable: None, data: None });
+        }
+    }
+
     fn get_user_profile(&mut self,
                         identity_id: &str) -> Promise<ProfileData, Error> {
         Promise::from_future(self.inner.borrow_mut().identity_map.get_by_text(identity_id).and_then(move |identity| {
@@ -381,350 +2122,4149 @@ impl SavedUiViewSet {
         }))
     }
 
-    fn update_description(&mut self, description: &[u8]) -> ::capnp::Result<()> {
-        use std::io::Write;
-
-        let desc_string: String = match ::std::str::from_utf8(description) {
-            Err(e) => return Err(::capnp::Error::failed(format!("{}", e))),
-            Ok(d) => d.into(),
-        };
-
-        let temp_path = format!("/var/description.uploading");
-        ::std::fs::File::create(&temp_path)?.write_all(description)?;
-        ::std::fs::rename(temp_path, "/var/description")?;
+    /// The profile picture of `identity_id`: from the cache if it's fresh enough, otherwise
+    /// fetched through `context`. If fetching fails, a stale copy is better than nothing.
+    fn avatar(&mut self,
+              identity_id: &str,
+              context: hack_session_context::Client) -> Promise<CachedAvatar, Error> {
+        let now = self.inner.borrow().clock.now_millis();
+        let cached = pry!(self.inner.borrow().avatars.get(identity_id));
+        if let Some(ref avatar) = cached {
+            if avatar.fetched + AVATAR_MAX_AGE_MILLIS > now {
+                return Promise::ok(avatar.clone())
+            }
+        }
 
-        self.inner.borrow_mut().description = desc_string.clone();
-        self.send_action_to_subscribers(Action::Description(desc_string));
-        Ok(())
+        Promise::from_future(self.fetch_avatar(identity_id, context).or_else(move |e| match cached {
+            Some(avatar) => Ok(avatar),
+            None => Err(e),
+        }))
     }
 
-    fn insert(&mut self,
-              token: String,
-              title: String,
-              added_by: Option<String>) -> ::capnp::Result<()> {
-        let dur = try!(::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
-            .map_err(|e| Error::failed(format!("{}", e))));
-        let date_added = dur.as_secs() * 1000 + (dur.subsec_nanos() / 1000000) as u64;
-
-        let mut token_path = ::std::path::PathBuf::new();
-        token_path.push(self.inner.borrow().sturdyref_dir.clone());
-        token_path.push(token.clone());
+    /// Fetches the profile picture of `identity_id` through `context` and caches it.
+    fn fetch_avatar(&mut self,
+                    identity_id: &str,
+                    context: hack_session_context::Client) -> Promise<CachedAvatar, Error> {
+        let now = self.inner.borrow().clock.now_millis();
+        let self1 = self.clone();
+        let identity_id = identity_id.to_string();
+        let fetched = self.get_user_profile(&identity_id).and_then(move |profile| {
+            let mut req = context.http_get_request();
+            req.get().set_url(&profile.picture_url);
+            req.send().promise
+        }).and_then(move |response| {
+            let response = try!(response.get());
+            let mime_type = try!(response.get_mime_type());
+            if !mime_type.starts_with("image/") {
+                // Never serve anything that a browser might render as a document.
+                return Err(Error::failed(format!("avatar has unexpected type {}", mime_type)))
+            }
+            let avatar = CachedAvatar {
+                mime_type: mime_type.into(),
+                content: try!(response.get_content()).to_vec(),
+                fetched: now,
+            };
+            let inner = self1.inner.borrow();
+            inner.failures.storage("cache avatar", inner.avatars.put(&identity_id, &avatar));
+            Ok(avatar)
+        });
+        Promise::from_future(fetched)
+    }
 
-        let mut temp_path = ::std::path::PathBuf::new();
-        temp_path.push(self.inner.borrow().tmp_dir.clone());
-        temp_path.push(format!("{}.uploading", token));
+    /// Re-fetches the profiles of everyone who added or commented on an entry, through the
+    /// identity capabilities that we saved when they opened the collection. Clients hear about
+    /// changed names and pictures, and changed pictures are fetched into the avatar cache.
+    fn refresh_profiles(&mut self) {
+        let mut people = BTreeSet::new();
+        for data in self.inner.borrow().views.values() {
+            people.extend(data.added_by.iter().cloned());
+            people.extend(data.comments.iter().filter_map(|c| c.author.clone()));
+        }
 
-        let mut writer = try!(::std::fs::File::create(&temp_path));
+        for identity_id in people {
+            let mut self1 = self.clone();
+            let id = identity_id.clone();
+            let task = self.get_user_profile(&identity_id).map(move |profile| {
+                let previous =
+                    self1.inner.borrow_mut().profiles.insert(id.clone(), profile.clone());
+                let picture_changed = match previous {
+                    Some(ref previous) if *previous == profile => return,
+                    Some(ref previous) => previous.picture_url != profile.picture_url,
+                    None => false,
+                };
+                self1.send_action_to_subscribers(Action::User { id: id.clone(), data: profile });
 
-        let mut message = ::capnp::message::Builder::new_default();
-        {
-            let mut metadata: ui_view_metadata::Builder = message.init_root();
-            metadata.set_title(&title);
-            metadata.set_date_added(date_added);
-            match added_by {
-                Some(ref s) => metadata.set_added_by(s),
-                None => (),
-            }
+                let context = self1.inner.borrow().mail_context.clone();
+                if let (true, Some(context)) = (picture_changed, context) {
+                    let fetch = self1.fetch_avatar(&id, context).map(|_| ());
+                    self1.inner.borrow_mut().tasks.add(labeled(format!("avatar of {}", id), fetch));
+                }
+            });
+            self.inner.borrow_mut().tasks.add(labeled(format!("profile of {}", identity_id), task));
         }
+    }
 
-        try!(::capnp::serialize::write_message(&mut writer, &message));
-        try!(::std::fs::rename(temp_path, token_path));
-        try!(writer.sync_all());
-
-        if !self.inner.borrow().subscribers.is_empty() {
-            if let Some(ref id) = added_by {
-                let mut self1 = self.clone();
-                let identity_id: String = id.to_string();
-                let task = self.get_user_profile(&identity_id).map(move |profile_data| {
-                    self1.send_action_to_subscribers(
-                        Action::User { id: identity_id, data: profile_data });
-                });
-                self.inner.borrow_mut().tasks.add(task);
-            }
-        }
+    /// Refreshes profiles periodically, for as long as the grain is running.
+    fn schedule_profile_refresh(&self) {
+        use futures::future::{Loop, loop_fn};
+        let self1 = self.clone();
+        let timer = self.inner.borrow().timer.clone();
+        let task = loop_fn((), move |()| {
+            let mut self2 = self1.clone();
+            let delay = ::std::time::Duration::from_millis(PROFILE_REFRESH_INTERVAL_MILLIS);
+            timer.after(delay).map(move |()| {
+                self2.refresh_profiles();
+                Loop::Continue(())
+            })
+        });
+        self.inner.borrow_mut().tasks.add(Promise::from_future(task));
+    }
 
-        let entry = SavedUiViewData {
-            title: title,
-            date_added: date_added,
-            added_by: added_by,
+    /// Notes that `identity_id` has just opened the collection, returning the time of their
+    /// previous visit.
+    fn visit(&mut self, identity_id: &str) -> Option<u64> {
+        let inner = &mut *self.inner.borrow_mut();
+        let now = inner.clock.now_millis();
+        inner.failures.storage("record visit", inner.last_seen.visit(identity_id, now))
+            .unwrap_or(None)
+    }
+
+    /// Charges one write against `identity_id`'s rate limit. The web-session schema has no
+    /// "429 Too Many Requests" status, so callers report failures as 403.
+    fn check_write_rate(&self,
+                        identity_id: &Option<String>,
+                        language: Language) -> Result<(), Error> {
+        let key = match identity_id {
+            &Some(ref id) => &id[..],
+            &None => "",  // all anonymous sessions share one bucket
         };
 
-        self.send_action_to_subscribers(Action::Insert {
-            token: token.clone(),
-            data: entry.clone(),
-        });
-        self.inner.borrow_mut().views.insert(token, entry);
+        let inner = &mut *self.inner.borrow_mut();
+        let now = inner.clock.now_millis();
+        inner.write_limiter.prune(now);
+        match inner.write_limiter.try_acquire(key, now) {
+            Ok(()) => Ok(()),
+            Err(wait_millis) => Err(Error::failed(
+                Message::TooManyChanges((wait_millis + 999) / 1000).localize(language))),
+        }
+    }
+
+    /// Fails if a manager has locked the collection against changes, or if storage maintenance
+    /// is under way.
+    fn require_unlocked(&self, language: Language) -> Result<(), Error> {
+        let inner = self.inner.borrow();
+        if inner.settings.locked {
+            Err(Error::failed(Message::Locked.localize(language)))
+        } else if inner.maintenance {
+            Err(Error::failed(Message::Maintenance(MAINTENANCE_RETRY_SECS).localize(language)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn in_maintenance(&self) -> bool {
+        self.inner.borrow().maintenance
+    }
 
+    /// Fails if any of the entries in `versions` no longer has the version given for it, i.e.
+    /// someone has edited it since the client last saw it. Unknown tokens are left for the edit
+    /// itself to report.
+    fn require_versions(&self, versions: &HashMap<String, u64>) -> Result<(), Error> {
+        let inner = self.inner.borrow();
+        for (token, &expected) in versions {
+            match inner.views.get(token) {
+                Some(data) if data.version != expected => {
+                    return Err(Error::failed(format!(
+                        "entry {} has been changed by someone else (version {}, expected {})",
+                        token, data.version, expected)))
+                }
+                _ => (),
+            }
+        }
         Ok(())
     }
 
-    fn send_action_to_subscribers(&mut self, action: Action) {
-        let json_string = action.to_json();
-        let &mut SavedUiViewSetInner { ref subscribers, ref mut tasks, ..} =
-            &mut *self.inner.borrow_mut();
-        for (_, sub) in &*subscribers {
-            let mut req = sub.send_bytes_request();
-            web_socket::encode_text_message(req.get(), &json_string);
-            tasks.add(req.send().promise.map(|_| ()));
+    /// Like `require_versions()`, for one entry and an optional version.
+    fn require_version(&self, token: &str, expected: Option<u64>) -> Result<(), Error> {
+        let mut versions = HashMap::new();
+        if let Some(version) = expected {
+            versions.insert(token.to_string(), version);
         }
+        self.require_versions(&versions)
     }
 
-    fn remove(&mut self, token: &str) -> Result<(), Error> {
-        let mut path = self.inner.borrow().sturdyref_dir.clone();
-        path.push(token);
-        if let Err(e) = ::std::fs::remove_file(path) {
-            if e.kind() != ::std::io::ErrorKind::NotFound {
-                return Err(e.into())
-            }
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.inner.borrow().settings.features.is_enabled(feature)
+    }
+
+    /// Fails if a manager has turned `feature` off in this collection.
+    fn require_feature(&self, feature: Feature, language: Language) -> Result<(), Error> {
+        if self.is_enabled(feature) {
+            Ok(())
+        } else {
+            Err(Error::failed(Message::FeatureDisabled(feature.name()).localize(language)))
         }
+    }
 
-        self.send_action_to_subscribers(Action::Remove { token: token.into() });
-        self.inner.borrow_mut().views.remove(token);
-        Ok(())
+    /// Appends an entry to the journal, and notifies the webhook if the entry is of interest to
+    /// it. The change has already been made at this point, so a failure here is logged rather
+    /// than reported to the client.
+    fn record(&mut self,
+              actor: Option<String>,
+              op: &str,
+              token: Option<String>,
+              title: Option<String>) {
+        self.record_at(None, actor, None, op, token, title)
     }
 
-    fn new_subscribed_websocket(&mut self,
-                                client_stream: web_socket_stream::Client,
-                                can_write: bool,
-                                user_id: Option<String>,
-                                handle: &::tokio_core::reactor::Handle)
-                                 -> web_socket_stream::Client
-    {
-        fn send_action(task: Promise<(), Error>,
-                       client_stream: &web_socket_stream::Client,
-                       action: Action) -> Promise<(), Error> {
-            let json_string = action.to_json();
-            let mut req = client_stream.send_bytes_request();
-            web_socket::encode_text_message(req.get(), &json_string);
-            let promise = req.send().promise.map(|_| ());
-            Promise::from_future(task.and_then(|_| promise))
+    /// Like `record()`, but also with the actor's display name, and at `time` if given rather
+    /// than now.
+    fn record_at(&mut self,
+                 time: Option<u64>,
+                 actor: Option<String>,
+                 actor_name: Option<String>,
+                 op: &str,
+                 token: Option<String>,
+                 title: Option<String>) {
+        let activity = {
+            let inner = &mut *self.inner.borrow_mut();
+            let entry = JournalEntry {
+                time: time.unwrap_or_else(|| inner.clock.now_millis()),
+                actor: actor,
+                actor_name: actor_name,
+                op: op.into(),
+                token: token,
+                title: title,
+            };
+            // Nobody else is told about hidden entries.
+            let hidden = entry.token.as_ref()
+                .map_or(false, |t| inner.visibility(t) != Visibility::Everyone);
+            if WEBHOOK_OPS.contains(&op) && inner.settings.features.is_enabled(Feature::Webhooks) &&
+                !hidden
+            {
+                let actor_name = entry.actor_name.clone().or_else(|| {
+                    entry.actor.as_ref().and_then(|id| inner.profiles.get(id))
+                        .map(|profile| profile.display_name.clone())
+                });
+                let task = inner.webhooks.notify(entry.to_webhook_json(&actor_name));
+                inner.tasks.add(labeled(format!("webhook for {}", op), task));
+            }
+            let activity = if ACTIVITY_CHANNEL_OPS.contains(&op) && !hidden {
+                Some(Action::Activity(entry.to_json()))
+            } else {
+                None
+            };
+            inner.failures.storage("write journal entry", inner.journal.append(entry));
+            activity
+        };
+        if let Some(action) = activity {
+            self.send_action_to_activity_subscribers(action);
         }
+    }
 
-        let id = self.inner.borrow().next_id;
-        self.inner.borrow_mut().next_id = id + 1;
+    fn subscribe_digest(&mut self,
+                        identity_id: &str,
+                        address: String,
+                        frequency: ::digest::Frequency) -> ::capnp::Result<()> {
+        let inner = &mut *self.inner.borrow_mut();
+        let now = inner.clock.now_millis();
+        inner.digests.subscribe(identity_id, address, frequency, now)
+    }
 
-        self.inner.borrow_mut().subscribers.insert(id, client_stream.clone());
+    fn unsubscribe_digest(&mut self, identity_id: &str) -> ::capnp::Result<()> {
+        self.inner.borrow_mut().digests.unsubscribe(identity_id)
+    }
+
+    /// Checks for due digests periodically, for as long as the grain is running.
+    fn schedule_digests(&self) {
+        use futures::future::{Loop, loop_fn};
+        let self1 = self.clone();
+        let timer = self.inner.borrow().timer.clone();
+        let task = loop_fn((), move |()| {
+            let mut self2 = self1.clone();
+            let delay = ::std::time::Duration::from_millis(DIGEST_CHECK_INTERVAL_MILLIS);
+            timer.after(delay).map(move |()| {
+                self2.send_due_digests();
+                Loop::Continue(())
+            })
+        });
+        self.inner.borrow_mut().tasks.add(Promise::from_future(task));
+    }
 
-        let mut task = Promise::ok(());
+    /// Moves expired entries to the trash periodically, for as long as the grain is running.
+    fn schedule_expirations(&self) {
+        use futures::future::{Loop, loop_fn};
+        let self1 = self.clone();
+        let timer = self.inner.borrow().timer.clone();
+        let task = loop_fn((), move |()| {
+            let mut self2 = self1.clone();
+            let delay = ::std::time::Duration::from_millis(EXPIRY_CHECK_INTERVAL_MILLIS);
+            timer.after(delay).map(move |()| {
+                self2.expire_due_entries();
+                Loop::Continue(())
+            })
+        });
+        self.inner.borrow_mut().tasks.add(Promise::from_future(task));
+    }
 
-        task = send_action(task, &client_stream, Action::CanWrite(can_write));
-        task = send_action(task, &client_stream, Action::UserId(user_id));
-        task = send_action(task, &client_stream,
-                           Action::Description(self.inner.borrow().description.clone()));
+    /// Publishes scheduled entries once their time comes, for as long as the grain is running.
+    fn schedule_publications(&self) {
+        use futures::future::{Loop, loop_fn};
+        let self1 = self.clone();
+        let timer = self.inner.borrow().timer.clone();
+        let task = loop_fn((), move |()| {
+            let mut self2 = self1.clone();
+            let delay = ::std::time::Duration::from_millis(PUBLICATION_CHECK_INTERVAL_MILLIS);
+            timer.after(delay).map(move |()| {
+                self2.publish_due_entries();
+                Loop::Continue(())
+            })
+        });
+        self.inner.borrow_mut().tasks.add(Promise::from_future(task));
+    }
 
-        let mut added_by_identities: HashSet<String> = HashSet::new();
+    /// Shows everyone the scheduled entries whose publication time has passed. Nothing is
+    /// written, since `publish_at` is already in the past the next time the grain starts.
+    fn publish_due_entries(&mut self) {
+        let due: Vec<String> = {
+            let inner = self.inner.borrow();
+            let now = inner.clock.now_millis();
+            inner.scheduled.iter()
+                .filter(|&(_, &time)| time <= now)
+                .map(|(token, _)| token.clone())
+                .collect()
+        };
 
-        for (t, v) in &self.inner.borrow().views {
-            if let &Some(ref id) = &v.added_by {
-                added_by_identities.insert(id.clone());
+        for token in due {
+            self.inner.borrow_mut().scheduled.remove(&token);
+            let data = match self.inner.borrow().views.get(&token) {
+                Some(data) => data.clone(),
+                None => continue,
+            };
+            self.record(None, "publish", Some(token.clone()), Some(data.title.clone()));
+            self.send_action_to_subscribers(
+                Action::Insert { token: token.clone(), data: data, is_new: false });
+            let view_info = self.inner.borrow().view_infos.get(&token).cloned();
+            if let Some(view_info) = view_info {
+                self.send_action_to_subscribers(
+                    Action::ViewInfo { token: token.clone(), data: view_info });
+            }
+            let unavailable = self.inner.borrow().unavailable.get(&token).cloned();
+            if let Some(reason) = unavailable {
+                self.send_action_to_subscribers(
+                    Action::Update { token: token, unavailable: Some(reason), data: None });
             }
+        }
+    }
 
-            task = send_action(
-                task, &client_stream,
-                Action::Insert {
-                    token: t.clone(),
-                    data: v.clone()
-                }
-            );
+    fn expire_due_entries(&mut self) {
+        // A locked collection is final; its entries stay until a manager unlocks it.
+        if self.inner.borrow().settings.locked {
+            return
         }
 
-        for (t, vi) in &self.inner.borrow().view_infos {
-            task = send_action(
-                task, &client_stream,
-                Action::ViewInfo {
-                    token: t.clone(),
-                    data: vi.clone(),
-                }
-            );
+        let expired: Vec<String> = {
+            let inner = self.inner.borrow();
+            let now = inner.clock.now_millis();
+            inner.views.iter()
+                .filter(|&(_, data)| data.expires.map_or(false, |t| t <= now))
+                .map(|(token, _)| token.clone())
+                .collect()
+        };
+
+        let mut grains = Vec::new();
+        for token in expired {
+            let is_bookmark =
+                self.inner.borrow().views.get(&token).map_or(false, |d| d.bookmark.is_some());
+            if let Err(e) = self.move_to_trash(&token) {
+                let failures = self.inner.borrow().failures.clone();
+                failures.storage::<()>(&format!("move expired entry {} to trash", token),
+                                       Err(e.into()));
+                continue
+            }
+            let removed = self.forget(&token);
+            let time = self.inner.borrow().clock.now_millis();
+            self.send_action_to_subscribers(Action::Remove {
+                token: token.clone(),
+                actor: None,
+                actor_name: None,
+                time: time,
+            });
+            self.record(None, "expire", Some(token.clone()), removed.map(|data| data.title));
+            if !is_bookmark {
+                grains.push(token);
+            }
         }
 
-        self.inner.borrow_mut().tasks.add(task);
+        if !grains.is_empty() {
+            let drops = self.drop_sturdyrefs(grains);
+            self.inner.borrow_mut().tasks.add(labeled("drop expired sturdyrefs".into(), drops));
+        }
+    }
 
-        for ref text_id in &added_by_identities {
-            let id = text_id.to_string();
-            let client_stream1 = client_stream.clone();
+    /// Sets or clears the time at which the entry `token` expires.
+    fn set_expiry(&mut self,
+                  token: &str,
+                  expires: Option<u64>,
+                  actor: Option<String>) -> ::capnp::Result<()> {
+        let mut data = match self.inner.borrow().views.get(token) {
+            Some(data) => data.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        data.expires = expires;
+        data.version += 1;
+        try!(self.write_metadata(token, &data));
 
-            let task = self.get_user_profile(text_id).and_then(move |profile_data| {
-                let action = Action::User { id: id, data: profile_data };
-                let json_string = action.to_json();
-                let mut req = client_stream1.send_bytes_request();
-                web_socket::encode_text_message(req.get(), &json_string);
-                req.send().promise.map(|_| ())
-            });
+        self.send_action_to_subscribers(Action::Insert {
+            token: token.into(),
+            data: data.clone(),
+            is_new: false,
+        });
+        self.record(actor, "expiry", Some(token.into()), Some(data.title.clone()));
+        self.inner.borrow_mut().views.insert(token.into(), data);
+        Ok(())
+    }
 
-            self.inner.borrow_mut().tasks.add(task);
+    fn send_due_digests(&mut self) {
+        let mut tasks = Vec::new();
+        {
+            let inner = self.inner.borrow();
+            let context = match inner.mail_context {
+                Some(ref context) => context.clone(),
+                None => return,  // We have no way to send mail until someone opens the grain.
+            };
+
+            let now = inner.clock.now_millis();
+            let title = inner.collection_title().unwrap_or("your collection".to_string());
+            for (identity_id, subscription) in inner.digests.due(now) {
+                let audience = Audience { identity_id: Some(identity_id.clone()), editor: false };
+                let entries: Vec<JournalEntry> = inner.journal.entries().iter()
+                    .filter(|e| e.token.as_ref().map_or(true, |t| inner.is_visible(t, &audience)))
+                    .cloned()
+                    .collect();
+                let body = ::digest::render(&title, &entries, subscription.last_sent);
+                let self1 = self.clone();
+                let task = match body {
+                    None => Promise::ok(()),
+                    Some(body) => send_email(&context,
+                                             subscription.address,
+                                             format!("Activity in {}", title),
+                                             body),
+                }.map(move |()| {
+                    let inner = &mut *self1.inner.borrow_mut();
+                    inner.failures.storage("record digest delivery",
+                                           inner.digests.mark_sent(&identity_id, now));
+                });
+                tasks.push(task);
+            }
         }
 
+        for task in tasks {
+            self.inner.borrow_mut().tasks.add(task);
+        }
+    }
 
-        web_socket_stream::ToClient::new(
-            web_socket::Adapter::new(
-                WebSocketStream::new(id, self.clone()),
-                client_stream,
-                handle.clone(),
-                self.inner.borrow().tasks.clone())).from_server::<::capnp_rpc::Server>()
+    /// Makes the ApiSession saved under `token` the webhook target, dropping the previous one.
+    fn set_webhook(&mut self,
+                   token: Vec<u8>,
+                   url: String,
+                   actor: Option<String>) -> ::capnp::Result<()> {
+        let old = try!(self.inner.borrow_mut().webhooks.set(token, url.clone()));
+        self.drop_webhook_token(old);
+        self.record(actor, "webhook", None, Some(url));
+        Ok(())
     }
-}
 
-const ADD_GRAIN_ACTIVITY_INDEX: u16 = 0;
-const REMOVE_GRAIN_ACTIVITY_INDEX: u16 = 1;
-const EDIT_DESCRIPTION_ACTIVITY_INDEX: u16 = 2;
+    fn clear_webhook(&mut self, actor: Option<String>) -> ::capnp::Result<()> {
+        let old = try!(self.inner.borrow_mut().webhooks.clear());
+        self.drop_webhook_token(old);
+        self.record(actor, "webhook", None, None);
+        Ok(())
+    }
 
-pub struct WebSession {
-    handle: ::tokio_core::reactor::Handle,
-    can_write: bool,
-    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
-    context: session_context::Client,
-    saved_ui_views: SavedUiViewSet,
-    identity_id: Option<String>,
-}
+    fn drop_webhook_token(&mut self, token: Option<Vec<u8>>) {
+        if let Some(token) = token {
+            let mut req = self.inner.borrow().sandstorm_api.drop_request();
+            req.get().set_token(&token);
+            let task = req.send().promise.map(|_| ());
+            self.inner.borrow_mut().tasks.add(task);
+        }
+    }
 
-impl WebSession {
-    pub fn new(handle: ::tokio_core::reactor::Handle,
-               user_info: user_info::Reader,
-               context: session_context::Client,
-               _params: web_session::params::Reader,
-               sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
-               saved_ui_views: SavedUiViewSet)
-               -> ::capnp::Result<WebSession>
-    {
-        // Permission #0 is "write". Check if bit 0 in the PermissionSet is set.
-        let permissions = try!(user_info.get_permissions());
-        let can_write = permissions.len() > 0 && permissions.get(0);
+    fn update_description(&mut self,
+                          description: &[u8],
+                          actor: Option<String>) -> ::capnp::Result<()> {
+        use std::io::Write;
 
-        let identity_id = if user_info.has_identity_id() {
-            Some(hex::ToHex::to_hex(try!(user_info.get_identity_id())))
-        } else {
-            None
+        let desc_string: String = match ::std::str::from_utf8(description) {
+            Err(e) => return Err(::capnp::Error::failed(format!("{}", e))),
+            Ok(d) => d.into(),
         };
 
-        Ok(WebSession {
-            handle: handle,
-            can_write: can_write,
-            sandstorm_api: sandstorm_api,
-            context: context,
-            saved_ui_views: saved_ui_views,
-            identity_id: identity_id,
-        })
-
-        // `UserInfo` is defined in `sandstorm/grain.capnp` and contains info like:
-        // - A stable ID for the user, so you can correlate sessions from the same user.
-        // - The user's display name, e.g. "Mark Miller", useful for identifying the user to other
-        //   users.
-        // - The user's permissions (seen above).
+        let path = self.inner.borrow().config.var_path("description");
+        let temp_path = path.with_extension("uploading");
+        ::std::fs::File::create(&temp_path)?.write_all(description)?;
+        ::std::fs::rename(temp_path, path)?;
 
-        // `WebSession::Params` is defined in `sandstorm/web-session.capnp` and contains info like:
-        // - The hostname where the grain was mapped for this user. Every time a user opens a grain,
-        //   it is mapped at a new random hostname for security reasons.
-        // - The user's User-Agent and Accept-Languages headers.
+        self.inner.borrow_mut().description = desc_string.clone();
+        self.send_action_to_subscribers(Action::Description(desc_string));
+        self.record(actor, "description", None, None);
+        Ok(())
+    }
 
-        // `SessionContext` is defined in `sandstorm/grain.capnp` and implements callbacks for
-        // sharing/access control and service publishing/discovery.
+    /// Puts back the description that new collections start with.
+    fn reset_description(&mut self, actor: Option<String>) -> ::capnp::Result<()> {
+        self.update_description(DEFAULT_DESCRIPTION.as_bytes(), actor)
     }
-}
 
-impl ui_session::Server for WebSession {}
+    /// Atomically replaces the metadata file for `token`.
+    fn write_metadata(&self, token: &str, data: &SavedUiViewData) -> ::capnp::Result<()> {
+        use std::io::Write;
+        let token_path = try!(::shards::create_token_path(&self.inner.borrow().sturdyref_dir, token)
+                              .map_err(storage_error));
 
-impl web_session::Server for WebSession {
-    fn get(&mut self,
-           params: web_session::GetParams,
-           mut results: web_session::GetResults)
-	-> Promise<(), Error>
-    {
-        // HTTP GET request.
-        let path = pry!(pry!(params.get()).get_path());
-        pry!(self.require_canonical_path(path));
+        let mut temp_path = ::std::path::PathBuf::new();
+        temp_path.push(self.inner.borrow().tmp_dir.clone());
+        temp_path.push(format!("{}.uploading", token));
 
-        if path == "" {
-            let text = "<!DOCTYPE html>\
-                       <html><head>\
-                       <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\">\
-                       <script type=\"text/javascript\" src=\"script.js\" async></script>
-                       </head><body><div id=\"main\"></div></body></html>";
-            let mut content = results.get().init_content();
-            content.set_mime_type("text/html; charset=UTF-8");
-            content.init_body().set_bytes(text.as_bytes());
-            Promise::ok(())
-        } else if path == "script.js" {
-            self.read_file("/script.js.gz", results, "text/javascript; charset=UTF-8", Some("gzip"))
-        } else if path == "style.css" {
-            self.read_file("/style.css.gz", results, "text/css; charset=UTF-8", Some("gzip"))
-        } else {
-            let mut error = results.get().init_client_error();
-            error.set_status_code(web_session::response::ClientErrorCode::NotFound);
-            Promise::ok(())
+        let mut message = ::capnp::message::Builder::new_default();
+        data.to_metadata(message.init_root());
+        let mut bytes = Vec::new();
+        try!(::capnp::serialize::write_message(&mut bytes, &message));
+
+        // Only the file operations can fail transiently, and callers retry those.
+        let mut writer = try!(::std::fs::File::create(&temp_path).map_err(storage_error));
+        try!(writer.write_all(&bytes).map_err(storage_error));
+        try!(writer.sync_all().map_err(storage_error));
+        try!(::std::fs::rename(temp_path, token_path).map_err(storage_error));
+
+        let inner = &mut *self.inner.borrow_mut();
+        inner.dirty_metadata.remove(token);
+        let update = inner.search_index.update(token, &data.search_text());
+        inner.failures.storage("update search index", update);
+        Ok(())
+    }
+
+    /// Notes that the entry `token` in `views` has changed, but only writes it out after
+    /// METADATA_FLUSH_DELAY_MILLIS, so that a storm of changes (like dragging entries from
+    /// folder to folder) costs one write per entry rather than one per change. Only for changes
+    /// that are cheap to lose or that are also journaled; see `recover_moves()`.
+    fn write_metadata_later(&mut self, token: &str) {
+        self.inner.borrow_mut().dirty_metadata.insert(token.to_string());
+        self.schedule_flush();
+    }
+
+    /// Calls `flush_metadata()` in METADATA_FLUSH_DELAY_MILLIS, unless it is already due to run.
+    fn schedule_flush(&mut self) {
+        let schedule =
+            !::std::mem::replace(&mut self.inner.borrow_mut().metadata_flush_scheduled, true);
+        if schedule {
+            let mut self1 = self.clone();
+            let delay = ::std::time::Duration::from_millis(METADATA_FLUSH_DELAY_MILLIS);
+            let task = self.inner.borrow().timer.after(delay).map(move |()| {
+                self1.flush_metadata();
+            });
+            self.inner.borrow_mut().tasks.add(labeled("flush metadata".into(), task));
         }
     }
 
-    fn post(&mut self,
-            params: web_session::PostParams,
-            mut results: web_session::PostResults)
-            -> Promise<(), Error>
-    {
-        let path = {
-            let path = pry!(pry!(params.get()).get_path());
-            pry!(self.require_canonical_path(path));
-            path.to_string()
+    /// Writes out every change held back by `write_metadata_later()`, and the acks.
+    fn flush_metadata(&mut self) {
+        let tokens = {
+            let inner = &mut *self.inner.borrow_mut();
+            inner.metadata_flush_scheduled = false;
+            ::std::mem::replace(&mut inner.dirty_metadata, BTreeSet::new())
         };
+        for token in tokens {
+            let data = match self.inner.borrow().views.get(&token) {
+                Some(data) => data.clone(),
+                None => continue,  // Removed in the meantime.
+            };
+            let result = self.write_metadata(&token, &data);
+            self.inner.borrow().failures.storage("flush metadata", result);
+        }
+        let inner = &mut *self.inner.borrow_mut();
+        inner.failures.storage("save acks", inner.acks.flush());
+    }
 
-        if path.starts_with("token/") {
-            self.receive_request_token(path[6..].to_string(), params, results)
-        } else if path.starts_with("offer/") {
-            let token = path[6..].to_string();
-            let title = match self.saved_ui_views.inner.borrow().get_saved_data(&token) {
-                None => {
-                    let mut error = results.get().init_client_error();
-                    error.set_status_code(web_session::response::ClientErrorCode::NotFound);
-                    return Promise::ok(())
+    /// Redoes journaled moves that are newer than the metadata file of the entry moved, which
+    /// can only happen if we stopped before `flush_metadata()` wrote them out. `written_at`
+    /// holds the modification time of each file. Returns how many moves were redone.
+    fn recover_moves(&mut self, written_at: &HashMap<String, u64>) -> usize {
+        let inner = &mut *self.inner.borrow_mut();
+        let mut recovered = 0;
+        for entry in inner.journal.entries() {
+            if entry.op != "move" { continue }
+            let token = match entry.token {
+                Some(ref token) if written_at.get(token).map_or(false, |&w| entry.time > w) => {
+                    token
                 }
-                Some(saved_ui_view) => saved_ui_view.title.to_string(),
+                _ => continue,
             };
+            if let Some(data) = inner.views.get_mut(token) {
+                data.folder = entry.title.clone();
+                data.version += 1;
+                inner.dirty_metadata.insert(token.clone());
+                recovered += 1;
+            }
+        }
+        recovered
+    }
 
-            self.offer_ui_view(token, title, params, results)
+    /// Counts one more opening of `token`.
+    fn record_open(&mut self, token: &str) -> ::capnp::Result<()> {
+        let mut data = match self.inner.borrow().views.get(token) {
+            Some(data) => data.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        data.open_count += 1;
 
-        } else if path.starts_with("refresh/") {
-            let token = path[8..].to_string();
-            match SavedUiViewSet::retrieve_view_info(&self.saved_ui_views, token) {
-                Ok(()) => {
-                    results.get().init_no_content();
-                }
-                Err(e) => {
-                    fill_in_client_error(results, e);
-                }
+        self.send_action_to_subscribers(Action::Update {
+            token: token.into(),
+            unavailable: None,
+            data: Some(data.clone()),
+        });
+        self.inner.borrow_mut().views.insert(token.into(), data);
+        self.write_metadata_later(token);
+        Ok(())
+    }
+
+    /// Records the fingerprint of the entry `token` now that we know its app, if it has none yet,
+    /// as is the case for entries added before we kept fingerprints.
+    fn note_app_id(&mut self, token: &str, app_id: &str) {
+        let updated = match self.inner.borrow_mut().views.get_mut(token) {
+            Some(data) if data.fingerprint.is_none() => {
+                data.fingerprint = Some(grain_fingerprint(app_id, &data.title));
+                true
             }
-            Promise::ok(())
-        } else {
-            let mut error = results.get().init_client_error();
-            error.set_status_code(web_session::response::ClientErrorCode::NotFound);
-            Promise::ok(())
+            _ => false,
+        };
+        if updated {
+            self.write_metadata_later(token);
         }
     }
 
-    fn put(&mut self,
-           params: web_session::PutParams,
-           mut results: web_session::PutResults)
-	-> Promise<(), Error>
-    {
-        // HTTP PUT request.
+    /// Journals that the grain already held by `token` was added again, and merged into it.
+    fn note_duplicate(&mut self, actor: Option<String>, token: &str) {
+        let title = self.inner.borrow().views.get(token).map(|data| data.title.clone());
+        self.record(actor, "merge", Some(token.into()), title);
+    }
 
-        let params = pry!(params.get());
-        let path = pry!(params.get_path());
-        pry!(self.require_canonical_path(path));
+    /// An entry that `audience` can see and that already holds the grain with `fingerprint`, if
+    /// any. Oldest first, so that merging keeps the original.
+    fn find_by_fingerprint(&self, fingerprint: &str, audience: &Audience) -> Option<String> {
+        let inner = self.inner.borrow();
+        inner.by_date.iter()
+            .find(|&&(_, ref token)| {
+                inner.is_visible(token, audience) &&
+                    inner.views.get(token).and_then(|data| data.fingerprint.as_ref())
+                        .map_or(false, |f| f == fingerprint)
+            })
+            .map(|&(_, ref token)| token.clone())
+    }
 
-        if !self.can_write {
-            results.get().init_client_error()
-                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
-            Promise::ok(())
-        } else if path == "description" {
-            let content = pry!(pry!(params.get_content()).get_content());
-            pry!(self.saved_ui_views.update_description(content));
+    /// Whether the distinct entries `a` and `b` hold what looks like the same grain.
+    fn same_grain(&self, a: &str, b: &str) -> bool {
+        let inner = self.inner.borrow();
+        let fingerprint = |token| inner.views.get(token).and_then(|data| data.fingerprint.clone());
+        a != b && fingerprint(a).is_some() && fingerprint(a) == fingerprint(b)
+    }
+
+    /// Creates a file in /var/uploads to hold the body of a streaming upload while it arrives.
+    fn create_upload_file(&self) -> Result<(::std::path::PathBuf, ::std::fs::File), Error> {
+        let mut inner = self.inner.borrow_mut();
+        let id = inner.next_id;
+        inner.next_id = id + 1;
+        let path = inner.config.var_path("uploads").join(id.to_string());
+        let file = try!(::std::fs::File::create(&path));
+        Ok((path, file))
+    }
+
+    /// JSON list of groups of entries that hold the same grain, by fingerprint, each oldest
+    /// first, for `GET api/duplicates`. Only entries that `audience` can see are listed.
+    fn duplicates_json(&self, audience: &Audience) -> String {
+        let inner = self.inner.borrow();
+        let mut groups: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for &(_, ref token) in &inner.by_date {
+            if !inner.is_visible(token, audience) {
+                continue
+            }
+            let fingerprint = inner.views.get(token).and_then(|data| data.fingerprint.as_ref());
+            if let Some(f) = fingerprint {
+                groups.entry(&f[..]).or_insert(Vec::new()).push(&token[..]);
+            }
+        }
+        let groups: Vec<String> = groups.values().filter(|tokens| tokens.len() > 1)
+            .map(|tokens| format!("{}", json::ToJson::to_json(
+                &tokens.iter().map(|t| t.to_string()).collect::<Vec<String>>())))
+            .collect();
+        format!("[{}]", groups.join(","))
+    }
+
+    fn set_is_collection(&mut self, token: &str, is_collection: bool) -> ::capnp::Result<()> {
+        let mut data = match self.inner.borrow().views.get(token) {
+            Some(data) if data.is_collection != is_collection => data.clone(),
+            _ => return Ok(()),
+        };
+        data.is_collection = is_collection;
+        try!(self.write_metadata(token, &data));
+
+        self.send_action_to_subscribers(Action::Update {
+            token: token.into(),
+            unavailable: None,
+            data: Some(data.clone()),
+        });
+        self.inner.borrow_mut().views.insert(token.into(), data);
+        Ok(())
+    }
+
+    /// Stores `content` as the thumbnail of the entry `token`, replacing any previous one.
+    fn set_thumbnail(&mut self,
+                     token: &str,
+                     mime_type: &str,
+                     content: &[u8],
+                     actor: Option<String>) -> ::capnp::Result<()> {
+        try!(::thumbnails::validate(mime_type, content));
+        let mut data = match self.inner.borrow().views.get(token) {
+            Some(data) => data.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        try!(self.inner.borrow().thumbnails.put(token, content));
+        data.thumbnail = Some(ThumbnailData {
+            mime_type: mime_type.into(),
+            updated: self.inner.borrow().clock.now_millis(),
+        });
+        data.version += 1;
+        try!(self.write_metadata(token, &data));
+
+        self.send_action_to_subscribers(Action::Insert {
+            token: token.into(),
+            data: data.clone(),
+            is_new: false,
+        });
+        self.record(actor, "thumbnail", Some(token.into()), Some(data.title.clone()));
+        self.inner.borrow_mut().views.insert(token.into(), data);
+        Ok(())
+    }
+
+    /// The thumbnail of the entry `token`, with its MIME type, if it has one.
+    fn thumbnail(&self, token: &str) -> ::capnp::Result<Option<(String, Vec<u8>)>> {
+        let inner = self.inner.borrow();
+        let mime_type = match inner.views.get(token).and_then(|d| d.thumbnail.as_ref()) {
+            Some(thumbnail) => thumbnail.mime_type.clone(),
+            None => return Ok(None),
+        };
+        Ok(try!(inner.thumbnails.get(token)).map(|content| (mime_type, content)))
+    }
+
+    /// Counts the entries of a nested collection, by opening an anonymous web session on it and
+    /// requesting its `api/views` listing.
+    fn fetch_entry_count(&self, token: &str) -> Promise<u64, Error> {
+        use ::capnp::traits::HasTypeId;
+
+        let binary_token = match base64::FromBase64::from_base64(token) {
+            Ok(b) => b,
+            Err(e) => return Promise::err(Error::failed(format!("{}", e))),
+        };
+
+        let mut req = self.inner.borrow().sandstorm_api.restore_request();
+        req.get().set_token(&binary_token);
+        Promise::from_future(req.send().promise.and_then(move |response| {
+            let view: ui_view::Client =
+                pry!(pry!(response.get()).get_cap().get_as_capability());
+            let mut req = view.new_session_request();
+            {
+                let mut params = req.get();
+                params.borrow().init_user_info().init_permissions(0);
+                params.set_context(
+                    session_context::ToClient::new(NullSessionContext)
+                        .from_server::<::capnp_rpc::Server>());
+                params.set_session_type(web_session::Client::type_id());
+                params.init_session_params().init_as::<web_session::params::Builder>();
+            }
+            req.send().promise
+        }).and_then(move |response| {
+            // The reverse of the upcast in `UiView::new_session()`.
+            let session = web_session::Client {
+                client: pry!(pry!(response.get()).get_session()).client,
+            };
+            let mut req = session.get_request();
+            req.get().set_path("api/views");
+            req.get().init_context();
+            Promise::from_future(req.send().promise.and_then(move |response| {
+                use sandstorm::web_session_capnp::web_session::response;
+                let body = match pry!(pry!(response.get()).which()) {
+                    response::Which::Content(content) => match pry!(content.get_body().which()) {
+                        response::content::body::Which::Bytes(bytes) => pry!(bytes),
+                        _ => return Promise::err(Error::failed(
+                            "nested collection streamed its listing".into())),
+                    },
+                    _ => return Promise::err(Error::failed(
+                        "nested collection did not return a listing".into())),
+                };
+                let listing = pry!(::std::str::from_utf8(body)
+                                   .map_err(|e| Error::failed(format!("{}", e))));
+                match json::Json::from_str(listing) {
+                    Ok(json::Json::Array(entries)) => Promise::ok(entries.len() as u64),
+                    _ => Promise::err(Error::failed("malformed listing from nested collection".into())),
+                }
+            }))
+        }))
+    }
+
+    fn set_sort_order(&mut self, order: SortOrder, actor: Option<String>) -> ::capnp::Result<()> {
+        let mut settings = self.inner.borrow().settings.clone();
+        settings.sort_order = order;
+        try!(settings.save(self.inner.borrow().config.var_path("settings")));
+
+        self.inner.borrow_mut().settings = settings;
+        self.record(actor, "sort-order", None, Some(sort_order_name(order).into()));
+        self.send_action_to_subscribers(Action::SortOrder(order));
+        Ok(())
+    }
+
+    /// Locks or unlocks the collection. While it is locked, nobody can change it.
+    fn set_locked(&mut self, locked: bool, actor: Option<String>) -> ::capnp::Result<()> {
+        let mut settings = self.inner.borrow().settings.clone();
+        settings.locked = locked;
+        try!(settings.save(self.inner.borrow().config.var_path("settings")));
+
+        self.inner.borrow_mut().settings = settings;
+        self.record(actor, if locked { "lock" } else { "unlock" }, None, None);
+        self.send_action_to_subscribers(Action::Locked(locked));
+        Ok(())
+    }
+
+    /// Chooses the role that new shares get by default. Sandstorm caches the view info, so this
+    /// takes effect once the grain restarts.
+    fn set_default_role(&mut self, role: Role, actor: Option<String>) -> ::capnp::Result<()> {
+        let mut settings = self.inner.borrow().settings.clone();
+        settings.default_role = role;
+        try!(settings.save(self.inner.borrow().config.var_path("settings")));
+
+        self.inner.borrow_mut().settings = settings;
+        self.record(actor, "default-role", None, Some(role_name(role).into()));
+        Ok(())
+    }
+
+    /// Changes how long the trash, journal and replay buffer keep things, and applies the new
+    /// limits right away.
+    fn set_retention(&mut self,
+                     retention: Retention,
+                     actor: Option<String>) -> ::capnp::Result<()> {
+        let mut settings = self.inner.borrow().settings.clone();
+        settings.retention = retention;
+        try!(settings.save(self.inner.borrow().config.var_path("settings")));
+
+        {
+            let inner = &mut *self.inner.borrow_mut();
+            inner.settings = settings;
+            inner.replay.set_capacity(replay_capacity(&retention));
+        }
+        self.record(actor, "retention", None, Some(retention_json(&retention)));
+        self.purge();
+        Ok(())
+    }
+
+    /// Enforces the retention settings periodically, for as long as the grain is running.
+    fn schedule_purges(&self) {
+        use futures::future::{Loop, loop_fn};
+        let self1 = self.clone();
+        let timer = self.inner.borrow().timer.clone();
+        let task = loop_fn((), move |()| {
+            let mut self2 = self1.clone();
+            let delay = ::std::time::Duration::from_millis(PURGE_INTERVAL_MILLIS);
+            timer.after(delay).map(move |()| {
+                self2.purge();
+                Loop::Continue(())
+            })
+        });
+        self.inner.borrow_mut().tasks.add(Promise::from_future(task));
+    }
+
+    /// Deletes trashed entries and journal entries that the retention settings no longer keep.
+    fn purge(&mut self) {
+        let retention = {
+            let inner = &mut *self.inner.borrow_mut();
+            let retention = inner.settings.retention;
+            if let Some(days) = retention.trash_days {
+                let max_age_secs = days as u64 * 24 * 60 * 60;
+                let now_secs = inner.clock.now_millis() / 1000;
+                let trash_dir = inner.config.var_path(TRASH_DIR);
+                let cutoff = now_secs.saturating_sub(max_age_secs);
+                inner.failures.storage("purge trash", purge_trash(&trash_dir, cutoff));
+            }
+            retention
+        };
+        if let Some(keep) = retention.journal_entries {
+            if self.inner.borrow().journal.entries().len() > keep as usize {
+                // Rewrites the whole journal, which must not change underneath it.
+                self.run_maintenance(move |set| {
+                    let inner = &mut *set.inner.borrow_mut();
+                    let truncated = inner.journal.truncate(keep as usize);
+                    inner.failures.storage("truncate journal", truncated);
+                });
+            }
+        }
+    }
+
+    /// Runs `work`, which blocks on storage, with writes turned away. Clients are told first,
+    /// so that failed commands don't come as a surprise, and told again once it is done; that
+    /// message carries the current revision. Does nothing if maintenance is already running.
+    fn run_maintenance<F>(&mut self, work: F)
+        where F: FnOnce(&mut SavedUiViewSet) + 'static
+    {
+        if ::std::mem::replace(&mut self.inner.borrow_mut().maintenance, true) {
+            return
+        }
+        self.send_action_to_subscribers(Action::Maintenance(true));
+
+        // Gives the notice a chance to go out before we block.
+        let mut self1 = self.clone();
+        let delay = ::std::time::Duration::from_millis(0);
+        let task = self.inner.borrow().timer.after(delay).map(move |()| {
+            work(&mut self1);
+            self1.inner.borrow_mut().maintenance = false;
+            self1.send_action_to_subscribers(Action::Maintenance(false));
+        });
+        self.inner.borrow_mut().tasks.add(Promise::from_future(task));
+    }
+
+    /// Turns features on or off for this collection.
+    fn set_features(&mut self,
+                    changes: Vec<(Feature, bool)>,
+                    actor: Option<String>) -> ::capnp::Result<()> {
+        let mut settings = self.inner.borrow().settings.clone();
+        for &(feature, enabled) in &changes {
+            settings.features.set(feature, enabled);
+        }
+        try!(settings.save(self.inner.borrow().config.var_path("settings")));
+
+        let summary = changes.iter()
+            .map(|&(feature, on)| format!("{}: {}", feature.name(), if on { "on" } else { "off" }))
+            .collect::<Vec<String>>()
+            .join(", ");
+        self.inner.borrow_mut().settings = settings;
+        self.record(actor, "features", None, Some(summary));
+        if changes.iter().any(|&(feature, _)| feature == Feature::WebPublishing) {
+            // Publishes the page, or takes it down.
+            self.publish_later();
+        }
+        Ok(())
+    }
+
+    /// Restricts what the "add grain" button asks the powerbox for. Empty lists lift the
+    /// corresponding restriction.
+    fn set_powerbox_filter(&mut self,
+                           app_ids: Vec<String>,
+                           tag_ids: Vec<u64>,
+                           actor: Option<String>) -> ::capnp::Result<()> {
+        if tag_ids.len() + 1 > MAX_POWERBOX_TAGS {
+            return Err(Error::failed(format!("too many powerbox tags ({})", tag_ids.len())))
+        }
+
+        let mut settings = self.inner.borrow().settings.clone();
+        settings.allowed_app_ids = app_ids;
+        settings.required_tag_ids = tag_ids;
+        try!(settings.save(self.inner.borrow().config.var_path("settings")));
+
+        let summary = format!("apps: [{}], tags: [{}]",
+                              settings.allowed_app_ids.join(", "),
+                              settings.required_tag_ids.iter()
+                                  .map(|id| format!("{:x}", id))
+                                  .collect::<Vec<_>>().join(", "));
+        self.inner.borrow_mut().settings = settings;
+        self.record(actor, "powerbox-filter", None, Some(summary));
+        Ok(())
+    }
+
+    fn create_folder(&mut self, name: &str, actor: Option<String>) -> ::capnp::Result<()> {
+        try!(validate_folder_name(name));
+        if self.inner.borrow().folders.contains(name) {
+            return Err(Error::failed(format!("folder already exists: {}", name)));
+        }
+
+        let mut folders = self.inner.borrow().folders.clone();
+        folders.insert(name.to_string());
+        try!(write_folders(&self.inner.borrow().config.var_path("folders"), &folders));
+
+        let names: Vec<String> = folders.iter().cloned().collect();
+        self.inner.borrow_mut().folders = folders;
+        self.record(actor, "create-folder", None, Some(name.to_string()));
+        self.send_action_to_subscribers(Action::Folders(names));
+        Ok(())
+    }
+
+    /// Renames folder `from` to `to`, taking its entries along.
+    fn rename_folder(&mut self, from: &str, to: &str, actor: Option<String>) -> ::capnp::Result<()> {
+        try!(validate_folder_name(to));
+        if !self.inner.borrow().folders.contains(from) {
+            return Err(Error::failed(format!("no such folder: {}", from)));
+        } else if self.inner.borrow().folders.contains(to) {
+            return Err(Error::failed(format!("folder already exists: {}", to)));
+        }
+
+        // The entries move first and the folder list is written last, so that a failure at
+        // either step leaves the list and the entries' folders in agreement.
+        let tokens: Vec<String> = self.inner.borrow().views.iter()
+            .filter(|&(_, data)| data.folder.as_ref().map(|f| &f[..]) == Some(from))
+            .map(|(token, _)| token.clone())
+            .collect();
+        self.inner.borrow_mut().folders.insert(to.to_string());
+        if !tokens.is_empty() {
+            if let Err(e) = self.move_entries(&tokens, Some(to.to_string()), actor.clone()) {
+                self.inner.borrow_mut().folders.remove(to);
+                return Err(e)
+            }
+        }
+
+        let mut folders = self.inner.borrow().folders.clone();
+        folders.remove(from);
+        let path = self.inner.borrow().config.var_path("folders");
+        if let Err(e) = write_folders(&path, &folders) {
+            if !tokens.is_empty() {
+                try!(self.move_entries(&tokens, Some(from.to_string()), actor.clone()));
+            }
+            self.inner.borrow_mut().folders.remove(to);
+            return Err(e)
+        }
+        self.inner.borrow_mut().folders = folders.clone();
+
+        self.record(actor, "rename-folder", None, Some(format!("{} -> {}", from, to)));
+        self.send_action_to_subscribers(Action::Folders(folders.into_iter().collect()));
+        Ok(())
+    }
+
+    /// Adds a second entry for the same grain or link as `token`, in `folder`. For a saved
+    /// grain this saves a fresh sturdyref, so that either entry can be removed on its own.
+    fn duplicate_entry(&mut self,
+                       token: &str,
+                       folder: Option<String>,
+                       actor: Option<String>) -> Promise<(), Error> {
+        let data = match self.inner.borrow().views.get(token) {
+            Some(data) => data.clone(),
+            None => return Promise::err(Error::failed(format!("no such entry: {}", token))),
+        };
+
+        let is_bookmark = data.bookmark.is_some();
+        let collection_title = self.inner.borrow().collection_title();
+        let grain_title = data.title.clone();
+        let mut set = self.clone();
+        let finish = move |set: &mut SavedUiViewSet, new_token: String| -> ::capnp::Result<()> {
+            try!(set.insert(new_token.clone(), data.title.clone(), actor.clone(),
+                            data.bookmark.clone(), data.publish_at));
+            if folder.is_some() {
+                try!(set.move_entries(&[new_token.clone()], folder, actor));
+            }
+            if !is_bookmark {
+                try!(SavedUiViewSet::retrieve_view_info(set, new_token));
+            }
+            Ok(())
+        };
+
+        if is_bookmark {
+            let new_token = pry!(random_hex_string(16));
+            return Promise::from_future(::futures::future::result(finish(&mut set, new_token)));
+        }
+
+        let binary_token = match base64::FromBase64::from_base64(token) {
+            Ok(b) => b,
+            Err(e) => return Promise::err(Error::failed(format!("{}", e))),
+        };
+        let sandstorm_api = self.inner.borrow().sandstorm_api.clone();
+        let mut req = sandstorm_api.restore_request();
+        req.get().set_token(&binary_token);
+        Promise::from_future(req.send().promise.and_then(move |response| {
+            let sealed_ui_view: ui_view::Client =
+                pry!(pry!(response.get()).get_cap().get_as_capability());
+            let mut req = sandstorm_api.save_request();
+            req.get().get_cap().set_as_capability(sealed_ui_view.client.hook);
+            fill_in_save_label(req.get().init_label(), collection_title.as_ref().map(|t| &t[..]),
+                               &grain_title);
+            Promise::from_future(req.send().promise.and_then(move |response| {
+                let binary_token = try!(try!(response.get()).get_token());
+                finish(&mut set, base64::ToBase64::to_base64(binary_token, base64::URL_SAFE))
+            }))
+        }))
+    }
+
+    /// Moves all of `tokens` into `folder` (or to the top level, if `folder` is None). Nothing
+    /// is changed unless every token and the folder exist.
+    fn move_entries(&mut self,
+                    tokens: &[String],
+                    folder: Option<String>,
+                    actor: Option<String>) -> ::capnp::Result<()> {
+        if let Some(ref f) = folder {
+            if !self.inner.borrow().folders.contains(f) {
+                return Err(Error::failed(format!("no such folder: {}", f)));
+            }
+        }
+
+        let mut updated = Vec::new();
+        for token in tokens {
+            match self.inner.borrow().views.get(token) {
+                Some(data) => {
+                    let mut data = data.clone();
+                    data.folder = folder.clone();
+                    data.version += 1;
+                    updated.push((token.clone(), data));
+                }
+                None => return Err(Error::failed(format!("no such entry: {}", token))),
+            }
+        }
+
+        // The journal is written right away, so moves can be written out lazily.
+        let versions: Vec<u64> = updated.iter().map(|&(_, ref data)| data.version).collect();
+        for (token, data) in updated {
+            self.record(actor.clone(), "move", Some(token.clone()), folder.clone());
+            self.inner.borrow_mut().views.insert(token.clone(), data);
+            self.write_metadata_later(&token);
+        }
+
+        self.send_action_to_subscribers(
+            Action::Move { tokens: tokens.to_vec(), folder: folder, versions: versions });
+        Ok(())
+    }
+
+    fn add_comment(&mut self,
+                   token: &str,
+                   text: &[u8],
+                   author: Option<String>) -> ::capnp::Result<()> {
+        let text = match ::std::str::from_utf8(text) {
+            Err(e) => return Err(Error::failed(format!("{}", e))),
+            Ok(t) => t.trim().to_string(),
+        };
+        if text.is_empty() {
+            return Err(Error::failed("comment is empty".into()));
+        } else if text.len() > MAX_COMMENT_LENGTH {
+            return Err(Error::failed(format!("comment is longer than {} bytes", MAX_COMMENT_LENGTH)));
+        }
+
+        let mut data = match self.inner.borrow().views.get(token) {
+            Some(data) => data.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        let comment = CommentData {
+            author: author.clone(),
+            date: self.inner.borrow().clock.now_millis(),
+            text: text,
+        };
+        data.comments.push(comment.clone());
+        data.version += 1;
+        try!(self.write_metadata(token, &data));
+
+        self.record(author, "comment", Some(token.into()), Some(data.title.clone()));
+        let version = data.version;
+        self.inner.borrow_mut().views.insert(token.into(), data);
+        self.send_action_to_subscribers(
+            Action::Comment { token: token.into(), data: comment, version: version });
+        Ok(())
+    }
+
+    /// Records that `rater` gives the entry `token` between 1 and 5 stars, replacing their earlier
+    /// rating, or (if `stars` is None) that they withdraw it.
+    fn set_rating(&mut self,
+                  token: &str,
+                  rater: String,
+                  stars: Option<u8>) -> ::capnp::Result<()> {
+        if let Some(stars) = stars {
+            if stars < 1 || stars > 5 {
+                return Err(Error::failed(format!("a rating must be 1 to 5 stars, not {}", stars)))
+            }
+        }
+        let mut data = match self.inner.borrow().views.get(token) {
+            Some(data) => data.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        let changed = match stars {
+            Some(stars) => data.ratings.insert(rater.clone(), stars) != Some(stars),
+            None => data.ratings.remove(&rater).is_some(),
+        };
+        if !changed {
+            return Ok(())
+        }
+        try!(self.write_metadata(token, &data));
+
+        self.record(Some(rater.clone()), "rate", Some(token.into()), Some(data.title.clone()));
+        let summary = data.rating_summary();
+        self.inner.borrow_mut().views.insert(token.into(), data);
+        self.send_action_to_subscribers(Action::Rating {
+            token: token.into(),
+            rater: rater,
+            stars: stars,
+            summary: summary,
+        });
+        Ok(())
+    }
+
+    /// Records that `voter` upvotes the entry `token`, or (if `up` is false) no longer does.
+    fn set_upvote(&mut self, token: &str, voter: String, up: bool) -> ::capnp::Result<()> {
+        let mut data = match self.inner.borrow().views.get(token) {
+            Some(data) => data.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        let changed = if up {
+            data.upvoters.insert(voter.clone())
+        } else {
+            data.upvoters.remove(&voter)
+        };
+        if !changed {
+            return Ok(())
+        }
+        try!(self.write_metadata(token, &data));
+
+        let op = if up { "upvote" } else { "unvote" };
+        self.record(Some(voter.clone()), op, Some(token.into()), Some(data.title.clone()));
+        let upvotes = data.upvoters.len();
+        self.inner.borrow_mut().views.insert(token.into(), data);
+        self.send_action_to_subscribers(Action::Upvote {
+            token: token.into(),
+            voter: voter,
+            up: up,
+            upvotes: upvotes,
+        });
+        Ok(())
+    }
+
+    /// Makes the entry `token` visible only to `actor`, who must have added it, or to everyone
+    /// again. Everyone else sees it disappear, or appear.
+    fn set_private(&mut self, token: &str, private: bool, actor: String) -> ::capnp::Result<()> {
+        let mut data = match self.inner.borrow().views.get(token) {
+            Some(data) => data.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        if data.added_by.as_ref() != Some(&actor) {
+            return Err(Error::failed("only whoever added an entry can make it private".into()))
+        }
+        if data.private == private {
+            return Ok(())
+        }
+        data.private = private;
+        data.version += 1;
+        try!(self.write_metadata(token, &data));
+
+        if private {
+            // Told while the entry is still public, so this reaches everyone.
+            let time = self.inner.borrow().clock.now_millis();
+            self.send_action_to_subscribers(Action::Remove {
+                token: token.into(),
+                actor: None,
+                actor_name: None,
+                time: time,
+            });
+            self.inner.borrow_mut().private_owners.insert(token.into(), actor.clone());
+        } else {
+            self.inner.borrow_mut().private_owners.remove(token);
+        }
+        let op = if private { "hide" } else { "unhide" };
+        self.record(Some(actor), op, Some(token.into()), Some(data.title.clone()));
+        self.inner.borrow_mut().views.insert(token.into(), data.clone());
+        self.send_action_to_subscribers(
+            Action::Insert { token: token.into(), data: data, is_new: false });
+        let view_info = self.inner.borrow().view_infos.get(token).cloned();
+        if let (false, Some(view_info)) = (private, view_info) {
+            self.send_action_to_subscribers(
+                Action::ViewInfo { token: token.into(), data: view_info });
+        }
+        Ok(())
+    }
+
+    /// Adds an entry. If `publish_at` is in the future, only editors see it until then.
+    fn insert(&mut self,
+              token: String,
+              title: String,
+              added_by: Option<String>,
+              bookmark: Option<BookmarkData>,
+              publish_at: Option<u64>) -> ::capnp::Result<()> {
+        let title = try!(sanitize_title(&title));
+        let date_added = self.inner.borrow().clock.now_millis();
+        let publish_at = match publish_at {
+            Some(time) if time > date_added => Some(time),
+            _ => None,
+        };
+
+        let entry = SavedUiViewData {
+            title: title,
+            date_added: date_added,
+            added_by: added_by,
+            open_count: 0,
+            comments: Vec::new(),
+            folder: None,
+            is_collection: false,
+            bookmark: bookmark,
+            thumbnail: None,
+            expires: None,
+            publish_at: publish_at,
+            version: 0,
+            fingerprint: None,
+            ratings: BTreeMap::new(),
+            upvoters: BTreeSet::new(),
+            private: false,
+        };
+        try!(self.write_metadata(&token, &entry));
+        if let Some(time) = publish_at {
+            self.inner.borrow_mut().scheduled.insert(token.clone(), time);
+        }
+
+        let added_by = entry.added_by.clone();
+        if !self.inner.borrow().subscribers.is_empty() {
+            if let Some(ref id) = added_by {
+                let mut self1 = self.clone();
+                let identity_id: String = id.to_string();
+                let task = self.get_user_profile(&identity_id).map(move |profile_data| {
+                    self1.send_action_to_subscribers(
+                        Action::User { id: identity_id, data: profile_data });
+                });
+                self.inner.borrow_mut().tasks.add(task);
+            }
+        }
+
+        self.record(added_by, "add", Some(token.clone()), Some(entry.title.clone()));
+
+        // Everyone who is connected right now sees the entry arrive, so it isn't "new" to them.
+        self.send_action_to_subscribers(Action::Insert {
+            token: token.clone(),
+            data: entry.clone(),
+            is_new: false,
+        });
+        self.inner.borrow_mut().by_date.insert((entry.date_added, token.clone()));
+        self.inner.borrow_mut().views.insert(token, entry);
+
+        Ok(())
+    }
+
+    /// JSON list of the `limit` most recently added entries, newest first.
+    fn recent_json(&self, viewer: &Viewer, limit: usize) -> String {
+        let inner = self.inner.borrow();
+        let audience = viewer.audience();
+        let entries: Vec<String> = inner.by_date.iter().rev()
+            .filter(|&&(_, ref token)| inner.is_visible(token, &audience))
+            .take(limit)
+            .filter_map(|&(_, ref token)| {
+                inner.views.get(token).map(|data| entry_json(token, data, viewer.is_new(data)))
+            }).collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    /// JSON list of the entries selected by `options`, by default all of them in the
+    /// collection's sort order.
+    fn views_json(&self, viewer: &Viewer, options: &ListOptions) -> String {
+        let inner = self.inner.borrow();
+        let entries: Vec<String> = inner.list_tokens(options, viewer, |_| true).iter()
+            .filter_map(|token| {
+                inner.views.get(token).map(|data| entry_json(token, data, viewer.is_new(data)))
+            }).collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    /// Like `views_json()`, but only the entries whose searchable text matches `query`.
+    fn search_json(&self, viewer: &Viewer, query: &str, options: &ListOptions) -> String {
+        let inner = self.inner.borrow();
+        let matches = inner.search_index.search(query);
+        let keep = |t: &String| matches.contains(t);
+        let entries: Vec<String> = inner.list_tokens(options, viewer, keep).iter()
+            .filter_map(|token| {
+                inner.views.get(token).map(|data| entry_json(token, data, viewer.is_new(data)))
+            }).collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    /// Names of all folders, whether or not they contain entries.
+    fn folder_names(&self) -> BTreeSet<String> {
+        let inner = self.inner.borrow();
+        let mut names = inner.folders.clone();
+        names.extend(inner.views.values().filter_map(|data| data.folder.clone()));
+        names
+    }
+
+    /// (file name, token) for each entry directly in `folder`, as presented over WebDAV. Names
+    /// come from titles; entries whose titles collide get a numeric suffix, in sort order.
+    fn dav_entry_names(&self, folder: Option<&str>) -> Vec<(String, String)> {
+        let inner = self.inner.borrow();
+        let mut taken = HashSet::new();
+        let mut result = Vec::new();
+
+        // Every user must see the same names, so this doesn't depend on anyone's language.
+        for token in inner.sorted_tokens(Collation::Root, &Audience::public()) {
+            let data = match inner.views.get(&token) {
+                Some(data) if data.folder.as_ref().map(|f| &f[..]) == folder => data,
+                _ => continue,
+            };
+            let stem = webdav::file_name(&data.title);
+            let mut name = format!("{}.url", stem);
+            let mut n = 2;
+            while !taken.insert(name.clone()) {
+                name = format!("{} ({}).url", stem, n);
+                n += 1;
+            }
+            result.push((name, token));
+        }
+        result
+    }
+
+    /// JSON list of `{"appTitle": ..., "entries": [...]}` groups, ordered by app title. Entries
+    /// whose view info we haven't retrieved (or failed to retrieve) are grouped under a null
+    /// app title, which sorts first. Within a group, entries follow the collection's sort order.
+    fn views_by_app_json(&self, viewer: &Viewer) -> String {
+        let inner = self.inner.borrow();
+        let mut groups: BTreeMap<Option<String>, Vec<String>> = BTreeMap::new();
+        for token in &inner.sorted_tokens(viewer.collation, &viewer.audience()) {
+            if let Some(data) = inner.views.get(token) {
+                let app_title = match inner.view_infos.get(token) {
+                    Some(&Ok(ref info)) => Some(info.app_title.clone()),
+                    _ => None,
+                };
+                groups.entry(app_title).or_insert(Vec::new())
+                    .push(entry_json(token, data, viewer.is_new(data)));
+            }
+        }
+
+        let groups: Vec<String> = groups.into_iter().map(|(app_title, entries)| {
+            format!("{{\"appTitle\":{},\"entries\":[{}]}}",
+                    optional_string_to_json(&app_title),
+                    entries.join(","))
+        }).collect();
+        format!("[{}]", groups.join(","))
+    }
+
+    fn audit_json(&self, audience: &Audience) -> String {
+        let inner = self.inner.borrow();
+        let entries: Vec<String> =
+            inner.visible_journal(audience).iter().map(|e| e.to_json()).collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    /// JSON for `GET api/changes`: what happened to entries after journal position `since`, for
+    /// clients that mirror the collection. Revisions here are positions in the journal, not the
+    /// websocket broadcast revisions. Each changed entry appears once, at its latest change,
+    /// either as an `upsert` with its current data or, if it is gone, as a `remove` tombstone.
+    /// The new description is included if it changed. Entries that `audience` may not see count
+    /// as gone.
+    fn changes_json(&self, since: usize, audience: &Audience) -> Result<String, Error> {
+        let inner = self.inner.borrow();
+        let entries = inner.journal.entries();
+        let purged = inner.journal.purged();
+        if since > purged + entries.len() {
+            return Err(Error::failed(format!("revision {} is ahead of the journal ({})",
+                                             since, purged + entries.len())))
+        }
+        if since < purged {
+            return Err(Error::failed(format!(
+                "revision {} has been purged from the journal; start again from a full listing",
+                since)))
+        }
+
+        // Revision of the latest change to each token.
+        let mut latest: HashMap<&str, (usize, &JournalEntry)> = HashMap::new();
+        let mut description_changed = false;
+        for (idx, entry) in entries.iter().enumerate().skip(since - purged) {
+            match entry.token {
+                Some(ref token) => { latest.insert(token, (purged + idx + 1, entry)); }
+                None if entry.op == "description" => description_changed = true,
+                None => (),
+            }
+        }
+
+        let mut changes: Vec<(usize, String)> = latest.into_iter().map(|(token, (rev, entry))| {
+            let token_json = json::ToJson::to_json(token);
+            let change = match inner.views.get(token) {
+                Some(data) if inner.is_visible(token, audience) => {
+                    format!("{{\"revision\":{},\"upsert\":{{\"token\":{},\"data\":{}}}}}",
+                            rev, token_json, data.to_json())
+                }
+                _ => {
+                    format!("{{\"revision\":{},\"remove\":{{\"token\":{},\"time\":{}}}}}",
+                            rev, token_json, entry.time)
+                }
+            };
+            (rev, change)
+        }).collect();
+        changes.sort_by_key(|&(revision, _)| revision);
+        let changes: Vec<String> = changes.into_iter().map(|(_, change)| change).collect();
+
+        let description = if description_changed {
+            format!("{}", json::ToJson::to_json(&inner.description))
+        } else {
+            "null".to_string()
+        };
+        Ok(format!("{{\"revision\":{},\"changes\":[{}],\"description\":{}}}",
+                   purged + entries.len(), changes.join(","), description))
+    }
+
+    /// JSON for `GET api/activity-series`: how many entries were added and removed on each of
+    /// the last `days` days, counting only the entries that `audience` can see.
+    fn activity_series_json(&self, days: u64, audience: &Audience) -> String {
+        let inner = self.inner.borrow();
+        let entries = inner.visible_journal(audience);
+        let counts = ::journal::daily_counts(&entries, inner.clock.now_millis(), days);
+        let items: Vec<String> = counts.iter().map(|c| {
+            format!("{{\"day\":{},\"added\":{},\"removed\":{}}}", c.day, c.added, c.removed)
+        }).collect();
+        format!("[{}]", items.join(","))
+    }
+
+    fn audit_csv(&self, audience: &Audience) -> String {
+        let mut result = String::new();
+        ::csv::write_record(&mut result, &["time", "actor", "op", "token", "title"]);
+        for entry in self.inner.borrow().visible_journal(audience) {
+            ::csv::write_record(&mut result, &[
+                &entry.time.to_string(),
+                entry.actor.as_ref().map(|s| &s[..]).unwrap_or(""),
+                &entry.op,
+                entry.token.as_ref().map(|s| &s[..]).unwrap_or(""),
+                entry.title.as_ref().map(|s| &s[..]).unwrap_or(""),
+            ]);
+        }
+        result
+    }
+
+    /// The display name of everyone who added or commented on an entry, by identity ID. Where
+    /// looking up a profile fails, the identity ID stands in for the name.
+    fn display_names(&mut self) -> Promise<HashMap<String, String>, Error> {
+        let mut people = BTreeSet::new();
+        for data in self.inner.borrow().views.values() {
+            people.extend(data.added_by.iter().cloned());
+            people.extend(data.comments.iter().filter_map(|c| c.author.clone()));
+        }
+        let lookups: Vec<_> = people.into_iter().map(|id| {
+            self.get_user_profile(&id).then(move |result| {
+                let name = match result {
+                    Ok(profile) => profile.display_name,
+                    Err(_) => id.clone(),
+                };
+                Ok::<_, Error>((id, name))
+            })
+        }).collect();
+        Promise::from_future(::futures::future::join_all(lookups).map(|names| {
+            names.into_iter().collect()
+        }))
+    }
+
+    /// JSON for `GET api/usage`: the storage taken up by each identity's entries and comments,
+    /// largest first. An entry's metadata and thumbnail count toward whoever added it, and the
+    /// text of each comment toward its author rather than the entry's. Entries and comments
+    /// from before identities were recorded are counted under a null identity.
+    fn usage_json(&mut self) -> Promise<String, Error> {
+        let mut usage: BTreeMap<Option<String>, Usage> = BTreeMap::new();
+        {
+            let inner = self.inner.borrow();
+            for (token, data) in &inner.views {
+                let path = ::shards::token_path(&inner.sturdyref_dir, token);
+                let metadata_bytes = match ::std::fs::metadata(&path) {
+                    Ok(metadata) => metadata.len(),
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => 0,
+                    Err(e) => return Promise::err(e.into()),
+                };
+                let thumbnail_bytes = pry!(inner.thumbnails.size(token));
+                let mut comment_bytes = 0;
+                for comment in &data.comments {
+                    let bytes = comment.text.len() as u64;
+                    comment_bytes += bytes;
+                    let author = usage.entry(comment.author.clone()).or_insert(Usage::default());
+                    author.comments += 1;
+                    author.comment_bytes += bytes;
+                }
+                let adder = usage.entry(data.added_by.clone()).or_insert(Usage::default());
+                adder.entries += 1;
+                adder.metadata_bytes += metadata_bytes.saturating_sub(comment_bytes);
+                adder.thumbnail_bytes += thumbnail_bytes;
+            }
+        }
+        Promise::from_future(self.display_names().map(move |names| {
+            let mut usage: Vec<(Option<String>, Usage)> = usage.into_iter().collect();
+            usage.sort_by(|&(_, ref a), &(_, ref b)| b.total_bytes().cmp(&a.total_bytes()));
+            let entries: Vec<String> = usage.iter().map(|&(ref id, ref u)| {
+                let name = id.as_ref().and_then(|id| names.get(id)).cloned();
+                format!("{{\"identityId\":{},\"name\":{},\"entries\":{},\
+                         \"metadataBytes\":{},\"thumbnailBytes\":{},\"comments\":{},\
+                         \"commentBytes\":{},\"totalBytes\":{}}}",
+                        optional_string_to_json(id), optional_string_to_json(&name),
+                        u.entries, u.metadata_bytes, u.thumbnail_bytes, u.comments,
+                        u.comment_bytes, u.total_bytes())
+            }).collect();
+            format!("[{}]", entries.join(","))
+        }))
+    }
+
+    /// The URL that opens `token`: the bookmarked page, or the entry within the collection
+    /// served at `base_url`. Sandstorm serves each session from a host of its own, so for saved
+    /// grains the URL only works for as long as that session lasts.
+    fn entry_url(base_url: &str, token: &str, data: &SavedUiViewData) -> String {
+        match data.bookmark {
+            Some(ref bookmark) => bookmark.url.clone(),
+            None => format!("{}/#open={}", base_url, token),
+        }
+    }
+
+    /// What browsers should save an export as, like "collection-reading-list-2024-05-01.csv".
+    /// `kind` distinguishes exports that share an extension, like the audit log.
+    fn export_file_name(&self, kind: Option<&str>, extension: &str) -> String {
+        let inner = self.inner.borrow();
+        let mut parts = vec!["collection".to_string()];
+        if let Some(title) = inner.collection_title() {
+            let slug = file_name_slug(&title);
+            if !slug.is_empty() {
+                parts.push(slug);
+            }
+        }
+        if let Some(kind) = kind {
+            parts.push(kind.to_string());
+        }
+        parts.push(::csv::datetime(inner.clock.now_millis())[..10].to_string());
+        format!("{}.{}", parts.join("-"), extension)
+    }
+
+    /// The entries as CSV, one row each, in the collection's sort order. `base_url` is where
+    /// the collection is served, for the URLs of grain entries.
+    fn export_csv(&mut self, base_url: String, viewer: &Viewer) -> Promise<String, Error> {
+        let snapshot = self.inner.borrow().export_snapshot(viewer.collation, &viewer.audience());
+        Promise::from_future(self.display_names().map(move |names| {
+            let mut result = String::new();
+            ::csv::write_record(&mut result, &["title", "app", "added by", "date added",
+                                               "folder", "url"]);
+            for &(ref token, ref data, ref app_title) in &snapshot.entries {
+                let app = match (&data.bookmark, app_title) {
+                    (&None, &Some(ref app_title)) => &app_title[..],
+                    _ => "",
+                };
+                let added_by = data.added_by.as_ref()
+                    .map(|id| names.get(id).unwrap_or(id)).map(|s| &s[..]).unwrap_or("");
+                let url = SavedUiViewSet::entry_url(&base_url, token, data);
+                ::csv::write_record(&mut result, &[
+                    &data.title,
+                    app,
+                    added_by,
+                    &::csv::datetime(data.date_added),
+                    data.folder.as_ref().map(|s| &s[..]).unwrap_or(""),
+                    &url,
+                ]);
+            }
+            result
+        }))
+    }
+
+    /// The whole collection as a standalone HTML document for printing or mailing: the
+    /// description, then the entries of each folder with their comments.
+    fn export_html(&mut self, base_url: String, viewer: &Viewer) -> Promise<String, Error> {
+        let snapshot = self.inner.borrow().export_snapshot(viewer.collation, &viewer.audience());
+        Promise::from_future(self.display_names().map(move |names| {
+            let name_of = |id: &Option<String>| match id {
+                &Some(ref id) => escape_html(names.get(id).unwrap_or(id)),
+                &None => "someone".to_string(),
+            };
+
+            // Entries outside any folder come first, under no heading.
+            let mut groups: BTreeMap<Option<String>, Vec<_>> = BTreeMap::new();
+            for entry in &snapshot.entries {
+                groups.entry(entry.1.folder.clone()).or_insert(Vec::new()).push(entry);
+            }
+
+            let mut body = String::new();
+            if !snapshot.description.is_empty() {
+                body.push_str(&format!("<p class=\"description\">{}</p>\n",
+                                       escape_html(&snapshot.description)));
+            }
+            for (folder, entries) in &groups {
+                if let &Some(ref folder) = folder {
+                    body.push_str(&format!("<h2>{}</h2>\n", escape_html(folder)));
+                }
+                body.push_str("<ul>\n");
+                for &&(ref token, ref data, ref app_title) in entries {
+                    let app = match (&data.bookmark, app_title) {
+                        (&None, &Some(ref app_title)) => format!(" ({})", escape_html(app_title)),
+                        _ => String::new(),
+                    };
+                    body.push_str(&format!(
+                        "<li><a href=\"{}\">{}</a>{}<br><small>added by {} on {}</small>",
+                        escape_html(&SavedUiViewSet::entry_url(&base_url, token, data)),
+                        escape_html(&data.title), app, name_of(&data.added_by),
+                        ::csv::datetime(data.date_added)));
+                    if !data.comments.is_empty() {
+                        body.push_str("\n<ul class=\"comments\">\n");
+                        for comment in &data.comments {
+                            body.push_str(&format!("<li>{}: {}</li>\n",
+                                                   name_of(&comment.author),
+                                                   escape_html(&comment.text)));
+                        }
+                        body.push_str("</ul>");
+                    }
+                    body.push_str("</li>\n");
+                }
+                body.push_str("</ul>\n");
+            }
+
+            format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                     <meta name=\"revision\" content=\"{}\">\n\
+                     <title>Collection</title>\n\
+                     <style>body {{ font-family: sans-serif; }} \
+                     .description {{ font-weight: bold; }} \
+                     .comments {{ font-size: smaller; }}</style>\n\
+                     </head>\n<body>\n{}</body>\n</html>\n", snapshot.revision, body)
+        }))
+    }
+
+    fn send_action_to_subscribers(&mut self, action: Action) {
+        if let Some(ref mut batch) = self.inner.borrow_mut().batch {
+            batch.push(action);
+            return
+        }
+
+        // Parts about hidden entries go only to those who can see them, and aren't kept for
+        // replay.
+        let split = {
+            let inner = self.inner.borrow();
+            if inner.private_owners.is_empty() && inner.scheduled.is_empty() {
+                None
+            } else {
+                let public = inner.restrict(&action, &|v| v == Visibility::Everyone);
+                let hidden: Vec<(u64, Action)> =
+                    inner.subscriber_audiences.iter().filter_map(|(&id, audience)| {
+                        inner.restrict(&action, &|v| {
+                            v != Visibility::Everyone && audience.can_see(v)
+                        }).map(|action| (id, action))
+                    }).collect();
+                Some((public, hidden))
+            }
+        };
+        let action = match split {
+            None => action,
+            Some((public, hidden)) => {
+                for (id, action) in hidden {
+                    self.send_action_to_subscriber(id, action);
+                }
+                match public {
+                    Some(action) => action,
+                    None => return,
+                }
+            }
+        };
+
+        let mut sends = Vec::new();
+        {
+            let &mut SavedUiViewSetInner {
+                ref subscribers, ref mut replay, ref clock, ref last_broadcast, ..
+            } = &mut *self.inner.borrow_mut();
+            let json_string = replay.push(&action.to_json());
+            for (&id, sub) in &*subscribers {
+                let mut req = sub.send_bytes_request();
+                web_socket::encode_text_message(req.get(), &json_string);
+                let clock = clock.clone();
+                let last_broadcast = last_broadcast.clone();
+                sends.push((id, Promise::from_future(req.send().promise.map(move |_| {
+                    last_broadcast.set(Some(clock.now_millis()));
+                }))));
+            }
+        }
+        for (id, send) in sends {
+            self.add_subscriber_task(id, send);
+        }
+
+        if self.is_enabled(Feature::WebPublishing) {
+            self.publish_later();
+        }
+    }
+
+    /// Regenerates the published page PUBLISH_DELAY_MILLIS from now, so that a burst of changes
+    /// costs one write.
+    fn publish_later(&mut self) {
+        if ::std::mem::replace(&mut self.inner.borrow_mut().publish_scheduled, true) {
+            return
+        }
+        let mut self1 = self.clone();
+        let delay = ::std::time::Duration::from_millis(PUBLISH_DELAY_MILLIS);
+        let task = self.inner.borrow().timer.after(delay).map(move |()| {
+            self1.inner.borrow_mut().publish_scheduled = false;
+            let result = self1.publish();
+            self1.inner.borrow().failures.storage("publish the collection", result);
+        });
+        self.inner.borrow_mut().tasks.add(labeled("publish".into(), task));
+    }
+
+    /// Writes the public, read-only page to /var/www, which Sandstorm serves at the grain's
+    /// public address, or removes it if web publishing is off.
+    fn publish(&self) -> Result<(), Error> {
+        let www_dir = self.inner.borrow().config.var_path(WWW_DIR);
+        let path = www_dir.join("index.html");
+        if !self.is_enabled(Feature::WebPublishing) {
+            return match ::std::fs::remove_file(&path) {
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(()),
+                result => result.map_err(|e| e.into()),
+            }
+        }
+        let html = self.inner.borrow().public_html();
+        try!(::std::fs::create_dir_all(&www_dir));
+        let temp_path = path.with_extension("uploading");
+        {
+            use std::io::Write;
+            let mut file = try!(::std::fs::File::create(&temp_path));
+            try!(file.write_all(html.as_bytes()));
+            try!(file.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, path));
+        Ok(())
+    }
+
+    /// Tells every connected client to reload the page, because the frontend has changed. This is
+    /// not kept for replay, so clients that connect later don't reload again.
+    pub fn reload_clients(&mut self) {
+        let ids: Vec<u64> = self.inner.borrow().subscribers.keys().cloned().collect();
+        for id in ids {
+            self.send_action_to_subscriber(id, Action::Reload);
+        }
+    }
+
+    /// Sends `action` to subscriber `id` alone. Unlike a broadcast, it is not kept for replay.
+    fn send_action_to_subscriber(&mut self, id: u64, action: Action) {
+        let send = match self.inner.borrow().subscribers.get(&id) {
+            Some(sub) => {
+                let mut req = sub.send_bytes_request();
+                web_socket::encode_text_message(req.get(), &action.to_json());
+                Promise::from_future(req.send().promise.map(|_| ()))
+            }
+            None => return,
+        };
+        self.add_subscriber_task(id, send);
+    }
+
+    /// Sends `action` to the activity channel. Unlike the views channel, this is not kept for
+    /// replay: a widget that reconnects just starts again from the summary.
+    fn send_action_to_activity_subscribers(&mut self, action: Action) {
+        let json_string = action.to_json();
+        let sends: Vec<(u64, Promise<(), Error>)> =
+            self.inner.borrow().activity_subscribers.iter().map(|(&id, sub)| {
+                let mut req = sub.send_bytes_request();
+                web_socket::encode_text_message(req.get(), &json_string);
+                (id, Promise::from_future(req.send().promise.map(|_| ())))
+            }).collect();
+        for (id, send) in sends {
+            self.add_subscriber_task(id, send);
+        }
+    }
+
+    /// Tells the activity channel how many clients are now connected.
+    fn broadcast_presence(&mut self) {
+        let connected = {
+            let inner = self.inner.borrow();
+            inner.subscribers.len() + inner.activity_subscribers.len()
+        };
+        self.send_action_to_activity_subscribers(Action::Presence(connected));
+    }
+
+    /// Forgets subscriber `id`, whose websocket has closed.
+    fn unsubscribe(&mut self, id: u64) {
+        let removed = {
+            let inner = &mut *self.inner.borrow_mut();
+            inner.subscriber_audiences.remove(&id);
+            inner.subscribers.remove(&id).is_some() ||
+                inner.activity_subscribers.remove(&id).is_some()
+        };
+        if removed {
+            self.broadcast_presence();
+        }
+    }
+
+    /// Runs `task`, which sends to subscriber `id` and nothing else. If it fails, the
+    /// subscriber's connection is presumably broken, so we stop sending to it and close its
+    /// websocket; the client will reconnect and resync.
+    fn add_subscriber_task(&mut self, id: u64, task: Promise<(), Error>) {
+        let self1 = self.clone();
+        let task = task.map_err(move |e| {
+            let removed = {
+                let inner = &mut *self1.inner.borrow_mut();
+                inner.subscriber_audiences.remove(&id);
+                match inner.subscribers.remove(&id) {
+                    Some(stream) => Some(stream),
+                    None => inner.activity_subscribers.remove(&id),
+                }
+            };
+            if let Some(stream) = removed {
+                let mut req = stream.send_bytes_request();
+                web_socket::encode_message(req.get(), web_socket::OpCode::Terminate, &[]);
+                let close = req.send().promise.map(|_| ());
+                let inner = &mut *self1.inner.borrow_mut();
+                inner.failures.subscribers.set(inner.failures.subscribers.get() + 1);
+                inner.tasks.add(labeled(format!("close subscriber {}", id), close));
+            }
+            e
+        });
+        self.inner.borrow_mut().tasks.add(labeled(format!("send to subscriber {}", id), task));
+    }
+
+    /// JSON for `GET healthz`: whether we can write to storage, whether our connection to
+    /// Sandstorm has been established, how many clients are listening, and when a broadcast
+    /// last reached one of them. Anyone may ask, so the storage probe runs at most once every
+    /// HEALTH_PROBE_INTERVAL_MILLIS, and answers in between reuse its result.
+    fn health_json(&self) -> String {
+        use std::io::Write;
+        let inner = self.inner.borrow();
+        let now = inner.clock.now_millis();
+        let due = inner.storage_probe.borrow().as_ref()
+            .map_or(true, |&(time, _)| now >= time + HEALTH_PROBE_INTERVAL_MILLIS);
+        if due {
+            let probe_path = inner.tmp_dir.join("healthz-probe");
+            let result = ::std::fs::File::create(&probe_path)
+                .and_then(|mut f| { try!(f.write_all(b"ok")); f.sync_all() })
+                .and_then(|()| ::std::fs::remove_file(&probe_path))
+                .map_err(|e| format!("{}", e));
+            *inner.storage_probe.borrow_mut() = Some((now, result));
+        }
+        let storage = match *inner.storage_probe.borrow() {
+            Some((_, ref result)) => result.clone(),
+            None => Ok(()),
+        };
+
+        // `sandstorm_api` starts out as a promise for the bootstrap capability; it resolves once
+        // Sandstorm has answered.
+        let bootstrapped = inner.sandstorm_api.client.hook.get_resolved().is_some();
+
+        let last_broadcast = match inner.last_broadcast.get() {
+            Some(time) => time.to_string(),
+            None => "null".into(),
+        };
+        format!("{{\"ok\":{},\"storage\":{},\"sandstormApi\":\"{}\",\"subscribers\":{},\
+                 \"lastBroadcast\":{},\"requests\":{}}}",
+                storage.is_ok() && inner.failures.storage_healthy() && bootstrapped,
+                match storage {
+                    // The probe can succeed while real writes keep failing, e.g. when the disk
+                    // has just enough room for a two-byte file.
+                    Ok(()) if !inner.failures.storage_healthy() => "\"failing\"".to_string(),
+                    Ok(()) => "\"ok\"".to_string(),
+                    Err(e) => format!("{}", json::ToJson::to_json(&e)),
+                },
+                if bootstrapped { "bootstrapped" } else { "pending" },
+                inner.subscribers.len(),
+                last_broadcast,
+                inner.request_stats.to_json())
+    }
+
+    /// Removes the entry `token` on behalf of `actor`, whose display name, if known, is
+    /// `actor_name`.
+    fn remove(&mut self,
+              token: &str,
+              actor: Option<String>,
+              actor_name: Option<String>) -> Result<(), Error> {
+        let path = ::shards::token_path(&self.inner.borrow().sturdyref_dir, token);
+        if let Err(e) = ::std::fs::remove_file(path) {
+            if e.kind() != ::std::io::ErrorKind::NotFound {
+                return Err(e.into())
+            }
+        }
+
+        let time = self.inner.borrow().clock.now_millis();
+        self.send_action_to_subscribers(Action::Remove {
+            token: token.into(),
+            actor: actor.clone(),
+            actor_name: actor_name.clone(),
+            time: time,
+        });
+        let removed = self.forget(token);
+        self.record_at(Some(time), actor, actor_name, "remove", Some(token.into()),
+                       removed.map(|data| data.title));
+        Ok(())
+    }
+
+    /// Empties the collection: every entry's metadata is moved into TRASH_DIR and its sturdyref is
+    /// dropped. Subscribers receive a single `cleared` action rather than one `remove` per entry.
+    /// Failures to drop a sturdyref are logged, since the entry is already gone by then.
+    fn clear(&mut self, actor: Option<String>) -> Promise<(), Error> {
+        let tokens: Vec<String> = self.inner.borrow().views.keys().cloned().collect();
+
+        let mut moved = Vec::new();
+        let mut bookmarks = HashSet::new();
+        let mut error = None;
+        for token in tokens {
+            if self.inner.borrow().views.get(&token).map_or(false, |d| d.bookmark.is_some()) {
+                bookmarks.insert(token.clone());
+            }
+            match self.move_to_trash(&token) {
+                Ok(()) => moved.push(token),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        for token in &moved {
+            let removed = self.forget(token);
+            if error.is_some() {
+                let time = self.inner.borrow().clock.now_millis();
+                self.send_action_to_subscribers(Action::Remove {
+                    token: token.clone(),
+                    actor: actor.clone(),
+                    actor_name: None,
+                    time: time,
+                });
+            }
+            self.record(actor.clone(), "clear", Some(token.clone()), removed.map(|data| data.title));
+        }
+
+        if let Some(e) = error {
+            return Promise::err(e.into())
+        }
+        self.send_action_to_subscribers(Action::Cleared);
+        self.drop_sturdyrefs(moved.into_iter().filter(|t| !bookmarks.contains(t)).collect())
+    }
+
+    /// Moves the metadata of the entry `token` into TRASH_DIR. An entry whose metadata is already
+    /// gone counts as moved.
+    fn move_to_trash(&self, token: &str) -> ::std::io::Result<()> {
+        // The trash keeps what is on disk, so first write out any change we were holding back.
+        let pending = {
+            let inner = self.inner.borrow();
+            if inner.dirty_metadata.contains(token) {
+                inner.views.get(token).cloned()
+            } else {
+                None
+            }
+        };
+        if let Some(data) = pending {
+            let result = self.write_metadata(token, &data);
+            self.inner.borrow().failures.storage("flush metadata", result);
+        }
+
+        let trash_dir = self.inner.borrow().config.var_path(TRASH_DIR);
+        try!(::std::fs::create_dir_all(&trash_dir));
+        let from = ::shards::token_path(&self.inner.borrow().sturdyref_dir, token);
+        let to = trash_dir.join(token);
+        match ::std::fs::rename(from, to) {
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Drops the entry `token` from our in-memory state, returning its data. Its thumbnail, if
+    /// it has one, is deleted.
+    fn forget(&mut self, token: &str) -> Option<SavedUiViewData> {
+        {
+            let inner = &mut *self.inner.borrow_mut();
+            inner.failures.storage("update search index", inner.search_index.remove(token));
+        }
+        let removed = self.inner.borrow_mut().views.remove(token);
+        if let Some(ref data) = removed {
+            self.inner.borrow_mut().by_date.remove(&(data.date_added, token.to_string()));
+            if data.thumbnail.is_some() {
+                let inner = self.inner.borrow();
+                inner.failures.storage("remove thumbnail", inner.thumbnails.remove(token));
+            }
+        }
+        self.inner.borrow_mut().view_infos.remove(token);
+        self.inner.borrow_mut().unavailable.remove(token);
+        self.inner.borrow_mut().dirty_metadata.remove(token);
+        removed
+    }
+
+    /// Tells Sandstorm that we no longer need the sturdyrefs of the given (already removed)
+    /// entries. Failures are logged rather than reported, since the entries are gone either way.
+    fn drop_sturdyrefs(&mut self, tokens: Vec<String>) -> Promise<(), Error> {
+        let drops: Vec<_> = tokens.into_iter().filter_map(|token| {
+            let binary_token = match base64::FromBase64::from_base64(&token[..]) {
+                Ok(b) => b,
+                Err(e) => {
+                    println!("not dropping malformed token {}: {}", token, e);
+                    return None
+                }
+            };
+            let mut req = self.inner.borrow().sandstorm_api.drop_request();
+            req.get().set_token(&binary_token);
+            Some(req.send().promise.then(move |result| {
+                if let Err(e) = result {
+                    println!("failed to drop sturdyref {}: {}", token, e);
+                }
+                Ok::<(), Error>(())
+            }))
+        }).collect();
+        Promise::from_future(::futures::future::join_all(drops).map(|_| ()))
+    }
+
+    /// JSON for `GET api/bundle`: everything needed to recreate this collection in a fresh grain.
+    /// Bookmarks carry over as they are. Our sturdyrefs are only valid for this grain, so saved
+    /// grains are listed by title and app, for the new owner to pick again from the powerbox.
+    fn bundle_json(&self) -> String {
+        let inner = self.inner.borrow();
+        let snapshot = inner.export_snapshot(Collation::Root, &Audience::public());
+        let mut bookmarks = Vec::new();
+        let mut grains = Vec::new();
+        for &(_, ref data, ref app_title) in &snapshot.entries {
+            match data.bookmark {
+                Some(ref bookmark) => {
+                    bookmarks.push(format!("{{\"title\":{},\"url\":{},\"faviconUrl\":{},\
+                                            \"folder\":{}}}",
+                                           json::ToJson::to_json(&data.title),
+                                           json::ToJson::to_json(&bookmark.url),
+                                           optional_string_to_json(&bookmark.favicon_url),
+                                           optional_string_to_json(&data.folder)));
+                }
+                None => {
+                    grains.push(format!("{{\"title\":{},\"appTitle\":{},\"folder\":{}}}",
+                                        json::ToJson::to_json(&data.title),
+                                        optional_string_to_json(app_title),
+                                        optional_string_to_json(&data.folder)));
+                }
+            }
+        }
+        let folders: Vec<String> =
+            inner.folders.iter().map(|f| format!("{}", json::ToJson::to_json(f))).collect();
+
+        format!("{{\"format\":\"{}\",\"version\":{},\"revision\":{},\"description\":{},\
+                 \"sortOrder\":\"{}\",\"folders\":[{}],\"bookmarks\":[{}],\"grains\":[{}]}}",
+                BUNDLE_FORMAT, BUNDLE_VERSION, snapshot.revision,
+                json::ToJson::to_json(&snapshot.description),
+                sort_order_name(inner.settings.sort_order),
+                folders.join(","), bookmarks.join(","), grains.join(","))
+    }
+
+    /// Recreates the collection described by a bundle from `GET api/bundle`. Only an empty
+    /// collection can take a bundle, so that we never have to merge. Returns the bundle's
+    /// `grains`, which the client walks the user through adding again.
+    fn apply_bundle(&mut self, text: &str, actor: Option<String>) -> Result<String, Error> {
+        let bundle = try!(json::Json::from_str(text).map_err(|e| Error::failed(format!("{}", e))));
+        if bundle.find("format").and_then(|f| f.as_string()) != Some(BUNDLE_FORMAT) {
+            return Err(Error::failed("not a collection bundle".into()))
+        }
+        match bundle.find("version").and_then(|v| v.as_u64()) {
+            Some(v) if v <= BUNDLE_VERSION as u64 => (),
+            _ => return Err(Error::failed("unsupported bundle version".into())),
+        }
+        if !self.inner.borrow().views.is_empty() || !self.inner.borrow().folders.is_empty() {
+            return Err(Error::failed("a bundle can only be imported into an empty collection".into()))
+        }
+
+        // Check everything up front, down to what the steps below would refuse, so that a bad
+        // bundle changes nothing.
+        let mut folders = Vec::new();
+        for value in bundle.find("folders").and_then(|f| f.as_array()).unwrap_or(&Vec::new()) {
+            match value.as_string() {
+                Some(name) if folders.iter().any(|f| f == name) => {
+                    return Err(Error::failed(format!("folder is listed twice: {}", name)))
+                }
+                Some(name) => {
+                    try!(validate_folder_name(name));
+                    folders.push(name.to_string());
+                }
+                None => return Err(Error::failed("folder names must be strings".into())),
+            }
+        }
+        let mut bookmarks = Vec::new();
+        for value in bundle.find("bookmarks").and_then(|b| b.as_array()).unwrap_or(&Vec::new()) {
+            let (title, bookmark) = try!(parse_bookmark(&format!("{}", value)));
+            let title = try!(sanitize_title(&title));
+            let folder = value.find("folder").and_then(|f| f.as_string()).map(|f| f.to_string());
+            if let Some(ref f) = folder {
+                if !folders.contains(f) {
+                    return Err(Error::failed(format!("no such folder: {}", f)))
+                }
+            }
+            bookmarks.push((title, bookmark, folder));
+        }
+        let sort_order = match bundle.find("sortOrder").and_then(|o| o.as_string()) {
+            Some(name) => Some(try!(parse_sort_order(name))),
+            None => None,
+        };
+        let description = match bundle.find("description") {
+            Some(&json::Json::String(ref d)) if !d.is_empty() => Some(d.clone()),
+            Some(&json::Json::String(_)) | Some(&json::Json::Null) | None => None,
+            Some(_) => return Err(Error::failed("the description must be a string".into())),
+        };
+        let grains = match bundle.find("grains") {
+            Some(grains @ &json::Json::Array(_)) => grains.clone(),
+            None => json::Json::Array(Vec::new()),
+            Some(_) => return Err(Error::failed("grains must be a list".into())),
+        };
+
+        if let Some(description) = description {
+            try!(self.update_description(description.as_bytes(), actor.clone()));
+        }
+        for name in &folders {
+            try!(self.create_folder(name, actor.clone()));
+        }
+        if let Some(order) = sort_order {
+            try!(self.set_sort_order(order, actor.clone()));
+        }
+        for (title, bookmark, folder) in bookmarks {
+            let token = try!(random_hex_string(16));
+            try!(self.insert(token.clone(), title, actor.clone(), Some(bookmark), None));
+            if folder.is_some() {
+                try!(self.move_entries(&[token], folder, actor.clone()));
+            }
+        }
+
+        Ok(format!("{{\"grains\":{}}}", grains))
+    }
+
+    /// A dump of our in-memory state for diagnosing drift between it and the disk: the entries
+    /// we know about, the connected subscribers, how many background tasks have failed, and the
+    /// tokens that are only on disk or only in memory. Entries that `audience` can't see are left
+    /// out, and tokens that are only on disk are cut short, since we can't tell whose they are.
+    fn debug_state_json(&self, audience: &Audience) -> Result<String, Error> {
+        let inner = self.inner.borrow();
+        let mut on_disk = BTreeSet::new();
+        for (token, _) in try!(::shards::list(&inner.sturdyref_dir)) {
+            on_disk.insert(token);
+        }
+        let in_memory: BTreeSet<String> = inner.views.keys()
+            .filter(|token| inner.is_visible(token, audience))
+            .cloned()
+            .collect();
+
+        let to_json_list = |tokens: Vec<String>| -> String {
+            let items: Vec<String> =
+                tokens.iter().map(|t| format!("{}", json::ToJson::to_json(t))).collect();
+            format!("[{}]", items.join(","))
+        };
+        let on_disk_only: Vec<String> = on_disk.iter()
+            .filter(|token| !inner.views.contains_key(*token))
+            .map(|token| format!("{}...", token.chars().take(6).collect::<String>()))
+            .collect();
+        let in_memory_only: Vec<String> = in_memory.difference(&on_disk).cloned().collect();
+        let visible_views = inner.views.iter().filter(|&(token, _)| in_memory.contains(token));
+        let views: Vec<String> = visible_views.map(|(token, data)| {
+            format!("{{\"token\":{},\"title\":{},\"hasViewInfo\":{}}}",
+                    json::ToJson::to_json(token),
+                    json::ToJson::to_json(&data.title),
+                    inner.view_infos.contains_key(token))
+        }).collect();
+        let subscribers: Vec<String> = inner.subscribers.keys().map(|id| id.to_string()).collect();
+
+        Ok(format!("{{\"views\":[{}],\"subscribers\":[{}],\"failedTasks\":{},\
+                    \"droppedSubscribers\":{},\"storageFailureStreak\":{},\
+                    \"onDiskOnly\":{},\"inMemoryOnly\":{}}}",
+                   views.join(","),
+                   subscribers.join(","),
+                   inner.failures.tasks.get(),
+                   inner.failures.subscribers.get(),
+                   inner.failures.storage_streak.get(),
+                   to_json_list(on_disk_only),
+                   to_json_list(in_memory_only)))
+    }
+
+    /// Applies the operations of a websocket `batch` command, all or nothing. Everything is
+    /// checked before anything changes, and subscribers get a single `batch` broadcast with all
+    /// the changes.
+    fn apply_batch(&mut self,
+                   ops: Vec<BatchOp>,
+                   actor: Option<String>,
+                   actor_name: Option<String>) -> Result<(), Error> {
+        {
+            let inner = self.inner.borrow();
+            let mut removed = HashSet::new();
+            for op in &ops {
+                let tokens = match op {
+                    &BatchOp::Move(MoveArgs { ref tokens, ref folder, .. }) => {
+                        if let &Some(ref f) = folder {
+                            if !inner.folders.contains(f) {
+                                return Err(Error::failed(format!("no such folder: {}", f)))
+                            }
+                        }
+                        tokens
+                    }
+                    &BatchOp::Remove { ref tokens } => tokens,
+                };
+                for token in tokens {
+                    if !inner.views.contains_key(token) || removed.contains(token) {
+                        return Err(Error::failed(format!("no such entry: {}", token)))
+                    }
+                }
+                if let &BatchOp::Remove { ref tokens } = op {
+                    removed.extend(tokens.iter().cloned());
+                }
+            }
+        }
+
+        // Deleting metadata is the only step that can fail once the checks have passed, so we
+        // first move the removed entries' metadata aside, and put it back if any of it won't go.
+        let staged = try!(self.stage_batch_removals(&ops));
+
+        self.inner.borrow_mut().batch = Some(Vec::new());
+        let mut result = Ok(());
+        let mut to_drop = Vec::new();
+        for op in ops {
+            result = match op {
+                BatchOp::Move(MoveArgs { tokens, folder, .. }) => {
+                    self.move_entries(&tokens, folder, actor.clone())
+                }
+                BatchOp::Remove { tokens } => {
+                    let mut removed = Ok(());
+                    for token in tokens {
+                        let is_bookmark = self.inner.borrow().views.get(&token)
+                            .map_or(false, |data| data.bookmark.is_some());
+                        removed = self.remove(&token, actor.clone(), actor_name.clone());
+                        if removed.is_err() {
+                            break
+                        } else if !is_bookmark {
+                            to_drop.push(token);
+                        }
+                    }
+                    removed
+                }
+            };
+            if result.is_err() {
+                break
+            }
+        }
+
+        // Metadata of entries that are still here goes back, which only happens if something
+        // failed after all.
+        let sturdyref_dir = self.inner.borrow().sturdyref_dir.clone();
+        for (token, path) in staged {
+            let result = if self.inner.borrow().views.contains_key(&token) {
+                ::std::fs::rename(&path, ::shards::token_path(&sturdyref_dir, &token))
+            } else {
+                ::std::fs::remove_file(&path)
+            };
+            self.inner.borrow().failures.storage("clean up batch", result.map_err(Error::from));
+        }
+
+        // Even if something failed part way, subscribers need to hear about what did happen.
+        let actions = self.inner.borrow_mut().batch.take().unwrap_or(Vec::new());
+        if !actions.is_empty() {
+            self.send_action_to_subscribers(Action::Batch(actions));
+        }
+        let drops = self.drop_sturdyrefs(to_drop);
+        self.inner.borrow_mut().tasks.add(drops);
+        result
+    }
+
+    /// Moves the metadata of every entry that `ops` removes into BATCH_DIR, returning the tokens
+    /// and where their metadata went. If one can't be moved, the ones already moved are put back
+    /// and the error is returned.
+    fn stage_batch_removals(&self, ops: &[BatchOp])
+                            -> Result<Vec<(String, ::std::path::PathBuf)>, Error>
+    {
+        let (sturdyref_dir, batch_dir) = {
+            let inner = self.inner.borrow();
+            (inner.sturdyref_dir.clone(), inner.config.var_path(BATCH_DIR))
+        };
+        try!(::std::fs::create_dir_all(&batch_dir));
+        let mut staged = Vec::new();
+        for op in ops {
+            let tokens = match op {
+                &BatchOp::Remove { ref tokens } => tokens,
+                &BatchOp::Move(_) => continue,
+            };
+            for token in tokens {
+                let from = ::shards::token_path(&sturdyref_dir, token);
+                let to = batch_dir.join(token);
+                match ::std::fs::rename(&from, &to) {
+                    Ok(()) => staged.push((token.clone(), to)),
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+                    Err(e) => {
+                        for (token, path) in staged {
+                            if let Err(e) = ::std::fs::rename(
+                                &path, ::shards::token_path(&sturdyref_dir, &token)) {
+                                println!("failed to restore metadata of {}: {}", token, e);
+                            }
+                        }
+                        return Err(e.into())
+                    }
+                }
+            }
+        }
+        Ok(staged)
+    }
+
+    /// Records that `owner`'s acked-mode client `client_id` has processed every broadcast up to
+    /// `revision`. Acks are written out along with held-back metadata.
+    fn ack(&mut self, owner: &str, client_id: &str, revision: u64) -> ::capnp::Result<()> {
+        {
+            let inner = &mut *self.inner.borrow_mut();
+            if revision > inner.replay.revision() {
+                return Err(Error::failed(
+                    format!("ack: revision {} has not been sent yet", revision)))
+            }
+            let now = inner.clock.now_millis();
+            try!(inner.acks.ack(owner, client_id, revision, now));
+        }
+        self.schedule_flush();
+        Ok(())
+    }
+
+    /// Subscribes a websocket to our broadcasts. A client that last saw revision `since` is
+    /// sent just the broadcasts it missed, if we still have them; otherwise it gets a full
+    /// snapshot, preceded by `cleared` if it had state to throw away. Either way, the initial
+    /// messages end with the current revision. Every broadcast after that carries the next
+    /// revision, so a client that sees a jump knows to reconnect.
+    ///
+    /// A client that connects with a `client_id` is in acked mode: it resumes after the last
+    /// revision that it acknowledged, rather than the last one that it claims to have seen, so
+    /// that broadcasts lost in transit are sent again.
+    fn new_subscribed_websocket(&mut self,
+                                client_stream: web_socket_stream::Client,
+                                permissions: Permissions,
+                                viewer: Viewer,
+                                since: Option<u64>,
+                                client_id: Option<String>)
+                                 -> web_socket_stream::Client
+    {
+        // Cap'n Proto delivers calls on the same capability in the order they were made, so we
+        // can send everything at once and only wait for the acknowledgements together.
+        fn send_action(sends: &mut Vec<Promise<(), Error>>,
+                       client_stream: &web_socket_stream::Client,
+                       action: Action) {
+            let json_string = action.to_json();
+            let mut req = client_stream.send_bytes_request();
+            web_socket::encode_text_message(req.get(), &json_string);
+            sends.push(Promise::from_future(req.send().promise.map(|_| ())));
+        }
+
+        fn join(sends: Vec<Promise<(), Error>>) -> Promise<(), Error> {
+            Promise::from_future(::futures::future::join_all(sends).map(|_| ()))
+        }
+
+        let since = match client_id {
+            Some(ref client_id) => {
+                self.inner.borrow().acks.get(::acks::owner(&viewer.identity_id), client_id)
+                    .or(since)
+            }
+            None => since,
+        };
+
+        let id = self.inner.borrow().next_id;
+        self.inner.borrow_mut().next_id = id + 1;
+
+        self.inner.borrow_mut().subscribers.insert(id, client_stream.clone());
+        self.inner.borrow_mut().subscriber_audiences.insert(id, viewer.audience());
+        self.broadcast_presence();
+
+        let mut sends = Vec::new();
+
+        // First, just enough for the client to draw its header while the rest streams in. The
+        // collection has no title of its own, so we offer its description.
+        let summary = {
+            let inner = self.inner.borrow();
+            Action::Summary {
+                count: inner.visible_count(&viewer.audience()),
+                revision: inner.replay.revision(),
+                title: inner.description.clone(),
+            }
+        };
+        send_action(&mut sends, &client_stream, summary);
+        send_action(&mut sends, &client_stream,
+                    Action::CanWrite(permissions.has(Permission::Write)));
+        send_action(&mut sends, &client_stream,
+                    Action::CanComment(permissions.has(Permission::Comment)));
+        send_action(&mut sends, &client_stream, Action::UserId(viewer.identity_id.clone()));
+
+        // Changes to hidden entries aren't replayed, so those who see them start over.
+        let missed = if self.inner.borrow().sees_hidden(&viewer.audience()) {
+            None
+        } else {
+            since.and_then(|revision| self.inner.borrow().replay.since(revision))
+        };
+        if let Some(missed) = missed {
+            for json_string in missed {
+                let mut req = client_stream.send_bytes_request();
+                web_socket::encode_text_message(req.get(), &json_string);
+                sends.push(Promise::from_future(req.send().promise.map(|_| ())));
+            }
+            let revision = self.inner.borrow().replay.revision();
+            send_action(&mut sends, &client_stream, Action::Revision(revision));
+            self.add_subscriber_task(id, join(sends));
+            return self.websocket_adapter(id, client_stream, permissions, viewer, client_id,
+                                          Channel::Views);
+        }
+
+        if since.is_some() {
+            send_action(&mut sends, &client_stream, Action::Cleared);
+        }
+        send_action(&mut sends, &client_stream,
+                    Action::Description(self.inner.borrow().description.clone()));
+        send_action(&mut sends, &client_stream,
+                    Action::Folders(self.inner.borrow().folders.iter().cloned().collect()));
+        send_action(&mut sends, &client_stream,
+                    Action::SortOrder(self.inner.borrow().settings.sort_order));
+        send_action(&mut sends, &client_stream,
+                    Action::Locked(self.inner.borrow().settings.locked));
+        if self.in_maintenance() {
+            send_action(&mut sends, &client_stream, Action::Maintenance(true));
+        }
+
+        let mut added_by_identities: HashSet<String> = HashSet::new();
+
+        {
+            let inner = self.inner.borrow();
+            let audience = viewer.audience();
+            for t in inner.sorted_tokens(viewer.collation, &audience) {
+                let v = match inner.views.get(&t) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                if let &Some(ref id) = &v.added_by {
+                    added_by_identities.insert(id.clone());
+                }
+
+                send_action(
+                    &mut sends, &client_stream,
+                    Action::Insert {
+                        token: t.clone(),
+                        data: v.clone(),
+                        is_new: viewer.is_new(v),
+                    }
+                );
+            }
+
+            for (t, vi) in &inner.view_infos {
+                if !inner.is_visible(t, &audience) {
+                    continue
+                }
+                send_action(
+                    &mut sends, &client_stream,
+                    Action::ViewInfo {
+                        token: t.clone(),
+                        data: vi.clone(),
+                    }
+                );
+            }
+
+            for (t, reason) in &inner.unavailable {
+                if !inner.is_visible(t, &audience) {
+                    continue
+                }
+                send_action(&mut sends, &client_stream,
+                            Action::Update {
+                                token: t.clone(), unavailable: Some(reason.clone()), data: None,
+                            });
+            }
+
+            send_action(&mut sends, &client_stream, Action::Revision(inner.replay.revision()));
+        }
+
+        self.add_subscriber_task(id, join(sends));
+
+        // A failed lookup is no reason to drop the subscriber, so only the sends themselves go
+        // through `add_subscriber_task()`.
+        for ref text_id in &added_by_identities {
+            let mut self1 = self.clone();
+            let identity_id = text_id.to_string();
+            let task = self.get_user_profile(text_id).map(move |profile_data| {
+                self1.send_action_to_subscriber(
+                    id, Action::User { id: identity_id, data: profile_data });
+            });
+            self.inner.borrow_mut().tasks.add(labeled(format!("profile of {}", text_id), task));
+        }
+
+        self.websocket_adapter(id, client_stream, permissions, viewer, client_id, Channel::Views)
+    }
+
+    /// Like `new_subscribed_websocket()`, but for the activity channel, which starts with just
+    /// the summary and the number of connected clients.
+    fn new_activity_websocket(&mut self,
+                              client_stream: web_socket_stream::Client,
+                              permissions: Permissions,
+                              viewer: Viewer)
+                              -> web_socket_stream::Client
+    {
+        let id = self.inner.borrow().next_id;
+        self.inner.borrow_mut().next_id = id + 1;
+
+        let summary = {
+            let inner = self.inner.borrow();
+            Action::Summary {
+                count: inner.visible_count(&viewer.audience()),
+                revision: inner.replay.revision(),
+                title: inner.description.clone(),
+            }
+        };
+        let mut req = client_stream.send_bytes_request();
+        web_socket::encode_text_message(req.get(), &summary.to_json());
+        self.add_subscriber_task(id, Promise::from_future(req.send().promise.map(|_| ())));
+
+        self.inner.borrow_mut().activity_subscribers.insert(id, client_stream.clone());
+        self.broadcast_presence();
+
+        self.websocket_adapter(id, client_stream, permissions, viewer, None, Channel::Activity)
+    }
+
+    fn websocket_adapter(&self,
+                         id: u64,
+                         client_stream: web_socket_stream::Client,
+                         permissions: Permissions,
+                         viewer: Viewer,
+                         client_id: Option<String>,
+                         channel: Channel)
+                         -> web_socket_stream::Client
+    {
+        web_socket_stream::ToClient::new(
+            web_socket::Adapter::new(
+                WebSocketStream::new(id, self.clone(), permissions, viewer, client_id, channel),
+                client_stream,
+                self.inner.borrow().timer.clone(),
+                self.inner.borrow().tasks.clone())).from_server::<::capnp_rpc::Server>()
+    }
+}
+
+/// Must match `appVersion` in sandstorm-pkgdef.capnp.
+const APP_VERSION: u32 = 7;
+
+/// The version of the data layout under /var. Bump this whenever a change needs existing grains
+/// to be migrated. Version 2 moved the files in /var/sturdyrefs into shard directories.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// The file under /var that records SCHEMA_VERSION once the grain's data has been migrated to
+/// it, so that `--check` and other tools can tell which layout they are looking at.
+pub const SCHEMA_VERSION_FILE: &'static str = "schema-version";
+
+/// The version of the messages sent over the websocket. Bump this whenever a change would break
+/// a client that was written against the old format.
+const WEBSOCKET_PROTOCOL_VERSION: u32 = 1;
+
+/// How long we wait for each Sandstorm or grain RPC when adding a grain or fetching its view
+/// info.
+const GRAIN_RPC_TIMEOUT_SECONDS: u64 = 30;
+
+/// How often `healthz` may write to storage to see whether it works.
+const HEALTH_PROBE_INTERVAL_MILLIS: u64 = 10 * 1000;
+
+/// Collection titles longer than this are cut short in save labels and email subjects.
+const MAX_COLLECTION_TITLE_CHARS: usize = 60;
+
+/// How many times we try to record a grain that was added from the powerbox, and how long we
+/// wait before the first retry. Sandstorm's `save()` itself is tried only once: a retry after a
+/// timeout could leave behind a second sturdyref that nothing refers to.
+const SAVE_ATTEMPTS: u32 = 3;
+const SAVE_INITIAL_BACKOFF_MILLIS: u64 = 500;
+
+/// Identifies the JSON served by `GET api/bundle`. Bump the version when the format changes in
+/// a way that older grains can't read.
+const BUNDLE_FORMAT: &'static str = "collections-bundle";
+const BUNDLE_VERSION: u32 = 1;
+
+/// How many recent broadcasts we keep for clients that reconnect, unless a manager chooses
+/// otherwise.
+const REPLAY_CAPACITY: usize = 1000;
+
+/// The most that a manager may choose to keep. Every kept broadcast is held in memory.
+const MAX_REPLAY_CAPACITY: u32 = 100000;
+
+fn replay_capacity(retention: &Retention) -> usize {
+    retention.replay_broadcasts.map_or(REPLAY_CAPACITY, |n| n as usize)
+}
+
+/// Deletes the entries in the trash directory `dir` that were moved there before `cutoff`, in
+/// seconds since the unix epoch. Moving a file into the trash changes its ctime but not its
+/// mtime, so the ctime tells when it was removed. Returns how many were deleted.
+fn purge_trash(dir: &::std::path::Path, cutoff: u64) -> Result<usize, Error> {
+    use std::os::unix::fs::MetadataExt;
+    let mut deleted = 0;
+    let entries = match ::std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = try!(entry);
+        let metadata = try!(entry.metadata());
+        if metadata.is_file() && metadata.ctime() >= 0 && (metadata.ctime() as u64) < cutoff {
+            try!(::std::fs::remove_file(entry.path()));
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// How long the nonce handed out by the first `api/clear` request remains valid.
+const CLEAR_NONCE_LIFETIME_MILLIS: u64 = 5 * 60 * 1000;
+
+/// Where, under /var, Sandstorm looks for the files that it serves at the grain's public address.
+const WWW_DIR: &'static str = "www";
+
+/// Longest that the published page lags behind the collection.
+const PUBLISH_DELAY_MILLIS: u64 = 5000;
+
+/// Where `api/clear` moves the metadata of removed entries, under /var. This is separate from
+/// /var/trash, which the identity map uses as scratch space for deletions.
+const TRASH_DIR: &'static str = "sturdyref-trash";
+
+/// Where a `batch` command puts the metadata of the entries that it removes, under /var, until
+/// it knows that all of them could be taken out of the way.
+const BATCH_DIR: &'static str = "batch";
+
+/// The HTTP methods accepted by each path we serve, along with a summary of what the path is
+/// for. A route ending in '/' matches every path under it.
+const ROUTES: &'static [(&'static str, &'static [&'static str], &'static str)] = &[
+    ("", &["GET"],
+     "The collection's main page."),
+    ("script.js", &["GET"],
+     "The frontend script."),
+    ("style.css", &["GET"],
+     "The frontend stylesheet."),
+    ("description", &["PUT", "DELETE"],
+     "Replaces the collection's description, or restores the default one."),
+    ("avatar/", &["GET"],
+     "A cached avatar image of a collection member."),
+    ("api/description", &["GET"],
+     "The collection's description, as plain text, with an ETag for revalidation."),
+    ("api/settings", &["GET"],
+     "The collection's settings."),
+    ("api/digest", &["GET", "PUT"],
+     "Reads or sets how often the change digest is sent."),
+    ("api/settings/sort", &["PUT"],
+     "Sets the order in which entries are listed."),
+    ("api/settings/powerbox", &["PUT"],
+     "Sets which apps and tags the powerbox offers when adding grains."),
+    ("api/settings/lock", &["PUT"],
+     "Locks (\"true\") or unlocks (\"false\") the collection against changes."),
+    ("api/settings/retention", &["PUT"],
+     "Limits how long the trash, the journal and the replay buffer keep things, given a JSON \
+      object such as {\"trashDays\":30,\"journalEntries\":null,\"replayBroadcasts\":500}."),
+    ("api/settings/default-role", &["PUT"],
+     "Sets the role (\"viewer\", \"commenter\", \"editor\" or \"manager\") that new shares get \
+      by default."),
+    ("api/settings/features", &["PUT"],
+     "Turns features on or off, given a JSON object such as {\"comments\":false}."),
+    ("api/openapi.json", &["GET"],
+     "This description of the API, in OpenAPI format."),
+    ("api/version", &["GET"],
+     "The app, storage schema and websocket protocol versions of this grain."),
+    ("api/powerbox-query", &["GET"],
+     "The powerbox query that the add-grain button should make."),
+    ("audit", &["GET"],
+     "The journal of changes to the collection."),
+    ("api/changes", &["GET"],
+     "The entries added, changed or removed after the journal revision given as since."),
+    ("api/public-url", &["GET"],
+     "Where the read-only page is published when web publishing is on."),
+    ("api/usage", &["GET"],
+     "How many entries and comments each identity has added, and the bytes they take up."),
+    ("api/duplicates", &["GET"],
+     "Groups of entries that hold the same grain, as far as can be told from app and title."),
+    ("debug/state", &["GET"],
+     "Compares the in-memory entries with the ones on disk."),
+    ("healthz", &["GET"],
+     "Reports whether storage and the Sandstorm API are usable."),
+    ("audit.csv", &["GET"],
+     "The journal of changes to the collection, as CSV."),
+    ("export.csv", &["GET"],
+     "Every entry, with its app, adder, date, folder and URL, as CSV."),
+    ("export.html", &["GET"],
+     "The description and every entry with its comments, grouped by folder, as one HTML page."),
+    ("api/bundle", &["GET", "POST"],
+     "Exports the collection as a bundle, or recreates one in this empty collection."),
+    ("api/activity-series", &["GET"],
+     "How many entries were added and removed on each recent day."),
+    ("api/recent", &["GET"],
+     "The most recently added entries."),
+    ("api/views", &["GET"],
+     "The entries in the collection, filtered by folder, sorted by sort, and paged by offset \
+      and limit."),
+    ("api/search", &["GET"],
+     "The entries whose title, comments or URL contain every word of the q parameter. Takes \
+      the same folder, sort, offset and limit parameters as api/views."),
+    ("api/views/", &["GET", "POST"],
+     "Reads the entry count of a nested collection, or comments on an entry."),
+    ("api/bookmarks", &["POST"],
+     "Adds a bookmark entry, hidden from non-editors until `publishAt` if given."),
+    ("api/webhook", &["GET", "DELETE"],
+     "Reads or removes the outgoing webhook."),
+    ("api/webhook/descriptor", &["GET"],
+     "The powerbox descriptor for requesting a webhook target."),
+    ("api/webhook/", &["POST"],
+     "Saves the webhook target named by a powerbox claim token."),
+    ("api/folders", &["POST"],
+     "Creates, renames or removes folders."),
+    ("api/move", &["POST"],
+     "Moves entries into a folder."),
+    ("api/clear", &["POST"],
+     "Removes every entry, after confirmation with a nonce."),
+    ("token/", &["POST"],
+     "Adds the grain named by a powerbox claim token, hidden from non-editors until `publishAt` \
+      if given."),
+    ("api/merge/", &["POST"],
+     "Removes a just-added entry in favor of the older entry named in the body, which holds the \
+      same grain."),
+    ("offer/", &["POST"],
+     "Offers an entry to the user through the powerbox."),
+    ("refresh/", &["POST"],
+     "Re-fetches the view info of an entry."),
+    ("api/opened/", &["POST"],
+     "Records that the user opened an entry."),
+    ("sturdyref/", &["PUT", "DELETE"],
+     "Sets an entry's thumbnail, expiry time, the user's rating of it (1 to 5 stars) or their \
+      upvote, or whether it is private (\"true\" or \"false\"); or removes the entry, the rating \
+      or the upvote."),
+    ("thumbnails/", &["GET"],
+     "An entry's uploaded thumbnail."),
+    ("collection/", &["GET"],
+     "WebDAV view of the entries."),
+    ("var/", &["GET"],
+     "WebDAV view of the grain's storage."),
+];
+
+fn allowed_methods(path: &str) -> Option<&'static [&'static str]> {
+    let (path, _) = router::split(path);
+    for &(route, methods, _) in ROUTES {
+        if router::matches(route, path) {
+            return Some(methods)
+        }
+    }
+    None
+}
+
+/// The permission that a POST, PUT or DELETE of `path` requires, if any. We check it before
+/// charging the request against the caller's rate limit, so that requests that are going to be
+/// refused don't use up the budget. The handlers check again, next to the code that relies on it.
+fn write_permission(method: &str, path: &str) -> Option<Permission> {
+    let entry_part = |suffix: &str| path.starts_with("sturdyref/") && path.ends_with(suffix);
+    match method {
+        "POST" => {
+            if path.starts_with("token/") || path.starts_with("api/merge/") ||
+                path == "api/bookmarks" || path == "api/folders" || path == "api/move"
+            {
+                Some(Permission::Write)
+            } else if path.starts_with("api/webhook/") || path == "api/bundle" ||
+                path == "api/clear"
+            {
+                Some(Permission::Manage)
+            } else if path.starts_with("api/views/") && path.ends_with("/comments") {
+                Some(Permission::Comment)
+            } else {
+                None
+            }
+        }
+        "PUT" => {
+            if entry_part("/rating") || entry_part("/upvote") {
+                Some(Permission::Comment)
+            } else if path == "description" || path.starts_with("sturdyref/") {
+                Some(Permission::Write)
+            } else if path.starts_with("api/settings/") {
+                Some(Permission::Manage)
+            } else {
+                None
+            }
+        }
+        "DELETE" => {
+            if entry_part("/rating") || entry_part("/upvote") {
+                Some(Permission::Comment)
+            } else if path == "api/webhook" || path == "description" {
+                Some(Permission::Manage)
+            } else if path.starts_with("sturdyref/") {
+                Some(Permission::Write)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Fills in the response for a request that no handler accepted: "405 Method Not Allowed" if the
+/// path is one we know about, otherwise "404 Not Found".
+fn fill_in_unrouted(response: web_session::response::Builder, path: &str) {
+    let mut error = response.init_client_error();
+    match allowed_methods(path) {
+        Some(methods) => {
+            error.set_status_code(web_session::response::ClientErrorCode::MethodNotAllowed);
+            error.set_description_html(
+                &escape_html(&format!("allowed methods: {}", methods.join(", "))));
+        }
+        None => {
+            error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+        }
+    }
+}
+
+/// If `path` names a GET route plus a trailing slash, like "api/views/", returns the route so
+/// that we can redirect there.
+fn directory_redirect_target(path: &str) -> Option<&str> {
+    if !path.ends_with('/') {
+        return None
+    }
+    let target = &path[..path.len() - 1];
+    ROUTES.iter()
+        .find(|&&(route, methods, _)| route == target && methods.contains(&"GET"))
+        .map(|_| target)
+}
+
+/// Describes the `api/` routes in OpenAPI 3 format, so that integrators have a contract that
+/// cannot drift from what we actually serve. A route ending in '/' becomes a path with a
+/// `{rest}` parameter.
+fn openapi_json() -> String {
+    let mut paths = Vec::new();
+    for &(route, methods, summary) in ROUTES {
+        if !route.starts_with("api/") { continue }
+        let (path, parameters) = if route.ends_with('/') {
+            (format!("/{}{{rest}}", route),
+             ",\"parameters\":[{\"name\":\"rest\",\"in\":\"path\",\"required\":true,\
+              \"schema\":{\"type\":\"string\"}}]")
+        } else {
+            (format!("/{}", route), "")
+        };
+        let operations: Vec<String> = methods.iter().map(|method| {
+            format!("{}:{{\"summary\":{}{},\"responses\":{{\"200\":{{\"description\":\"OK\"}}}}}}",
+                    json::ToJson::to_json(&method.to_lowercase()),
+                    json::ToJson::to_json(&summary.to_string()),
+                    parameters)
+        }).collect();
+        paths.push(format!("{}:{{{}}}", json::ToJson::to_json(&path), operations.join(",")));
+    }
+    format!("{{\"openapi\":\"3.0.0\",\"info\":{{\"title\":\"Collections\",\"version\":{}}},\
+             \"paths\":{{{}}}}}",
+            json::ToJson::to_json(&env!("CARGO_PKG_VERSION").to_string()),
+            paths.join(","))
+}
+
+const DEFAULT_RECENT_LIMIT: usize = 10;
+const MAX_RECENT_LIMIT: usize = 100;
+
+const DEFAULT_ACTIVITY_DAYS: u64 = 30;
+const MAX_ACTIVITY_DAYS: u64 = 366;
+
+/// Powerbox descriptors with more tags than this are rejected outright.
+const MAX_POWERBOX_TAGS: u32 = 8;
+
+/// Longest request path we are willing to look at.
+const MAX_PATH_LENGTH: usize = 4096;
+
+fn require_canonical_path(path: &str) -> Result<(), Error> {
+    // Require that the path doesn't contain "." or ".." or consecutive slashes, to prevent path
+    // injection attacks.
+    //
+    // Note that such attacks wouldn't actually accomplish much since everything outside /var
+    // is a read-only filesystem anyway, containing the app package contents which are non-secret.
+    //
+    // We also look at each component after percent-decoding it, so that things like "%2e%2e"
+    // or "a%2fb" can't sneak a traversal past a handler that decodes its arguments.
+
+    if path.len() > MAX_PATH_LENGTH {
+        return Err(Error::failed(format!("path is too long ({} bytes)", path.len())));
+    }
+
+    // The query string is not a filesystem path; handlers decode it separately.
+    let path = router::split(path).0;
+
+    if path.contains('\\') || path.contains('\0') {
+        return Err(Error::failed(format!("non-canonical path: {:?}", path)));
+    }
+
+    for (idx, component) in path.split_terminator("/").enumerate() {
+        if component == "." || component == ".." || (component == "" && idx > 0) {
+            return Err(Error::failed(format!("non-canonical path: {:?}", path)));
+        }
+
+        let decoded = percent_encoding::percent_decode(component.as_bytes()).collect::<Vec<u8>>();
+        if &decoded[..] == b"." || &decoded[..] == b".." ||
+            decoded.iter().any(|&b| b == b'/' || b == b'\\' || b == 0)
+        {
+            return Err(Error::failed(format!("non-canonical path: {:?}", path)));
+        }
+    }
+    Ok(())
+}
+
+// Indices into journal::ACTIVITY_EVENT_TYPES.
+const ADD_GRAIN_ACTIVITY_INDEX: u16 = 0;
+const REMOVE_GRAIN_ACTIVITY_INDEX: u16 = 1;
+const EDIT_DESCRIPTION_ACTIVITY_INDEX: u16 = 2;
+
+/// Request header that must carry the session's CSRF token on every state-changing request.
+/// Sandstorm only passes through request headers with the `x-sandstorm-app-` prefix.
+const CSRF_TOKEN_HEADER: &'static str = "x-sandstorm-app-csrf-token";
+
+fn random_hex_string(num_bytes: usize) -> ::std::io::Result<String> {
+    use std::io::Read;
+    let mut bytes = vec![0u8; num_bytes];
+    try!(try!(::std::fs::File::open("/dev/urandom")).read_exact(&mut bytes));
+    Ok(hex::ToHex::to_hex(&bytes[..]))
+}
+
+/// The permissions declared in `UiView::get_view_info()`. Each discriminant is the permission's
+/// index in that list, which is also its bit in a user's PermissionSet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Permission {
+    Write = 0,
+    Manage = 1,
+    Comment = 2,
+}
+
+impl Permission {
+    fn name(&self) -> &'static str {
+        match *self {
+            Permission::Write => "write",
+            Permission::Manage => "manage",
+            Permission::Comment => "comment",
+        }
+    }
+}
+
+/// The permissions held by one session. All permission checks, whether for HTTP requests or
+/// websocket messages, go through `require()`.
+#[derive(Clone, Debug)]
+struct Permissions {
+    bits: Vec<bool>,
+
+    /// The language in which `require()` explains failures.
+    language: Language,
+}
+
+impl Permissions {
+    fn from_reader(reader: ::capnp::primitive_list::Reader<bool>,
+                   language: Language) -> Permissions {
+        Permissions {
+            bits: (0..reader.len()).map(|idx| reader.get(idx)).collect(),
+            language: language,
+        }
+    }
+
+    fn has(&self, permission: Permission) -> bool {
+        self.bits.get(permission as usize).cloned().unwrap_or(false)
+    }
+
+    fn require(&self, permission: Permission) -> Result<(), Error> {
+        if self.has(permission) {
+            Ok(())
+        } else {
+            let message = Message::PermissionRequired(permission.name());
+            Err(Error::failed(message.localize(self.language)))
+        }
+    }
+}
+
+/// Something at a path in the WebDAV view of the grain. The collection's entries appear under
+/// "collection/" as internet shortcuts, grouped by folder; managers also see the raw files under "var/".
+enum DavNode {
+    Root,
+    Folder(Option<String>),
+    Entry(String),
+    File(::std::path::PathBuf),
+}
+
+const INTERNET_SHORTCUT_MIME_TYPE: &'static str = "application/internet-shortcut";
+
+/// Directories under /var that we keep out of the WebDAV view, because the file names in them
+/// are capability tokens or because their contents are transient.
+const DAV_HIDDEN_VAR_DIRS: &'static [&'static str] =
+    &["sturdyrefs", "sturdyref-trash", "thumbnails", "tmp"];
+
+pub struct WebSession {
+    permissions: Permissions,
+    csrf_token: String,
+    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+    context: session_context::Client,
+    saved_ui_views: SavedUiViewSet,
+    identity_id: Option<String>,
+    viewer: Viewer,
+
+    /// The best match between the user's acceptable languages and the ones we support.
+    language: Language,
+
+    /// The URL at which the grain is mapped for this session, without a trailing slash, e.g.
+    /// "https://ui-abcdef.sandstorm.example". Redirect targets are built from this.
+    base_path: String,
+
+    /// Nonce that must be echoed back to confirm an `api/clear` request, and the time at which
+    /// it expires.
+    clear_nonce: Option<(String, u64)>,
+
+    /// Shared with forks of this session, and held weakly by work that should stop once the user
+    /// is gone, like saving a grain picked in the powerbox.
+    lifetime: Rc<()>,
+}
+
+impl WebSession {
+    pub fn new(user_info: user_info::Reader,
+               context: session_context::Client,
+               params: web_session::params::Reader,
+               sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+               mut saved_ui_views: SavedUiViewSet)
+               -> ::capnp::Result<WebSession>
+    {
+        let acceptable_languages = try!(params.get_acceptable_languages());
+        let language = Language::negotiate(
+            acceptable_languages.iter().filter_map(|tag| tag.ok()));
+        let collation = Collation::negotiate(
+            acceptable_languages.iter().filter_map(|tag| tag.ok()));
+        let permissions = Permissions::from_reader(try!(user_info.get_permissions()), language);
+
+        let identity_id = if user_info.has_identity_id() {
+            Some(hex::ToHex::to_hex(try!(user_info.get_identity_id())))
+        } else {
+            None
+        };
+
+        let previous_visit = match identity_id {
+            Some(ref id) => saved_ui_views.visit(id),
+            None => None,
+        };
+        let display_name = if user_info.has_display_name() {
+            Some(try!(try!(user_info.get_display_name()).get_default_text()).to_string())
+        } else {
+            None
+        };
+
+        saved_ui_views.inner.borrow_mut().mail_context =
+            Some(hack_session_context::Client { client: context.client.clone() });
+
+        let can_write = permissions.has(Permission::Write);
+        Ok(WebSession {
+            permissions: permissions,
+            csrf_token: try!(random_hex_string(16)),
+            sandstorm_api: sandstorm_api,
+            context: context,
+            saved_ui_views: saved_ui_views,
+            viewer: Viewer {
+                identity_id: identity_id.clone(),
+                display_name: display_name,
+                previous_visit: previous_visit,
+                collation: collation,
+                can_write: can_write,
+            },
+            identity_id: identity_id,
+            language: language,
+            base_path: try!(params.get_base_path()).trim_right_matches('/').to_string(),
+            clear_nonce: None,
+            lifetime: Rc::new(()),
+        })
+
+        // `UserInfo` is defined in `sandstorm/grain.capnp` and contains info like:
+        // - A stable ID for the user, so you can correlate sessions from the same user.
+        // - The user's display name, e.g. "Mark Miller", useful for identifying the user to other
+        //   users.
+        // - The user's permissions (seen above).
+
+        // `WebSession::Params` is defined in `sandstorm/web-session.capnp` and contains info like:
+        // - The hostname where the grain was mapped for this user. Every time a user opens a grain,
+        //   it is mapped at a new random hostname for security reasons.
+        // - The user's User-Agent and Accept-Languages headers.
+
+        // `SessionContext` is defined in `sandstorm/grain.capnp` and implements callbacks for
+        // sharing/access control and service publishing/discovery.
+    }
+}
+
+impl ui_session::Server for WebSession {}
+
+impl web_session::Server for WebSession {
+    fn get(&mut self,
+           params: web_session::GetParams,
+           mut results: web_session::GetResults)
+	-> Promise<(), Error>
+    {
+        // HTTP GET request.
+        let raw_path = pry!(pry!(params.get()).get_path());
+        pry!(require_canonical_path(raw_path));
+        let (path, query) = router::split(raw_path);
+
+        if path == "" {
+            let text = format!("<!DOCTYPE html>\
+                       <html lang=\"{}\"><head>\
+                       <meta name=\"csrf-token\" content=\"{}\">\
+                       <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\">\
+                       <script type=\"text/javascript\" src=\"script.js\" async></script>
+                       </head><body><div id=\"main\"></div></body></html>",
+                       self.language.tag(),
+                       self.csrf_token);
+            let mut response = results.get();
+            set_additional_headers(response.borrow(), &[
+                ("Content-Security-Policy", &self.content_security_policy()[..]),
+            ]);
+            let mut content = response.init_content();
+            content.set_mime_type("text/html; charset=UTF-8");
+            content.init_body().set_bytes(text.as_bytes());
+            Promise::ok(())
+        } else if path == "script.js" {
+            self.read_asset("script.js", results, "text/javascript; charset=UTF-8")
+        } else if path == "style.css" {
+            self.read_asset("style.css", results, "text/css; charset=UTF-8")
+        } else if path == "api/recent" {
+            match query.count("limit", DEFAULT_RECENT_LIMIT, MAX_RECENT_LIMIT) {
+                Ok(limit) => {
+                    let text = self.saved_ui_views.recent_json(&self.viewer, limit);
+                    fill_in_text_content(results.get(), "application/json", &text);
+                }
+                Err(e) => fill_in_bad_query(results.get(), e),
+            }
+            Promise::ok(())
+        } else if path == "api/changes" {
+            let result = query.parse::<usize>("since")
+                .and_then(|since| {
+                    self.saved_ui_views.changes_json(since.unwrap_or(0), &self.viewer.audience())
+                });
+            match result {
+                Ok(text) => fill_in_text_content(results.get(), "application/json", &text),
+                Err(e) => fill_in_bad_query(results.get(), e),
+            }
+            Promise::ok(())
+        } else if path == "api/search" {
+            let result = query.required("q").and_then(|q| {
+                let options = try!(ListOptions::parse(&query));
+                Ok(self.saved_ui_views.search_json(&self.viewer, q, &options))
+            });
+            match result {
+                Ok(text) => fill_in_text_content(results.get(), "application/json", &text),
+                Err(e) => fill_in_bad_query(results.get(), e),
+            }
+            Promise::ok(())
+        } else if path == "api/activity-series" {
+            match query.count("days", DEFAULT_ACTIVITY_DAYS, MAX_ACTIVITY_DAYS) {
+                Ok(days) => {
+                    let text =
+                        self.saved_ui_views.activity_series_json(days, &self.viewer.audience());
+                    fill_in_text_content(results.get(), "application/json", &text);
+                }
+                Err(e) => fill_in_bad_query(results.get(), e),
+            }
+            Promise::ok(())
+        } else if path == "api/views" {
+            let result = match query.get("group") {
+                None => ListOptions::parse(&query)
+                    .map(|options| self.saved_ui_views.views_json(&self.viewer, &options)),
+                Some("app") => Ok(self.saved_ui_views.views_by_app_json(&self.viewer)),
+                Some(g) => Err(Error::failed(format!("unsupported grouping: {}", g))),
+            };
+            match result {
+                Ok(text) => fill_in_text_content(results.get(), "application/json", &text),
+                Err(e) => fill_in_bad_query(results.get(), e),
+            }
+            Promise::ok(())
+        } else if path.starts_with("api/views/") && path.ends_with("/entry-count") {
+            let token = match router::between(&path, "api/views/", "/entry-count") {
+                Some(token) => token.to_string(),
+                None => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+            // Only collections have an entry count.
+            let is_collection = self.can_see(&token) &&
+                self.saved_ui_views.inner.borrow().get_saved_data(&token)
+                    .map_or(false, |data| data.is_collection);
+            if !is_collection {
+                fill_in_not_found(results.get());
+                return Promise::ok(())
+            }
+
+            Promise::from_future(self.saved_ui_views.fetch_entry_count(&token).map(move |count| {
+                let text = format!("{{\"count\":{}}}", count);
+                fill_in_text_content(results.get(), "application/json", &text);
+            }))
+        } else if path == "api/webhook" || path == "api/webhook/descriptor" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+            } else if let Err(e) =
+                self.saved_ui_views.require_feature(Feature::Webhooks, self.language)
+            {
+                fill_in_feature_disabled(results.get(), e);
+            } else if path == "api/webhook" {
+                let url = self.saved_ui_views.inner.borrow().webhooks.url().map(|u| u.to_string());
+                let text = format!("{{\"url\":{}}}", optional_string_to_json(&url));
+                fill_in_text_content(results.get(), "application/json", &text);
+            } else {
+                let descriptor = query.required("url")
+                    .and_then(|url| webhook_powerbox_descriptor(url));
+                match descriptor {
+                    Ok(d) => fill_in_text_content(results.get(), "text/plain", &d),
+                    Err(e) => fill_in_bad_query(results.get(), e),
+                }
+            }
+            Promise::ok(())
+        } else if path.starts_with("avatar/") {
+            let identity_id = &path["avatar/".len()..];
+            let context = hack_session_context::Client { client: self.context.client.clone() };
+            Promise::from_future(self.saved_ui_views.avatar(identity_id, context).then(move |result| {
+                match result {
+                    Ok(avatar) => {
+                        let mut response = results.get();
+                        set_additional_headers(response.borrow(), &[NOSNIFF_HEADER]);
+                        let mut content = response.init_content();
+                        content.set_status_code(web_session::response::SuccessCode::Ok);
+                        content.set_mime_type(&avatar.mime_type);
+                        content.init_body().set_bytes(&avatar.content);
+                    }
+                    Err(_) => {
+                        let mut error = results.get().init_client_error();
+                        error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                    }
+                }
+                Ok(())
+            }))
+        } else if path.starts_with("thumbnails/") {
+            let token = &path["thumbnails/".len()..];
+            let thumbnail = if self.can_see(token) {
+                self.saved_ui_views.thumbnail(token)
+            } else {
+                Ok(None)
+            };
+            match thumbnail {
+                Ok(Some((mime_type, content))) => {
+                    let mut response = results.get();
+                    set_additional_headers(response.borrow(), &[NOSNIFF_HEADER]);
+                    let mut body = response.init_content();
+                    body.set_status_code(web_session::response::SuccessCode::Ok);
+                    body.set_mime_type(&mime_type);
+                    body.init_body().set_bytes(&content);
+                }
+                _ => {
+                    let mut error = results.get().init_client_error();
+                    error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                }
+            }
+            Promise::ok(())
+        } else if path == "api/digest" {
+            let frequency = self.identity_id.as_ref().and_then(|id| {
+                self.saved_ui_views.inner.borrow().digests.get(id)
+                    .map(|s| ::digest::frequency_name(s.frequency).to_string())
+            });
+            let text = format!("{{\"frequency\":{}}}", optional_string_to_json(&frequency));
+            fill_in_text_content(results.get(), "application/json", &text);
+            Promise::ok(())
+        } else if path == "api/openapi.json" {
+            fill_in_text_content(results.get(), "application/json", &openapi_json());
+            Promise::ok(())
+        } else if path == "api/bundle" {
+            let text = self.saved_ui_views.bundle_json();
+            let file_name = self.saved_ui_views.export_file_name(None, "json");
+            fill_in_download(results.get(), "application/json", &text, &file_name);
+            Promise::ok(())
+        } else if path == "api/version" {
+            let text = format!("{{\"app\":{},\"appMarketingVersion\":{},\"schema\":{},\
+                                \"websocketProtocol\":{}}}",
+                               APP_VERSION,
+                               json::ToJson::to_json(&env!("CARGO_PKG_VERSION").to_string()),
+                               SCHEMA_VERSION,
+                               WEBSOCKET_PROTOCOL_VERSION);
+            fill_in_text_content(results.get(), "application/json", &text);
+            Promise::ok(())
+        } else if path == "healthz" {
+            let text = self.saved_ui_views.health_json();
+            fill_in_text_content(results.get(), "application/json", &text);
+            Promise::ok(())
+        } else if path == "api/public-url" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+            if let Err(e) = self.saved_ui_views.require_feature(Feature::WebPublishing,
+                                                                self.language) {
+                fill_in_feature_disabled(results.get(), e);
+                return Promise::ok(())
+            }
+            // Sandstorm serves /var/www at an address derived from the grain's public ID.
+            let context = hack_session_context::Client { client: self.context.client.clone() };
+            Promise::from_future(context.get_public_id_request().send().promise.and_then(
+                move |response| {
+                    let response = pry!(response.get());
+                    let text = format!("{{\"url\":{},\"hostname\":{}}}",
+                                       json::ToJson::to_json(pry!(response.get_auto_url())),
+                                       json::ToJson::to_json(pry!(response.get_hostname())));
+                    fill_in_text_content(results.get(), "application/json", &text);
+                    Promise::ok(())
+                }))
+        } else if path == "api/usage" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+            Promise::from_future(self.saved_ui_views.usage_json().map(move |text| {
+                fill_in_text_content(results.get(), "application/json", &text);
+            }))
+        } else if path == "api/duplicates" {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+            } else {
+                let text = self.saved_ui_views.duplicates_json(&self.viewer.audience());
+                fill_in_text_content(results.get(), "application/json", &text);
+            }
+            Promise::ok(())
+        } else if path == "debug/state" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+            } else {
+                let audience = self.viewer.audience();
+                let text = pry!(self.saved_ui_views.debug_state_json(&audience));
+                fill_in_text_content(results.get(), "application/json", &text);
+            }
+            Promise::ok(())
+        } else if path == "api/description" {
+            // Lets an embedding page show the description without opening a websocket. The
+            // client can revalidate with If-None-Match, and gets "304 Not Modified" if unchanged.
+            let description = self.saved_ui_views.inner.borrow().description.clone();
+            let etag = description_etag(&description);
+            let context = pry!(pry!(params.get()).get_context());
+            if !pry!(precondition_holds(context, &etag)) {
+                results.get().init_precondition_failed().init_matching_e_tag().set_value(&etag);
+                return Promise::ok(())
+            }
+            let mut content = results.get().init_content();
+            content.set_status_code(web_session::response::SuccessCode::Ok);
+            content.set_mime_type("text/plain; charset=UTF-8");
+            content.borrow().init_e_tag().set_value(&etag);
+            content.init_body().set_bytes(description.as_bytes());
+            Promise::ok(())
+        } else if path == "api/settings" {
+            let text = {
+                let inner = self.saved_ui_views.inner.borrow();
+                let settings = &inner.settings;
+                let app_ids: Vec<String> = settings.allowed_app_ids.iter()
+                    .map(|id| format!("{}", json::ToJson::to_json(id))).collect();
+                let tag_ids: Vec<String> = settings.required_tag_ids.iter()
+                    .map(|id| format!("\"{:x}\"", id)).collect();
+                format!("{{\"sortOrder\":\"{}\",\"allowedAppIds\":[{}],\"requiredTagIds\":[{}],\
+                         \"locked\":{},\"features\":{},\"defaultRole\":\"{}\",\"retention\":{}}}",
+                        sort_order_name(settings.sort_order), app_ids.join(","), tag_ids.join(","),
+                        settings.locked, features_json(&settings.features),
+                        role_name(settings.default_role), retention_json(&settings.retention))
+            };
+            fill_in_text_content(results.get(), "application/json", &text);
+            Promise::ok(())
+        } else if path == "api/powerbox-query" {
+            let tag_ids = self.saved_ui_views.inner.borrow().settings.required_tag_ids.clone();
+            let descriptor = pry!(ui_view_powerbox_descriptor(&tag_ids));
+            fill_in_text_content(results.get(), "text/plain", &descriptor);
+            Promise::ok(())
+        } else if path == "audit" || path == "audit.csv" {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+            } else if path == "audit" {
+                let text = self.saved_ui_views.audit_json(&self.viewer.audience());
+                fill_in_text_content(results.get(), "application/json", &text);
+            } else {
+                let text = self.saved_ui_views.audit_csv(&self.viewer.audience());
+                let file_name = self.saved_ui_views.export_file_name(Some("audit"), "csv");
+                fill_in_download(results.get(), "text/csv; charset=UTF-8", &text, &file_name);
+            }
+            Promise::ok(())
+        } else if path == "export.csv" {
+            let file_name = self.saved_ui_views.export_file_name(None, "csv");
+            let export = self.saved_ui_views.export_csv(self.base_path.clone(), &self.viewer);
+            Promise::from_future(export.map(
+                move |text| {
+                    fill_in_download(results.get(), "text/csv; charset=UTF-8", &text, &file_name);
+                }))
+        } else if path == "export.html" {
+            let file_name = self.saved_ui_views.export_file_name(None, "html");
+            let export = self.saved_ui_views.export_html(self.base_path.clone(), &self.viewer);
+            Promise::from_future(export.map(
+                move |text| {
+                    fill_in_download(results.get(), "text/html; charset=UTF-8", &text,
+                                     &file_name);
+                }))
+        } else if path.starts_with("collection/") || path.starts_with("var/") {
+            match self.resolve_dav_path(path) {
+                Some(DavNode::Entry(token)) => {
+                    let text = self.internet_shortcut(&token);
+                    fill_in_text_content(results.get(), INTERNET_SHORTCUT_MIME_TYPE, &text);
+                }
+                Some(DavNode::File(ref file_path)) if !file_path.is_dir() => {
+                    use std::io::Read;
+                    let mut bytes = Vec::new();
+                    pry!(pry!(::std::fs::File::open(file_path)).read_to_end(&mut bytes));
+                    let mut content = results.get().init_content();
+                    content.set_mime_type("application/octet-stream");
+                    content.init_body().set_bytes(&bytes);
+                }
+                Some(_) => {
+                    // Directories have no content of their own; PROPFIND lists them.
+                    let mut error = results.get().init_client_error();
+                    error.set_status_code(web_session::response::ClientErrorCode::MethodNotAllowed);
+                    error.set_description_html("directories can't be downloaded");
+                }
+                None => {
+                    let mut error = results.get().init_client_error();
+                    error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                }
+            }
+            Promise::ok(())
+        } else if let Some(target) = directory_redirect_target(path) {
+            let query_string = raw_path.find('?').map(|idx| &raw_path[idx..]).unwrap_or("");
+            let mut redirect = results.get().init_redirect();
+            redirect.set_is_permanent(true);
+            redirect.set_switch_to_get(true);
+            redirect.set_location(&format!("{}/{}{}", self.base_path, target, query_string));
+            Promise::ok(())
+        } else {
+            fill_in_unrouted(results.get(), path);
+            Promise::ok(())
+        }
+    }
+
+    fn post(&mut self,
+            params: web_session::PostParams,
+            mut results: web_session::PostResults)
+            -> Promise<(), Error>
+    {
+        let (path, query) = {
+            let path = pry!(pry!(params.get()).get_path());
+            pry!(require_canonical_path(path));
+            let (path, query) = router::split(path);
+            (path.to_string(), query)
+        };
+
+        if let Err(e) = self.require_csrf_token(pry!(pry!(params.get()).get_context())) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+
+        if let Some(permission) = write_permission("POST", &path) {
+            if let Err(e) = self.permissions.require(permission) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+        }
+
+        if let Err(e) = self.saved_ui_views.check_write_rate(&self.identity_id, self.language) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+
+        // Opening and refreshing entries reads the collection without changing it.
+        let changes_collection = !(path.starts_with("offer/") || path.starts_with("api/opened/") ||
+                                   path.starts_with("refresh/"));
+        if changes_collection {
+            if let Err(e) = self.saved_ui_views.require_unlocked(self.language) {
+                fill_in_conflict(results.get(), e);
+                return Promise::ok(())
+            }
+        }
+
+        if path.starts_with("token/") {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+            // As for bookmarks, `publishAt` schedules the entry.
+            let publish_at = match query.parse::<u64>("publishAt") {
+                Ok(publish_at) => publish_at,
+                Err(e) => {
+                    fill_in_bad_query(results.get(), e);
+                    return Promise::ok(())
+                }
+            };
+            self.receive_request_token(path[6..].to_string(), publish_at, params, results)
+        } else if path.starts_with("api/merge/") {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+            let token = path["api/merge/".len()..].to_string();
+            let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
+            let existing = match ::std::str::from_utf8(content) {
+                Ok(existing) => existing.trim().to_string(),
+                Err(e) => {
+                    fill_in_client_error(results, Error::failed(format!("{}", e)));
+                    return Promise::ok(())
+                }
+            };
+            if !self.can_see(&token) || !self.can_see(&existing) {
+                fill_in_not_found(results.get());
+                return Promise::ok(())
+            }
+            if !self.saved_ui_views.same_grain(&token, &existing) {
+                fill_in_client_error(results, Error::failed(
+                    format!("{} and {} don't hold the same grain", token, existing)));
+                return Promise::ok(())
+            }
+            self.merge_duplicate(token, existing, results)
+        } else if path.starts_with("offer/") {
+            let token = path[6..].to_string();
+            if !self.can_see(&token) {
+                fill_in_not_found(results.get());
+                return Promise::ok(())
+            }
+            let title = match self.saved_ui_views.inner.borrow().get_saved_data(&token) {
+                None => {
+                    let mut error = results.get().init_client_error();
+                    error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                    return Promise::ok(())
+                }
+                Some(saved_ui_view) if saved_ui_view.bookmark.is_some() => {
+                    fill_in_client_error(results, Error::failed(
+                        "bookmarks are opened by following their URL".into()));
+                    return Promise::ok(())
+                }
+                Some(saved_ui_view) => saved_ui_view.title.to_string(),
+            };
+
+            self.offer_ui_view(token, title, params, results)
+
+        } else if path.starts_with("api/opened/") {
+            let token = &path[11..];
+            if !self.can_see(token) {
+                fill_in_not_found(results.get());
+                return Promise::ok(())
+            }
+            match self.saved_ui_views.record_open(token) {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    fill_in_client_error(results, e);
+                }
+            }
+            Promise::ok(())
+        } else if path.starts_with("api/views/") && path.ends_with("/comments") {
+            let token = match router::between(&path, "api/views/", "/comments") {
+                Some(token) => token.to_string(),
+                None => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+            if let Err(e) = self.permissions.require(Permission::Comment) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+            if let Err(e) = self.saved_ui_views.require_feature(Feature::Comments, self.language) {
+                fill_in_feature_disabled(results.get(), e);
+                return Promise::ok(())
+            }
+            if !self.can_see(&token) {
+                fill_in_not_found(results.get());
+                return Promise::ok(())
+            }
+
+            // Editors may pass the version they last saw, to avoid overwriting a newer edit.
+            let expected = match query.parse::<u64>("version") {
+                Ok(expected) => expected,
+                Err(e) => {
+                    fill_in_bad_query(results.get(), e);
+                    return Promise::ok(())
+                }
+            };
+            if let Err(e) = self.saved_ui_views.require_version(&token, expected) {
+                fill_in_conflict(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
+            match self.saved_ui_views.add_comment(&token, content, self.identity_id.clone()) {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    fill_in_client_error(results, e);
+                }
+            }
+            Promise::ok(())
+        } else if path.starts_with("api/webhook/") {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+            if let Err(e) = self.saved_ui_views.require_feature(Feature::Webhooks, self.language) {
+                fill_in_feature_disabled(results.get(), e);
+                return Promise::ok(())
+            }
+            self.receive_webhook_token(path["api/webhook/".len()..].to_string(), params, results)
+        } else if path == "api/bookmarks" {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            // An optional `publishAt`, in milliseconds since the unix epoch, hides the entry from
+            // non-editors until then.
+            let publish_at = match query.parse::<u64>("publishAt") {
+                Ok(publish_at) => publish_at,
+                Err(e) => {
+                    fill_in_bad_query(results.get(), e);
+                    return Promise::ok(())
+                }
+            };
+            let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
+            let result = ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(parse_bookmark)
+                .and_then(|(title, bookmark)| {
+                    let token = try!(random_hex_string(16));
+                    self.saved_ui_views.insert(token, title, self.identity_id.clone(),
+                                               Some(bookmark), publish_at)
+                });
+            match result {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    fill_in_client_error(results, e);
+                }
+            }
+            Promise::ok(())
+        } else if path == "api/bundle" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
+            let result = ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(|text| self.saved_ui_views.apply_bundle(text, self.identity_id.clone()));
+            match result {
+                Ok(text) => fill_in_text_content(results.get(), "application/json", &text),
+                Err(e) => fill_in_client_error(results, e),
+            }
+            Promise::ok(())
+        } else if path == "api/folders" || path == "api/move" {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
+            let result = if path == "api/folders" {
+                match ::std::str::from_utf8(content) {
+                    Ok(name) => self.saved_ui_views.create_folder(name, self.identity_id.clone()),
+                    Err(e) => Err(Error::failed(format!("{}", e))),
+                }
+            } else {
+                let args = ::std::str::from_utf8(content)
+                    .map_err(|e| Error::failed(format!("{}", e)))
+                    .and_then(|text| json::Json::from_str(text)
+                              .map_err(|e| Error::failed(format!("{}", e))))
+                    .and_then(|args| parse_move_args(&args));
+                match args {
+                    Ok(args) => {
+                        if let Err(e) = self.saved_ui_views.require_versions(&args.versions) {
+                            fill_in_conflict(results.get(), e);
+                            return Promise::ok(())
+                        }
+                        self.saved_ui_views.move_entries(&args.tokens, args.folder,
+                                                         self.identity_id.clone())
+                    }
+                    Err(e) => Err(e),
+                }
+            };
+
+            match result {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    fill_in_client_error(results, e);
+                }
+            }
+            Promise::ok(())
+        } else if path == "api/clear" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            // Clearing takes two requests: the first returns a nonce, and the second performs
+            // the clear only if it echoes that nonce back as `?confirm=`.
+            let now = self.saved_ui_views.inner.borrow().clock.now_millis();
+            let confirmed = match (query.get("confirm"), self.clear_nonce.take()) {
+                (Some(given), Some((ref expected, expires))) => {
+                    given == &expected[..] && now < expires
+                }
+                _ => false,
+            };
+            if !confirmed {
+                let nonce = pry!(random_hex_string(16));
+                self.clear_nonce = Some((nonce.clone(), now + CLEAR_NONCE_LIFETIME_MILLIS));
+                fill_in_text_content(results.get(), "application/json",
+                                     &format!("{{\"confirm\":\"{}\"}}", nonce));
+                return Promise::ok(())
+            }
+
+            Promise::from_future(self.saved_ui_views.clear(self.identity_id.clone()).map(move |()| {
+                results.get().init_no_content();
+            }))
+        } else if path.starts_with("refresh/") {
+            let token = path[8..].to_string();
+            match SavedUiViewSet::retrieve_view_info(&self.saved_ui_views, token) {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    fill_in_client_error(results, e);
+                }
+            }
+            Promise::ok(())
+        } else {
+            fill_in_unrouted(results.get(), &path);
+            Promise::ok(())
+        }
+    }
+
+    fn put(&mut self,
+           params: web_session::PutParams,
+           mut results: web_session::PutResults)
+	-> Promise<(), Error>
+    {
+        // HTTP PUT request.
+
+        let params = pry!(params.get());
+        let path = pry!(params.get_path());
+        pry!(require_canonical_path(path));
+        let (path, query) = router::split(path);
+
+        if let Err(e) = self.require_csrf_token(pry!(params.get_context())) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+
+        if let Some(permission) = write_permission("PUT", path) {
+            if let Err(e) = self.permissions.require(permission) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+        }
+
+        if let Err(e) = self.saved_ui_views.check_write_rate(&self.identity_id, self.language) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+
+        // Managers must still be able to unlock a locked collection.
+        if path != "api/settings/lock" {
+            if let Err(e) = self.saved_ui_views.require_unlocked(self.language) {
+                fill_in_conflict(results.get(), e);
+                return Promise::ok(())
+            }
+        }
+
+        if path == "description" {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(params.get_content()).get_content());
+            pry!(self.saved_ui_views.update_description(content, self.identity_id.clone()));
             let mut req = self.context.activity_request();
             req.get().init_event().set_type(EDIT_DESCRIPTION_ACTIVITY_INDEX);
             Promise::from_future(req.send().promise.map(move |_| {
                 results.get().init_no_content();
             }))
+        } else if path.starts_with("sturdyref/") && path.ends_with("/expires") {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let token = match router::between(&path, "sturdyref/", "/expires") {
+                Some(token) if self.can_see(token) => token,
+                _ => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+
+            // Editors may pass the version they last saw, to avoid overwriting a newer edit.
+            let expected = match query.parse::<u64>("version") {
+                Ok(expected) => expected,
+                Err(e) => {
+                    fill_in_bad_query(results.get(), e);
+                    return Promise::ok(())
+                }
+            };
+            if let Err(e) = self.saved_ui_views.require_version(token, expected) {
+                fill_in_conflict(results.get(), e);
+                return Promise::ok(())
+            }
+
+            // The body is a time in milliseconds since the unix epoch, or empty for "never".
+            let content = pry!(pry!(params.get_content()).get_content());
+            let expires = match ::std::str::from_utf8(content).map(|t| t.trim()) {
+                Ok("") => Ok(None),
+                Ok(text) => text.parse::<u64>().map(Some)
+                    .map_err(|e| Error::failed(format!("invalid expiry time: {}", e))),
+                Err(e) => Err(Error::failed(format!("{}", e))),
+            };
+            match expires.and_then(|t| {
+                self.saved_ui_views.set_expiry(token, t, self.identity_id.clone())
+            }) {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                }
+            }
+            Promise::ok(())
+        } else if path.starts_with("sturdyref/") && path.ends_with("/rating") {
+            let token = match router::between(&path, "sturdyref/", "/rating") {
+                Some(token) => token,
+                None => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+            let content = pry!(pry!(params.get_content()).get_content());
+            let stars = match ::std::str::from_utf8(content).ok()
+                .and_then(|text| text.trim().parse::<u8>().ok())
+            {
+                Some(stars) => stars,
+                None => {
+                    results.get().init_client_error()
+                        .set_description_html("expected a rating of 1 to 5 stars");
+                    return Promise::ok(())
+                }
+            };
+            self.react(token, Reaction::Rate(Some(stars)), results.get());
+            Promise::ok(())
+        } else if path.starts_with("sturdyref/") && path.ends_with("/upvote") {
+            let token = match router::between(&path, "sturdyref/", "/upvote") {
+                Some(token) => token,
+                None => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+            self.react(token, Reaction::Upvote(true), results.get());
+            Promise::ok(())
+        } else if path.starts_with("sturdyref/") && path.ends_with("/private") {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let token = match router::between(&path, "sturdyref/", "/private") {
+                Some(token) if self.can_see(token) => token,
+                _ => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+            let content = pry!(pry!(params.get_content()).get_content());
+            let private = match ::std::str::from_utf8(content).map(|text| text.trim()) {
+                Ok("true") => true,
+                Ok("false") => false,
+                _ => {
+                    results.get().init_client_error()
+                        .set_description_html("expected \"true\" or \"false\"");
+                    return Promise::ok(())
+                }
+            };
+            let actor = match self.identity_id {
+                Some(ref id) => id.clone(),
+                None => {
+                    fill_in_forbidden(results.get(), Error::failed(
+                        "only whoever added an entry can make it private".into()));
+                    return Promise::ok(())
+                }
+            };
+            match self.saved_ui_views.set_private(token, private, actor) {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                }
+            }
+            Promise::ok(())
+        } else if path.starts_with("sturdyref/") && path.ends_with("/thumbnail") {
+            if let Err(e) = self.permissions.require(Permission::Write) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let token = match router::between(&path, "sturdyref/", "/thumbnail") {
+                Some(token) if self.can_see(token) => token,
+                _ => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+
+            // Editors may pass the version they last saw, to avoid overwriting a newer edit.
+            let expected = match query.parse::<u64>("version") {
+                Ok(expected) => expected,
+                Err(e) => {
+                    fill_in_bad_query(results.get(), e);
+                    return Promise::ok(())
+                }
+            };
+            if let Err(e) = self.saved_ui_views.require_version(token, expected) {
+                fill_in_conflict(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(params.get_content());
+            let mime_type = pry!(content.get_mime_type());
+            let bytes = pry!(content.get_content());
+            if let Err(e) = ::thumbnails::validate(mime_type, bytes) {
+                let mut error = results.get().init_client_error();
+                error.set_status_code(web_session::response::ClientErrorCode::UnsupportedMediaType);
+                error.set_description_html(&escape_html(&format!("{}", e)));
+                return Promise::ok(())
+            }
+
+            pry!(self.saved_ui_views.set_thumbnail(token, mime_type, bytes, self.identity_id.clone()));
+            results.get().init_no_content();
+            Promise::ok(())
+        } else if path == "api/digest" {
+            let identity_id = match self.identity_id {
+                Some(ref id) => id.clone(),
+                None => {
+                    fill_in_forbidden(results.get(), Error::failed(
+                        Message::AnonymousDigest.localize(self.language)));
+                    return Promise::ok(())
+                }
+            };
+
+            let content = pry!(pry!(params.get_content()).get_content());
+            let frequency = match ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(::digest::parse_frequency)
+            {
+                Ok(f) => f,
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                    return Promise::ok(())
+                }
+            };
+
+            match frequency {
+                None => {
+                    pry!(self.saved_ui_views.unsubscribe_digest(&identity_id));
+                    results.get().init_no_content();
+                    Promise::ok(())
+                }
+                Some(frequency) => {
+                    let context = hack_session_context::Client { client: self.context.client.clone() };
+                    let mut saved_ui_views = self.saved_ui_views.clone();
+                    let req = context.get_user_address_request();
+                    Promise::from_future(req.send().promise.and_then(move |response| {
+                        let address = try!(try!(try!(response.get()).get_address()).get_address());
+                        try!(saved_ui_views.subscribe_digest(&identity_id, address.into(), frequency));
+                        results.get().init_no_content();
+                        Ok(())
+                    }))
+                }
+            }
+        } else if path == "api/settings/powerbox" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(params.get_content()).get_content());
+            let result = ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(|text| json::Json::from_str(text)
+                          .map_err(|e| Error::failed(format!("{}", e))))
+                .and_then(|args| parse_powerbox_filter(&args))
+                .and_then(|(app_ids, tag_ids)| {
+                    self.saved_ui_views.set_powerbox_filter(app_ids, tag_ids,
+                                                            self.identity_id.clone())
+                });
+            match result {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                }
+            }
+            Promise::ok(())
+        } else if path == "api/settings/lock" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(params.get_content()).get_content());
+            let result = ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(parse_lock)
+                .and_then(|locked| self.saved_ui_views.set_locked(locked, self.identity_id.clone()));
+            match result {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                }
+            }
+            Promise::ok(())
+        } else if path == "api/settings/retention" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(params.get_content()).get_content());
+            let result = ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(parse_retention)
+                .and_then(|retention| {
+                    self.saved_ui_views.set_retention(retention, self.identity_id.clone())
+                });
+            match result {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                }
+            }
+            Promise::ok(())
+        } else if path == "api/settings/default-role" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(params.get_content()).get_content());
+            let result = ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(parse_role)
+                .and_then(|role| {
+                    self.saved_ui_views.set_default_role(role, self.identity_id.clone())
+                });
+            match result {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                }
+            }
+            Promise::ok(())
+        } else if path == "api/settings/features" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(params.get_content()).get_content());
+            let result = ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(parse_feature_changes)
+                .and_then(|changes| {
+                    self.saved_ui_views.set_features(changes, self.identity_id.clone())
+                });
+            match result {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                }
+            }
+            Promise::ok(())
+        } else if path == "api/settings/sort" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+
+            let content = pry!(pry!(params.get_content()).get_content());
+            let result = ::std::str::from_utf8(content)
+                .map_err(|e| Error::failed(format!("{}", e)))
+                .and_then(parse_sort_order)
+                .and_then(|order| self.saved_ui_views.set_sort_order(order, self.identity_id.clone()));
+            match result {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    results.get().init_client_error()
+                        .set_description_html(&escape_html(&format!("{}", e))[..]);
+                }
+            }
+            Promise::ok(())
         } else {
-            results.get().init_client_error()
-                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            fill_in_unrouted(results.get(), path);
             Promise::ok(())
         }
     }
@@ -737,32 +6277,142 @@ impl web_session::Server for WebSession {
         // HTTP DELETE request.
 
         let path = pry!(pry!(params.get()).get_path());
-        pry!(self.require_canonical_path(path));
+        pry!(require_canonical_path(path));
+
+        if let Err(e) = self.require_csrf_token(pry!(pry!(params.get()).get_context())) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+
+        if let Some(permission) = write_permission("DELETE", path) {
+            if let Err(e) = self.permissions.require(permission) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+        }
+
+        if let Err(e) = self.saved_ui_views.check_write_rate(&self.identity_id, self.language) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+
+        if let Err(e) = self.saved_ui_views.require_unlocked(self.language) {
+            fill_in_conflict(results.get(), e);
+            return Promise::ok(())
+        }
+
+        if path == "api/webhook" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+            if let Err(e) = self.saved_ui_views.require_feature(Feature::Webhooks, self.language) {
+                fill_in_feature_disabled(results.get(), e);
+                return Promise::ok(())
+            }
+            pry!(self.saved_ui_views.clear_webhook(self.identity_id.clone()));
+            results.get().init_no_content();
+            return Promise::ok(())
+        }
+
+        if path == "description" {
+            if let Err(e) = self.permissions.require(Permission::Manage) {
+                fill_in_forbidden(results.get(), e);
+                return Promise::ok(())
+            }
+            pry!(self.saved_ui_views.reset_description(self.identity_id.clone()));
+            let mut req = self.context.activity_request();
+            req.get().init_event().set_type(EDIT_DESCRIPTION_ACTIVITY_INDEX);
+            return Promise::from_future(req.send().promise.map(move |_| {
+                results.get().init_no_content();
+            }))
+        }
+
+        if path.starts_with("sturdyref/") && path.ends_with("/rating") {
+            let token = match router::between(&path, "sturdyref/", "/rating") {
+                Some(token) => token,
+                None => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+            self.react(token, Reaction::Rate(None), results.get());
+            return Promise::ok(())
+        }
+
+        if path.starts_with("sturdyref/") && path.ends_with("/upvote") {
+            let token = match router::between(&path, "sturdyref/", "/upvote") {
+                Some(token) => token,
+                None => {
+                    fill_in_not_found(results.get());
+                    return Promise::ok(())
+                }
+            };
+            self.react(token, Reaction::Upvote(false), results.get());
+            return Promise::ok(())
+        }
 
         if !path.starts_with("sturdyref/") {
-            return Promise::err(Error::failed("DELETE only supported under sturdyref/".to_string()));
+            fill_in_unrouted(results.get(), path);
+            return Promise::ok(())
         }
 
-        if !self.can_write {
-            results.get().init_client_error()
-                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+        if let Err(e) = self.permissions.require(Permission::Write) {
+            fill_in_forbidden(results.get(), e);
             Promise::ok(())
         } else {
             let token_string = path[10..].to_string();
-            let binary_token = match base64::FromBase64::from_base64(&token_string[..]) {
-                Ok(b) => b,
-                Err(e) => {
-                    results.get().init_client_error().set_description_html(&format!("{}", e)[..]);
+            let entry = if self.can_see(&token_string) {
+                self.saved_ui_views.inner.borrow().get_saved_data(&token_string)
+                    .map(|data| (data.bookmark.is_some(), data.version))
+            } else {
+                None
+            };
+            let is_bookmark = match entry {
+                Some((is_bookmark, version)) => {
+                    // A client acting on stale data can send If-Match with the version it saw.
+                    let context = pry!(pry!(params.get()).get_context());
+                    if !pry!(entry_precondition_holds(context, version)) {
+                        fill_in_conflict(results.get(), Error::failed(format!(
+                            "entry {} has been changed by someone else (version {})",
+                            token_string, version)));
+                        return Promise::ok(())
+                    }
+                    is_bookmark
+                }
+                None => {
+                    // Either a typo or someone else removed it first; either way, nothing to do.
+                    let mut error = results.get().init_client_error();
+                    error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                    error.set_description_html(
+                        &escape_html(&format!("no such entry: {}", token_string))[..]);
                     return Promise::ok(())
                 }
             };
 
+            let dropped = if is_bookmark {
+                // Bookmarks have no sturdyref to drop.
+                Promise::ok(())
+            } else {
+                let binary_token = match base64::FromBase64::from_base64(&token_string[..]) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        results.get().init_client_error()
+                            .set_description_html(&escape_html(&format!("{}", e))[..]);
+                        return Promise::ok(())
+                    }
+                };
+                let mut req = self.sandstorm_api.drop_request();
+                req.get().set_token(&binary_token);
+                Promise::from_future(req.send().promise.map(|_| ()))
+            };
+
             let mut saved_ui_views = self.saved_ui_views.clone();
             let context = self.context.clone();
-            let mut req = self.sandstorm_api.drop_request();
-            req.get().set_token(&binary_token);
-            Promise::from_future(req.send().promise.and_then(move |_| {
-                pry!(saved_ui_views.remove(&token_string));
+            let identity_id = self.identity_id.clone();
+            let display_name = self.viewer.display_name.clone();
+            Promise::from_future(dropped.and_then(move |()| {
+                pry!(saved_ui_views.remove(&token_string, identity_id, display_name));
                 let mut req = context.activity_request();
                 req.get().init_event().set_type(REMOVE_GRAIN_ACTIVITY_INDEX);
                 Promise::from_future(req.send().promise.and_then(move |_| {
@@ -773,31 +6423,728 @@ impl web_session::Server for WebSession {
         }
     }
 
-    fn open_web_socket(&mut self,
-                     params: web_session::OpenWebSocketParams,
-                     mut results: web_session::OpenWebSocketResults)
-                     -> Promise<(), Error>
-    {
-        let client_stream = pry!(pry!(params.get()).get_client_stream());
+    fn open_web_socket(&mut self,
+                     params: web_session::OpenWebSocketParams,
+                     mut results: web_session::OpenWebSocketResults)
+                     -> Promise<(), Error>
+    {
+        let params = pry!(params.get());
+        let client_stream = pry!(params.get_client_stream());
+
+        // The channel is chosen by path, unless the client asks for one of our subprotocols.
+        let (path, query) = router::split(pry!(params.get_path()));
+        let mut channel = pry!(channel_for_path(path));
+        let protocols = pry!(params.get_protocol());
+        for idx in 0..protocols.len() {
+            let requested = pry!(protocols.get(idx));
+            if let Some(&(name, c)) = CHANNEL_PROTOCOLS.iter().find(|&&(n, _)| n == requested) {
+                channel = c;
+                results.get().init_protocol(1).set(0, name);
+                break
+            }
+        }
+
+        if channel == Channel::Activity {
+            results.get().set_server_stream(
+                self.saved_ui_views.new_activity_websocket(
+                    client_stream,
+                    self.permissions.clone(),
+                    self.viewer.clone()));
+            return Promise::ok(())
+        }
+
+        // A reconnecting client passes the last revision it saw as `?since=`. A client that wants
+        // acked delivery names itself with `?client=`.
+        let since = pry!(query.parse::<u64>("since"));
+        let client_id = query.get("client").map(|id| id.to_string());
+        if let Some(ref id) = client_id {
+            pry!(::acks::validate_client_id(id));
+        }
+
+        results.get().set_server_stream(
+            self.saved_ui_views.new_subscribed_websocket(
+                client_stream,
+                self.permissions.clone(),
+                self.viewer.clone(),
+                since,
+                client_id));
+
+        Promise::ok(())
+    }
+
+    fn propfind(&mut self,
+                params: web_session::PropfindParams,
+                mut results: web_session::PropfindResults)
+                -> Promise<(), Error>
+    {
+        // WebDAV PROPFIND request. We ignore the requested property names and always answer with
+        // the handful of live properties that file managers look at.
+        let params = pry!(params.get());
+        let path = pry!(params.get_path());
+        pry!(require_canonical_path(path));
+
+        let node = match self.resolve_dav_path(path) {
+            Some(node) => node,
+            None => {
+                let mut error = results.get().init_client_error();
+                error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                return Promise::ok(())
+            }
+        };
+
+        let max_depth = match pry!(params.get_depth()) {
+            web_session::PropfindDepth::Zero => 0,
+            web_session::PropfindDepth::One => 1,
+            web_session::PropfindDepth::Infinity => usize::max_value(),
+        };
+
+        let path = match webdav::decode_path(path.trim_right_matches('/')) {
+            Some(p) => p,
+            None => return Promise::err(Error::failed("path is not UTF-8".into())),
+        };
+        let mut resources = Vec::new();
+        let mut pending = vec![(node, path, 0)];
+        while let Some((node, path, depth)) = pending.pop() {
+            if depth < max_depth {
+                for (child, name) in pry!(self.dav_children(&node)) {
+                    let child_path =
+                        if path.is_empty() { name } else { format!("{}/{}", path, name) };
+                    pending.push((child, child_path, depth + 1));
+                }
+            }
+            resources.push(pry!(self.dav_resource(&node, path)));
+        }
+        resources.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let text = webdav::multistatus(&self.base_path, &resources);
+        let mut content = results.get().init_content();
+        content.set_status_code(web_session::response::SuccessCode::MultiStatus);
+        content.set_mime_type("application/xml; charset=UTF-8");
+        content.init_body().set_bytes(text.as_bytes());
+        Promise::ok(())
+    }
+
+    fn mkcol(&mut self,
+             params: web_session::MkcolParams,
+             mut results: web_session::MkcolResults)
+             -> Promise<(), Error>
+    {
+        // WebDAV MKCOL request. We don't ask for the CSRF token here: browsers won't send a
+        // cross-origin MKCOL without a CORS preflight, which we never approve.
+        let params = pry!(params.get());
+        let path = pry!(params.get_path());
+        pry!(require_canonical_path(path));
+
+        if let Err(e) = self.saved_ui_views.require_feature(Feature::WebDav, self.language) {
+            fill_in_feature_disabled(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.permissions.require(Permission::Write) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.saved_ui_views.check_write_rate(&self.identity_id, self.language) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.saved_ui_views.require_unlocked(self.language) {
+            fill_in_conflict(results.get(), e);
+            return Promise::ok(())
+        }
+
+        if pry!(pry!(params.get_content()).get_content()).len() > 0 {
+            let mut error = results.get().init_client_error();
+            error.set_status_code(web_session::response::ClientErrorCode::UnsupportedMediaType);
+            error.set_description_html("MKCOL with a request body is not supported");
+            return Promise::ok(())
+        }
+
+        // Folders only exist directly under "collection/".
+        let name = webdav::decode_path(path.trim_right_matches('/')).and_then(|path| {
+            if path.starts_with("collection/") && !path[11..].contains('/') {
+                Some(path[11..].to_string())
+            } else {
+                None
+            }
+        });
+        let status = match name {
+            _ if self.resolve_dav_path(path).is_some() => {
+                web_session::response::ClientErrorCode::MethodNotAllowed
+            }
+            Some(name) => {
+                match self.saved_ui_views.create_folder(&name, self.identity_id.clone()) {
+                    Ok(()) => {
+                        let mut content = results.get().init_content();
+                        content.set_status_code(web_session::response::SuccessCode::Created);
+                        content.set_mime_type("text/plain");
+                        content.init_body().set_bytes(&[]);
+                    }
+                    Err(e) => {
+                        results.get().init_client_error()
+                            .set_description_html(&escape_html(&format!("{}", e))[..]);
+                    }
+                }
+                return Promise::ok(())
+            }
+            None => web_session::response::ClientErrorCode::Conflict,
+        };
+        results.get().init_client_error().set_status_code(status);
+        Promise::ok(())
+    }
+
+    fn copy(&mut self,
+            params: web_session::CopyParams,
+            results: web_session::CopyResults)
+            -> Promise<(), Error>
+    {
+        // WebDAV COPY request. As with MKCOL, no CSRF token is needed.
+        let params = pry!(params.get());
+        let path = pry!(params.get_path());
+        let destination = pry!(params.get_destination());
+        pry!(require_canonical_path(path));
+
+        if let Err(e) = self.saved_ui_views.require_feature(Feature::WebDav, self.language) {
+            fill_in_feature_disabled(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.permissions.require(Permission::Write) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.saved_ui_views.check_write_rate(&self.identity_id, self.language) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.saved_ui_views.require_unlocked(self.language) {
+            fill_in_conflict(results.get(), e);
+            return Promise::ok(())
+        }
+
+        let transfer = self.dav_transfer(path, destination, params.get_no_overwrite(),
+                                         params.get_shallow(), false);
+        WebSession::respond_to_dav_transfer(transfer, results)
+    }
+
+    fn move_(&mut self,
+             params: web_session::MoveParams,
+             results: web_session::MoveResults)
+             -> Promise<(), Error>
+    {
+        // WebDAV MOVE request. As with MKCOL, no CSRF token is needed.
+        let params = pry!(params.get());
+        let path = pry!(params.get_path());
+        let destination = pry!(params.get_destination());
+        pry!(require_canonical_path(path));
+
+        if let Err(e) = self.saved_ui_views.require_feature(Feature::WebDav, self.language) {
+            fill_in_feature_disabled(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.permissions.require(Permission::Write) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.saved_ui_views.check_write_rate(&self.identity_id, self.language) {
+            fill_in_forbidden(results.get(), e);
+            return Promise::ok(())
+        }
+        if let Err(e) = self.saved_ui_views.require_unlocked(self.language) {
+            fill_in_conflict(results.get(), e);
+            return Promise::ok(())
+        }
+
+        let transfer = self.dav_transfer(path, destination, params.get_no_overwrite(), false, true);
+        WebSession::respond_to_dav_transfer(transfer, results)
+    }
+
+    fn post_streaming(&mut self,
+                      params: web_session::PostStreamingParams,
+                      mut results: web_session::PostStreamingResults)
+                      -> Promise<(), Error>
+    {
+        let params = pry!(params.get());
+        let stream = pry!(UploadStream::new(
+            &self.saved_ui_views, self.fork(), UploadMethod::Post,
+            pry!(params.get_path()), pry!(params.get_mime_type()),
+            if params.has_encoding() { Some(pry!(params.get_encoding())) } else { None },
+            pry!(params.get_context())));
+        results.get().set_stream(
+            web_session::request_stream::ToClient::new(stream).from_server::<::capnp_rpc::Server>());
+        Promise::ok(())
+    }
+
+    fn put_streaming(&mut self,
+                     params: web_session::PutStreamingParams,
+                     mut results: web_session::PutStreamingResults)
+                     -> Promise<(), Error>
+    {
+        let params = pry!(params.get());
+        let stream = pry!(UploadStream::new(
+            &self.saved_ui_views, self.fork(), UploadMethod::Put,
+            pry!(params.get_path()), pry!(params.get_mime_type()),
+            if params.has_encoding() { Some(pry!(params.get_encoding())) } else { None },
+            pry!(params.get_context())));
+        results.get().set_stream(
+            web_session::request_stream::ToClient::new(stream).from_server::<::capnp_rpc::Server>());
+        Promise::ok(())
+    }
+}
+
+/// Streaming uploads larger than this are rejected.
+const MAX_UPLOAD_BYTES: u64 = 32 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+enum UploadMethod { Post, Put }
+
+/// The body of a streaming POST or PUT, as it arrives. Sandstorm streams bodies that are too big
+/// for one message or whose size isn't known up front. Each chunk goes straight to a file in
+/// /var/uploads, and `write()` only returns once it is there, so a fast uploader can't pile up
+/// chunks in memory. Once the uploader is done we read the file into a regular `post()` or `put()`
+/// on a fork of the session, so that every route accepts both forms with the same checks.
+struct UploadStream {
+    state: Rc<RefCell<UploadState>>,
+}
+
+struct UploadState {
+    session: web_session::Client,
+    method: UploadMethod,
+    path: String,
+    mime_type: String,
+    encoding: Option<String>,
+
+    /// A copy of the request's `web_session::Context`, which carries the CSRF header.
+    context: ::capnp::message::Builder<::capnp::message::HeapAllocator>,
+
+    /// Where the body is kept until the upload is done, and how many bytes of it there are.
+    body_path: ::std::path::PathBuf,
+    body_file: ::std::fs::File,
+    size: u64,
+    done: bool,
+
+    /// Fired by `done()` if `getResponse()` is already waiting.
+    on_done: Option<::futures::sync::oneshot::Sender<()>>,
+}
+
+impl Drop for UploadState {
+    fn drop(&mut self) {
+        if let Err(e) = ::std::fs::remove_file(&self.body_path) {
+            println!("failed to remove upload {:?}: {}", self.body_path, e);
+        }
+    }
+}
+
+impl UploadStream {
+    fn new(saved_ui_views: &SavedUiViewSet,
+           session: web_session::Client,
+           method: UploadMethod,
+           path: &str,
+           mime_type: &str,
+           encoding: Option<&str>,
+           context: web_session::context::Reader)
+           -> Result<UploadStream, Error>
+    {
+        let mut context_message = ::capnp::message::Builder::new_default();
+        try!(context_message.set_root(context));
+        let (body_path, body_file) = try!(saved_ui_views.create_upload_file());
+        Ok(UploadStream {
+            state: Rc::new(RefCell::new(UploadState {
+                session: session,
+                method: method,
+                path: path.to_string(),
+                mime_type: mime_type.to_string(),
+                encoding: encoding.map(|e| e.to_string()),
+                context: context_message,
+                body_path: body_path,
+                body_file: body_file,
+                size: 0,
+                done: false,
+                on_done: None,
+            })),
+        })
+    }
+}
+
+fn upload_too_large(size: u64) -> Error {
+    Error::failed(format!("upload of {} bytes exceeds the limit of {} bytes",
+                          size, MAX_UPLOAD_BYTES))
+}
+
+impl byte_stream::Server for UploadStream {
+    fn write(&mut self,
+             params: byte_stream::WriteParams,
+             _results: byte_stream::WriteResults)
+             -> Promise<(), Error>
+    {
+        let data = pry!(pry!(params.get()).get_data());
+        let mut state = self.state.borrow_mut();
+        if state.done {
+            return Promise::err(Error::failed("write() after done()".into()));
+        }
+        let size = state.size + data.len() as u64;
+        if size > MAX_UPLOAD_BYTES {
+            return Promise::err(upload_too_large(size));
+        }
+        pry!(::std::io::Write::write_all(&mut state.body_file, data));
+        state.size = size;
+        Promise::ok(())
+    }
+
+    fn done(&mut self,
+            _params: byte_stream::DoneParams,
+            _results: byte_stream::DoneResults)
+            -> Promise<(), Error>
+    {
+        let mut state = self.state.borrow_mut();
+        state.done = true;
+        if let Some(on_done) = state.on_done.take() {
+            let _ = on_done.send(());
+        }
+        Promise::ok(())
+    }
+
+    fn expect_size(&mut self,
+                   params: byte_stream::ExpectSizeParams,
+                   _results: byte_stream::ExpectSizeResults)
+                   -> Promise<(), Error>
+    {
+        let size = pry!(params.get()).get_size();
+        if size > MAX_UPLOAD_BYTES {
+            Promise::err(upload_too_large(size))
+        } else {
+            Promise::ok(())
+        }
+    }
+}
+
+impl web_session::request_stream::Server for UploadStream {
+    fn get_response(&mut self,
+                    _params: web_session::request_stream::GetResponseParams,
+                    mut results: web_session::request_stream::GetResponseResults)
+                    -> Promise<(), Error>
+    {
+        // The uploader usually asks for the response before it starts writing.
+        let ready = if self.state.borrow().done {
+            Promise::ok(())
+        } else {
+            let (tx, rx) = ::futures::sync::oneshot::channel();
+            self.state.borrow_mut().on_done = Some(tx);
+            Promise::from_future(rx.map_err(|_| {
+                Error::failed("upload stream was dropped before it was done".into())
+            }))
+        };
+
+        let state = self.state.clone();
+        Promise::from_future(ready.and_then(move |()| {
+            use std::io::Read;
+            let state = state.borrow();
+            let context = pry!(state.context.get_root_as_reader::<web_session::context::Reader>());
+            let mut body = pry!(::std::fs::File::open(&state.body_path));
+            match state.method {
+                UploadMethod::Post => {
+                    let mut req = state.session.post_request();
+                    {
+                        let mut params = req.get();
+                        params.set_path(&state.path);
+                        pry!(params.set_context(context));
+                        let mut content = params.init_content();
+                        content.set_mime_type(&state.mime_type);
+                        if let Some(ref encoding) = state.encoding {
+                            content.set_encoding(encoding);
+                        }
+                        pry!(body.read_exact(content.init_content(state.size as u32)));
+                    }
+                    Promise::from_future(req.send().promise.and_then(move |response| {
+                        results.set(try!(response.get()))
+                    }))
+                }
+                UploadMethod::Put => {
+                    let mut req = state.session.put_request();
+                    {
+                        let mut params = req.get();
+                        params.set_path(&state.path);
+                        pry!(params.set_context(context));
+                        let mut content = params.init_content();
+                        content.set_mime_type(&state.mime_type);
+                        if let Some(ref encoding) = state.encoding {
+                            content.set_encoding(encoding);
+                        }
+                        pry!(body.read_exact(content.init_content(state.size as u32)));
+                    }
+                    Promise::from_future(req.send().promise.and_then(move |response| {
+                        results.set(try!(response.get()))
+                    }))
+                }
+            }
+        }))
+    }
+}
+
+/// Escapes text so that it can be safely embedded in an HTML document. Anything that ends up in
+/// `description_html` must go through here, because the browser will render it as markup.
+fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Decodes base64 written in either the standard or the URL-safe alphabet, with or without
+/// padding, ignoring whitespace. Different powerbox frontends encode descriptors differently.
+fn decode_base64_leniently(input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut normalized: Vec<u8> = input.iter()
+        .filter(|&&b| !(b as char).is_whitespace())
+        .map(|&b| match b {
+            b'-' => b'+',
+            b'_' => b'/',
+            b => b,
+        })
+        .collect();
+
+    while normalized.last() == Some(&b'=') {
+        normalized.pop();
+    }
+    while normalized.len() % 4 != 0 {
+        normalized.push(b'=');
+    }
+
+    base64::FromBase64::from_base64(&normalized[..]).map_err(|e| Error::failed(format!("{}", e)))
+}
+
+/// Asks browsers not to second-guess the MIME type of content that came from /var, which
+/// collaborators (or whoever they fetched it from) control.
+const NOSNIFF_HEADER: (&'static str, &'static str) = ("X-Content-Type-Options", "nosniff");
+
+fn set_additional_headers(mut response: web_session::response::Builder,
+                          headers: &[(&str, &str)])
+{
+    let mut list = response.borrow().init_additional_headers(headers.len() as u32);
+    for (idx, &(name, value)) in headers.iter().enumerate() {
+        let mut header = list.borrow().get(idx as u32);
+        header.set_name(name);
+        header.set_value(value);
+    }
+}
+
+fn fill_in_text_content(response: web_session::response::Builder, mime_type: &str, text: &str)
+{
+    let mut content = response.init_content();
+    content.set_status_code(web_session::response::SuccessCode::Ok);
+    content.set_mime_type(mime_type);
+    content.init_body().set_bytes(text.as_bytes());
+}
+
+/// The ETag of the collection's description: a hash of its text (64-bit FNV-1a), so that it
+/// needs no storage of its own and survives restarts.
+fn description_etag(description: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in description.as_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Like `fill_in_text_content()`, but asks the browser to save the response as `file_name`
+/// rather than display it.
+fn fill_in_download(response: web_session::response::Builder,
+                    mime_type: &str,
+                    text: &str,
+                    file_name: &str)
+{
+    let mut content = response.init_content();
+    content.set_status_code(web_session::response::SuccessCode::Ok);
+    content.set_mime_type(mime_type);
+    content.borrow().init_disposition().set_download(file_name);
+    content.init_body().set_bytes(text.as_bytes());
+}
+
+/// Lowercase ASCII letters and digits of `text`, with each run of anything else turned into a
+/// single '-', for use in file names.
+fn file_name_slug(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_digit(36) {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_right_matches('-').to_string()
+}
+
+/// Labels a sturdyref that we save for a grain, in English by default and in every other
+/// language we support, so that Sandstorm can show it in the user's own.
+fn fill_in_save_label(mut label: localized_text::Builder, collection: Option<&str>, grain: &str) {
+    let message = Message::SaveLabel { collection: collection, grain: grain };
+    label.set_default_text(&message.localize(Language::English));
+    let others: Vec<Language> =
+        Language::all().into_iter().filter(|&l| l != Language::English).collect();
+    let mut localizations = label.init_localizations(others.len() as u32);
+    for (idx, &language) in others.iter().enumerate() {
+        let mut localization = localizations.borrow().get(idx as u32);
+        localization.set_locale(language.tag());
+        localization.set_text(&message.localize(language));
+    }
+}
+
+fn fill_in_forbidden(response: web_session::response::Builder, e: Error)
+{
+    let mut client_error = response.init_client_error();
+    client_error.set_status_code(web_session::response::ClientErrorCode::Forbidden);
+    client_error.set_description_html(&escape_html(&format!("{}", e))[..]);
+}
+
+/// Whether the If-Match or If-None-Match header of a request on an entry is satisfied. The ETag
+/// of an entry is its version.
+fn entry_precondition_holds(context: web_session::context::Reader, version: u64)
+                            -> Result<bool, Error>
+{
+    precondition_holds(context, &version.to_string())
+}
+
+/// Whether the If-Match or If-None-Match header of a request is satisfied by a resource whose
+/// current ETag is `etag`.
+fn precondition_holds(context: web_session::context::Reader, etag: &str) -> Result<bool, Error>
+{
+    use sandstorm::web_session_capnp::web_session::context::e_tag_precondition::Which;
+    match try!(context.get_e_tag_precondition().which()) {
+        Which::None(()) | Which::Exists(()) => Ok(true),
+        Which::DoesntExist(()) => Ok(false),
+        Which::MatchesOneOf(etags) => etag_listed(try!(etags), etag),
+        Which::MatchesNoneOf(etags) => etag_listed(try!(etags), etag).map(|listed| !listed),
+    }
+}
+
+fn etag_listed(etags: ::capnp::struct_list::Reader<web_session::e_tag::Owned>, etag: &str)
+               -> Result<bool, Error>
+{
+    for listed in etags.iter() {
+        if try!(listed.get_value()) == etag {
+            return Ok(true)
+        }
+    }
+    Ok(false)
+}
+
+/// Reports a missing or malformed query parameter as "400 Bad Request".
+fn fill_in_bad_query(response: web_session::response::Builder, e: Error)
+{
+    response.init_client_error().set_description_html(&escape_html(&format!("{}", e))[..]);
+}
 
-        results.get().set_server_stream(
-            self.saved_ui_views.new_subscribed_websocket(
-                client_stream,
-                self.can_write,
-                self.identity_id.clone(),
-                &self.handle));
+/// Reports that the request conflicts with the collection's current state: it is locked or under
+/// maintenance, or an entry was edited since the client last saw it.
+fn fill_in_conflict(response: web_session::response::Builder, e: Error)
+{
+    let mut client_error = response.init_client_error();
+    client_error.set_status_code(web_session::response::ClientErrorCode::Conflict);
+    client_error.set_description_html(&escape_html(&format!("{}", e))[..]);
+}
 
-        Promise::ok(())
-    }
+fn fill_in_feature_disabled(response: web_session::response::Builder, e: Error)
+{
+    let mut client_error = response.init_client_error();
+    client_error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+    client_error.set_description_html(&escape_html(&format!("{}", e))[..]);
+}
+
+fn fill_in_not_found(response: web_session::response::Builder)
+{
+    response.init_client_error().set_status_code(web_session::response::ClientErrorCode::NotFound);
 }
 
 fn fill_in_client_error(mut results: web_session::PostResults, e: Error)
 {
     let mut client_error = results.get().init_client_error();
-    client_error.set_description_html(&format!("{}", e)[..]);
+    client_error.set_description_html(&escape_html(&format!("{}", e))[..]);
+}
+
+/// What a user can do to an entry without editing it.
+#[derive(Clone, Copy)]
+enum Reaction {
+    /// Gives it 1 to 5 stars, or withdraws the user's rating if None.
+    Rate(Option<u8>),
+
+    /// Upvotes it, or takes the user's upvote back if false.
+    Upvote(bool),
 }
 
 impl WebSession {
+    /// A new session capability for the same user, through which we replay streaming requests.
+    fn fork(&self) -> web_session::Client {
+        let session = WebSession {
+            permissions: self.permissions.clone(),
+            csrf_token: self.csrf_token.clone(),
+            sandstorm_api: self.sandstorm_api.clone(),
+            context: self.context.clone(),
+            saved_ui_views: self.saved_ui_views.clone(),
+            identity_id: self.identity_id.clone(),
+            viewer: self.viewer.clone(),
+            language: self.language,
+            base_path: self.base_path.clone(),
+            clear_nonce: None,
+            lifetime: self.lifetime.clone(),
+        };
+        web_session::ToClient::new(session).from_server::<::capnp_rpc::Server>()
+    }
+
+    /// Whether the entry `token` exists and isn't someone else's private entry.
+    fn can_see(&self, token: &str) -> bool {
+        let inner = self.saved_ui_views.inner.borrow();
+        inner.views.contains_key(token) && inner.is_visible(token, &self.viewer.audience())
+    }
+
+    /// Handles `PUT` and `DELETE` of `sturdyref/<token>/rating` and `sturdyref/<token>/upvote`.
+    fn react(&mut self,
+             token: &str,
+             reaction: Reaction,
+             response: web_session::response::Builder) {
+        if let Err(e) = self.permissions.require(Permission::Comment) {
+            fill_in_forbidden(response, e);
+            return
+        }
+        let feature = match reaction {
+            Reaction::Rate(_) => Feature::Ratings,
+            Reaction::Upvote(_) => Feature::Upvotes,
+        };
+        if let Err(e) = self.saved_ui_views.require_feature(feature, self.language) {
+            fill_in_feature_disabled(response, e);
+            return
+        }
+        let rater = match self.identity_id {
+            Some(ref id) => id.clone(),
+            None => {
+                fill_in_forbidden(response, Error::failed(
+                    Message::AnonymousRating.localize(self.language)));
+                return
+            }
+        };
+        if !self.can_see(token) {
+            let mut error = response.init_client_error();
+            error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+            return
+        }
+        let result = match reaction {
+            Reaction::Rate(stars) => self.saved_ui_views.set_rating(token, rater, stars),
+            Reaction::Upvote(up) => self.saved_ui_views.set_upvote(token, rater, up),
+        };
+        match result {
+            Ok(()) => { response.init_no_content(); }
+            Err(e) => {
+                response.init_client_error()
+                    .set_description_html(&escape_html(&format!("{}", e))[..]);
+            }
+        }
+    }
+
     fn offer_ui_view(&mut self,
                      text_token: String,
                      title: String,
@@ -816,6 +7163,7 @@ impl WebSession {
         req.get().set_token(&token);
         Promise::from_future(req.send().promise.then(move |response| match response {
             Ok(v) => {
+                set.mark_available(&text_token);
                 let sealed_ui_view: ui_view::Client =
                     pry!(pry!(v.get()).get_cap().get_as_capability());
                 let mut req = session_context.offer_request();
@@ -832,6 +7180,7 @@ impl WebSession {
                 Promise::from_future(req.send().promise.map(|_| ()))
             }
             Err(e) => {
+                set.mark_unavailable(&text_token, &e);
                 set.inner.borrow_mut().view_infos.insert(text_token.clone(), Err(e.clone()));
                 set.send_action_to_subscribers(Action::ViewInfo {
                     token: text_token,
@@ -859,32 +7208,134 @@ impl WebSession {
         let desc: powerbox_descriptor::Reader = try!(message.get_root());
         let tags = try!(desc.get_tags());
         if tags.len() == 0 {
-            Err(Error::failed("no powerbox tag".into()))
-        } else {
-            let value: ui_view::powerbox_tag::Reader = try!(tags.get(0).get_value().get_as());
-            Ok(try!(value.get_title()).into())
+            return Err(Error::failed("no powerbox tag".into()))
+        } else if tags.len() > MAX_POWERBOX_TAGS {
+            return Err(Error::failed(format!("too many powerbox tags ({})", tags.len())))
+        }
+
+        // We only ever request UiViews, possibly narrowed by the tags that the managers require,
+        // so those are the only kinds of tag we expect to see.
+        use capnp::traits::HasTypeId;
+        let required_tag_ids = self.saved_ui_views.inner.borrow().settings.required_tag_ids.clone();
+        let mut ui_view_tag = None;
+        for tag in tags.iter() {
+            if tag.get_id() == ui_view::Client::type_id() {
+                if ui_view_tag.is_none() {
+                    ui_view_tag = Some(tag);
+                }
+            } else if !required_tag_ids.contains(&tag.get_id()) {
+                return Err(Error::failed(format!("unexpected powerbox tag ID {:x}", tag.get_id())))
+            }
+        }
+
+        match ui_view_tag {
+            Some(tag) => {
+                let value: ui_view::powerbox_tag::Reader = try!(tag.get_value().get_as());
+                Ok(try!(value.get_title()).into())
+            }
+            None => Err(Error::failed("no UiView powerbox tag".into())),
+        }
+    }
+
+    /// Claims an ApiSession that the user granted through the powerbox in response to the
+    /// descriptor from `api/webhook/descriptor`, and makes it the webhook target. The request
+    /// body is the URL that was asked for.
+    /// Handles `POST api/merge/<token>`, once the user has confirmed that the entry `token`,
+    /// which was just added, holds the same grain as `existing`: drops its sturdyref and removes
+    /// it, leaving the original.
+    fn merge_duplicate(&mut self,
+                       token: String,
+                       existing: String,
+                       mut results: web_session::PostResults)
+                       -> Promise<(), Error>
+    {
+        let binary_token = match base64::FromBase64::from_base64(&token[..]) {
+            Ok(b) => b,
+            Err(e) => {
+                fill_in_client_error(results, Error::failed(format!("{}", e)));
+                return Promise::ok(())
+            }
+        };
+        let mut req = self.sandstorm_api.drop_request();
+        req.get().set_token(&binary_token);
+
+        let mut saved_ui_views = self.saved_ui_views.clone();
+        let identity_id = self.identity_id.clone();
+        let display_name = self.viewer.display_name.clone();
+        Promise::from_future(req.send().promise.and_then(move |_| {
+            try!(saved_ui_views.remove(&token, identity_id.clone(), display_name));
+            saved_ui_views.note_duplicate(identity_id, &existing);
+            results.get().init_no_content();
+            Ok(())
+        }))
+    }
+
+    fn receive_webhook_token(&mut self,
+                             token: String,
+                             params: web_session::PostParams,
+                             mut results: web_session::PostResults)
+                             -> Promise<(), Error>
+    {
+        let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
+        let url = match ::std::str::from_utf8(content) {
+            Ok(url) => url.trim().to_string(),
+            Err(e) => {
+                fill_in_client_error(results, Error::failed(format!("{}", e)));
+                return Promise::ok(())
+            }
+        };
+
+        let mut req = self.context.claim_request_request();
+        req.get().set_request_token(&token[..]);
+        {
+            // The webhook stops working if the manager who set it up loses the permission.
+            let mut required = req.get().init_required_permissions(2);
+            required.set(Permission::Manage as u32, true);
         }
+
+        let sandstorm_api = self.sandstorm_api.clone();
+        let mut saved_ui_views = self.saved_ui_views.clone();
+        let identity_id = self.identity_id.clone();
+        Promise::from_future(req.send().promise.and_then(move |response| {
+            let session: api_session::Client =
+                pry!(pry!(response.get()).get_cap().get_as_capability());
+            let mut req = sandstorm_api.save_request();
+            req.get().get_cap().set_as_capability(session.client.hook);
+            req.get().init_label().set_default_text(&format!("webhook: {}", url));
+            Promise::from_future(req.send().promise.and_then(move |response| {
+                let binary_token = try!(try!(response.get()).get_token()).to_vec();
+                try!(saved_ui_views.set_webhook(binary_token, url, identity_id));
+                results.get().init_no_content();
+                Ok(())
+            }))
+        }))
     }
 
     fn receive_request_token(&mut self,
                              token: String,
+                             publish_at: Option<u64>,
                              params: web_session::PostParams,
                              mut results: web_session::PostResults)
                              -> Promise<(), Error>
     {
         let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
 
-        let decoded_content = match base64::FromBase64::from_base64(content) {
+        let decoded_content = match decode_base64_leniently(content) {
             Ok(c) => c,
-            Err(_) => {
-                fill_in_client_error(results, Error::failed("failed to convert from base64".into()));
+            Err(e) => {
+                fill_in_client_error(results, Error::failed(
+                    format!("failed to convert from base64: {}", e)));
                 return Promise::ok(())
             }
         };
-        let grain_title: String = match self.read_powerbox_tag(decoded_content) {
+        // A bad title fails here, before anything is saved.
+        let title = self.read_powerbox_tag(decoded_content).and_then(|t| sanitize_title(&t));
+        let grain_title: String = match title {
             Ok(t) => t,
             Err(e) => {
-                fill_in_client_error(results, e);
+                let mut error = results.get().init_client_error();
+                error.set_status_code(web_session::response::ClientErrorCode::UnprocessableEntity);
+                error.set_description_html(&escape_html(&format!("{}", e)));
                 return Promise::ok(());
             }
         };
@@ -893,60 +7344,448 @@ impl WebSession {
         let mut req = self.context.claim_request_request();
         let sandstorm_api = self.sandstorm_api.clone();
         req.get().set_request_token(&token[..]);
-        let mut saved_ui_views = self.saved_ui_views.clone();
+        let saved_ui_views = self.saved_ui_views.clone();
         let identity_id = self.identity_id.clone();
-
-        let do_stuff = req.send().promise.and_then(move |response| {
+        let audience = self.viewer.audience();
+        let settings = self.saved_ui_views.inner.borrow().settings.clone();
+        let collection_title = self.saved_ui_views.inner.borrow().collection_title();
+        let timer = self.saved_ui_views.inner.borrow().timer.clone();
+        let timeout = ::std::time::Duration::from_secs(GRAIN_RPC_TIMEOUT_SECONDS);
+
+        // If the user closes the tab partway through, we stop at the next step rather than add an
+        // entry that nobody is there to see. Dropping the pipeline drops the claimed capability.
+        let session = Rc::downgrade(&self.lifetime);
+        let session1 = session.clone();
+
+        let claimed = with_timeout(&*timer, timeout, "the powerbox claim",
+                                   Promise::from_future(req.send().promise));
+        let do_stuff = claimed.and_then(move |response| {
             let sealed_ui_view: ui_view::Client =
                 pry!(pry!(response.get()).get_cap().get_as_capability());
-            let mut req = sandstorm_api.save_request();
-            req.get().get_cap().set_as_capability(sealed_ui_view.client.hook);
-            {
-                let mut save_label = req.get().init_label();
-                save_label.set_default_text(&format!("grain with title: {}", grain_title)[..]);
-            }
-            Promise::from_future(req.send().promise.and_then(move |response| {
-                let binary_token = response.get()?.get_token()?;
-                let token = base64::ToBase64::to_base64(binary_token, base64::URL_SAFE);
 
-                try!(saved_ui_views.insert(token.clone(), grain_title, identity_id));
+            // The powerbox can't filter by app, so we check that ourselves before keeping it.
+            let view_info = Promise::from_future(
+                sealed_ui_view.get_view_info_request().send().promise);
+            let view_info = with_timeout(&*timer, timeout, "the grain's view info", view_info);
+            let merge = settings.features.is_enabled(Feature::MergeDuplicates);
+            let saved_ui_views2 = saved_ui_views.clone();
+            let title = grain_title.clone();
+            let check = view_info.and_then(move |response| {
+                if session.upgrade().is_none() {
+                    return Err(session_closed_error())
+                }
+                let app_id = try!(try!(response.get()).get_app_id());
+                if !settings.allows_app(app_id) {
+                    return Err(Error::failed(
+                        format!("this collection does not accept grains of app {}", app_id)))
+                }
+                if !merge {
+                    return Ok(None)
+                }
+                let fingerprint = grain_fingerprint(app_id, &title);
+                Ok(saved_ui_views2.find_by_fingerprint(&fingerprint, &audience))
+            });
 
-                try!(SavedUiViewSet::retrieve_view_info(&saved_ui_views, token));
-                Ok(())
+            let label_title = grain_title.clone();
+            let backoff = ::std::time::Duration::from_millis(SAVE_INITIAL_BACKOFF_MILLIS);
+            let sandstorm_api1 = sandstorm_api.clone();
+            let timer1 = timer.clone();
+            // A grain that looks like one we already hold is still added: the fingerprint is
+            // only the app and title, so it's up to the user to confirm that it's the same.
+            Promise::from_future(check.and_then(move |existing| {
+                let mut req = sandstorm_api.save_request();
+                req.get().get_cap().set_as_capability(sealed_ui_view.client.hook);
+                fill_in_save_label(req.get().init_label(),
+                                   collection_title.as_ref().map(|t| &t[..]),
+                                   &label_title);
+                let saved = with_timeout(&*timer, timeout, "Sandstorm to save the grain",
+                                         Promise::from_future(req.send().promise));
+                Promise::from_future(saved.and_then(move |response| {
+                    let binary_token = pry!(pry!(response.get()).get_token()).to_vec();
+                    let token = base64::ToBase64::to_base64(&binary_token[..], base64::URL_SAFE);
+
+                    let mut saved_ui_views1 = saved_ui_views.clone();
+                    let token1 = token.clone();
+                    let inserted = if session1.upgrade().is_none() {
+                        Promise::err(session_closed_error())
+                    } else {
+                        retry(timer1, SAVE_ATTEMPTS, backoff, is_transient_rpc_error, move || {
+                            Promise::from_future(::futures::future::result(
+                                saved_ui_views1.insert(token1.clone(), grain_title.clone(),
+                                                       identity_id.clone(), None, publish_at)))
+                        })
+                    };
+                    Promise::from_future(inserted.then(move |result| match result {
+                        Ok(()) => {
+                            pry!(SavedUiViewSet::retrieve_view_info(&saved_ui_views,
+                                                                    token.clone()));
+                            Promise::ok(existing.map(|existing| (token, existing)))
+                        }
+                        Err(e) => {
+                            // Don't leave behind a sturdyref that no entry refers to.
+                            let mut req = sandstorm_api1.drop_request();
+                            req.get().set_token(&binary_token);
+                            Promise::from_future(req.send().promise.then(move |_| Err(e)))
+                        }
+                    }))
+                }))
             }))
         });
 
         let context = self.context.clone();
         Promise::from_future(do_stuff.then(move |r| match r {
-            Ok(()) => {
+            Ok(duplicate) => {
                 let mut req = context.activity_request();
                 req.get().init_event().set_type(ADD_GRAIN_ACTIVITY_INDEX);
                 Promise::from_future(req.send().promise.and_then(move |_| {
-                    let mut _content = results.get().init_content();
+                    match duplicate {
+                        // The client asks the user whether to merge, with `POST api/merge/`.
+                        Some((token, existing)) => fill_in_text_content(
+                            results.get(), "application/json",
+                            &format!("{{\"token\":{},\"duplicateOf\":{}}}",
+                                     json::ToJson::to_json(&token),
+                                     json::ToJson::to_json(&existing))),
+                        None => {
+                            let mut _content = results.get().init_content();
+                        }
+                    }
                     Promise::ok(())
                 }))
             }
+            Err(ref e) if e.kind == ::capnp::ErrorKind::Overloaded => {
+                let mut error = results.get().init_client_error();
+                error.set_description_html(
+                    &escape_html(&format!("{}. Please try adding the grain again.", e.description)));
+                Promise::ok(())
+            }
             Err(e) => {
                 let mut error = results.get().init_client_error();
-                error.set_description_html(&format!("error: {:?}", e));
+                error.set_description_html(&escape_html(&format!("error: {:?}", e)));
                 Promise::ok(())
             }
         }))
     }
 
-    fn require_canonical_path(&self, path: &str) -> Result<(), Error> {
-        // Require that the path doesn't contain "." or ".." or consecutive slashes, to prevent path
-        // injection attacks.
-        //
-        // Note that such attacks wouldn't actually accomplish much since everything outside /var
-        // is a read-only filesystem anyway, containing the app package contents which are non-secret.
+    /// Finds what a (percent-encoded) request path refers to in the WebDAV view, if anything.
+    fn resolve_dav_path(&self, path: &str) -> Option<DavNode> {
+        if !self.saved_ui_views.is_enabled(Feature::WebDav) {
+            return None
+        }
+        let path = match webdav::decode_path(path.trim_right_matches('/')) {
+            Some(p) => p,
+            None => return None,
+        };
+        if path.is_empty() {
+            return Some(DavNode::Root)
+        }
+        let components: Vec<&str> = path.split('/').collect();
+        let entry_named = |folder: Option<&str>, name: &str| {
+            self.saved_ui_views.dav_entry_names(folder).into_iter()
+                .find(|&(ref n, _)| n == name)
+                .map(|(_, token)| DavNode::Entry(token))
+        };
+
+        if components[0] == "collection" {
+            match components.len() {
+                1 => Some(DavNode::Folder(None)),
+                2 if self.saved_ui_views.folder_names().contains(components[1]) => {
+                    Some(DavNode::Folder(Some(components[1].to_string())))
+                }
+                2 => entry_named(None, components[1]),
+                3 => entry_named(Some(components[1]), components[2]),
+                _ => None,
+            }
+        } else if components[0] == "var" && self.permissions.has(Permission::Manage) {
+            // The path was percent-decoded, so it can spell out "." and ".." that the check for
+            // canonical paths never saw; those could lead out of /var or into a hidden
+            // directory by another name.
+            if components[1..].iter().any(|c| c.is_empty() || *c == "." || *c == "..") {
+                return None
+            }
+            if components.len() > 1 && DAV_HIDDEN_VAR_DIRS.contains(&components[1]) {
+                return None
+            }
+            let var_dir = self.saved_ui_views.inner.borrow().config.var_dir.clone();
+            let file_path = var_dir.join(path["var".len()..].trim_left_matches('/'));
+            if file_path.exists() { Some(DavNode::File(file_path)) } else { None }
+        } else {
+            None
+        }
+    }
+
+    /// The members of a WebDAV collection, with their names. Non-collections have none.
+    fn dav_children(&self, node: &DavNode) -> Result<Vec<(DavNode, String)>, Error> {
+        let mut children = Vec::new();
+        match node {
+            &DavNode::Root => {
+                children.push((DavNode::Folder(None), "collection".to_string()));
+                if self.permissions.has(Permission::Manage) {
+                    let var_dir = self.saved_ui_views.inner.borrow().config.var_dir.clone();
+                    children.push((DavNode::File(var_dir), "var".to_string()));
+                }
+            }
+            &DavNode::Folder(ref folder) => {
+                if folder.is_none() {
+                    for name in self.saved_ui_views.folder_names() {
+                        children.push((DavNode::Folder(Some(name.clone())), name));
+                    }
+                }
+                let folder = folder.as_ref().map(|f| &f[..]);
+                for (name, token) in self.saved_ui_views.dav_entry_names(folder) {
+                    children.push((DavNode::Entry(token), name));
+                }
+            }
+            &DavNode::Entry(_) => (),
+            &DavNode::File(ref file_path) => {
+                if file_path.is_dir() {
+                    for dir_entry in try!(::std::fs::read_dir(file_path)) {
+                        let dir_entry = try!(dir_entry);
+                        let name = match dir_entry.file_name().into_string() {
+                            Ok(name) => name,
+                            Err(_) => continue,
+                        };
+                        if *file_path == self.saved_ui_views.inner.borrow().config.var_dir &&
+                            DAV_HIDDEN_VAR_DIRS.contains(&&name[..])
+                        {
+                            continue
+                        }
+                        children.push((DavNode::File(dir_entry.path()), name));
+                    }
+                }
+            }
+        }
+        Ok(children)
+    }
+
+    fn dav_resource(&self, node: &DavNode, path: String) -> Result<webdav::Resource, Error> {
+        Ok(match node {
+            &DavNode::Root | &DavNode::Folder(_) => webdav::Resource {
+                path: if path.is_empty() { path } else { format!("{}/", path) },
+                is_collection: true,
+                content_length: 0,
+                content_type: None,
+                modified: None,
+            },
+            &DavNode::Entry(ref token) => {
+                let date_added = self.saved_ui_views.inner.borrow().views.get(token)
+                    .map(|data| data.date_added);
+                webdav::Resource {
+                    path: path,
+                    is_collection: false,
+                    content_length: self.internet_shortcut(token).len() as u64,
+                    content_type: Some(INTERNET_SHORTCUT_MIME_TYPE),
+                    modified: date_added,
+                }
+            }
+            &DavNode::File(ref file_path) => {
+                let metadata = try!(::std::fs::metadata(file_path));
+                let modified = metadata.modified().ok()
+                    .and_then(|t| t.duration_since(::std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64);
+                webdav::Resource {
+                    path: if metadata.is_dir() { format!("{}/", path) } else { path },
+                    is_collection: metadata.is_dir(),
+                    content_length: metadata.len(),
+                    content_type: None,
+                    modified: modified,
+                }
+            }
+        })
+    }
 
-        for (idx, component) in path.split_terminator("/").enumerate() {
-            if component == "." || component == ".." || (component == "" && idx > 0) {
-                return Err(Error::failed(format!("non-canonical path: {:?}", path)));
+    /// Fills in the response to a WebDAV COPY or MOVE, once the transfer that `dav_transfer()`
+    /// started is done, or right away if it refused the request.
+    fn respond_to_dav_transfer(
+        transfer: Result<Promise<(), Error>, (web_session::response::ClientErrorCode, String)>,
+        mut results: ::capnp::capability::Results<web_session::response::Owned>)
+        -> Promise<(), Error>
+    {
+        match transfer {
+            Ok(transfer) => Promise::from_future(transfer.then(move |r| {
+                match r {
+                    Ok(()) => {
+                        let mut content = results.get().init_content();
+                        content.set_status_code(web_session::response::SuccessCode::Created);
+                        content.set_mime_type("text/plain");
+                        content.init_body().set_bytes(&[]);
+                    }
+                    Err(e) => {
+                        results.get().init_client_error()
+                            .set_description_html(&escape_html(&format!("{}", e))[..]);
+                    }
+                }
+                Ok(())
+            })),
+            Err((status, message)) => {
+                let mut error = results.get().init_client_error();
+                error.set_status_code(status);
+                error.set_description_html(&escape_html(&message)[..]);
+                Promise::ok(())
             }
         }
-        Ok(())
+    }
+
+    /// Starts a WebDAV COPY or MOVE of the entry or folder at `path` to `destination`. Entries
+    /// keep their file names, which come from their titles, so only the folder part of an
+    /// entry's destination may differ. Fails right away, with the status to report, if the
+    /// request doesn't map onto the collection.
+    fn dav_transfer(&mut self,
+                    path: &str,
+                    destination: &str,
+                    no_overwrite: bool,
+                    shallow: bool,
+                    is_move: bool)
+                    -> Result<Promise<(), Error>, (web_session::response::ClientErrorCode, String)>
+    {
+        use sandstorm::web_session_capnp::web_session::response::ClientErrorCode;
+
+        // Clients may send the destination as a full URL.
+        let destination = if destination.starts_with(&self.base_path[..]) {
+            &destination[self.base_path.len()..]
+        } else {
+            destination
+        }.trim_left_matches('/');
+        if let Err(e) = require_canonical_path(destination) {
+            return Err((ClientErrorCode::BadRequest, format!("{}", e)))
+        }
+        if self.resolve_dav_path(destination).is_some() {
+            return Err(if no_overwrite {
+                (ClientErrorCode::PreconditionFailed, "destination exists".into())
+            } else {
+                (ClientErrorCode::Forbidden, "replacing existing entries is not supported".into())
+            })
+        }
+
+        let unsupported = || {
+            (ClientErrorCode::Forbidden, "only entries and folders in the collection can be \
+                                          copied or moved".to_string())
+        };
+        let source = try!(webdav::decode_path(path.trim_right_matches('/')).ok_or_else(&unsupported));
+        let destination = try!(webdav::decode_path(destination.trim_right_matches('/'))
+                               .ok_or_else(&unsupported));
+        let source: Vec<&str> = source.split('/').collect();
+        let destination: Vec<&str> = destination.split('/').collect();
+        if destination.len() < 2 || destination[0] != "collection" {
+            return Err(unsupported())
+        }
+
+        let actor = self.identity_id.clone();
+        match self.resolve_dav_path(path) {
+            Some(DavNode::Entry(token)) => {
+                let folder = match destination.len() {
+                    2 => None,
+                    3 if self.saved_ui_views.folder_names().contains(destination[1]) => {
+                        Some(destination[1].to_string())
+                    }
+                    3 => return Err((ClientErrorCode::Conflict,
+                                     format!("no such folder: {}", destination[1]))),
+                    _ => return Err(unsupported()),
+                };
+                if source.last() != destination.last() {
+                    return Err((ClientErrorCode::Forbidden,
+                                "entries can't be renamed; their names come from their titles"
+                                .into()))
+                }
+
+                if is_move {
+                    Ok(Promise::from_future(::futures::future::result(
+                        self.saved_ui_views.move_entries(&[token], folder, actor))))
+                } else {
+                    Ok(self.saved_ui_views.duplicate_entry(&token, folder, actor))
+                }
+            }
+            Some(DavNode::Folder(Some(from))) => {
+                if destination.len() != 2 {
+                    return Err((ClientErrorCode::Conflict,
+                                "folders can only be placed directly in collection/".into()))
+                }
+                let to = destination[1];
+                if is_move {
+                    return Ok(Promise::from_future(::futures::future::result(
+                        self.saved_ui_views.rename_folder(&from, to, actor))))
+                }
+
+                if let Err(e) = self.saved_ui_views.create_folder(to, actor.clone()) {
+                    return Err((ClientErrorCode::Conflict, format!("{}", e)))
+                }
+                if shallow {
+                    return Ok(Promise::ok(()))
+                }
+                let copies: Vec<_> = self.saved_ui_views.dav_entry_names(Some(&from[..])).into_iter()
+                    .map(|(_, token)| {
+                        self.saved_ui_views.duplicate_entry(&token, Some(to.to_string()),
+                                                            actor.clone())
+                    })
+                    .collect();
+                Ok(Promise::from_future(::futures::future::join_all(copies).map(|_| ())))
+            }
+            Some(_) => Err(unsupported()),
+            None => Err((ClientErrorCode::NotFound, String::new())),
+        }
+    }
+
+    /// Renders an entry as a Windows-style internet shortcut, which desktop file managers on all
+    /// major platforms know how to open. Bookmarks point at their URL. A UiView can't tell us the
+    /// URL of its grain, so saved grains point back at this collection, which offers the grain
+    /// when it's opened with an "#open=" fragment. That goes through this session's own host, so
+    /// those shortcuts stop working when the session ends: they are for opening grains from a
+    /// WebDAV client, not for keeping on the desktop.
+    fn internet_shortcut(&self, token: &str) -> String {
+        let url = match self.saved_ui_views.inner.borrow().views.get(token) {
+            Some(&SavedUiViewData { bookmark: Some(ref bookmark), .. }) => bookmark.url.clone(),
+            _ => format!("{}/#open={}", self.base_path, token),
+        };
+        // A line break in the URL would start another line of the file.
+        let url: String = url.chars().filter(|c| !c.is_control()).collect();
+        format!("[InternetShortcut]\r\nURL={}\r\n", url)
+    }
+
+    fn require_csrf_token(&self, context: web_session::context::Reader) -> Result<(), Error> {
+        // A page on some other origin can make the browser send requests into this session, but
+        // it can't read our HTML, so it can't learn the token that the page embeds.
+        for header in try!(context.get_additional_headers()).iter() {
+            if try!(header.get_name()).to_lowercase() == CSRF_TOKEN_HEADER {
+                if try!(header.get_value()) == self.csrf_token {
+                    return Ok(())
+                } else {
+                    return Err(Error::failed("invalid CSRF token".into()));
+                }
+            }
+        }
+        Err(Error::failed("missing CSRF token".into()))
+    }
+
+    /// The Content-Security-Policy for the main page. Scripts, styles and avatars come from the
+    /// grain itself; grain icons come from Sandstorm's static asset host, whose name we don't
+    /// know in advance.
+    fn content_security_policy(&self) -> String {
+        let websocket_origin = if self.base_path.starts_with("https://") {
+            format!("wss://{}", &self.base_path["https://".len()..])
+        } else {
+            format!("ws://{}", self.base_path.trim_left_matches("http://"))
+        };
+        format!("default-src 'none'; script-src 'self'; style-src 'self'; \
+                 img-src 'self' https: http:; connect-src 'self' {}; form-action 'none'; \
+                 base-uri 'none'",
+                websocket_origin)
+    }
+
+    /// Serves one of the frontend's files: in a grain, the gzipped copy at the root of the
+    /// package, and in dev mode, the uncompressed build in the asset directory.
+    fn read_asset(&self,
+                  name: &str,
+                  results: web_session::GetResults,
+                  content_type: &str)
+                  -> Promise<(), Error>
+    {
+        let asset_dir = self.saved_ui_views.inner.borrow().config.asset_dir.clone();
+        match asset_dir {
+            Some(dir) => {
+                let path = dir.join(name);
+                self.read_file(&path.to_string_lossy(), results, content_type, None)
+            }
+            None => self.read_file(&format!("/{}.gz", name), results, content_type, Some("gzip")),
+        }
     }
 
     fn read_file(&self,
@@ -981,19 +7820,16 @@ impl WebSession {
 }
 
 pub struct UiView {
-    handle: ::tokio_core::reactor::Handle,
     sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
     saved_ui_views: SavedUiViewSet,
 }
 
 impl UiView {
-    fn new(handle: ::tokio_core::reactor::Handle,
-           client: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+    fn new(client: sandstorm_api::Client<::capnp::any_pointer::Owned>,
            saved_ui_views: SavedUiViewSet)
            -> UiView
     {
         UiView {
-            handle: handle,
             sandstorm_api: client,
             saved_ui_views: saved_ui_views,
         }
@@ -1010,47 +7846,97 @@ impl ui_view::Server for UiView {
 
         // Define a "write" permission, and then define roles "editor" and "viewer" where only
         // "editor" has the "write" permission. This will allow people to share read-only.
+        // The "manage" permission guards destructive whole-collection operations; only the
+        // "manager" role has it. The "comment" permission lets the "commenter" role discuss
+        // entries without changing them. Sandstorm remembers roles and permissions by index, so
+        // new ones go at the end. A manager chooses which role sharing offers first.
+        let default_role = self.saved_ui_views.inner.borrow().settings.default_role;
         {
-            let perms = view_info.borrow().init_permissions(1);
-            let mut write = perms.get(0);
-            write.set_name("write");
-            write.init_title().set_default_text("write");
+            let mut perms = view_info.borrow().init_permissions(3);
+            {
+                let mut write = perms.borrow().get(0);
+                write.set_name("write");
+                write.init_title().set_default_text("write");
+            }
+            {
+                let mut manage = perms.borrow().get(1);
+                manage.set_name("manage");
+                manage.init_title().set_default_text("manage");
+            }
+            {
+                let mut comment = perms.get(2);
+                comment.set_name("comment");
+                comment.init_title().set_default_text("comment");
+            }
         }
 
         {
-            let mut roles = view_info.borrow().init_roles(2);
+            let mut roles = view_info.borrow().init_roles(4);
             {
                 let mut editor = roles.borrow().get(0);
+                editor.set_default(default_role == Role::Editor);
                 editor.borrow().init_title().set_default_text("editor");
                 editor.borrow().init_verb_phrase().set_default_text("can edit");
-                editor.init_permissions(1).set(0, true);   // has "write" permission
+                let mut perms = editor.init_permissions(3);
+                perms.set(0, true);   // has "write" permission
+                perms.set(1, false);  // does not have "manage" permission
+                perms.set(2, true);   // has "comment" permission
             }
             {
-                let mut viewer = roles.get(1);
-                viewer.set_default(true);
+                let mut viewer = roles.borrow().get(1);
+                viewer.set_default(default_role == Role::Viewer);
                 viewer.borrow().init_title().set_default_text("viewer");
                 viewer.borrow().init_verb_phrase().set_default_text("can view");
-                viewer.init_permissions(1).set(0, false);  // does not have "write" permission
+                let mut perms = viewer.init_permissions(3);
+                perms.set(0, false);  // does not have "write" permission
+                perms.set(1, false);  // does not have "manage" permission
+                perms.set(2, false);  // does not have "comment" permission
             }
-        }
-
-        {
-            let mut event_types = view_info.init_event_types(3);
             {
-                let mut added = event_types.borrow().get(ADD_GRAIN_ACTIVITY_INDEX as u32);
-                added.set_name("add");
-                added.borrow().init_verb_phrase().set_default_text("added grain");
+                let mut manager = roles.borrow().get(2);
+                manager.set_default(default_role == Role::Manager);
+                manager.borrow().init_title().set_default_text("manager");
+                manager.borrow().init_verb_phrase().set_default_text("can manage");
+                let mut perms = manager.init_permissions(3);
+                perms.set(0, true);   // has "write" permission
+                perms.set(1, true);   // has "manage" permission
+                perms.set(2, true);   // has "comment" permission
             }
             {
-                let mut removed = event_types.borrow().get(REMOVE_GRAIN_ACTIVITY_INDEX as u32);
-                removed.set_name("remove");
-                removed.borrow().init_verb_phrase().set_default_text("removed grain");
+                let mut commenter = roles.get(3);
+                commenter.set_default(default_role == Role::Commenter);
+                commenter.borrow().init_title().set_default_text("commenter");
+                commenter.borrow().init_verb_phrase().set_default_text("can comment");
+                let mut perms = commenter.init_permissions(3);
+                perms.set(0, false);  // does not have "write" permission
+                perms.set(1, false);  // does not have "manage" permission
+                perms.set(2, true);   // has "comment" permission
+            }
+        }
+
+        {
+            // These are listed in order of ADD_GRAIN_ACTIVITY_INDEX and friends. Email digests
+            // describe changes with the same verb phrases.
+            let mut event_types =
+                view_info.borrow().init_event_types(ACTIVITY_EVENT_TYPES.len() as u32);
+            for (idx, &(name, verb_phrase)) in ACTIVITY_EVENT_TYPES.iter().enumerate() {
+                let mut event_type = event_types.borrow().get(idx as u32);
+                event_type.set_name(name);
+                event_type.init_verb_phrase().set_default_text(verb_phrase);
             }
+        }
+
+        {
+            // Tell the powerbox that we take offered UiViews, so that this grain shows up as a
+            // destination when someone shares a grain, and that we can fulfill UiView requests
+            // by handing out the grains we've collected.
+            use capnp::traits::HasTypeId;
             {
-                let mut removed = event_types.borrow().get(EDIT_DESCRIPTION_ACTIVITY_INDEX as u32);
-                removed.set_name("description");
-                removed.borrow().init_verb_phrase().set_default_text("edited description");
+                let offers = view_info.borrow().init_match_offers(1);
+                offers.get(0).init_tags(1).get(0).set_id(ui_view::Client::type_id());
             }
+            let requests = view_info.init_match_requests(1);
+            requests.get(0).init_tags(1).get(0).set_id(ui_view::Client::type_id());
         }
 
         Promise::ok(())
@@ -1072,14 +7958,18 @@ impl ui_view::Server for UiView {
         let user_info = pry!(params.get_user_info());
 
         let session = pry!(WebSession::new(
-            self.handle.clone(),
             user_info.clone(),
             pry!(params.get_context()),
             pry!(params.get_session_params().get_as()),
             self.sandstorm_api.clone(),
             self.saved_ui_views.clone()));
-        let client: web_session::Client =
+        let identity_id = session.identity_id.clone();
+        let session: web_session::Client =
             web_session::ToClient::new(session).from_server::<::capnp_rpc::Server>();
+        let request_stats = self.saved_ui_views.inner.borrow().request_stats.clone();
+        let client: web_session::Client =
+            web_session::ToClient::new(LoggedSession::new(session, identity_id, request_stats))
+            .from_server::<::capnp_rpc::Server>();
 
         // We need to do this silly dance to upcast.
         results.get().set_session(ui_session::Client { client : client.client});
@@ -1095,6 +7985,41 @@ impl ui_view::Server for UiView {
     }
 }
 
+/// The app-persistence hooks. Sandstorm calls `restore()` when someone uses a capability that
+/// we handed out before the grain last restarted, and `drop()` when the last reference to such a
+/// capability goes away. When adding a new kind of capability, add a variant to `ObjectId` and
+/// handle it here.
+impl main_view::Server<object_id::Owned> for UiView {
+    fn restore(&mut self,
+               params: main_view::RestoreParams<object_id::Owned>,
+               mut results: main_view::RestoreResults<object_id::Owned>)
+               -> Promise<(), Error>
+    {
+        let object_id = pry!(pry!(params.get()).get_object_id());
+        match pry!(object_id.which()) {
+            object_id::Which::MainView(()) => {
+                let view = UiView::new(self.sandstorm_api.clone(), self.saved_ui_views.clone());
+                let client: ui_view::Client =
+                    ui_view::ToClient::new(view).from_server::<::capnp_rpc::Server>();
+                results.get().get_cap().set_as_capability(client.client.hook);
+                Promise::ok(())
+            }
+        }
+    }
+
+    fn drop(&mut self,
+            params: main_view::DropParams<object_id::Owned>,
+            _results: main_view::DropResults<object_id::Owned>)
+            -> Promise<(), Error>
+    {
+        let object_id = pry!(pry!(params.get()).get_object_id());
+        match pry!(object_id.which()) {
+            // The main view is not backed by any state of its own, so there is nothing to release.
+            object_id::Which::MainView(()) => Promise::ok(()),
+        }
+    }
+}
+
 pub fn main() -> Result<(), Box<::std::error::Error>> {
     use tokio_core::io::Io;
     use ::std::os::unix::io::{FromRawFd, IntoRawFd};
@@ -1120,31 +8045,270 @@ pub fn main() -> Result<(), Box<::std::error::Error>> {
             ::capnp_rpc::new_promise_client(rx.map_err(|e| e.into()));
 
 
+    let config = Rc::new(Config::grain());
     let identity_map = try!(IdentityMap::new(
-        "/var/identities",
-        "/var/trash",
+        config.var_path("identities"),
+        config.var_path("trash"),
         &sandstorm_api,
         &handle));
     let saved_uiviews = try!(SavedUiViewSet::new(
-        "/var/tmp",
-        "/var/sturdyrefs",
+        config,
         &sandstorm_api,
         identity_map,
+        Rc::new(SystemClock),
+        Rc::new(ReactorTimer::new(&handle)),
         &handle));
 
 
+    let mut saved_uiviews1 = saved_uiviews.clone();
     let uiview = UiView::new(
-        handle.clone(),
         sandstorm_api,
         saved_uiviews);
 
-    let client = ui_view::ToClient::new(uiview).from_server::<::capnp_rpc::Server>();
+    let client: main_view::Client<object_id::Owned> =
+        main_view::ToClient::new(uiview).from_server::<::capnp_rpc::Server>();
 
     let mut rpc_system = RpcSystem::new(network, Some(client.client));
 
     tx.complete(rpc_system.bootstrap::<sandstorm_api::Client<::capnp::any_pointer::Owned>>(
                 ::capnp_rpc::rpc_twoparty_capnp::Side::Server).client);
 
-    try!(core.run(rpc_system));
+    let result = core.run(rpc_system);
+
+    // Sandstorm disconnects before shutting the grain down.
+    saved_uiviews1.flush_metadata();
+    try!(result);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, BookmarkData, CommentData, ProfileData, SavedUiViewData, ThumbnailData,
+                ViewInfoData, MAX_PATH_LENGTH, is_transient_rpc_error, require_canonical_path,
+                storage_error};
+    use capnp::Error;
+    use rustc_serialize::json;
+    use settings::SortOrder;
+
+    use std::collections::btree_map::BTreeMap;
+    use std::collections::btree_set::BTreeSet;
+
+    // Clients outside this repository read these messages, so a change to any of the expected
+    // strings below is a change to the protocol.
+
+    fn entry() -> SavedUiViewData {
+        SavedUiViewData {
+            title: "Notes".into(),
+            date_added: 1500000000000,
+            added_by: Some("alice".into()),
+            open_count: 2,
+            comments: Vec::new(),
+            folder: None,
+            is_collection: false,
+            bookmark: None,
+            thumbnail: None,
+            expires: None,
+            publish_at: None,
+            version: 3,
+            fingerprint: None,
+            ratings: BTreeMap::new(),
+            upvoters: BTreeSet::new(),
+            private: false,
+        }
+    }
+
+    const ENTRY: &'static str =
+        "{\"title\":\"Notes\",\"dateAdded\": \"1500000000000\",\"addedBy\":\"alice\",\
+         \"openCount\":2,\"comments\":[],\"folder\":null,\"isCollection\":false,\
+         \"bookmark\":null,\"thumbnailUpdated\":null,\"expires\":null,\"version\":3,\
+         \"ratings\":{},\"rating\":null,\"upvoters\":[],\"upvotes\":0,\"private\":false,\
+         \"publishAt\":null}";
+
+    fn comment() -> CommentData {
+        CommentData { author: Some("bob".into()), date: 1500000000001, text: "Hi".into() }
+    }
+
+    const COMMENT: &'static str = "{\"author\":\"bob\",\"date\":\"1500000000001\",\"text\":\"Hi\"}";
+
+    #[test]
+    fn entry_data() {
+        assert_eq!(entry().to_json(), ENTRY);
+
+        let mut data = entry();
+        data.comments.push(comment());
+        data.folder = Some("Work".into());
+        data.bookmark = Some(BookmarkData {
+            url: "https://example.com/".into(),
+            favicon_url: None,
+        });
+        data.thumbnail = Some(ThumbnailData { mime_type: "image/png".into(), updated: 5 });
+        data.expires = Some(6);
+        data.publish_at = Some(7);
+        data.ratings.insert("bob".into(), 4);
+        data.ratings.insert("carol".into(), 5);
+        data.upvoters.insert("bob".into());
+        data.private = true;
+        assert_eq!(data.to_json(),
+                   format!("{{\"title\":\"Notes\",\"dateAdded\": \"1500000000000\",\
+                            \"addedBy\":\"alice\",\"openCount\":2,\"comments\":[{}],\
+                            \"folder\":\"Work\",\"isCollection\":false,\
+                            \"bookmark\":{{\"url\":\"https://example.com/\",\"faviconUrl\":null}},\
+                            \"thumbnailUpdated\":5,\"expires\":6,\"version\":3,\
+                            \"ratings\":{{\"bob\":4,\"carol\":5}},\
+                            \"rating\":{{\"count\":2,\"average\":4.50}},\"upvoters\":[\"bob\"],\
+                            \"upvotes\":1,\"private\":true,\"publishAt\":7}}",
+                           COMMENT));
+    }
+
+    #[test]
+    fn actions() {
+        let failure = "unreachable".to_string();
+        let cases = vec![
+            (Action::Insert { token: "t1".into(), data: entry(), is_new: true },
+             format!("{{\"insert\":{{\"token\":\"t1\",\"data\":{},\"isNew\":true }} }}", ENTRY)),
+            (Action::Remove {
+                token: "t1".into(), actor: Some("alice".into()), actor_name: None, time: 9,
+             },
+             "{\"remove\":{\"token\":\"t1\",\"actor\":\"alice\",\"actorName\":null,\"time\":9}}"
+                 .into()),
+            (Action::ViewInfo {
+                token: "t1".into(),
+                data: Ok(ViewInfoData {
+                    app_id: "app".into(),
+                    app_title: "Etherpad".into(),
+                    grain_icon_url: "https://example.com/icon.png".into(),
+                    is_collection: false,
+                }),
+             },
+             "{\"viewInfo\":{\"token\":\"t1\",\"data\":{\"appTitle\":\"Etherpad\",\
+              \"grainIconUrl\":\"https://example.com/icon.png\"} } }".into()),
+            (Action::ViewInfo { token: "t1".into(), data: Err(Error::failed(failure.clone())) },
+             format!("{{\"viewInfo\":{{\"token\":\"t1\",\"failed\": {} }} }}",
+                     json::ToJson::to_json(&format!("{}", Error::failed(failure))))),
+            (Action::Update { token: "t1".into(), unavailable: Some("gone".into()), data: None },
+             "{\"update\":{\"token\":\"t1\",\"unavailable\":\"gone\"}}".into()),
+            (Action::Update { token: "t1".into(), unavailable: None, data: None },
+             "{\"update\":{\"token\":\"t1\",\"unavailable\":null}}".into()),
+            (Action::Update { token: "t1".into(), unavailable: None, data: Some(entry()) },
+             format!("{{\"update\":{{\"token\":\"t1\",\"data\":{}}}}}", ENTRY)),
+            (Action::CanWrite(true), "{\"canWrite\":true}".into()),
+            (Action::CanComment(false), "{\"canComment\":false}".into()),
+            (Action::UserId(Some("alice".into())), "{\"userId\":\"alice\"}".into()),
+            (Action::UserId(None), "{\"userId\":null}".into()),
+            (Action::Description("a \"b\"".into()), "{\"description\":\"a \\\"b\\\"\"}".into()),
+            (Action::User {
+                id: "alice".into(),
+                data: ProfileData {
+                    display_name: "Alice".into(),
+                    picture_url: "https://example.com/alice.png".into(),
+                },
+             },
+             "{\"user\":{\"id\":\"alice\", \"data\":{\"pictureUrl\":\
+              \"https://example.com/alice.png\", \"displayName\":\"Alice\"} }}".into()),
+            (Action::Comment { token: "t1".into(), data: comment(), version: 4 },
+             format!("{{\"comment\":{{\"token\":\"t1\",\"data\":{},\"version\":4 }} }}",
+                     COMMENT)),
+            (Action::Rating {
+                token: "t1".into(), rater: "bob".into(), stars: Some(4), summary: Some((1, 4.0)),
+             },
+             "{\"rating\":{\"token\":\"t1\",\"rater\":\"bob\",\"stars\":4,\
+              \"summary\":{\"count\":1,\"average\":4.00}}}".into()),
+            (Action::Rating { token: "t1".into(), rater: "bob".into(), stars: None, summary: None },
+             "{\"rating\":{\"token\":\"t1\",\"rater\":\"bob\",\"stars\":null,\"summary\":null}}"
+                 .into()),
+            (Action::Upvote { token: "t1".into(), voter: "bob".into(), up: true, upvotes: 1 },
+             "{\"upvote\":{\"token\":\"t1\",\"voter\":\"bob\",\"up\":true,\"upvotes\":1}}".into()),
+            (Action::Folders(vec!["Work".into(), "Home".into()]),
+             "{\"folders\":[\"Work\",\"Home\"]}".into()),
+            (Action::Move {
+                tokens: vec!["t1".into(), "t2".into()],
+                folder: Some("Work".into()),
+                versions: vec![4, 5],
+             },
+             "{\"move\":{\"tokens\":[\"t1\",\"t2\"],\"folder\":\"Work\",\"versions\":[4,5]}}"
+                 .into()),
+            (Action::Move { tokens: vec!["t1".into()], folder: None, versions: vec![4] },
+             "{\"move\":{\"tokens\":[\"t1\"],\"folder\":null,\"versions\":[4]}}".into()),
+            (Action::Cleared, "{\"cleared\":true}".into()),
+            (Action::SortOrder(SortOrder::Title), "{\"sortOrder\":\"title\"}".into()),
+            (Action::Locked(true), "{\"locked\":true}".into()),
+            (Action::Maintenance(false), "{\"maintenance\":false}".into()),
+            (Action::CommandFailed { id: None, reason: "missing".into() },
+             "{\"commandFailed\":{\"id\":null,\"reason\":\"missing\"}}".into()),
+            (Action::CommandFailed {
+                id: Some(json::Json::String("c1".into())), reason: "locked".into(),
+             },
+             "{\"commandFailed\":{\"id\":\"c1\",\"reason\":\"locked\"}}".into()),
+            (Action::CommandResult {
+                id: json::Json::U64(7),
+                outcome: Ok(vec![("t1".into(), Some(entry())), ("t2".into(), None)]),
+             },
+             format!("{{\"commandResult\":{{\"id\":7,\"ok\":true,\"entries\":[\
+                      {{\"token\":\"t1\",\"data\":{}}},{{\"token\":\"t2\",\"data\":null}}]}}}}",
+                     ENTRY)),
+            (Action::CommandResult {
+                id: json::Json::String("c1".into()),
+                outcome: Err((Some("conflict"), "stale version".into())),
+             },
+             "{\"commandResult\":{\"id\":\"c1\",\"ok\":false,\"reason\":\"conflict\",\
+              \"error\":\"stale version\"}}".into()),
+            (Action::Reload, "{\"reload\":true}".into()),
+            (Action::Revision(12), "{\"revision\":12}".into()),
+            (Action::Batch(vec![Action::Cleared, Action::Locked(false)]),
+             "{\"batch\":[{\"cleared\":true},{\"locked\":false}]}".into()),
+            (Action::Summary { count: 3, revision: 12, title: "Reading".into() },
+             "{\"summary\":{\"count\":3,\"revision\":12,\"title\":\"Reading\"}}".into()),
+            (Action::Activity("{\"type\":\"insert\"}".into()),
+             "{\"activity\":{\"type\":\"insert\"}}".into()),
+            (Action::Presence(2), "{\"presence\":{\"connected\":2}}".into()),
+        ];
+        for (action, expected) in cases {
+            assert_eq!(action.to_json(), expected);
+        }
+    }
+
+    #[test]
+    fn canonical_paths() {
+        let long: String = ::std::iter::repeat('a').take(MAX_PATH_LENGTH + 1).collect();
+        let cases: Vec<(&str, bool)> = vec![
+            ("", true),
+            ("index.html", true),
+            ("sturdyref/abc/thumbnail", true),
+            ("var/thumbnails/", true),
+            ("search?q=../..", true),
+            ("a%20b", true),
+            (".", false),
+            ("..", false),
+            ("a/./b", false),
+            ("a/../b", false),
+            ("a//b", false),
+            ("a\\b", false),
+            ("a\0b", false),
+            ("%2e%2e/b", false),
+            ("a/%2E", false),
+            ("a%2fb", false),
+            ("a%5cb", false),
+            ("a%00b", false),
+            (&long[..], false),
+        ];
+        for (path, accepted) in cases {
+            assert_eq!(require_canonical_path(path).is_ok(), accepted, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn storage_errors() {
+        use std::io::ErrorKind;
+        let cases = vec![
+            (ErrorKind::Interrupted, true),
+            (ErrorKind::WouldBlock, true),
+            (ErrorKind::TimedOut, true),
+            (ErrorKind::NotFound, false),
+            (ErrorKind::PermissionDenied, false),
+        ];
+        for (kind, transient) in cases {
+            let error = storage_error(::std::io::Error::new(kind, "oops"));
+            assert_eq!(is_transient_rpc_error(&error), transient, "{:?}", kind);
+        }
+    }
+}
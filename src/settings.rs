@@ -0,0 +1,163 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Collection-wide preferences that managers can change, stored as a CollectionSettings message.
+
+use capnp::Error;
+use collections_capnp::collection_settings;
+use config::FeatureFlags;
+
+pub use collections_capnp::collection_settings::{Role, SortOrder};
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The order in which listings and snapshots present entries.
+    pub sort_order: SortOrder,
+
+    /// If non-empty, only grains of these apps may be added.
+    pub allowed_app_ids: Vec<String>,
+
+    /// Powerbox tag IDs that the "add grain" request asks for in addition to UiView.
+    pub required_tag_ids: Vec<u64>,
+
+    /// Whether the collection is read-only.
+    pub locked: bool,
+
+    /// Which optional subsystems are turned on.
+    pub features: FeatureFlags,
+
+    /// The role that new shares get unless the sharer picks another.
+    pub default_role: Role,
+
+    /// How much of what is no longer needed the collection keeps.
+    pub retention: Retention,
+}
+
+/// Limits on the trash, the journal and the replay buffer. None means the default: keep
+/// everything, except for the replay buffer, which has a built-in capacity.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    pub trash_days: Option<u32>,
+    pub journal_entries: Option<u32>,
+    pub replay_broadcasts: Option<u32>,
+}
+
+/// Settings store "no limit" as 0.
+fn limit(value: u32) -> Option<u32> {
+    if value == 0 { None } else { Some(value) }
+}
+
+impl Settings {
+    pub fn load<P>(path: P) -> Result<Settings, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let mut f = match ::std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
+                return Ok(Settings {
+                    sort_order: SortOrder::DateAdded,
+                    allowed_app_ids: Vec::new(),
+                    required_tag_ids: Vec::new(),
+                    locked: false,
+                    features: FeatureFlags::default(),
+                    default_role: Role::Viewer,
+                    retention: Retention::default(),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let message = try!(::capnp::serialize::read_message(&mut f, Default::default()));
+        let settings: collection_settings::Reader = try!(message.get_root());
+        let mut allowed_app_ids = Vec::new();
+        for app_id in try!(settings.get_allowed_app_ids()).iter() {
+            allowed_app_ids.push(try!(app_id).to_string());
+        }
+        let mut features = FeatureFlags::default();
+        for flag in try!(settings.get_feature_flags()).iter() {
+            features.overrides.insert(try!(flag.get_name()).to_string(), flag.get_enabled());
+        }
+        Ok(Settings {
+            sort_order: try!(settings.get_sort_order()),
+            allowed_app_ids: allowed_app_ids,
+            required_tag_ids: try!(settings.get_required_tag_ids()).iter().collect(),
+            locked: settings.get_locked(),
+            features: features,
+            default_role: try!(settings.get_default_role()),
+            retention: Retention {
+                trash_days: limit(settings.get_trash_retention_days()),
+                journal_entries: limit(settings.get_journal_limit()),
+                replay_broadcasts: limit(settings.get_replay_capacity()),
+            },
+        })
+    }
+
+    /// Writes the settings to `path`, replacing the previous file atomically.
+    pub fn save<P>(&self, path: P) -> Result<(), Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let mut settings = message.init_root::<collection_settings::Builder>();
+            settings.set_sort_order(self.sort_order);
+            settings.set_locked(self.locked);
+            settings.set_default_role(self.default_role);
+            settings.set_trash_retention_days(self.retention.trash_days.unwrap_or(0));
+            settings.set_journal_limit(self.retention.journal_entries.unwrap_or(0));
+            settings.set_replay_capacity(self.retention.replay_broadcasts.unwrap_or(0));
+            {
+                let mut list = settings.borrow().init_allowed_app_ids(
+                    self.allowed_app_ids.len() as u32);
+                for (idx, app_id) in self.allowed_app_ids.iter().enumerate() {
+                    list.set(idx as u32, app_id);
+                }
+            }
+            {
+                let mut list = settings.borrow().init_required_tag_ids(
+                    self.required_tag_ids.len() as u32);
+                for (idx, &tag_id) in self.required_tag_ids.iter().enumerate() {
+                    list.set(idx as u32, tag_id);
+                }
+            }
+            let mut list = settings.init_feature_flags(self.features.overrides.len() as u32);
+            for (idx, (name, &enabled)) in self.features.overrides.iter().enumerate() {
+                let mut flag = list.borrow().get(idx as u32);
+                flag.set_name(name);
+                flag.set_enabled(enabled);
+            }
+        }
+
+        let temp_path = path.as_ref().with_extension("uploading");
+        {
+            let mut writer = try!(::std::fs::File::create(&temp_path));
+            try!(::capnp::serialize::write_message(&mut writer, &message));
+            try!(writer.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, path));
+        Ok(())
+    }
+
+    /// Whether grains of the given app may be added to the collection.
+    pub fn allows_app(&self, app_id: &str) -> bool {
+        self.allowed_app_ids.is_empty() || self.allowed_app_ids.iter().any(|id| id == app_id)
+    }
+}
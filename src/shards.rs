@@ -0,0 +1,93 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Layout of the per-token metadata files, which live in subdirectories named by the first two
+//! characters of the token (e.g. `sturdyrefs/Ab/AbCd...`) so that no one directory gets huge.
+//! Older grains kept every file directly in `sturdyrefs/`; `migrate()` moves them into place.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the subdirectory holding `token`.
+fn shard_name(token: &str) -> String {
+    token.chars().take(2).collect()
+}
+
+/// Where the metadata of `token` lives within `dir`.
+pub fn token_path(dir: &Path, token: &str) -> PathBuf {
+    dir.join(shard_name(token)).join(token)
+}
+
+/// Like `token_path()`, but first creates the subdirectory if needed.
+pub fn create_token_path(dir: &Path, token: &str) -> io::Result<PathBuf> {
+    let shard = dir.join(shard_name(token));
+    try!(::std::fs::create_dir_all(&shard));
+    Ok(shard.join(token))
+}
+
+/// (file name, path) of every file in `dir`, in both the sharded and the flat layout. Files
+/// whose names aren't UTF-8 are skipped.
+pub fn list(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut result = Vec::new();
+    for dir_entry in try!(::std::fs::read_dir(dir)) {
+        let dir_entry = try!(dir_entry);
+        let name = match dir_entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                println!("skipping file with non-UTF-8 name {:?}", name);
+                continue
+            }
+        };
+        if try!(dir_entry.file_type()).is_dir() {
+            for inner_entry in try!(::std::fs::read_dir(dir_entry.path())) {
+                let inner_entry = try!(inner_entry);
+                match inner_entry.file_name().into_string() {
+                    Ok(inner_name) => result.push((inner_name, inner_entry.path())),
+                    Err(inner_name) => {
+                        println!("skipping file with non-UTF-8 name {:?}", inner_name)
+                    }
+                }
+            }
+        } else {
+            result.push((name, dir_entry.path()));
+        }
+    }
+    Ok(result)
+}
+
+/// Moves files left in `dir` by the flat layout into their subdirectories, returning how many
+/// were moved. Each move is a rename, so if we are interrupted, the next run picks up the rest.
+/// Temporary ".uploading" files are left where they are.
+pub fn migrate(dir: &Path) -> io::Result<usize> {
+    let mut moved = 0;
+    for dir_entry in try!(::std::fs::read_dir(dir)) {
+        let dir_entry = try!(dir_entry);
+        if try!(dir_entry.file_type()).is_dir() { continue }
+        let name = match dir_entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name.ends_with(".uploading") { continue }
+        try!(::std::fs::rename(dir_entry.path(), try!(create_token_path(dir, &name))));
+        moved += 1;
+    }
+    Ok(moved)
+}
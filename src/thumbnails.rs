@@ -0,0 +1,123 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Images that editors upload to stand in for an entry's app icon, for grains whose icons don't
+//! tell them apart. Each image is stored as raw bytes in a file named by the entry's token; its
+//! MIME type lives in the entry's metadata.
+
+use capnp::Error;
+
+/// Thumbnails larger than this are rejected.
+pub const MAX_THUMBNAIL_BYTES: usize = 256 * 1024;
+
+/// Checks that `content` really is an image of type `mime_type`, and one that we're willing to
+/// serve from our origin. SVG is not accepted, because it can carry script.
+pub fn validate(mime_type: &str, content: &[u8]) -> Result<(), Error> {
+    if content.len() > MAX_THUMBNAIL_BYTES {
+        return Err(Error::failed(
+            format!("thumbnails may be at most {} bytes", MAX_THUMBNAIL_BYTES)))
+    }
+    let matches = match mime_type {
+        "image/png" => content.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => content.starts_with(b"\xff\xd8\xff"),
+        "image/gif" => content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a"),
+        "image/webp" => {
+            content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == b"WEBP"
+        }
+        _ => return Err(Error::failed(format!("unsupported thumbnail type {}", mime_type))),
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::failed(format!("thumbnail is not a valid {}", mime_type)))
+    }
+}
+
+pub struct Thumbnails {
+    directory: ::std::path::PathBuf,
+}
+
+fn check_token(token: &str) -> Result<(), Error> {
+    let valid = !token.is_empty() &&
+        token.chars().all(|c| c.is_digit(36) || c == '-' || c == '_' || c == '=');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::failed(format!("invalid token {}", token)))
+    }
+}
+
+impl Thumbnails {
+    pub fn new<P>(directory: P) -> Result<Thumbnails, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        try!(::std::fs::create_dir_all(&directory));
+        Ok(Thumbnails { directory: directory.as_ref().to_path_buf() })
+    }
+
+    pub fn get(&self, token: &str) -> Result<Option<Vec<u8>>, Error> {
+        use std::io::Read;
+        try!(check_token(token));
+        let mut f = match ::std::fs::File::open(self.directory.join(token)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut content = Vec::new();
+        try!(f.read_to_end(&mut content));
+        Ok(Some(content))
+    }
+
+    pub fn put(&self, token: &str, content: &[u8]) -> Result<(), Error> {
+        use std::io::Write;
+        try!(check_token(token));
+        let path = self.directory.join(token);
+        let temp_path = path.with_extension("uploading");
+        {
+            let mut writer = try!(::std::fs::File::create(&temp_path));
+            try!(writer.write_all(content));
+            try!(writer.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, path));
+        Ok(())
+    }
+
+    /// The size in bytes of the thumbnail of `token`, or 0 if it has none.
+    pub fn size(&self, token: &str) -> Result<u64, Error> {
+        try!(check_token(token));
+        match ::std::fs::metadata(self.directory.join(token)) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn remove(&self, token: &str) -> Result<(), Error> {
+        try!(check_token(token));
+        match ::std::fs::remove_file(self.directory.join(token)) {
+            Err(ref e) if e.kind() != ::std::io::ErrorKind::NotFound => {
+                Err(Error::failed(format!("{}", e)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
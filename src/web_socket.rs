@@ -26,6 +26,7 @@ use std::rc::Rc;
 use sandstorm::web_session_capnp::web_session::web_socket_stream;
 use futures::{Future};
 use futures::future::{Loop, loop_fn};
+use clock::Timer;
 
 #[repr(u8)]
 pub enum OpCode {
@@ -84,25 +85,23 @@ pub trait MessageHandler {
 }
 
 fn do_ping_pong(client_stream: web_socket_stream::Client,
-                handle: ::tokio_core::reactor::Handle,
+                timer: Rc<Timer>,
                 awaiting_pong: Rc<Cell<bool>>) -> Promise<(), Error>
 {
-    Promise::from_future(loop_fn((client_stream, handle, awaiting_pong), move |(client_stream, handle, awaiting_pong)| {
+    Promise::from_future(loop_fn((client_stream, timer, awaiting_pong), move |(client_stream, timer, awaiting_pong)| {
         let mut req = client_stream.send_bytes_request();
         req.get().set_message(&[0x89, 0]); // PING
         let promise = req.send().promise;
         awaiting_pong.set(true);
         promise.then(move |_| {
-            let timeout = pry!(::tokio_core::reactor::Timeout::new(
-                ::std::time::Duration::new(10, 0),
-                &handle));
-            Promise::from_future(timeout.map_err(Into::into).and_then(move |_| {
+            let timeout = timer.after(::std::time::Duration::new(10, 0));
+            timeout.and_then(move |_| {
                 if awaiting_pong.get() {
                     Err(Error::failed("pong not received within 10 seconds".into()))
                 } else {
-                    Ok(Loop::Continue((client_stream, handle, awaiting_pong)))
+                    Ok(Loop::Continue((client_stream, timer, awaiting_pong)))
                 }
-            }))
+            })
         })
     }))
 }
@@ -276,13 +275,13 @@ pub struct Adapter<T> where T: MessageHandler {
 impl <T> Adapter<T> where T: MessageHandler {
     pub fn new(handler: T,
                client_stream: web_socket_stream::Client,
-               reactor_handle: ::tokio_core::reactor::Handle,
+               timer: Rc<Timer>,
                mut task_handle: ::multipoll::PollerHandle<(), Error>)
                -> Adapter<T> {
         let awaiting = Rc::new(Cell::new(false));
         let ping_pong_promise = Promise::from_future(task_handle.eagerly_evaluate(do_ping_pong(
             client_stream.clone(),
-            reactor_handle,
+            timer,
             awaiting.clone()
         ).then(|r| match r {
             Ok(_) => Ok(()),
@@ -0,0 +1,139 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Pieces for answering WebDAV requests: the multistatus document that PROPFIND returns, and the
+//! helpers for naming and dating the resources in it.
+
+use url::percent_encoding;
+
+/// One entry in a PROPFIND response.
+pub struct Resource {
+    /// Path relative to the grain root, percent-decoded, with a trailing slash for collections.
+    pub path: String,
+    pub is_collection: bool,
+    pub content_length: u64,
+    pub content_type: Option<&'static str>,
+
+    /// Milliseconds since the epoch.
+    pub modified: Option<u64>,
+}
+
+/// Renders a 207 Multi-Status body listing `resources`. `base_url` is the URL at which the grain
+/// root is mapped, without a trailing slash.
+pub fn multistatus(base_url: &str, resources: &[Resource]) -> String {
+    let mut result = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+    for resource in resources {
+        let name = resource.path.trim_right_matches('/').rsplit('/').next().unwrap_or("");
+        result.push_str("<D:response><D:href>");
+        result.push_str(&escape_xml(&format!("{}/{}", base_url, encode_path(&resource.path))));
+        result.push_str("</D:href><D:propstat><D:prop>");
+        result.push_str(&format!("<D:displayname>{}</D:displayname>", escape_xml(name)));
+        if resource.is_collection {
+            result.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            result.push_str("<D:resourcetype/>");
+            result.push_str(&format!("<D:getcontentlength>{}</D:getcontentlength>",
+                                     resource.content_length));
+        }
+        if let Some(content_type) = resource.content_type {
+            result.push_str(&format!("<D:getcontenttype>{}</D:getcontenttype>", content_type));
+        }
+        if let Some(modified) = resource.modified {
+            result.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>",
+                                     http_date(modified)));
+        }
+        result.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>");
+        result.push_str("</D:response>\n");
+    }
+    result.push_str("</D:multistatus>\n");
+    result
+}
+
+/// Percent-encodes each component of `path`, leaving the slashes between them alone.
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|component| percent_encoding::utf8_percent_encode(
+            component, percent_encoding::PATH_SEGMENT_ENCODE_SET).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Percent-decodes each component of `path`. Returns None if the result is not UTF-8.
+pub fn decode_path(path: &str) -> Option<String> {
+    let bytes: Vec<u8> = percent_encoding::percent_decode(path.as_bytes()).collect();
+    String::from_utf8(bytes).ok()
+}
+
+/// Turns a title into something that file managers will accept as a file name.
+pub fn file_name(title: &str) -> String {
+    let name: String = title.trim().chars()
+        .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect();
+    if name.is_empty() || name == "." || name == ".." {
+        "untitled".into()
+    } else {
+        name
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Formats a time as an RFC 1123 date, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn http_date(millis: u64) -> String {
+    const DAYS: [&'static str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                                        "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let seconds = millis / 1000;
+    let days = seconds / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+            (seconds % 86400) / 3600, (seconds % 3600) / 60, seconds % 60)
+}
+
+/// Converts days since 1970-01-01 to (year, month, day), per Howard Hinnant's `civil_from_days`.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,180 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Outbound webhook notifications. A manager grants the grain an ApiSession for some URL
+//! through the powerbox; we keep its sturdyref in a small capnp file and POST a JSON payload
+//! to it whenever the collection changes, retrying failed deliveries with exponential backoff.
+
+use capnp::capability::Promise;
+use capnp::Error;
+use futures::Future;
+use futures::future::{Loop, loop_fn};
+use std::rc::Rc;
+
+use clock::Timer;
+use collections_capnp::webhook_config;
+use sandstorm::grain_capnp::sandstorm_api;
+use sandstorm::web_session_capnp::web_session;
+
+/// How many times we try to deliver each payload before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry. Each further retry waits twice as long as the previous one.
+const INITIAL_BACKOFF_MILLIS: u64 = 1000;
+
+struct Target {
+    token: Vec<u8>,
+    url: String,
+}
+
+pub struct Webhooks {
+    config_path: ::std::path::PathBuf,
+    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+    timer: Rc<Timer>,
+    target: Option<Target>,
+}
+
+impl Webhooks {
+    pub fn new<P>(config_path: P,
+                  sandstorm_api: &sandstorm_api::Client<::capnp::any_pointer::Owned>,
+                  timer: Rc<Timer>) -> Result<Webhooks, Error>
+        where P: AsRef<::std::path::Path>
+    {
+        let target = match ::std::fs::File::open(&config_path) {
+            Ok(mut f) => {
+                let message = try!(::capnp::serialize::read_message(&mut f, Default::default()));
+                let config: webhook_config::Reader = try!(message.get_root());
+                Some(Target {
+                    token: try!(config.get_token()).to_vec(),
+                    url: try!(config.get_url()).into(),
+                })
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Webhooks {
+            config_path: config_path.as_ref().to_path_buf(),
+            sandstorm_api: sandstorm_api.clone(),
+            timer: timer,
+            target: target,
+        })
+    }
+
+    /// The URL that notifications are currently sent to, if any.
+    pub fn url(&self) -> Option<&str> {
+        self.target.as_ref().map(|t| &t.url[..])
+    }
+
+    /// Starts sending notifications to the ApiSession saved under `token`. Returns the token of
+    /// the previous webhook, which the caller should drop.
+    pub fn set(&mut self, token: Vec<u8>, url: String) -> Result<Option<Vec<u8>>, Error> {
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let mut config = message.init_root::<webhook_config::Builder>();
+            config.set_token(&token);
+            config.set_url(&url);
+        }
+
+        let mut temp_path = self.config_path.clone();
+        temp_path.set_extension("uploading");
+        {
+            let mut writer = try!(::std::fs::File::create(&temp_path));
+            try!(::capnp::serialize::write_message(&mut writer, &message));
+            try!(writer.sync_all());
+        }
+        try!(::std::fs::rename(temp_path, &self.config_path));
+
+        let old = self.target.take();
+        self.target = Some(Target { token: token, url: url });
+        Ok(old.map(|t| t.token))
+    }
+
+    /// Stops sending notifications. Returns the token of the removed webhook, which the caller
+    /// should drop.
+    pub fn clear(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if let Err(e) = ::std::fs::remove_file(&self.config_path) {
+            if e.kind() != ::std::io::ErrorKind::NotFound {
+                return Err(e.into())
+            }
+        }
+        Ok(self.target.take().map(|t| t.token))
+    }
+
+    /// Delivers `payload`, a JSON document, to the configured webhook. Resolves once the payload
+    /// has been accepted, or fails once we've run out of attempts.
+    pub fn notify(&self, payload: String) -> Promise<(), Error> {
+        let token = match self.target {
+            Some(ref target) => target.token.clone(),
+            None => return Promise::ok(()),
+        };
+        let sandstorm_api = self.sandstorm_api.clone();
+        let timer = self.timer.clone();
+
+        Promise::from_future(loop_fn(0, move |attempt| {
+            let timer = timer.clone();
+            deliver(&sandstorm_api, &token, &payload).then(move |result| match result {
+                Ok(()) => Promise::ok(Loop::Break(())),
+                Err(ref e) if attempt + 1 >= MAX_ATTEMPTS => {
+                    Promise::err(Error::failed(format!(
+                        "giving up on webhook delivery after {} attempts: {}", MAX_ATTEMPTS, e)))
+                }
+                Err(_) => {
+                    let delay = ::std::time::Duration::from_millis(INITIAL_BACKOFF_MILLIS << attempt);
+                    Promise::from_future(timer.after(delay).map(move |()| Loop::Continue(attempt + 1)))
+                }
+            })
+        }))
+    }
+}
+
+fn deliver(sandstorm_api: &sandstorm_api::Client<::capnp::any_pointer::Owned>,
+           token: &[u8],
+           payload: &str) -> Promise<(), Error>
+{
+    let payload = payload.to_string();
+    let mut req = sandstorm_api.restore_request();
+    req.get().set_token(token);
+    Promise::from_future(req.send().promise.and_then(move |response| {
+        // ApiSession extends WebSession, and we only need the WebSession methods.
+        let session: web_session::Client =
+            pry!(pry!(response.get()).get_cap().get_as_capability());
+        let mut req = session.post_request();
+        {
+            let mut params = req.get();
+            params.set_path("");
+            {
+                let mut content = params.borrow().init_content();
+                content.set_mime_type("application/json");
+                content.set_content(payload.as_bytes());
+            }
+            params.init_context();
+        }
+        Promise::from_future(req.send().promise.and_then(|response| {
+            use sandstorm::web_session_capnp::web_session::response::Which;
+            match try!(try!(response.get()).which()) {
+                Which::Content(_) | Which::NoContent(_) | Which::Redirect(_) => Ok(()),
+                _ => Err(Error::failed("webhook endpoint returned an error".into())),
+            }
+        }))
+    }))
+}